@@ -0,0 +1,92 @@
+//! Compares the lookups of the hash table backend (`TableStateMachine`) and the sorted
+//! slice backend (`CompactTableStateMachine`) on tables of several sizes, with cheap keys
+//! (`u32`) and expensive-to-hash keys (`String`).
+//! Run with `cargo bench --bench compact`.
+//!
+//! As measured, the sorted slice is on par with or slightly faster than the hash table
+//! only on tiny tables (around ten states) with cheap keys. The hash table wins as the table
+//! grows since the binary search grows logarithmically, and on keys sharing long prefixes
+//! since every comparison walks the prefix. The sorted slice still holds no buckets,
+//! so it is smaller in memory.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use statemachine_rs::machine::{builder::TableStateMachineBuilder, StateMachine};
+
+const LOOKUPS: usize = 1_000_000;
+
+fn measure<State, Input: Clone>(
+    sm: &impl StateMachine<State, Input>,
+    inputs: &[Input],
+) -> Duration {
+    let start = Instant::now();
+    for input in inputs.iter().cycle().take(LOOKUPS) {
+        black_box(sm.consume(black_box(input.clone())));
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, size: usize, elapsed: Duration) {
+    println!(
+        "{:>14} ({:>5} states): {:>10.2?} ({:.2} ns/consume)",
+        name,
+        size,
+        elapsed,
+        elapsed.as_nanos() as f64 / LOOKUPS as f64
+    );
+}
+
+fn bench_u32(size: u32) {
+    // a cycle through every state with two inputs
+    let build = || {
+        TableStateMachineBuilder::start()
+            .initial_state(0)
+            .extend_transitions(
+                (0..size).flat_map(|state| vec![(state, 0, (state + 1) % size), (state, 1, 0)]),
+            )
+            .build()
+            .unwrap()
+    };
+    let inputs: Vec<u32> = (0..64).map(|i| u32::from(i % 16 == 15)).collect();
+    report("hash u32", size as usize, measure(&build(), &inputs));
+    report(
+        "compact u32",
+        size as usize,
+        measure(&build().compact(), &inputs),
+    );
+}
+
+fn bench_string(size: u32) {
+    let name = |state: u32| format!("a-fairly-long-state-name-{}", state);
+    let build = || {
+        TableStateMachineBuilder::start()
+            .initial_state(name(0))
+            .extend_transitions((0..size).flat_map(|state| {
+                vec![
+                    (name(state), "next".to_string(), name((state + 1) % size)),
+                    (name(state), "rewind".to_string(), name(0)),
+                ]
+            }))
+            .build()
+            .unwrap()
+    };
+    let inputs: Vec<String> = (0..64)
+        .map(|i| if i % 16 == 15 { "rewind" } else { "next" }.to_string())
+        .collect();
+    report("hash String", size as usize, measure(&build(), &inputs));
+    report(
+        "compact String",
+        size as usize,
+        measure(&build().compact(), &inputs),
+    );
+}
+
+fn main() {
+    for size in [8, 64, 4096] {
+        bench_u32(size);
+        bench_string(size);
+    }
+}
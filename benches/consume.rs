@@ -0,0 +1,47 @@
+//! Compares the throughput of `consume` on the fast path, which is taken when nothing
+//! but the transition has to be called, and the full path forced by a no-op enter hook.
+//! Run with `cargo bench --bench consume`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use statemachine_rs::machine::{
+    builder::{BasicStateMachineBuilder, StateMachineBuilder},
+    StateMachine,
+};
+
+const TRANSITIONS: u32 = 1_000_000;
+
+fn measure(sm: &impl StateMachine<u32, u32>) -> Duration {
+    let start = Instant::now();
+    for input in 0..TRANSITIONS {
+        black_box(sm.consume(black_box(input)));
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let transition = |state: &u32, input: u32| state.wrapping_mul(31).wrapping_add(input) % 1024;
+    let fast = BasicStateMachineBuilder::start()
+        .initial_state(0)
+        .transition(transition)
+        .build()
+        .unwrap();
+    let full = BasicStateMachineBuilder::start()
+        .initial_state(0)
+        .transition(transition)
+        .on_enter_state(u32::MAX, |_| {})
+        .build()
+        .unwrap();
+
+    for (name, elapsed) in [("fast", measure(&fast)), ("full", measure(&full))] {
+        println!(
+            "{:>4}: {:>10.2?} ({:.2} ns/consume)",
+            name,
+            elapsed,
+            elapsed.as_nanos() as f64 / f64::from(TRANSITIONS)
+        );
+    }
+}
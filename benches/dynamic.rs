@@ -0,0 +1,49 @@
+//! Compares the throughput of `consume` on the statically dispatched machine
+//! and the dynamically dispatched one ([`DynStateMachine`]).
+//! Run with `cargo bench --bench dynamic`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use statemachine_rs::machine::{
+    builder::{BasicStateMachineBuilder, StateMachineBuilder},
+    DynStateMachine, StateMachine,
+};
+
+const ITERATIONS: u32 = 10_000_000;
+
+fn transition(state: &u32, input: u32) -> u32 {
+    state.wrapping_mul(31).wrapping_add(input) % 1024
+}
+
+fn measure(sm: &impl StateMachine<u32, u32>) -> Duration {
+    let start = Instant::now();
+    for input in 0..ITERATIONS {
+        black_box(sm.consume(black_box(input)));
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let sm = BasicStateMachineBuilder::start()
+        .initial_state(0)
+        .transition(|state: &u32, input: u32| transition(state, input))
+        .build()
+        .unwrap();
+    let dynamic: DynStateMachine<u32, u32> = BasicStateMachineBuilder::start()
+        .initial_state(0)
+        .transition(|state: &u32, input: u32| transition(state, input))
+        .dynamic()
+        .unwrap();
+
+    for (name, elapsed) in [("static", measure(&sm)), ("dynamic", measure(&dynamic))] {
+        println!(
+            "{:>8}: {:>10.2?} ({:.2} ns/consume)",
+            name,
+            elapsed,
+            elapsed.as_nanos() as f64 / f64::from(ITERATIONS)
+        );
+    }
+}
@@ -0,0 +1,63 @@
+//! Compares the bounded history of the state machine, which is a pre-allocated ring buffer,
+//! with the naive `Vec::push` + `Vec::remove(0)` approach.
+//! Run with `cargo bench --bench history`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use statemachine_rs::machine::{
+    builder::{BasicStateMachineBuilder, StateMachineBuilder},
+    StateMachine,
+};
+
+const TRANSITIONS: u32 = 1_000_000;
+const LIMIT: usize = 1024;
+
+fn transition(state: &u32, input: u32) -> u32 {
+    state.wrapping_mul(31).wrapping_add(input)
+}
+
+fn ring_buffer() -> Duration {
+    let sm = BasicStateMachineBuilder::start()
+        .initial_state(0)
+        .transition(transition)
+        .history_limit(LIMIT)
+        .build()
+        .unwrap();
+    let start = Instant::now();
+    for input in 0..TRANSITIONS {
+        black_box(sm.consume(black_box(input)));
+    }
+    start.elapsed()
+}
+
+fn naive() -> Duration {
+    let sm = BasicStateMachineBuilder::start()
+        .initial_state(0)
+        .transition(transition)
+        .build()
+        .unwrap();
+    let mut history = vec![0];
+    let start = Instant::now();
+    for input in 0..TRANSITIONS {
+        history.push(black_box(sm.consume(black_box(input))));
+        if history.len() > LIMIT {
+            history.remove(0);
+        }
+    }
+    black_box(history);
+    start.elapsed()
+}
+
+fn main() {
+    for (name, elapsed) in [("ring buffer", ring_buffer()), ("naive", naive())] {
+        println!(
+            "{:>11}: {:>10.2?} ({:.2} ns/consume)",
+            name,
+            elapsed,
+            elapsed.as_nanos() as f64 / f64::from(TRANSITIONS)
+        );
+    }
+}
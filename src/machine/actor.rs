@@ -0,0 +1,403 @@
+use std::{
+    error::Error,
+    fmt::{self, Debug, Display},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use super::StateMachine;
+
+/// How [`MachineHandle::shutdown()`] stops the machine thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Consumes every input already sent and then exits.
+    Drain,
+    /// Exits after the input in flight. The inputs still queued are returned
+    /// as [`JoinResult::dropped`] without being consumed.
+    Abort,
+}
+
+/// The result of [`MachineHandle::shutdown()`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct JoinResult<State, Input> {
+    /// The current state of the machine when the thread exited.
+    pub final_state: State,
+    /// The inputs which were sent but never consumed. It is always empty for
+    /// [`ShutdownMode::Drain`].
+    pub dropped: Vec<Input>,
+}
+
+/// The error of [`MachineHandle::send()`] carrying the input back,
+/// returned once the machine is shut down.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SendError<Input>(pub Input);
+
+impl<Input> Debug for SendError<Input> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SendError { .. }")
+    }
+}
+
+impl<Input> Display for SendError<Input> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("The machine is already shut down.")
+    }
+}
+
+impl<Input> Error for SendError<Input> {}
+
+/// The error of [`MachineHandle::ask()`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AskError<Input> {
+    /// The machine is already shut down, so the input is carried back.
+    Closed(Input),
+    /// The machine stopped without answering, i.e. it was shut down via
+    /// [`ShutdownMode::Abort`] before consuming the input, which is then returned as
+    /// [`JoinResult::dropped`], or it panicked.
+    Unanswered,
+}
+
+impl<Input> Debug for AskError<Input> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AskError::Closed(_) => f.write_str("Closed(..)"),
+            AskError::Unanswered => f.write_str("Unanswered"),
+        }
+    }
+}
+
+impl<Input> Display for AskError<Input> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AskError::Closed(_) => f.write_str("The machine is already shut down."),
+            AskError::Unanswered => f.write_str("The machine stopped without answering."),
+        }
+    }
+}
+
+impl<Input> Error for AskError<Input> {}
+
+/// The callback which sends the response of [`MachineHandle::ask()`] back.
+type Reply<State> = Box<dyn FnOnce(&State, &State) + Send>;
+
+enum Message<State, Input> {
+    Input(Input),
+    Ask(Input, Reply<State>),
+    Shutdown,
+}
+
+struct Shared<State, Input> {
+    /// `None` once the machine is shut down. Sending holds the lock, so that no input
+    /// can be queued after the shutdown message.
+    sender: Mutex<Option<Sender<Message<State, Input>>>>,
+    abort: AtomicBool,
+    thread: Mutex<Option<JoinHandle<JoinResult<State, Input>>>>,
+}
+
+/// The handle of a state machine running on its own thread, which consumes
+/// the inputs sent via [`MachineHandle::send()`] in order.
+/// The handle can be cloned to send inputs from several threads.
+/// It is `Send` and `Sync` if the input is `Send`, even though the machine itself
+/// isn't `Sync`, since only the machine thread touches it.
+///
+/// # Example
+/// ```
+/// use statemachine_rs::machine::{
+///     actor::{MachineHandle, ShutdownMode},
+///     builder::{BasicStateMachineBuilder, StateMachineBuilder},
+/// };
+///
+/// let sm = BasicStateMachineBuilder::start()
+///     .initial_state(0)
+///     .transition(|count: &u32, step: u32| count + step)
+///     .build()
+///     .unwrap();
+///
+/// let handle = MachineHandle::spawn(sm);
+/// handle.send(1).unwrap();
+/// handle.send(2).unwrap();
+/// let result = handle.shutdown(ShutdownMode::Drain).unwrap();
+/// assert_eq!(3, result.final_state);
+/// assert_eq!(4, handle.send(4).unwrap_err().0);
+/// ```
+pub struct MachineHandle<State, Input> {
+    shared: Arc<Shared<State, Input>>,
+}
+
+impl<State, Input> Clone for MachineHandle<State, Input> {
+    fn clone(&self) -> Self {
+        MachineHandle {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<State, Input> MachineHandle<State, Input>
+where
+    State: Send + 'static,
+    Input: Send + 'static,
+{
+    /// Moves `machine` to a new thread and returns the handle to it.
+    pub fn spawn<M>(machine: M) -> Self
+    where
+        M: StateMachine<State, Input> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            sender: Mutex::new(Some(sender)),
+            abort: AtomicBool::new(false),
+            thread: Mutex::new(None),
+        });
+        let thread = {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || run(machine, receiver, &shared.abort))
+        };
+        *shared.thread.lock().unwrap() = Some(thread);
+        MachineHandle { shared }
+    }
+
+    /// Queues `input` to be consumed by the machine.
+    /// It returns the input back in [`SendError`] if the machine is already shut down.
+    pub fn send(&self, input: Input) -> Result<(), SendError<Input>> {
+        self.post(Message::Input(input))
+            .map_err(|message| match message {
+                Message::Input(input) => SendError(input),
+                _ => unreachable!("only the inputs are sent here"),
+            })
+    }
+
+    /// Queues `input` like [`MachineHandle::send()`] and waits for the machine to consume it,
+    /// and then returns the response computed by `respond` from the previous state and
+    /// the new state on the machine thread. See [`StateMachine::consume_respond()`].
+    ///
+    /// It returns the input back in [`AskError::Closed`] if the machine is already shut
+    /// down, and [`AskError::Unanswered`] if the machine stops before consuming it.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     actor::{MachineHandle, ShutdownMode},
+    ///     builder::{BasicStateMachineBuilder, StateMachineBuilder},
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(10)
+    ///     .transition(|balance: &u32, amount: u32| balance.saturating_sub(amount))
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// let handle = MachineHandle::spawn(sm);
+    /// assert_eq!(Ok(4), handle.ask(4, |prev, new| prev - new));
+    /// assert_eq!(Ok(6), handle.ask(15, |prev, new| prev - new));
+    /// handle.shutdown(ShutdownMode::Drain);
+    /// assert!(handle.ask(1, |prev, new| prev - new).is_err());
+    /// ```
+    pub fn ask<R, F>(&self, input: Input, respond: F) -> Result<R, AskError<Input>>
+    where
+        F: FnOnce(&State, &State) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (answer, on_answer) = mpsc::channel();
+        let reply: Reply<State> = Box::new(move |prev, new| {
+            // The asking thread is blocked on the answer, so it can't be disconnected.
+            let _ = answer.send(respond(prev, new));
+        });
+        self.post(Message::Ask(input, reply))
+            .map_err(|message| match message {
+                Message::Ask(input, _) => AskError::Closed(input),
+                _ => unreachable!("only the questions are sent here"),
+            })?;
+        on_answer.recv().map_err(|_| AskError::Unanswered)
+    }
+
+    /// Sends `message` to the machine thread, or returns it back if the machine is
+    /// already shut down.
+    fn post(&self, message: Message<State, Input>) -> Result<(), Message<State, Input>> {
+        match self.shared.sender.lock().unwrap().as_ref() {
+            Some(sender) => sender.send(message).map_err(|error| error.0),
+            None => Err(message),
+        }
+    }
+
+    /// Stops the machine thread in `mode`, waits for it and returns the final state.
+    /// The inputs sent afterward via any clone of the handle are rejected with [`SendError`].
+    /// It returns `None` if the machine is already shut down via another call.
+    ///
+    /// # Panics
+    /// It resumes the panic if the machine thread panicked.
+    pub fn shutdown(&self, mode: ShutdownMode) -> Option<JoinResult<State, Input>> {
+        {
+            let mut sender = self.shared.sender.lock().unwrap();
+            let sender = sender.take()?;
+            if mode == ShutdownMode::Abort {
+                self.shared.abort.store(true, Ordering::SeqCst);
+            }
+            // The thread exits only after receiving it, so it can't be disconnected here.
+            let _ = sender.send(Message::Shutdown);
+        }
+        let thread = self.shared.thread.lock().unwrap().take()?;
+        match thread.join() {
+            Ok(result) => Some(result),
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+/// Consumes the inputs until the shutdown message arrives.
+fn run<M, State, Input>(
+    machine: M,
+    receiver: Receiver<Message<State, Input>>,
+    abort: &AtomicBool,
+) -> JoinResult<State, Input>
+where
+    M: StateMachine<State, Input>,
+{
+    let mut dropped = Vec::new();
+    for message in receiver {
+        match message {
+            Message::Input(input) if abort.load(Ordering::SeqCst) => dropped.push(input),
+            Message::Input(input) => {
+                machine.consume(input);
+            }
+            Message::Ask(input, _) if abort.load(Ordering::SeqCst) => dropped.push(input),
+            Message::Ask(input, reply) => machine.consume_respond(input, reply),
+            Message::Shutdown => break,
+        }
+    }
+    JoinResult {
+        final_state: machine.current_state(),
+        dropped,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{mpsc, Mutex},
+        thread,
+    };
+
+    use super::{AskError, MachineHandle, SendError, ShutdownMode};
+    use crate::machine::builder::{BasicStateMachineBuilder, StateMachineBuilder};
+
+    #[test]
+    fn test_shutdown_drain() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|count: &u32, step: u32| count + step)
+            .build()
+            .unwrap();
+        let handle = MachineHandle::spawn(sm);
+        for step in 1..=100 {
+            handle.send(step).unwrap();
+        }
+
+        let result = handle.shutdown(ShutdownMode::Drain).unwrap();
+        assert_eq!(5050, result.final_state);
+        assert!(result.dropped.is_empty());
+        assert_eq!(None, handle.shutdown(ShutdownMode::Drain));
+    }
+
+    #[test]
+    fn test_shutdown_abort() {
+        // The first input blocks the machine thread until the test lets it go,
+        // so that the rest are still queued on the shutdown.
+        let (started, on_started) = mpsc::channel();
+        let (release, on_release) = mpsc::channel::<()>();
+        let on_release = Mutex::new(on_release);
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(move |count: &u32, step: u32| {
+                if step == 1 {
+                    started.send(()).unwrap();
+                    on_release.lock().unwrap().recv().unwrap();
+                }
+                count + step
+            })
+            .build()
+            .unwrap();
+        let handle = MachineHandle::spawn(sm);
+        for step in 1..=4 {
+            handle.send(step).unwrap();
+        }
+        on_started.recv().unwrap();
+
+        let shutdown = {
+            let handle = handle.clone();
+            std::thread::spawn(move || handle.shutdown(ShutdownMode::Abort))
+        };
+        // Wait until the shutdown closes the handle before letting the first input finish.
+        while handle.send(0).is_ok() {}
+        release.send(()).unwrap();
+
+        let result = shutdown.join().unwrap().unwrap();
+        assert_eq!(1, result.final_state);
+        let dropped: Vec<u32> = result
+            .dropped
+            .into_iter()
+            .filter(|step| *step != 0)
+            .collect();
+        assert_eq!(vec![2, 3, 4], dropped);
+    }
+
+    #[test]
+    fn test_send_after_shutdown() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(String::new())
+            .transition(|log: &String, word: String| log.clone() + &word)
+            .build()
+            .unwrap();
+        let handle = MachineHandle::spawn(sm);
+        let other = handle.clone();
+        handle.send("a".to_string()).unwrap();
+        assert_eq!(
+            "a",
+            handle.shutdown(ShutdownMode::Drain).unwrap().final_state
+        );
+
+        match other.send("b".to_string()) {
+            Err(SendError(word)) => assert_eq!("b", word),
+            Ok(()) => panic!("the machine is already shut down"),
+        }
+        assert_eq!("c", handle.send("c".to_string()).unwrap_err().0);
+    }
+
+    #[test]
+    fn test_ask() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(20_000)
+            .transition(|balance: &u32, amount: u32| {
+                balance.checked_sub(amount).unwrap_or(*balance)
+            })
+            .build()
+            .unwrap();
+        let handle = MachineHandle::spawn(sm);
+
+        let clients: Vec<_> = (0..4)
+            .map(|_| {
+                let handle = handle.clone();
+                thread::spawn(move || {
+                    (0..6_000)
+                        .map(|_| handle.ask(1, |before: &u32, after: &u32| before != after))
+                        .filter(|accepted| *accepted.as_ref().unwrap())
+                        .count()
+                })
+            })
+            .collect();
+        let accepted: usize = clients
+            .into_iter()
+            .map(|client| client.join().unwrap())
+            .sum();
+        assert_eq!(20_000, accepted);
+
+        assert_eq!(0, handle.shutdown(ShutdownMode::Drain).unwrap().final_state);
+        match handle.ask(1, |_, _| ()) {
+            Err(AskError::Closed(amount)) => assert_eq!(1, amount),
+            _ => panic!("the machine is already shut down"),
+        }
+    }
+}
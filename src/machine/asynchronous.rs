@@ -0,0 +1,614 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use super::error::StateMachineError;
+
+/// The boxed future used by [`AsyncStateMachine`] not to depend on any particular runtime.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// The asynchronous definition of the state transition.
+type AsyncTransition<State, Input> =
+    Arc<dyn Fn(&State, Input) -> BoxFuture<'static, State> + Send + Sync>;
+
+/// The asynchronous callback which is called with a state.
+type AsyncHook<State> = Arc<dyn Fn(&State) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// The asynchronous callback which is called with the previous and the next states.
+type AsyncObserver<State> = Arc<dyn Fn(&State, &State) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// The trait is representing the source of timers for [`AsyncStateMachine::consume_timeout()`],
+/// so that the machine doesn't depend on any particular runtime.
+/// It is typically implemented with the sleep function of the runtime, e.g.
+/// `Box::pin(tokio::time::sleep(duration))`.
+pub trait Timer {
+    /// Returns the future which resolves after `duration`.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The trait is representing the asynchronous sequence of values, in the same shape as
+/// `futures::Stream`, so that the machine doesn't depend on any particular crate.
+/// Streams from other crates can be adapted by implementing it for a wrapper type
+/// which forwards `poll_next`.
+pub trait Stream {
+    /// The type of the values yielded by the stream.
+    type Item;
+
+    /// Attempts to pull out the next value, returning `Poll::Ready(None)` at the end.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>>;
+}
+
+/// The stream which yields the items of an iterator without waiting. See [`iter()`].
+pub struct Iter<I> {
+    iter: I,
+}
+
+impl<I> Stream for Iter<I>
+where
+    I: Iterator + Unpin,
+{
+    type Item = I::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.iter.next())
+    }
+}
+
+/// Converts `iter` into the stream which is always ready, like `futures::stream::iter`.
+pub fn iter<I>(iter: I) -> Iter<I::IntoIter>
+where
+    I: IntoIterator,
+{
+    Iter {
+        iter: iter.into_iter(),
+    }
+}
+
+/// The state machine whose transition and hooks are asynchronous.
+/// It is built via [`AsyncStateMachineBuilder`].
+///
+/// [`AsyncStateMachine::consume()`] is cancellation-safe: the current state is swapped
+/// synchronously only after the transition future resolves, so dropping the future of
+/// `consume` leaves the state either fully the old one or fully the new one.
+///
+/// It is `Send` and `Sync` if the state is `Send + Sync`, so it can be shared among tasks
+/// via `Arc`.
+pub struct AsyncStateMachine<State, Input> {
+    initial_state: State,
+    current_state: Mutex<State>,
+    transition: AsyncTransition<State, Input>,
+    exit_hooks: Vec<AsyncHook<State>>,
+    observers: Vec<AsyncObserver<State>>,
+    enter_hooks: Vec<AsyncHook<State>>,
+    timer: Option<Arc<dyn Timer + Send + Sync>>,
+}
+
+impl<State, Input> AsyncStateMachine<State, Input>
+where
+    State: Clone,
+{
+    /// Returns the current state of the state machine.
+    pub fn current_state(&self) -> State {
+        self.current_state.lock().unwrap().clone()
+    }
+
+    /// Resets the current state to the initial state and returns it. No hooks are called.
+    pub fn reset(&self) -> State {
+        *self.current_state.lock().unwrap() = self.initial_state.clone();
+        self.initial_state.clone()
+    }
+
+    /// Transits to the next state and returns it. Each step is awaited in the following order.
+    ///
+    /// 1. The exit hooks are called with the current state.
+    /// 2. The transition computes the next state.
+    /// 3. The current state is swapped to the next state.
+    /// 4. The enter hooks are called with the next state.
+    /// 5. The observers are called with the previous and the next states.
+    ///
+    /// If the future is dropped before the step 3, nothing is changed. If it is dropped after
+    /// that, the state is already the next one and the remaining hooks simply don't run.
+    /// The overlapping calls compute the next states from the state at the time they started,
+    /// so call it sequentially to apply every input in order.
+    ///
+    /// # Example
+    /// ```
+    /// use std::{
+    ///     future::Future,
+    ///     pin::pin,
+    ///     sync::Arc,
+    ///     task::{Context, Poll, Wake, Waker},
+    /// };
+    /// use statemachine_rs::machine::asynchronous::AsyncStateMachineBuilder;
+    ///
+    /// let sm = AsyncStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|state: &i32, input: i32| {
+    ///         let next = state + input;
+    ///         Box::pin(async move { next })
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// struct Noop;
+    /// impl Wake for Noop {
+    ///     fn wake(self: Arc<Self>) {}
+    /// }
+    ///
+    /// let mut consume = pin!(sm.consume(2));
+    /// let waker = Waker::from(Arc::new(Noop));
+    /// let mut cx = Context::from_waker(&waker);
+    /// assert_eq!(Poll::Ready(2), consume.as_mut().poll(&mut cx));
+    /// assert_eq!(2, sm.current_state());
+    /// ```
+    pub async fn consume(&self, input: Input) -> State {
+        let current = self.current_state();
+        for hook in &self.exit_hooks {
+            hook(&current).await;
+        }
+        let next = (self.transition)(&current, input).await;
+        *self.current_state.lock().unwrap() = next.clone();
+        for hook in &self.enter_hooks {
+            hook(&next).await;
+        }
+        for observer in &self.observers {
+            observer(&current, &next).await;
+        }
+        next
+    }
+
+    /// Returns the stream which consumes each input from `inputs` as it arrives via
+    /// [`AsyncStateMachine::consume()`] and yields the resulting states in order.
+    /// The next input isn't pulled until the previous one is consumed.
+    ///
+    /// # Example
+    /// ```
+    /// use std::{
+    ///     pin::Pin,
+    ///     sync::Arc,
+    ///     task::{Context, Poll, Wake, Waker},
+    /// };
+    /// use statemachine_rs::machine::asynchronous::{self, AsyncStateMachineBuilder, Stream};
+    ///
+    /// let sm = AsyncStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|state: &i32, input: i32| {
+    ///         let next = state + input;
+    ///         Box::pin(async move { next })
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// struct Noop;
+    /// impl Wake for Noop {
+    ///     fn wake(self: Arc<Self>) {}
+    /// }
+    ///
+    /// let mut states = sm.drive_stream(asynchronous::iter(vec![1, 2]));
+    /// let waker = Waker::from(Arc::new(Noop));
+    /// let mut cx = Context::from_waker(&waker);
+    /// assert_eq!(Poll::Ready(Some(1)), Pin::new(&mut states).poll_next(&mut cx));
+    /// assert_eq!(Poll::Ready(Some(3)), Pin::new(&mut states).poll_next(&mut cx));
+    /// assert_eq!(Poll::Ready(None), Pin::new(&mut states).poll_next(&mut cx));
+    /// ```
+    pub fn drive_stream<S>(&self, inputs: S) -> DriveStream<'_, State, Input, S>
+    where
+        S: Stream<Item = Input>,
+    {
+        DriveStream {
+            sm: self,
+            inputs: Box::pin(inputs),
+            consuming: None,
+        }
+    }
+
+    /// Consumes `input` like [`AsyncStateMachine::consume()`] but gives up after `timeout`
+    /// with [`StateMachineError::Timeout`], dropping the `consume` future.
+    /// The timer is set via [`AsyncStateMachineBuilder::timer()`], otherwise it returns
+    /// [`StateMachineError::MissingField`] without consuming the input.
+    pub async fn consume_timeout(
+        &self,
+        input: Input,
+        timeout: Duration,
+    ) -> Result<State, StateMachineError> {
+        let timer = match &self.timer {
+            Some(timer) => timer.sleep(timeout),
+            None => return Err(StateMachineError::MissingField("timer")),
+        };
+        Timeout {
+            future: Box::pin(self.consume(input)),
+            timer,
+        }
+        .await
+    }
+}
+
+/// The stream of the states returned by [`AsyncStateMachine::drive_stream()`].
+pub struct DriveStream<'a, State, Input, S> {
+    sm: &'a AsyncStateMachine<State, Input>,
+    inputs: Pin<Box<S>>,
+    consuming: Option<Pin<Box<dyn Future<Output = State> + 'a>>>,
+}
+
+impl<'a, State, Input, S> Stream for DriveStream<'a, State, Input, S>
+where
+    S: Stream<Item = Input>,
+    State: Clone,
+{
+    type Item = State;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<State>> {
+        loop {
+            if let Some(consuming) = &mut self.consuming {
+                let state = match consuming.as_mut().poll(cx) {
+                    Poll::Ready(state) => state,
+                    Poll::Pending => return Poll::Pending,
+                };
+                self.consuming = None;
+                return Poll::Ready(Some(state));
+            }
+            match self.inputs.as_mut().poll_next(cx) {
+                Poll::Ready(Some(input)) => {
+                    let sm = self.sm;
+                    self.consuming = Some(Box::pin(sm.consume(input)));
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// The future which resolves to the output of `future` unless `timer` resolves first.
+struct Timeout<F> {
+    future: Pin<Box<F>>,
+    timer: BoxFuture<'static, ()>,
+}
+
+impl<F> Future for Timeout<F>
+where
+    F: Future,
+{
+    type Output = Result<F::Output, StateMachineError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = self.future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match self.timer.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(StateMachineError::Timeout)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// The builder of [`AsyncStateMachine`].
+pub struct AsyncStateMachineBuilder<State, Input> {
+    initial_state: Option<State>,
+    transition: Option<AsyncTransition<State, Input>>,
+    exit_hooks: Vec<AsyncHook<State>>,
+    observers: Vec<AsyncObserver<State>>,
+    enter_hooks: Vec<AsyncHook<State>>,
+    timer: Option<Arc<dyn Timer + Send + Sync>>,
+}
+
+impl<State, Input> AsyncStateMachineBuilder<State, Input> {
+    /// Starts the builder.
+    pub fn start() -> Self {
+        Self::default()
+    }
+
+    /// Sets particular initial state to the state machine.
+    pub fn initial_state(mut self, state: State) -> Self {
+        self.initial_state = Some(state);
+        self
+    }
+
+    /// Sets particular asynchronous transition to the state machine.
+    pub fn transition<F>(mut self, transition: F) -> Self
+    where
+        F: Fn(&State, Input) -> BoxFuture<'static, State> + Send + Sync + 'static,
+    {
+        self.transition = Some(Arc::new(transition));
+        self
+    }
+
+    /// Registers particular hook which is awaited before the transition with the current state.
+    pub fn on_exit<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&State) -> BoxFuture<'static, ()> + Send + Sync + 'static,
+    {
+        self.exit_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Registers particular observer which is awaited after the enter hooks
+    /// with the previous and the next states.
+    pub fn observer<F>(mut self, observer: F) -> Self
+    where
+        F: Fn(&State, &State) -> BoxFuture<'static, ()> + Send + Sync + 'static,
+    {
+        self.observers.push(Arc::new(observer));
+        self
+    }
+
+    /// Registers particular hook which is awaited after the swap with the next state.
+    pub fn on_enter<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&State) -> BoxFuture<'static, ()> + Send + Sync + 'static,
+    {
+        self.enter_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Sets particular timer for [`AsyncStateMachine::consume_timeout()`].
+    pub fn timer<T>(mut self, timer: T) -> Self
+    where
+        T: Timer + Send + Sync + 'static,
+    {
+        self.timer = Some(Arc::new(timer));
+        self
+    }
+
+    /// To finish the builder. If it fails, returns [`StateMachineError::MissingField`].
+    pub fn build(self) -> Result<AsyncStateMachine<State, Input>, StateMachineError>
+    where
+        State: Clone,
+    {
+        let initial_state = self
+            .initial_state
+            .ok_or(StateMachineError::MissingField("initial_state"))?;
+        let transition = self
+            .transition
+            .ok_or(StateMachineError::MissingField("transition"))?;
+        Ok(AsyncStateMachine {
+            current_state: Mutex::new(initial_state.clone()),
+            initial_state,
+            transition,
+            exit_hooks: self.exit_hooks,
+            observers: self.observers,
+            enter_hooks: self.enter_hooks,
+            timer: self.timer,
+        })
+    }
+}
+
+impl<State, Input> Default for AsyncStateMachineBuilder<State, Input> {
+    fn default() -> Self {
+        AsyncStateMachineBuilder {
+            initial_state: None,
+            transition: None,
+            exit_hooks: Vec::new(),
+            observers: Vec::new(),
+            enter_hooks: Vec::new(),
+            timer: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        future::Future,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        task::{Context, Poll, Wake, Waker},
+        time::Duration,
+    };
+
+    use super::{iter, AsyncStateMachine, AsyncStateMachineBuilder, BoxFuture, Stream, Timer};
+    use crate::machine::error::StateMachineError;
+
+    /// The future which stays pending until the gate is opened.
+    fn gate(open: Arc<AtomicBool>) -> BoxFuture<'static, ()> {
+        Box::pin(std::future::poll_fn(move |_| {
+            if open.load(Ordering::SeqCst) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }))
+    }
+
+    struct Noop;
+
+    impl Wake for Noop {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    /// Returns the waker which does nothing.
+    fn noop_waker() -> Waker {
+        Waker::from(Arc::new(Noop))
+    }
+
+    /// Polls `future` once with the no-op waker.
+    fn poll_once<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+        future.poll(&mut Context::from_waker(&noop_waker()))
+    }
+
+    struct Gates {
+        exit: Arc<AtomicBool>,
+        transition: Arc<AtomicBool>,
+        enter: Arc<AtomicBool>,
+    }
+
+    fn machine(log: Arc<Mutex<Vec<String>>>) -> (AsyncStateMachine<i32, i32>, Gates) {
+        let gates = Gates {
+            exit: Arc::new(AtomicBool::new(false)),
+            transition: Arc::new(AtomicBool::new(false)),
+            enter: Arc::new(AtomicBool::new(false)),
+        };
+        let (exit, transition, enter) = (
+            Arc::clone(&gates.exit),
+            Arc::clone(&gates.transition),
+            Arc::clone(&gates.enter),
+        );
+        let (exit_log, observer_log, enter_log) =
+            (Arc::clone(&log), Arc::clone(&log), Arc::clone(&log));
+        let sm = AsyncStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(move |state: &i32, input: i32| {
+                let (next, gate) = (state + input, self::gate(Arc::clone(&transition)));
+                Box::pin(async move {
+                    gate.await;
+                    next
+                })
+            })
+            .on_exit(move |state: &i32| {
+                let (state, gate, log) =
+                    (*state, self::gate(Arc::clone(&exit)), Arc::clone(&exit_log));
+                Box::pin(async move {
+                    gate.await;
+                    log.lock().unwrap().push(format!("exit {}", state));
+                })
+            })
+            .observer(move |prev: &i32, next: &i32| {
+                let entry = format!("{} -> {}", prev, next);
+                let log = Arc::clone(&observer_log);
+                Box::pin(async move { log.lock().unwrap().push(entry) })
+            })
+            .on_enter(move |state: &i32| {
+                let (state, gate, log) = (
+                    *state,
+                    self::gate(Arc::clone(&enter)),
+                    Arc::clone(&enter_log),
+                );
+                Box::pin(async move {
+                    gate.await;
+                    log.lock().unwrap().push(format!("enter {}", state));
+                })
+            })
+            .build()
+            .unwrap();
+        (sm, gates)
+    }
+
+    #[test]
+    fn test_consume_in_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (sm, gates) = machine(Arc::clone(&log));
+        for gate in [&gates.exit, &gates.transition, &gates.enter] {
+            gate.store(true, Ordering::SeqCst);
+        }
+
+        let mut consume = Box::pin(sm.consume(3));
+        assert_eq!(Poll::Ready(3), poll_once(consume.as_mut()));
+        assert_eq!(vec!["exit 0", "enter 3", "0 -> 3"], *log.lock().unwrap());
+        assert_eq!(3, sm.current_state());
+    }
+
+    #[test]
+    fn test_drop_before_swap() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (sm, gates) = machine(Arc::clone(&log));
+
+        let mut consume = Box::pin(sm.consume(3));
+        assert!(poll_once(consume.as_mut()).is_pending());
+        gates.exit.store(true, Ordering::SeqCst);
+        assert!(poll_once(consume.as_mut()).is_pending());
+        assert_eq!(vec!["exit 0"], *log.lock().unwrap());
+        drop(consume);
+        assert_eq!(0, sm.current_state());
+    }
+
+    #[test]
+    fn test_drop_after_swap() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (sm, gates) = machine(Arc::clone(&log));
+        gates.exit.store(true, Ordering::SeqCst);
+        gates.transition.store(true, Ordering::SeqCst);
+
+        let mut consume = Box::pin(sm.consume(3));
+        assert!(poll_once(consume.as_mut()).is_pending());
+        assert_eq!(3, sm.current_state());
+        drop(consume);
+        assert_eq!(vec!["exit 0"], *log.lock().unwrap());
+        assert_eq!(3, sm.current_state());
+    }
+
+    #[test]
+    fn test_drive_stream() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (sm, gates) = machine(Arc::clone(&log));
+        for gate in [&gates.exit, &gates.transition, &gates.enter] {
+            gate.store(true, Ordering::SeqCst);
+        }
+
+        let mut states = sm.drive_stream(iter(vec![1, 2, 3]));
+        let mut emitted = Vec::new();
+        while let Poll::Ready(Some(state)) =
+            Pin::new(&mut states).poll_next(&mut Context::from_waker(&noop_waker()))
+        {
+            emitted.push(state);
+        }
+        assert_eq!(vec![1, 3, 6], emitted);
+        assert_eq!(6, sm.current_state());
+        assert_eq!(9, log.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_drive_stream_waits_for_transition() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (sm, gates) = machine(Arc::clone(&log));
+        gates.exit.store(true, Ordering::SeqCst);
+        gates.enter.store(true, Ordering::SeqCst);
+
+        let mut states = sm.drive_stream(iter(vec![1, 2]));
+        let mut next = || Pin::new(&mut states).poll_next(&mut Context::from_waker(&noop_waker()));
+        assert_eq!(Poll::Pending, next());
+        gates.transition.store(true, Ordering::SeqCst);
+        assert_eq!(Poll::Ready(Some(1)), next());
+        assert_eq!(Poll::Ready(Some(3)), next());
+        assert_eq!(Poll::Ready(None), next());
+    }
+
+    struct ManualTimer(Arc<AtomicBool>);
+
+    impl Timer for ManualTimer {
+        fn sleep(&self, _: Duration) -> BoxFuture<'static, ()> {
+            gate(Arc::clone(&self.0))
+        }
+    }
+
+    #[test]
+    fn test_consume_timeout() {
+        let expired = Arc::new(AtomicBool::new(false));
+        let transition = Arc::new(AtomicBool::new(false));
+        let sm = {
+            let transition = Arc::clone(&transition);
+            AsyncStateMachineBuilder::start()
+                .initial_state(0)
+                .transition(move |state: &i32, input: i32| {
+                    let (next, gate) = (state + input, gate(Arc::clone(&transition)));
+                    Box::pin(async move {
+                        gate.await;
+                        next
+                    })
+                })
+                .timer(ManualTimer(Arc::clone(&expired)))
+                .build()
+                .unwrap()
+        };
+
+        let mut consume = Box::pin(sm.consume_timeout(1, Duration::from_secs(1)));
+        assert!(poll_once(consume.as_mut()).is_pending());
+        expired.store(true, Ordering::SeqCst);
+        assert!(matches!(
+            poll_once(consume.as_mut()),
+            Poll::Ready(Err(StateMachineError::Timeout))
+        ));
+        assert_eq!(0, sm.current_state());
+
+        expired.store(false, Ordering::SeqCst);
+        transition.store(true, Ordering::SeqCst);
+        let mut consume = Box::pin(sm.consume_timeout(1, Duration::from_secs(1)));
+        assert!(matches!(poll_once(consume.as_mut()), Poll::Ready(Ok(1))));
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use super::{stats::TelemetrySnapshot, time::OccupancySnapshot, BasicStateMachine, Deferred};
+
+/// Everything [`BasicStateMachine::consume_all_atomic()`] restores if the batch fails.
+/// The history isn't cloned here but rewound from its checkpoint.
+struct BatchSnapshot<State, Input> {
+    state: State,
+    generation: u64,
+    epoch: u64,
+    entered_at: Instant,
+    terminal_reached: bool,
+    timings: Option<HashMap<State, Duration>>,
+    metrics: Option<HashMap<(State, State), Duration>>,
+    frequencies: Option<HashMap<State, usize>>,
+    trajectory: Option<u64>,
+    occupancy: Option<OccupancySnapshot<State>>,
+    visited: Option<HashSet<State>>,
+    telemetry: TelemetrySnapshot<Input>,
+}
+
+/// The batch of [`BasicStateMachine::consume_all_atomic()`] in progress.
+///
+/// It buffers the notifications while it is alive, and rolls the state machine back
+/// when dropped unless [`AtomicBatch::commit()`] is called, so that a panic in the middle
+/// of the batch doesn't leave it half applied.
+pub(crate) struct AtomicBatch<'a, State, Input, Transition, M>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+{
+    sm: &'a BasicStateMachine<State, Input, Transition, M>,
+    snapshot: Option<BatchSnapshot<State, Input>>,
+}
+
+impl<'a, State, Input, Transition, M> AtomicBatch<'a, State, Input, Transition, M>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+{
+    pub(crate) fn begin(sm: &'a BasicStateMachine<State, Input, Transition, M>) -> Self {
+        let snapshot = BatchSnapshot {
+            state: sm.current_state.borrow().get(),
+            generation: sm.generation.get(),
+            epoch: sm.epoch.get(),
+            entered_at: sm.entered_at.get(),
+            terminal_reached: sm.terminal_reached.get(),
+            timings: sm.timings.as_ref().map(|timings| timings.get()),
+            metrics: sm.metrics.as_ref().map(|metrics| metrics.get()),
+            frequencies: sm.frequencies.as_ref().map(|frequencies| frequencies.get()),
+            trajectory: sm.trajectory.as_ref().map(|trajectory| trajectory.get()),
+            occupancy: sm.occupancy.as_ref().map(|occupancy| occupancy.snapshot()),
+            visited: sm.visited.as_ref().map(|visited| visited.get()),
+            telemetry: sm.telemetry.snapshot(),
+        };
+        if let Some(history) = &sm.history {
+            history.borrow_mut().checkpoint();
+        }
+        *sm.deferred.borrow_mut() = Some(Vec::new());
+        AtomicBatch {
+            sm,
+            snapshot: Some(snapshot),
+        }
+    }
+
+    /// Keeps every change made during the batch and returns the buffered notifications.
+    pub(crate) fn commit(mut self) -> Deferred<State> {
+        self.snapshot = None;
+        self.sm.deferred.borrow_mut().take().unwrap_or_default()
+    }
+}
+
+impl<State, Input, Transition, M> Drop for AtomicBatch<'_, State, Input, Transition, M>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+{
+    fn drop(&mut self) {
+        let sm = self.sm;
+        sm.deferred.borrow_mut().take();
+        let snapshot = match self.snapshot.take() {
+            Some(snapshot) => snapshot,
+            None => {
+                if let Some(history) = &sm.history {
+                    history.borrow_mut().release();
+                }
+                return;
+            }
+        };
+        sm.current_state.borrow_mut().set(snapshot.state);
+        if let Some(history) = &sm.history {
+            history.borrow_mut().rewind();
+        }
+        sm.generation.set(snapshot.generation);
+        sm.epoch.set(snapshot.epoch);
+        sm.entered_at.set(snapshot.entered_at);
+        sm.terminal_reached.set(snapshot.terminal_reached);
+        sm.telemetry.restore(snapshot.telemetry);
+        if let (Some(current), Some(timings)) = (&sm.timings, snapshot.timings) {
+            current.restore(timings);
+        }
+        if let (Some(current), Some(metrics)) = (&sm.metrics, snapshot.metrics) {
+            current.restore(metrics);
+        }
+        if let (Some(current), Some(frequencies)) = (&sm.frequencies, snapshot.frequencies) {
+            current.restore(frequencies);
+        }
+        if let (Some(current), Some(trajectory)) = (&sm.trajectory, snapshot.trajectory) {
+            current.restore(trajectory);
+        }
+        if let (Some(current), Some(occupancy)) = (&sm.occupancy, snapshot.occupancy) {
+            current.restore(occupancy);
+        }
+        if let (Some(current), Some(visited)) = (&sm.visited, snapshot.visited) {
+            current.restore(visited);
+        }
+        if let Some(guard_cache) = &sm.guard_cache {
+            guard_cache.clear();
+        }
+    }
+}
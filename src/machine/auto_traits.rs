@@ -0,0 +1,92 @@
+//! Compile-time assertions of which state machines cross threads.
+//!
+//! The assertions fail to compile rather than to run, so a field added to a state machine
+//! can't silently change its auto traits.
+
+use std::{cell::Cell, rc::Rc};
+
+use super::{
+    actor::MachineHandle, asynchronous::AsyncStateMachine, compact::CompactTableStateMachine,
+    const_table::ConstTableStateMachine, effect::EffectfulStateMachine,
+    event_sourced::EventSourcedStateMachine, shared::SharedStateMachine, table::TableStateMachine,
+    BasicStateMachine,
+};
+
+/// The transition which is both `Send` and `Sync`.
+type Transition = fn(&u32, u32) -> u32;
+
+/// Asserts that the types implement the traits.
+macro_rules! assert_impl {
+    ($($ty:ty),+ : $($traits:tt)+) => {
+        const _: fn() = || {
+            fn assert_impl<T: ?Sized + $($traits)+>() {}
+            $(assert_impl::<$ty>();)+
+        };
+    };
+}
+
+/// Asserts that the types don't implement the trait. Resolving `some_item` is ambiguous
+/// only if the blanket impl bounded by the trait also applies, which fails to compile.
+macro_rules! assert_not_impl {
+    ($($ty:ty),+ : $trait:path) => {
+        const _: fn() = || {
+            trait AmbiguousIfImpl<A> {
+                fn some_item() {}
+            }
+            impl<T: ?Sized> AmbiguousIfImpl<()> for T {}
+            impl<T: ?Sized + $trait> AmbiguousIfImpl<u8> for T {}
+            $(let _ = <$ty as AmbiguousIfImpl<_>>::some_item;)+
+        };
+    };
+}
+
+// The machines holding the current state in a `RefCell` or a `Cell` move across threads
+// but are never shared among them.
+assert_impl!(
+    BasicStateMachine<u32, u32, Transition>,
+    TableStateMachine<u32, u32>,
+    CompactTableStateMachine<u32, u32>,
+    ConstTableStateMachine<4>,
+    EffectfulStateMachine<u32, u32, (), fn(&u32, u32) -> (u32, ())>,
+    EventSourcedStateMachine<u32, u32, (), fn(&u32, u32) -> (u32, Vec<()>)>
+    : Send
+);
+assert_not_impl!(
+    BasicStateMachine<u32, u32, Transition>,
+    TableStateMachine<u32, u32>,
+    CompactTableStateMachine<u32, u32>,
+    ConstTableStateMachine<4>,
+    EffectfulStateMachine<u32, u32, (), fn(&u32, u32) -> (u32, ())>,
+    EventSourcedStateMachine<u32, u32, (), fn(&u32, u32) -> (u32, Vec<()>)>
+    : Sync
+);
+
+// The machines locking or publishing the current state are shared among threads.
+assert_impl!(
+    SharedStateMachine<u32, u32, Transition>,
+    AsyncStateMachine<u32, u32>,
+    MachineHandle<u32, u32>
+    : Send + Sync
+);
+
+// The states and the inputs which can't cross threads make the machines stay on one.
+assert_not_impl!(
+    BasicStateMachine<Rc<u32>, u32, fn(&Rc<u32>, u32) -> Rc<u32>>,
+    BasicStateMachine<u32, Rc<u32>, fn(&u32, Rc<u32>) -> u32>,
+    TableStateMachine<Rc<u32>, u32>,
+    TableStateMachine<u32, Rc<u32>>
+    : Send
+);
+assert_not_impl!(
+    SharedStateMachine<Cell<u32>, u32, fn(&Cell<u32>, u32) -> Cell<u32>>,
+    AsyncStateMachine<Cell<u32>, u32>
+    : Sync
+);
+assert_not_impl!(SharedStateMachine<u32, Rc<u32>, fn(&u32, Rc<u32>) -> u32> : Sync);
+// The rows shared via `Arc` need the states and the inputs to be `Sync` to move the machine.
+assert_not_impl!(
+    TableStateMachine<Cell<u32>, u32>,
+    TableStateMachine<u32, Cell<u32>>
+    : Send
+);
+assert_not_impl!(MachineHandle<u32, Rc<u32>> : Send);
@@ -1,6 +1,39 @@
-use std::{cell::RefCell, marker::PhantomData};
+use std::{
+    borrow::Cow,
+    cell::{Cell, OnceCell, RefCell},
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    fmt::{Debug, Display},
+    hash::Hash,
+    io::Write,
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    rc::Rc,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::Duration,
+};
 
-use super::{error::StateMachineError, BasicStateMachine, StateWrapper};
+use super::{
+    dedupe::DedupeKeys,
+    error::{InsertError, ParseInputError, StateMachineError},
+    history::History,
+    memo::{self, GuardCache},
+    observer::Observers,
+    queue::InputQueue,
+    snapshot::{FullSnapshot, StalePolicy},
+    stats::{self, CloneInput, Count, Fold, Frequencies, Insert, Telemetry, Trajectory, Visited},
+    strict::ActiveOperation,
+    table::{Coverage, Enumerable, TableStateMachine, TransitionContext, TransitionObserver},
+    time::{
+        self, Accumulate, Clock, KeepSlowest, Occupancy, OccupancyBasis, SystemClock, Timings,
+        TransitionMetrics, Weigh,
+    },
+    trace::{PanicContext, Tracer},
+    BasicStateMachine, DynStateMachine, DynTransition, EpsilonStep, ExitHook, Guard, Hook,
+    InputParser, InputValidator, MetricSink, SlowHook, StaleSnapshotHook, StatePredicate,
+    StateWrapper, TransitionFilter,
+};
 
 pub trait StateMachineBuilder<State, Input, Transition>
 where
@@ -23,167 +56,3860 @@ where
 
     /// To finish the builder. If it fails, returns [`crate::machine::error::StateMachineError`].
     fn build(self) -> Result<Self::Output, Box<dyn std::error::Error>>;
+
+    /// To finish the builder with building one state machine per given initial state.
+    /// All the machines share the same transition (cloned) and definitions such as metadata,
+    /// and each of them holds its own current state. `initial_state` and `current_state`
+    /// set to this builder are ignored. The builder is checked once like
+    /// [`StateMachineBuilder::build()`] before any machine is constructed, and only
+    /// the declared states are checked per machine.
+    /// If it fails, returns [`crate::machine::error::StateMachineError`].
+    fn build_many(
+        self,
+        initials: Vec<State>,
+    ) -> Result<Vec<Self::Output>, Box<dyn std::error::Error>>
+    where
+        Transition: Clone;
+
+    /// To finish the builder without consuming it, so that the same builder can build
+    /// more state machines, possibly after further tweaks such as another `current_state`.
+    /// The lazy initial state set by [`BasicStateMachineBuilder::initial_state_with()`] is
+    /// evaluated on the first build and reused on the later ones.
+    /// If it fails, returns [`crate::machine::error::StateMachineError`].
+    fn build_ref(&self) -> Result<Self::Output, Box<dyn std::error::Error>>
+    where
+        Transition: Clone;
+}
+
+/// Returns `Err` with the description of the state if it isn't declared.
+/// See [`BasicStateMachineBuilder::states()`].
+type DeclaredStates<State> = Rc<dyn Fn(&State) -> Result<(), String>>;
+
+/// The initial state set by [`BasicStateMachineBuilder::initial_state_with()`].
+/// It is shared among the clones of the builder and evaluated at most once.
+struct LazyState<State> {
+    f: Cell<Option<Box<dyn FnOnce() -> State>>>,
+    value: OnceCell<State>,
+}
+
+impl<State: Clone> LazyState<State> {
+    fn new(f: impl FnOnce() -> State + 'static) -> Self {
+        LazyState {
+            f: Cell::new(Some(Box::new(f))),
+            value: OnceCell::new(),
+        }
+    }
+
+    fn get(&self) -> State {
+        self.value
+            .get_or_init(|| (self.f.take().expect("evaluated only once"))())
+            .clone()
+    }
+}
+
+/// The metadata attached to [`BasicStateMachineBuilder`] so far. It is `()` until
+/// [`BasicStateMachineBuilder::metadata()`] is called, and the map from the states to
+/// the metadata after that.
+pub trait MetadataMap<State> {
+    /// The type of the metadata, which becomes the type parameter `M` of the state machine.
+    type Value;
+
+    /// Converts it into the map held by the state machine.
+    fn into_map(self: Arc<Self>) -> Arc<HashMap<State, Self::Value>>;
+}
+
+impl<State> MetadataMap<State> for () {
+    type Value = ();
+
+    fn into_map(self: Arc<Self>) -> Arc<HashMap<State, ()>> {
+        Arc::new(HashMap::new())
+    }
+}
+
+impl<State, V> MetadataMap<State> for HashMap<State, V> {
+    type Value = V;
+
+    fn into_map(self: Arc<Self>) -> Arc<HashMap<State, V>> {
+        self
+    }
+}
+
+/// The metadata to which [`BasicStateMachineBuilder::metadata()`] can add a value of `V`.
+/// The first call fixes the type of the metadata, so that adding a value of
+/// another type later doesn't compile.
+pub trait AttachMetadata<State, V> {
+    /// Returns the map with `value` attached to `state`, replacing the one attached
+    /// to it before, if any.
+    fn attach(self: Arc<Self>, state: State, value: V) -> Arc<HashMap<State, V>>;
+}
+
+impl<State, V> AttachMetadata<State, V> for ()
+where
+    State: Eq + Hash,
+{
+    fn attach(self: Arc<Self>, state: State, value: V) -> Arc<HashMap<State, V>> {
+        let mut map = HashMap::new();
+        map.insert(state, value);
+        Arc::new(map)
+    }
+}
+
+impl<State, V> AttachMetadata<State, V> for HashMap<State, V>
+where
+    State: Eq + Hash + Clone,
+    V: Clone,
+{
+    fn attach(self: Arc<Self>, state: State, value: V) -> Arc<HashMap<State, V>> {
+        // The map is shared only if the builder has been cloned.
+        let mut map = Arc::try_unwrap(self).unwrap_or_else(|map| (*map).clone());
+        map.insert(state, value);
+        Arc::new(map)
+    }
 }
 
 /// This builder enables us to assemble StateMachine
 /// (like [`crate::machine::BasicStateMachine`]) more easily.
-pub struct BasicStateMachineBuilder<State, Input, Transition>
+/// `M` is the metadata attached via [`BasicStateMachineBuilder::metadata()`] so far.
+/// See [`MetadataMap`].
+pub struct BasicStateMachineBuilder<State, Input, Transition, M = ()>
 where
     Transition: Fn(&State, Input) -> State,
     State: Clone,
 {
+    transition: Option<Transition>,
+    metadata: Arc<M>,
+    settings: Settings<State, Input>,
+}
+
+/// Everything set to [`BasicStateMachineBuilder`] but the transition and the metadata,
+/// which is carried over as is when the builder is cloned, the transition is type-erased
+/// or the metadata is replaced.
+struct Settings<State, Input> {
     initial_state: Option<State>,
+    initial_state_with: Option<Rc<LazyState<State>>>,
+    declared: Option<DeclaredStates<State>>,
+    declared_states: Option<Rc<Vec<State>>>,
+    alphabet: Option<Rc<Vec<Input>>>,
     current_state: Option<State>,
-    transition: Option<Transition>,
-    _marker: PhantomData<Input>,
+    tags: Arc<HashMap<State, Vec<String>>>,
+    history: bool,
+    history_limit: Option<usize>,
+    input_validator: Option<InputValidator<Input>>,
+    guards: Vec<(Cow<'static, str>, Guard<State, Input>)>,
+    guard_cache: Option<memo::Evaluate<State, Input>>,
+    reset_forbidden: Vec<StatePredicate<State>>,
+    min_dwell: Option<Duration>,
+    state_order: Vec<State>,
+    post_conditions: Vec<StatePredicate<State>>,
+    epsilon: Option<EpsilonStep<State>>,
+    metric_sinks: Vec<MetricSink<State>>,
+    enter_hooks: Vec<Hook<State>>,
+    exit_hooks: Vec<ExitHook<State>>,
+    terminal: Option<StatePredicate<State>>,
+    terminal_hooks: Vec<Hook<State>>,
+    clock: Arc<dyn Clock + Send + Sync>,
+    timing: Option<Accumulate<State>>,
+    metrics: Option<KeepSlowest<State>>,
+    slow_hooks: Vec<(Duration, SlowHook<State>)>,
+    frequencies: Option<Count<State>>,
+    visited: Option<Insert<State>>,
+    trajectory: Option<Fold<State>>,
+    occupancy: Option<(Weigh<State>, OccupancyBasis)>,
+    restored: Option<FullSnapshot<State>>,
+    tracer: Option<Tracer<State, Input>>,
+    panic_context: Option<PanicContext<State, Input>>,
+    input_parser: Option<InputParser<Input>>,
+    log_filter: Option<TransitionFilter<State>>,
+    stale_snapshot_policy: StalePolicy,
+    stale_snapshot_hook: Option<StaleSnapshotHook>,
+    dedupe_keys: Option<usize>,
+    keep_dedupe_keys_on_reset: bool,
+    keep_occupancy_on_reset: bool,
+    strict_borrow: bool,
+    track_last_input: Option<CloneInput<Input>>,
+    timed: bool,
+}
+
+impl<State, Input, Transition, M> StateMachineBuilder<State, Input, Transition>
+    for BasicStateMachineBuilder<State, Input, Transition, M>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+    M: MetadataMap<State> + Default,
+{
+    type Output = BasicStateMachine<State, Input, Transition, M::Value>;
+
+    fn start() -> Self {
+        Self::default()
+    }
+
+    fn initial_state(mut self, state: State) -> Self {
+        self.settings.initial_state = Some(state);
+        self
+    }
+
+    fn current_state(mut self, state: State) -> Self {
+        self.settings.current_state = Some(state);
+        self
+    }
+
+    fn transition(mut self, next: Transition) -> Self {
+        self.transition = Some(next);
+        self
+    }
+
+    fn build(self) -> Result<Self::Output, Box<dyn std::error::Error>> {
+        match (
+            &self.settings.initial_state,
+            &self.settings.initial_state_with,
+        ) {
+            (Some(_), Some(_)) => {
+                return Err(Box::new(StateMachineError::ConflictingFields(
+                    "initial_state",
+                    "initial_state_with",
+                )))
+            }
+            (None, None) => return Err(Box::new(StateMachineError::MissingField("initial_state"))),
+            _ => {}
+        }
+        let mut parts = self.validate()?;
+        // The lazy initial state is evaluated only after every check passes.
+        let initial_state = match (
+            parts.settings.initial_state.take(),
+            parts.settings.initial_state_with.take(),
+        ) {
+            (Some(initial_state), _) => initial_state,
+            (None, Some(initial_state_with)) => initial_state_with.get(),
+            (None, None) => unreachable!("the initial state is checked above"),
+        };
+        // If `current_state` in this builder is still `None`,
+        // sets `initial_state` as the current state forcibly.
+        let current_state = parts
+            .settings
+            .current_state
+            .take()
+            .unwrap_or_else(|| initial_state.clone());
+        Ok(parts.construct(initial_state, current_state)?)
+    }
+
+    fn build_many(
+        self,
+        initials: Vec<State>,
+    ) -> Result<Vec<Self::Output>, Box<dyn std::error::Error>>
+    where
+        Transition: Clone,
+    {
+        let parts = self.validate()?;
+        initials
+            .into_iter()
+            .map(|initial_state| {
+                let current_state = initial_state.clone();
+                Ok(parts.clone().construct(initial_state, current_state)?)
+            })
+            .collect()
+    }
+
+    fn build_ref(&self) -> Result<Self::Output, Box<dyn std::error::Error>>
+    where
+        Transition: Clone,
+    {
+        self.clone().build()
+    }
+}
+
+impl<State, Input, Transition, M> BasicStateMachineBuilder<State, Input, Transition, M>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+    M: MetadataMap<State>,
+{
+    /// Checks everything but the initial and the current states, which are given per machine,
+    /// and returns the parts every machine is constructed from.
+    fn validate(self) -> Result<Validated<State, Input, Transition, M::Value>, StateMachineError> {
+        let transition = match self.transition {
+            Some(transition) => transition,
+            None => return Err(StateMachineError::MissingField("transition")),
+        };
+        if !self.settings.terminal_hooks.is_empty() && self.settings.terminal.is_none() {
+            return Err(StateMachineError::MissingField("terminal_states"));
+        }
+        Ok(Validated {
+            transition,
+            metadata: self.metadata.into_map(),
+            settings: self.settings,
+        })
+    }
+}
+
+/// The parts of [`BasicStateMachineBuilder`] which have passed the checks shared by
+/// every machine, so that [`StateMachineBuilder::build_many()`] checks them only once.
+struct Validated<State, Input, Transition, V> {
+    transition: Transition,
+    metadata: Arc<HashMap<State, V>>,
+    settings: Settings<State, Input>,
+}
+
+impl<State, Input, Transition, V> Validated<State, Input, Transition, V>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+{
+    /// Constructs a state machine starting from `initial_state` and `current_state`,
+    /// failing only if either of them isn't declared.
+    fn construct(
+        self,
+        initial_state: State,
+        current_state: State,
+    ) -> Result<BasicStateMachine<State, Input, Transition, V>, StateMachineError> {
+        let settings = self.settings;
+        if let Some(declared) = &settings.declared {
+            for state in &[&initial_state, &current_state] {
+                declared(state)
+                    .map_err(|state| StateMachineError::UndeclaredState(state.into()))?;
+            }
+        }
+        let history = if settings.history {
+            Some(RefCell::new(History::new(
+                current_state.clone(),
+                settings.history_limit,
+            )))
+        } else {
+            None
+        };
+        let keep_dedupe_keys_on_reset = settings.keep_dedupe_keys_on_reset;
+        let log_filter = settings.log_filter;
+        let tracer = settings.tracer.map(|tracer| tracer.with_filter(log_filter));
+        let now = settings.clock.now();
+        let (visited, transitions, generation, epoch, entered_at) = match settings.restored {
+            Some(restored) => (
+                restored.visited,
+                restored.transition_count,
+                restored.generation,
+                restored.epoch,
+                now - Duration::from_millis(restored.entered_ms_ago),
+            ),
+            None => (HashSet::new(), 0, 0, 0, now),
+        };
+        let telemetry = Telemetry::new(settings.track_last_input, settings.timed);
+        telemetry.set_transitions(transitions);
+        let visited = settings
+            .visited
+            .map(|insert| Visited::new(insert, visited, &current_state));
+        let trajectory = settings
+            .trajectory
+            .map(|fold| Trajectory::new(fold, &current_state));
+        let keep_occupancy_on_reset = settings.keep_occupancy_on_reset;
+        let occupancy = settings.occupancy.map(|(weigh, basis)| {
+            Occupancy::new(
+                weigh,
+                basis,
+                keep_occupancy_on_reset,
+                current_state.clone(),
+                entered_at,
+            )
+        });
+        Ok(BasicStateMachine {
+            initial_state,
+            current_state: RefCell::new(StateWrapper::new(current_state)),
+            transition: RefCell::new(self.transition),
+            metadata: self.metadata,
+            tags: settings.tags,
+            history,
+            input_validator: settings.input_validator,
+            guards: settings.guards,
+            guard_cache: settings.guard_cache.map(GuardCache::new),
+            reset_forbidden: settings.reset_forbidden,
+            min_dwell: settings.min_dwell,
+            state_order: settings.state_order,
+            post_conditions: settings.post_conditions,
+            epsilon: settings.epsilon,
+            metric_sinks: settings.metric_sinks,
+            observers: Observers::new(),
+            enter_hooks: settings.enter_hooks,
+            exit_hooks: settings.exit_hooks,
+            terminal: settings.terminal,
+            terminal_hooks: settings.terminal_hooks,
+            terminal_reached: Cell::new(false),
+            entered_at: Cell::new(entered_at),
+            clock: settings.clock,
+            timings: settings.timing.map(Timings::new),
+            metrics: settings.metrics.map(TransitionMetrics::new),
+            slow_hooks: settings.slow_hooks,
+            frequencies: settings.frequencies.map(Frequencies::new),
+            visited,
+            trajectory,
+            occupancy,
+            tracer,
+            panic_context: settings.panic_context,
+            input_parser: settings.input_parser,
+            generation: Cell::new(generation),
+            telemetry,
+            epoch: Cell::new(epoch),
+            stale_snapshot_policy: settings.stale_snapshot_policy,
+            stale_snapshot_hook: settings.stale_snapshot_hook,
+            watchers: RefCell::new(Vec::new()),
+            strict: settings.strict_borrow.then(ActiveOperation::new),
+            dedupe: settings
+                .dedupe_keys
+                .map(|capacity| RefCell::new(DedupeKeys::new(capacity, keep_dedupe_keys_on_reset))),
+            pending: RefCell::new(None),
+            deferred: RefCell::new(None),
+            queue: RefCell::new(InputQueue::new()),
+            in_transaction: Cell::new(false),
+            _maker: PhantomData,
+        })
+    }
+}
+
+impl<State, Input, Transition, V> Clone for Validated<State, Input, Transition, V>
+where
+    Transition: Clone,
+    State: Clone,
+{
+    fn clone(&self) -> Self {
+        Validated {
+            transition: self.transition.clone(),
+            metadata: Arc::clone(&self.metadata),
+            settings: self.settings.clone(),
+        }
+    }
+}
+
+impl<State, Input, Transition, M> Clone for BasicStateMachineBuilder<State, Input, Transition, M>
+where
+    Transition: Fn(&State, Input) -> State + Clone,
+    State: Clone,
+{
+    fn clone(&self) -> Self {
+        BasicStateMachineBuilder {
+            transition: self.transition.clone(),
+            metadata: Arc::clone(&self.metadata),
+            settings: self.settings.clone(),
+        }
+    }
+}
+
+impl<State, Input> Clone for Settings<State, Input>
+where
+    State: Clone,
+{
+    fn clone(&self) -> Self {
+        Settings {
+            initial_state: self.initial_state.clone(),
+            initial_state_with: self.initial_state_with.clone(),
+            declared: self.declared.clone(),
+            declared_states: self.declared_states.clone(),
+            alphabet: self.alphabet.clone(),
+            current_state: self.current_state.clone(),
+            tags: Arc::clone(&self.tags),
+            history: self.history,
+            history_limit: self.history_limit,
+            input_validator: self.input_validator.clone(),
+            guards: self.guards.clone(),
+            guard_cache: self.guard_cache,
+            reset_forbidden: self.reset_forbidden.clone(),
+            min_dwell: self.min_dwell,
+            state_order: self.state_order.clone(),
+            post_conditions: self.post_conditions.clone(),
+            epsilon: self.epsilon.clone(),
+            metric_sinks: self.metric_sinks.clone(),
+            enter_hooks: self.enter_hooks.clone(),
+            exit_hooks: self.exit_hooks.clone(),
+            terminal: self.terminal.clone(),
+            terminal_hooks: self.terminal_hooks.clone(),
+            clock: Arc::clone(&self.clock),
+            timing: self.timing,
+            metrics: self.metrics,
+            slow_hooks: self.slow_hooks.clone(),
+            frequencies: self.frequencies,
+            visited: self.visited,
+            trajectory: self.trajectory,
+            occupancy: self.occupancy,
+            restored: self.restored.clone(),
+            tracer: self.tracer.clone(),
+            panic_context: self.panic_context.clone(),
+            input_parser: self.input_parser.clone(),
+            log_filter: self.log_filter.clone(),
+            stale_snapshot_policy: self.stale_snapshot_policy,
+            stale_snapshot_hook: self.stale_snapshot_hook.clone(),
+            dedupe_keys: self.dedupe_keys,
+            keep_dedupe_keys_on_reset: self.keep_dedupe_keys_on_reset,
+            keep_occupancy_on_reset: self.keep_occupancy_on_reset,
+            strict_borrow: self.strict_borrow,
+            track_last_input: self.track_last_input,
+            timed: self.timed,
+        }
+    }
+}
+
+impl<State, Input, Transition> BasicStateMachineBuilder<State, Input, Transition>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+{
+    /// Starts the builder without metadata. It is the same as [`StateMachineBuilder::start()`],
+    /// but the metadata type is fixed to `()` until [`BasicStateMachineBuilder::metadata()`]
+    /// replaces it, so that it doesn't need to be annotated.
+    pub fn start() -> Self {
+        Self::default()
+    }
+}
+
+impl<State, Input, Transition, M> BasicStateMachineBuilder<State, Input, Transition, M>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+{
+    /// Declares every state of the state machine. If declared, `build()` fails with
+    /// [`crate::machine::error::StateMachineError::UndeclaredState`] when the initial state
+    /// or the current state isn't one of them. Since the transition is a closure,
+    /// the reachability of the states can't be checked.
+    pub fn states(mut self, states: impl IntoIterator<Item = State>) -> Self
+    where
+        State: Eq + Hash + Debug + 'static,
+    {
+        let states: Vec<State> = states.into_iter().collect();
+        let declared: HashSet<State> = states.iter().cloned().collect();
+        self.settings.declared_states = Some(Rc::new(states));
+        self.settings.declared = Some(Rc::new(move |state| {
+            if declared.contains(state) {
+                Ok(())
+            } else {
+                Err(format!("{:?}", state))
+            }
+        }));
+        self
+    }
+
+    /// Declares every input of the state machine. It is used by
+    /// [`BasicStateMachineBuilder::into_table()`] together with `states()`.
+    pub fn alphabet(mut self, inputs: impl IntoIterator<Item = Input>) -> Self {
+        self.settings.alphabet = Some(Rc::new(inputs.into_iter().collect()));
+        self
+    }
+
+    /// Sets particular function which computes the initial state lazily, e.g. loading it
+    /// from disk. It is called once in `build()` only if the other fields are valid.
+    /// Note that it is called even if the current state is overridden via `current_state`,
+    /// because [`crate::machine::StateMachine::reset()`] needs the initial state.
+    /// The clones of the builder share the function, so it is called at most once.
+    /// Setting both `initial_state` and `initial_state_with` is an error on building.
+    /// It is ignored by [`StateMachineBuilder::build_many()`].
+    pub fn initial_state_with<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce() -> State + 'static,
+    {
+        self.settings.initial_state_with = Some(Rc::new(LazyState::new(f)));
+        self
+    }
+
+    /// Attaches a tag (e.g. "billable", "terminal") to particular state.
+    /// A state can have multiple tags and a tag can be attached to multiple states.
+    pub fn tag(mut self, state: State, tag: &str) -> Self
+    where
+        State: Eq + Hash,
+    {
+        let tags = Arc::make_mut(&mut self.settings.tags)
+            .entry(state)
+            .or_default();
+        if !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+        }
+        self
+    }
+
+    /// Sets particular validator which checks inputs before they reach the transition.
+    /// Unlike the transition, the validator doesn't depend on the current state.
+    /// See [`crate::machine::BasicStateMachine::try_consume()`].
+    pub fn validate_input<V>(mut self, validator: V) -> Self
+    where
+        V: Fn(&Input) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.settings.input_validator = Some(Arc::new(validator));
+        self
+    }
+
+    /// Sets particular parser of raw inputs, e.g. the words typed in a console, for
+    /// [`crate::machine::BasicStateMachine::consume_str()`].
+    pub fn parse_input_with<P>(mut self, parser: P) -> Self
+    where
+        P: Fn(&str) -> Result<Input, ParseInputError> + Send + Sync + 'static,
+    {
+        self.settings.input_parser = Some(Arc::new(parser));
+        self
+    }
+
+    /// Parses the raw inputs for [`crate::machine::BasicStateMachine::consume_str()`] via
+    /// the `TryFrom<&str>` implementation of `Input`. The error is kept as its `Display`.
+    pub fn parse_input(self) -> Self
+    where
+        Input: for<'a> TryFrom<&'a str> + 'static,
+        for<'a> <Input as TryFrom<&'a str>>::Error: Display,
+    {
+        self.parse_input_with(|raw| {
+            Input::try_from(raw).map_err(|error| ParseInputError::new(error.to_string()))
+        })
+    }
+
+    /// Registers particular guard named `name` which must hold for the transition
+    /// from the current state on the input. Guards are evaluated in the order of registration
+    /// and the first failing one aborts the transition.
+    /// A `&'static str` name is kept borrowed, so the failing guard is reported without allocating.
+    /// See [`crate::machine::BasicStateMachine::consume_checked()`].
+    pub fn add_named_guard<N, G>(mut self, name: N, guard: G) -> Self
+    where
+        N: Into<Cow<'static, str>>,
+        G: Fn(&State, &Input) -> bool + Send + Sync + 'static,
+    {
+        self.settings.guards.push((name.into(), Arc::new(guard)));
+        self
+    }
+
+    /// Memoizes the results of the guards by the pair of the current state and the input,
+    /// so that an expensive guard isn't evaluated again for the same input, e.g. when
+    /// the same input is retried while it is blocked. The memo is dropped whenever
+    /// the current state changes, so the guards must be pure functions of the state
+    /// and the input. Only [`crate::machine::BasicStateMachine::try_consume()`] and its
+    /// variants consult the memo.
+    pub fn guard_cache(mut self) -> Self
+    where
+        State: Eq,
+        Input: Eq + Hash + Clone,
+    {
+        self.settings.guard_cache = Some(memo::evaluate);
+        self
+    }
+
+    /// Forbids resetting from any of `states` via
+    /// [`crate::machine::BasicStateMachine::try_reset()`], e.g. in the middle of a payment.
+    pub fn no_reset_from(mut self, states: impl IntoIterator<Item = State>) -> Self
+    where
+        State: PartialEq + Send + Sync + 'static,
+    {
+        let states: Vec<State> = states.into_iter().collect();
+        self.settings
+            .reset_forbidden
+            .push(Arc::new(move |state: &State| states.contains(state)));
+        self
+    }
+
+    /// Blocks the transitions until the machine has been in the current state for `duration`
+    /// according to the clock set via [`BasicStateMachineBuilder::clock()`], e.g. to debounce
+    /// rapid inputs. The blocked input is discarded and `consume` returns the unchanged state.
+    /// See [`crate::machine::BasicStateMachine::try_consume()`].
+    pub fn min_dwell(mut self, duration: Duration) -> Self {
+        self.settings.min_dwell = Some(duration);
+        self
+    }
+
+    /// Sets the order in which the states may only move forward, e.g. the steps of
+    /// a workflow. [`crate::machine::BasicStateMachine::consume_monotonic()`] rejects
+    /// the transitions to an earlier state in `states`. The states not in `states` are
+    /// unordered, so any transition from or to them is accepted.
+    pub fn state_order(mut self, states: impl IntoIterator<Item = State>) -> Self {
+        self.settings.state_order = states.into_iter().collect();
+        self
+    }
+
+    /// Registers `condition` which the next state must satisfy.
+    /// [`crate::machine::BasicStateMachine::consume_checked_post()`] rolls back
+    /// the transitions into a state failing any of the conditions.
+    pub fn post_condition<F>(mut self, condition: F) -> Self
+    where
+        F: Fn(&State) -> bool + Send + Sync + 'static,
+    {
+        self.settings.post_conditions.push(Arc::new(condition));
+        self
+    }
+
+    /// Sets the epsilon transition, which returns the state to move to from particular
+    /// state without any input, or `None` if no epsilon transition fires there.
+    /// [`crate::machine::BasicStateMachine::consume_closed()`] follows it after each input
+    /// until the state is stable.
+    pub fn epsilon<F>(mut self, step: F) -> Self
+    where
+        F: Fn(&State) -> Option<State> + Send + Sync + 'static,
+    {
+        self.settings.epsilon = Some(Arc::new(step));
+        self
+    }
+
+    /// Registers particular sink which is called with the previous and the next states
+    /// on every transition via [`crate::machine::StateMachine::consume()`], e.g. to bump
+    /// a Prometheus-style counter keyed by `(from, to)`.
+    /// Unlike the enter hooks and the watchers, the sink is called on the fast path of
+    /// [`crate::machine::BasicStateMachine::try_consume()`] too and receives only the borrows,
+    /// so nothing is cloned or allocated around it. It isn't called on `set()` or `reset()`.
+    pub fn metric_sink<F>(mut self, sink: F) -> Self
+    where
+        F: Fn(&State, &State) + Send + Sync + 'static,
+    {
+        self.settings.metric_sinks.push(Arc::new(sink));
+        self
+    }
+
+    /// Registers particular hook which is called only when the machine enters `state`
+    /// via [`crate::machine::StateMachine::consume()`].
+    /// Multiple hooks can be registered for the same state and they are called
+    /// in the order of registration.
+    pub fn on_enter_state<F>(mut self, state: State, hook: F) -> Self
+    where
+        State: PartialEq + Send + Sync + 'static,
+        F: Fn(&State) + Send + Sync + 'static,
+    {
+        self.settings
+            .enter_hooks
+            .push(Arc::new(move |entered: &State| {
+                if entered == &state {
+                    hook(entered)
+                }
+            }));
+        self
+    }
+
+    /// Declares the terminal states, where the workflow modeled by the machine finishes.
+    /// See [`BasicStateMachineBuilder::on_terminal()`].
+    pub fn terminal_states(mut self, states: impl IntoIterator<Item = State>) -> Self
+    where
+        State: PartialEq + Send + Sync + 'static,
+    {
+        let states: Vec<State> = states.into_iter().collect();
+        self.settings.terminal = Some(Arc::new(move |state: &State| states.contains(state)));
+        self
+    }
+
+    /// Registers particular callback which is called when
+    /// [`crate::machine::StateMachine::consume()`] lands on any of the terminal states
+    /// declared via [`BasicStateMachineBuilder::terminal_states()`], e.g. for cleanup.
+    /// Once it is called, it isn't called again until the machine leaves the terminal states
+    /// and returns. It is called after the enter hooks.
+    /// Building fails with [`StateMachineError::MissingField`] if no terminal states are declared.
+    pub fn on_terminal<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&State) + Send + Sync + 'static,
+    {
+        self.settings.terminal_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Registers particular hook which is called only when the machine leaves `state`
+    /// via [`crate::machine::StateMachine::consume()`].
+    /// If the hook returns `Err`, the transition is aborted before the current state is swapped.
+    /// See [`crate::machine::BasicStateMachine::try_consume()`] for the ordering of hooks.
+    pub fn on_exit_state<F>(mut self, state: State, hook: F) -> Self
+    where
+        State: PartialEq + Send + Sync + 'static,
+        F: Fn(&State) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.settings
+            .exit_hooks
+            .push(Arc::new(move |exited: &State| {
+                if exited == &state {
+                    hook(exited)
+                } else {
+                    Ok(())
+                }
+            }));
+        self
+    }
+
+    /// Sets particular clock which is used by the time-aware features.
+    /// [`crate::machine::time::SystemClock`] is used by default.
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + Send + Sync + 'static,
+    {
+        self.settings.clock = Arc::new(clock);
+        self
+    }
+
+    /// Enables reporting the time elapsed since the machine entered the current state,
+    /// measured by the clock of the machine (see [`BasicStateMachineBuilder::clock()`]).
+    /// See [`crate::machine::BasicStateMachine::status()`].
+    pub fn track_time_in_state(mut self) -> Self {
+        self.settings.timed = true;
+        self
+    }
+
+    /// Enables measuring the duration of each transition.
+    /// Only the committed transitions are measured, not the ones rejected by a guard or
+    /// vetoed by an exit hook. See [`crate::machine::BasicStateMachine::timing_by_state()`].
+    pub fn with_timing(mut self) -> Self
+    where
+        State: Eq + Hash,
+    {
+        self.settings.timing = Some(time::accumulate);
+        self
+    }
+
+    /// Enables keeping the longest duration of the transitions for each pair of
+    /// the previous and the next states.
+    /// See [`crate::machine::BasicStateMachine::slowest_transitions()`].
+    pub fn with_metrics(mut self) -> Self
+    where
+        State: Eq + Hash,
+    {
+        self.settings.metrics = Some(time::keep_slowest);
+        self
+    }
+
+    /// Registers particular callback which is called with the previous and the next states
+    /// and the duration when a transition takes longer than `threshold`.
+    /// The duration is measured like [`BasicStateMachineBuilder::with_metrics()`].
+    pub fn on_slow<F>(mut self, threshold: Duration, callback: F) -> Self
+    where
+        F: Fn(&State, &State, Duration) + Send + Sync + 'static,
+    {
+        self.settings
+            .slow_hooks
+            .push((threshold, Arc::new(callback)));
+        self
+    }
+
+    /// Enables counting the transitions by their resulting states.
+    /// See [`crate::machine::BasicStateMachine::state_frequencies()`].
+    pub fn with_state_frequencies(mut self) -> Self
+    where
+        State: Eq + Hash,
+    {
+        self.settings.frequencies = Some(stats::count);
+        self
+    }
+
+    /// Enables tracking the set of the states the machine has ever been in.
+    /// See [`crate::machine::BasicStateMachine::visited_states()`].
+    pub fn track_visited(mut self) -> Self
+    where
+        State: Eq + Hash,
+    {
+        self.settings.visited = Some(stats::insert);
+        self
+    }
+
+    /// Enables the running hash of the states the machine has been in.
+    /// See [`crate::machine::BasicStateMachine::trajectory_hash()`].
+    pub fn track_trajectory_hash(mut self) -> Self
+    where
+        State: Hash,
+    {
+        self.settings.trajectory = Some(stats::fold);
+        self
+    }
+
+    /// Enables keeping the input of the last consumed transition.
+    /// See [`crate::machine::BasicStateMachine::status()`].
+    pub fn track_last_input(mut self) -> Self
+    where
+        Input: Clone,
+    {
+        self.settings.track_last_input = Some(Input::clone);
+        self
+    }
+
+    /// Enables weighing the states the machine has been in by `basis`, e.g. for capacity
+    /// planning. See [`crate::machine::BasicStateMachine::occupancy()`].
+    /// The occupancy starts over on reset unless
+    /// [`BasicStateMachineBuilder::keep_occupancy_on_reset()`] is called.
+    pub fn track_occupancy(mut self, basis: OccupancyBasis) -> Self
+    where
+        State: Eq + Hash,
+    {
+        self.settings.occupancy = Some((time::weigh, basis));
+        self
+    }
+
+    /// Keeps the occupancy on reset, where the reset counts as a transition to
+    /// the initial state. See [`BasicStateMachineBuilder::track_occupancy()`].
+    pub fn keep_occupancy_on_reset(mut self) -> Self {
+        self.settings.keep_occupancy_on_reset = true;
+        self
+    }
+
+    /// Restores the state machine from `snapshot` taken via
+    /// [`crate::machine::BasicStateMachine::full_snapshot()`] on [`StateMachineBuilder::build()`].
+    /// It sets the initial state and the current state, and carries over the epoch,
+    /// the transition count, the generation, the entry time of the current state and the visited states,
+    /// enabling [`BasicStateMachineBuilder::track_visited()`].
+    pub fn restore_full(mut self, snapshot: FullSnapshot<State>) -> Self
+    where
+        State: Eq + Hash,
+    {
+        self.settings.initial_state = Some(snapshot.initial_state.clone());
+        self.settings.current_state = Some(snapshot.current_state.clone());
+        self.settings.visited = Some(stats::insert);
+        self.settings.restored = Some(snapshot);
+        self
+    }
+
+    /// Writes a line per transition to `writer`, e.g. `Off --Press--> On`.
+    /// Errors on writing are ignored.
+    pub fn trace_to<W>(mut self, writer: W) -> Self
+    where
+        W: Write + Send + 'static,
+        State: Display,
+        Input: Display,
+    {
+        self.settings.tracer = Some(Tracer::new(writer));
+        self
+    }
+
+    /// Sets particular predicate over the previous and the next states which decides whether
+    /// the transition is written via [`BasicStateMachineBuilder::trace_to()`], e.g. to log only the
+    /// transitions into an error state. Every transition is written by default.
+    pub fn log_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&State, &State) -> bool + Send + Sync + 'static,
+    {
+        self.settings.log_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Names the state machine `name` and enriches the panics in the transition function,
+    /// e.g. `unreachable!()` hit by an unexpected input, with the name, the `Debug` of
+    /// the current state and the `Debug` of the input. The original message is kept at
+    /// the end of the new one. It costs formatting the state and the input on every
+    /// transition, so it is opt-in.
+    pub fn contextual_panics<N>(mut self, name: N) -> Self
+    where
+        N: Into<Cow<'static, str>>,
+        State: Debug,
+        Input: Debug,
+    {
+        self.settings.panic_context = Some(PanicContext::new(name.into()));
+        self
+    }
+
+    /// Enables recording the history of states.
+    /// See [`crate::machine::BasicStateMachine::history()`].
+    pub fn with_history(mut self) -> Self {
+        self.settings.history = true;
+        self
+    }
+
+    /// Enables recording the history of states like [`BasicStateMachineBuilder::with_history()`],
+    /// keeping only the newest `limit` states. They are kept in a ring buffer pre-allocated
+    /// on building, so recording a state doesn't allocate.
+    /// See [`crate::machine::BasicStateMachine::consume_all_unlimited()`] to lift it temporarily.
+    pub fn history_limit(mut self, limit: usize) -> Self {
+        self.settings.history = true;
+        self.settings.history_limit = Some(limit);
+        self
+    }
+
+    /// Sets the policy applied when a stale snapshot is restored via
+    /// [`crate::machine::BasicStateMachine::restore()`].
+    /// The default is [`StalePolicy::Reject`].
+    pub fn stale_snapshot_policy(mut self, policy: StalePolicy) -> Self {
+        self.settings.stale_snapshot_policy = policy;
+        self
+    }
+
+    /// Registers particular hook which is called with [`StateMachineError::StaleSnapshot`]
+    /// when a stale snapshot is restored anyway under [`StalePolicy::Warn`], e.g. to log it.
+    pub fn on_stale_snapshot<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&StateMachineError) + Send + Sync + 'static,
+    {
+        self.settings.stale_snapshot_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Enables the deduplication of inputs by keys for
+    /// [`crate::machine::BasicStateMachine::consume_idempotent()`], keeping up to
+    /// `capacity` recently seen keys.
+    pub fn dedupe_keys(mut self, capacity: usize) -> Self {
+        self.settings.dedupe_keys = Some(capacity);
+        self
+    }
+
+    /// Keeps the recently seen deduplication keys on [`crate::machine::StateMachine::reset()`].
+    /// See [`BasicStateMachineBuilder::dedupe_keys()`].
+    pub fn keep_dedupe_keys_on_reset(mut self) -> Self {
+        self.settings.keep_dedupe_keys_on_reset = true;
+        self
+    }
+
+    /// Enables the strict borrow mode. The state machine tracks the operation in progress
+    /// (`consume`, `set`, `reset` and `update`) and panics with a clear message when another
+    /// one overlaps it, e.g. `set` called from inside a hook, instead of a `RefCell` panic.
+    /// It is intended for debugging.
+    pub fn strict_borrow(mut self) -> Self {
+        self.settings.strict_borrow = true;
+        self
+    }
+
+    /// Attaches arbitrary metadata (e.g. display names, UI colors) to particular state,
+    /// which is looked up via [`BasicStateMachine::current_metadata()`] and
+    /// [`BasicStateMachine::metadata_for()`]. The first call fixes the type of the metadata,
+    /// which becomes the type parameter `M` of the state machine, so it is looked up
+    /// without downcasting. The later calls add to it, and attaching metadata to the same
+    /// state again replaces the one attached before.
+    /// The metadata can't be changed after the state machine is built.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(false)
+    ///     .transition(|state: &bool, _: ()| !state)
+    ///     .metadata(false, "Button is off")
+    ///     .metadata(true, "Button is on")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// sm.consume(());
+    /// assert_eq!(Some(&"Button is on"), sm.current_metadata());
+    /// ```
+    pub fn metadata<V>(
+        self,
+        state: State,
+        value: V,
+    ) -> BasicStateMachineBuilder<State, Input, Transition, HashMap<State, V>>
+    where
+        M: AttachMetadata<State, V>,
+    {
+        BasicStateMachineBuilder {
+            transition: self.transition,
+            metadata: self.metadata.attach(state, value),
+            settings: self.settings,
+        }
+    }
+
+    /// To finish the builder with type-erasing the transition into [`DynTransition`].
+    /// See [`DynStateMachine`] for the trade-off.
+    /// If it fails, returns [`crate::machine::error::StateMachineError`].
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::{BasicStateMachineBuilder, StateMachineBuilder},
+    ///     DynStateMachine, StateMachine,
+    /// };
+    ///
+    /// let machines: Vec<DynStateMachine<i32, i32>> = vec![
+    ///     BasicStateMachineBuilder::start()
+    ///         .initial_state(0)
+    ///         .transition(|state: &i32, input: i32| state + input)
+    ///         .dynamic()
+    ///         .unwrap(),
+    ///     BasicStateMachineBuilder::start()
+    ///         .initial_state(0)
+    ///         .transition(|state: &i32, input: i32| state * input)
+    ///         .dynamic()
+    ///         .unwrap(),
+    /// ];
+    ///
+    /// assert_eq!(vec![2, 0], machines.iter().map(|sm| sm.consume(2)).collect::<Vec<_>>());
+    /// ```
+    pub fn dynamic(
+        self,
+    ) -> Result<DynStateMachine<State, Input, M::Value>, Box<dyn std::error::Error>>
+    where
+        Transition: Send + Sync + 'static,
+        M: MetadataMap<State> + Default,
+    {
+        BasicStateMachineBuilder {
+            transition: self
+                .transition
+                .map(|transition| Box::new(transition) as DynTransition<State, Input>),
+            metadata: self.metadata,
+            settings: self.settings,
+        }
+        .build()
+    }
 }
 
-impl<State, Input, Transition> StateMachineBuilder<State, Input, Transition>
-    for BasicStateMachineBuilder<State, Input, Transition>
-where
-    Transition: Fn(&State, Input) -> State,
-    State: Clone,
-{
-    type Output = BasicStateMachine<State, Input, Transition>;
+impl<State, Input, Transition, M> BasicStateMachineBuilder<State, Input, Transition, M>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone + Eq + Hash,
+    Input: Clone + Eq + Hash,
+    M: MetadataMap<State> + Default,
+{
+    /// To finish the builder with converting it into the equivalent
+    /// [`crate::machine::table::TableStateMachine`], e.g. to export or analyze it.
+    /// The transition is called for every pair of the states declared via
+    /// [`BasicStateMachineBuilder::states()`] and the inputs declared via
+    /// [`BasicStateMachineBuilder::alphabet()`], and the pairs on which it panics are left
+    /// undefined, i.e. the machine stays in the current state on them.
+    /// The initial state, the current state and the tags are carried over, but the other
+    /// definitions such as hooks and guards aren't.
+    /// Returns [`StateMachineError::MissingField`] if the states or the alphabet aren't declared.
+    ///
+    /// The panics are caught as if the transition were [`std::panic::UnwindSafe`], so it must
+    /// not leave what it captures (e.g. via `RefCell`) broken when it panics. The panic hook
+    /// is silenced for the current thread during the conversion, so that the undefined pairs
+    /// don't print their panic messages, while the panics of the other threads are reported
+    /// as usual.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::{BasicStateMachineBuilder, StateMachineBuilder},
+    ///     StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .transition(|state: &&str, input: &str| match (*state, input) {
+    ///         ("Off", "Press") => "On",
+    ///         ("On", "Press") => "Off",
+    ///         _ => unreachable!(),
+    ///     })
+    ///     .states(["Off", "On"])
+    ///     .alphabet(["Press"])
+    ///     .into_table()
+    ///     .unwrap();
+    ///
+    /// assert_eq!("On", sm.consume("Press"));
+    /// assert!(sm.to_dot().contains(r#""On" -> "Off" [label="Press"];"#));
+    /// ```
+    pub fn into_table(self) -> Result<TableStateMachine<State, Input>, StateMachineError> {
+        let states = match &self.settings.declared_states {
+            Some(states) => Rc::clone(states),
+            None => return Err(StateMachineError::MissingField("states")),
+        };
+        let alphabet = match &self.settings.alphabet {
+            Some(alphabet) => Rc::clone(alphabet),
+            None => return Err(StateMachineError::MissingField("alphabet")),
+        };
+        let sm = self.build().map_err(|err| {
+            *err.downcast::<StateMachineError>()
+                .expect("build() fails only with StateMachineError")
+        })?;
+
+        let mut table = HashMap::new();
+        {
+            let transition = sm.transition.borrow();
+            silence_panics(|| {
+                for state in states.iter() {
+                    for input in alphabet.iter() {
+                        let next = panic::catch_unwind(AssertUnwindSafe(|| {
+                            transition(state, input.clone())
+                        }));
+                        if let Ok(next) = next {
+                            table.insert((state.clone(), input.clone()), next);
+                        }
+                    }
+                }
+            });
+        }
+        Ok(TableStateMachine {
+            current_state: RefCell::new(sm.current_state.into_inner()),
+            initial_state: sm.initial_state,
+            table: Arc::new(table),
+            any_input: Arc::new(HashMap::new()),
+            any_state: Arc::new(HashMap::new()),
+            ignored: Arc::new(HashSet::new()),
+            labels: Arc::new(HashMap::new()),
+            forbidden: Arc::new(HashSet::new()),
+            observers: Arc::new(Vec::new()),
+            tags: Arc::clone(&sm.tags),
+        })
+    }
+}
+
+/// Serializes swapping the panic hook, which is global to the process.
+static PANIC_HOOK: Mutex<()> = Mutex::new(());
+
+/// Runs `f` with the panic hook silenced for the current thread and restores it afterward.
+/// The panics of the other threads are passed to the previous hook.
+fn silence_panics<R>(f: impl FnOnce() -> R) -> R {
+    let _lock = PANIC_HOOK.lock().unwrap_or_else(PoisonError::into_inner);
+    let prev = Arc::new(panic::take_hook());
+    let silenced = thread::current().id();
+    {
+        let prev = Arc::clone(&prev);
+        panic::set_hook(Box::new(move |info| {
+            if thread::current().id() != silenced {
+                prev(info)
+            }
+        }));
+    }
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    drop(panic::take_hook());
+    match Arc::try_unwrap(prev) {
+        Ok(prev) => panic::set_hook(prev),
+        Err(prev) => panic::set_hook(Box::new(move |info| prev(info))),
+    }
+    result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+}
+
+impl<State, Input, Transition, M> Default for BasicStateMachineBuilder<State, Input, Transition, M>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+    M: Default,
+{
+    fn default() -> Self {
+        BasicStateMachineBuilder {
+            transition: None,
+            metadata: Arc::new(M::default()),
+            settings: Settings::default(),
+        }
+    }
+}
+
+impl<State, Input> Default for Settings<State, Input> {
+    fn default() -> Self {
+        Settings {
+            initial_state: None,
+            initial_state_with: None,
+            declared: None,
+            declared_states: None,
+            alphabet: None,
+            current_state: None,
+            tags: Arc::new(HashMap::new()),
+            history: false,
+            history_limit: None,
+            input_validator: None,
+            guards: Vec::new(),
+            guard_cache: None,
+            reset_forbidden: Vec::new(),
+            min_dwell: None,
+            state_order: Vec::new(),
+            post_conditions: Vec::new(),
+            epsilon: None,
+            metric_sinks: Vec::new(),
+            enter_hooks: Vec::new(),
+            exit_hooks: Vec::new(),
+            terminal: None,
+            terminal_hooks: Vec::new(),
+            clock: Arc::new(SystemClock),
+            timing: None,
+            metrics: None,
+            slow_hooks: Vec::new(),
+            frequencies: None,
+            visited: None,
+            trajectory: None,
+            occupancy: None,
+            restored: None,
+            tracer: None,
+            panic_context: None,
+            input_parser: None,
+            log_filter: None,
+            stale_snapshot_policy: StalePolicy::Reject,
+            stale_snapshot_hook: None,
+            dedupe_keys: None,
+            keep_dedupe_keys_on_reset: false,
+            keep_occupancy_on_reset: false,
+            strict_borrow: false,
+            track_last_input: None,
+            timed: false,
+        }
+    }
+}
+
+/// Builds one state machine per given initial state, all of them using clones of
+/// the same `transition`. It is a shorthand of [`StateMachineBuilder::build_many()`].
+///
+/// # Example
+/// ```
+/// use statemachine_rs::machine::{builder::with_shared_transition, StateMachine};
+///
+/// let machines = with_shared_transition(vec![1, 10], |state: &i32, input: i32| state + input);
+///
+/// assert_eq!(2, machines[0].consume(1));
+/// assert_eq!(10, machines[1].current_state());
+/// ```
+pub fn with_shared_transition<State, Input, Transition>(
+    initial_states: Vec<State>,
+    transition: Transition,
+) -> Vec<BasicStateMachine<State, Input, Transition>>
+where
+    Transition: Fn(&State, Input) -> State + Clone,
+    State: Clone,
+{
+    BasicStateMachineBuilder::start()
+        .transition(transition)
+        .build_many(initial_states)
+        .expect("the transition is always set")
+}
+
+/// This builder enables us to assemble [`crate::machine::table::TableStateMachine`]
+/// by adding transitions one by one.
+pub struct TableStateMachineBuilder<State, Input> {
+    initial_state: Option<State>,
+    current_state: Option<State>,
+    table: HashMap<(State, Input), State>,
+    any_input: HashMap<State, State>,
+    any_state: HashMap<Input, State>,
+    ignored: HashSet<(State, Input)>,
+    labels: HashMap<(State, Input), String>,
+    forbidden: HashSet<(State, Input)>,
+    observers: Vec<TransitionObserver<State, Input>>,
+    tags: HashMap<State, Vec<String>>,
+    total: Option<CheckTotal<State, Input>>,
+    duplicate_policy: DuplicatePolicy,
+    rows: usize,
+    errors: Vec<(usize, InsertError)>,
+    declared: Option<HashSet<State>>,
+    require_reachable_current: bool,
+    describe: Option<fn(&State) -> String>,
+}
+
+/// The function which checks that the table decides every pair exactly once.
+/// It is captured where both types are [`Enumerable`], like [`stats::Count`].
+type CheckTotal<State, Input> = fn(&Coverage<'_, State, Input>) -> Result<(), StateMachineError>;
+
+/// The policy applied when a transition for the same pair of the state and the input
+/// is added twice to [`TableStateMachineBuilder`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// The later transition overwrites the earlier one. This is the default.
+    Overwrite,
+    /// The earlier transition is kept and the later one is discarded silently.
+    KeepFirst,
+    /// The earlier transition is kept and the later one is reported as
+    /// [`InsertError::Duplicate`] when the builder is finished.
+    Reject,
+}
+
+impl<State, Input> TableStateMachineBuilder<State, Input>
+where
+    State: Clone + Eq + Hash,
+    Input: Eq + Hash,
+{
+    /// Starts the builder.
+    pub fn start() -> Self {
+        Self::default()
+    }
+
+    /// Sets particular initial state to the state machine.
+    pub fn initial_state(mut self, state: State) -> Self {
+        self.initial_state = Some(state);
+        self
+    }
+
+    /// Sets particular state to the current state.
+    pub fn current_state(mut self, state: State) -> Self {
+        self.current_state = Some(state);
+        self
+    }
+
+    /// Declares every state of the state machine. If declared, `build()` fails with
+    /// [`StateMachineError::UndeclaredState`] when the initial state or the current state
+    /// isn't one of them.
+    pub fn states(mut self, states: impl IntoIterator<Item = State>) -> Self
+    where
+        State: Debug,
+    {
+        self.declared = Some(states.into_iter().collect());
+        self.describe = Some(|state| format!("{:?}", state));
+        self
+    }
+
+    /// Requires the current state to be reachable from the initial state via the transitions.
+    /// If it isn't, `build()` fails with [`StateMachineError::UnreachableState`].
+    /// It is useful to catch a wrong `current_state` override (e.g. restored from a stale snapshot).
+    pub fn require_reachable_current(mut self) -> Self
+    where
+        State: Debug,
+    {
+        self.require_reachable_current = true;
+        self.describe = Some(|state| format!("{:?}", state));
+        self
+    }
+
+    /// Sets particular policy applied when a transition for the same pair of the state
+    /// and the input is added twice. [`DuplicatePolicy::Overwrite`] is used by default.
+    pub fn duplicate_policy(mut self, policy: DuplicatePolicy) -> Self {
+        self.duplicate_policy = policy;
+        self
+    }
+
+    /// Adds a transition from `from` to `to` on `input`.
+    /// If the transition for the pair of `from` and `input` is already defined,
+    /// the duplicate policy is applied (it is overwritten by default).
+    /// See [`TableStateMachineBuilder::duplicate_policy()`].
+    pub fn add_transition(mut self, from: State, input: Input, to: State) -> Self {
+        self.insert_row(from, input, to);
+        self
+    }
+
+    /// Adds every transition of `(from, input, to)` yielded by `rows`, e.g. parsed from a file.
+    /// The capacity is reserved up front according to the size hint of `rows`, and
+    /// the duplicate policy is applied per row. The rows rejected by the policy don't
+    /// abort the insertion; all of them are reported at once as
+    /// [`StateMachineError::BatchInsert`] with their indices when the builder is finished.
+    /// The index counts every transition added to the builder from `0`.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::{DuplicatePolicy, TableStateMachineBuilder},
+    ///     StateMachine,
+    /// };
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .extend_transitions((0..1000).map(|n| (n, "next", n + 1)))
+    ///     .build()
+    ///     .unwrap();
+    /// assert_eq!(1, sm.consume("next"));
+    ///
+    /// let result = TableStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .duplicate_policy(DuplicatePolicy::Reject)
+    ///     .extend_transitions(vec![(0, "next", 1), (0, "next", 2)])
+    ///     .build();
+    /// assert!(result.is_err());
+    /// ```
+    pub fn extend_transitions(
+        mut self,
+        rows: impl IntoIterator<Item = (State, Input, State)>,
+    ) -> Self {
+        let rows = rows.into_iter();
+        self.table.reserve(rows.size_hint().0);
+        for (from, input, to) in rows {
+            self.insert_row(from, input, to);
+        }
+        self
+    }
+
+    /// Inserts a transition according to the duplicate policy.
+    /// Returns `true` if the transition is inserted.
+    fn insert_row(&mut self, from: State, input: Input, to: State) -> bool {
+        let row = self.rows;
+        self.rows += 1;
+        let key = (from, input);
+        if self.table.contains_key(&key) {
+            match self.duplicate_policy {
+                DuplicatePolicy::Overwrite => {
+                    // The overwritten row takes its marks with it.
+                    self.ignored.remove(&key);
+                    self.labels.remove(&key);
+                }
+                DuplicatePolicy::KeepFirst => return false,
+                DuplicatePolicy::Reject => {
+                    self.errors.push((row, InsertError::Duplicate));
+                    return false;
+                }
+            }
+        }
+        self.table.insert(key, to);
+        true
+    }
+
+    /// Adds a transition like [`TableStateMachineBuilder::add_transition()`] with
+    /// a human-readable description (e.g. "customer confirmed payment").
+    /// The label is shown in the exported diagrams.
+    pub fn add_transition_labeled(
+        mut self,
+        from: State,
+        input: Input,
+        to: State,
+        label: &str,
+    ) -> Self
+    where
+        Input: Clone,
+    {
+        if self.insert_row(from.clone(), input.clone(), to) {
+            self.labels.insert((from, input), label.to_string());
+        }
+        self
+    }
+
+    /// Declares that `state` deliberately ignores `inputs`.
+    /// They are recorded as self-loops marked as ignored, so that they are distinguished
+    /// from undefined transitions and unintentional self-loops.
+    /// Each of them is a row like [`TableStateMachineBuilder::add_transition()`], so it is
+    /// subject to [`TableStateMachineBuilder::duplicate_policy()`].
+    pub fn ignore(mut self, state: State, inputs: impl IntoIterator<Item = Input>) -> Self
+    where
+        Input: Clone,
+    {
+        for input in inputs {
+            if self.insert_row(state.clone(), input.clone(), state.clone()) {
+                self.ignored.insert((state.clone(), input));
+            }
+        }
+        self
+    }
+
+    /// Declares that `state` forbids `inputs`. Unlike the undefined transitions, consuming them
+    /// via [`TableStateMachine::try_consume()`] fails with
+    /// [`StateMachineError::ForbiddenTransition`], and they take precedence over
+    /// the wildcard rules. [`crate::machine::StateMachine::consume()`] retains the current state.
+    pub fn forbid(mut self, state: State, inputs: impl IntoIterator<Item = Input>) -> Self {
+        for input in inputs {
+            self.forbidden.insert((state.clone(), input));
+        }
+        self
+    }
+
+    /// Registers `observer` called with each transition taken via
+    /// [`crate::machine::StateMachine::consume()`] or [`TableStateMachine::try_consume()`],
+    /// including the label of the transition. The ignored inputs and the undefined
+    /// transitions aren't notified since the machine doesn't move.
+    ///
+    /// # Example
+    /// ```
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// use statemachine_rs::machine::{builder::TableStateMachineBuilder, StateMachine};
+    ///
+    /// let log = Arc::new(Mutex::new(Vec::new()));
+    /// let sm = {
+    ///     let log = Arc::clone(&log);
+    ///     TableStateMachineBuilder::start()
+    ///         .initial_state("Unpaid")
+    ///         .add_transition_labeled("Unpaid", "Pay", "Paid", "customer confirmed payment")
+    ///         .on_transition(move |context| {
+    ///             log.lock().unwrap().push(context.label.map(String::from))
+    ///         })
+    ///         .build()
+    ///         .unwrap()
+    /// };
+    ///
+    /// sm.consume("Pay");
+    /// assert_eq!(
+    ///     vec![Some("customer confirmed payment".to_string())],
+    ///     *log.lock().unwrap()
+    /// );
+    /// ```
+    pub fn on_transition<F>(mut self, observer: F) -> Self
+    where
+        F: Fn(&TransitionContext<'_, State, Input>) + Send + Sync + 'static,
+    {
+        self.observers.push(Arc::new(observer));
+        self
+    }
+
+    /// Requires the table to decide every pair of the state and the input listed by
+    /// [`Enumerable::all()`] exactly once, by a transition, [`TableStateMachineBuilder::ignore()`]
+    /// or [`TableStateMachineBuilder::forbid()`]. The wildcard rules decide the pairs which
+    /// are declared nothing else, and every wildcard rule must apply to some pair
+    /// (see [`TableStateMachine::shadowed_wildcards()`]). Otherwise `build()` fails with
+    /// [`StateMachineError::IncompleteCoverage`] listing the undecided and the conflicting pairs
+    /// and the shadowed wildcard rules.
+    /// See [`TableStateMachine::coverage_manifest()`] to render the decisions.
+    pub fn total(mut self) -> Self
+    where
+        State: Enumerable + Debug,
+        Input: Enumerable + Debug + Clone,
+    {
+        self.total = Some(|coverage| coverage.check_total());
+        self
+    }
+
+    /// Attaches a tag (e.g. "billable", "terminal") to particular state.
+    /// A state can have multiple tags and a tag can be attached to multiple states.
+    /// See [`TableStateMachine::current_tags()`].
+    pub fn tag(mut self, state: State, tag: &str) -> Self {
+        let tags = self.tags.entry(state).or_default();
+        if !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+        }
+        self
+    }
+
+    /// Adds a rule which transits from `from` to `to` on any input.
+    /// Exact transitions from `from` take precedence over it.
+    pub fn add_any_input(mut self, from: State, to: State) -> Self {
+        self.any_input.insert(from, to);
+        self
+    }
+
+    /// Adds a rule which transits from any state to `to` on `input`.
+    /// Exact transitions and any-input rules take precedence over it.
+    pub fn add_any_state(mut self, input: Input, to: State) -> Self {
+        self.any_state.insert(input, to);
+        self
+    }
+
+    /// To finish the builder. If it fails, returns [`crate::machine::error::StateMachineError`].
+    /// If some transitions are rejected by the duplicate policy, returns
+    /// [`StateMachineError::BatchInsert`].
+    pub fn build(mut self) -> Result<TableStateMachine<State, Input>, Box<dyn std::error::Error>> {
+        self.check_errors()?;
+        match self.initial_state.take() {
+            Some(initial_state) => {
+                // If `current_state` in this builder is still `None`,
+                // sets `initial_state` as the current state forcibly.
+                let current_state = self
+                    .current_state
+                    .take()
+                    .unwrap_or_else(|| initial_state.clone());
+                let mut machines = self.finish(vec![(initial_state, current_state)])?;
+                Ok(machines.remove(0))
+            }
+            None => Err(Box::new(StateMachineError::MissingField("initial_state"))),
+        }
+    }
+
+    /// To finish the builder with building one state machine per given initial state.
+    /// The table is checked once and its rows are shared via `Arc` among the machines,
+    /// each of which holds its own current state. `initial_state` and `current_state`
+    /// set to this builder are ignored.
+    /// If it fails, returns [`crate::machine::error::StateMachineError`].
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{builder::TableStateMachineBuilder, StateMachine};
+    ///
+    /// let machines = TableStateMachineBuilder::start()
+    ///     .add_transition("Off", "Press", "On")
+    ///     .add_transition("On", "Press", "Off")
+    ///     .build_many(vec!["Off", "On"])
+    ///     .unwrap();
+    ///
+    /// assert_eq!("On", machines[0].consume("Press"));
+    /// assert_eq!("Off", machines[1].consume("Press"));
+    /// ```
+    pub fn build_many(
+        mut self,
+        initials: Vec<State>,
+    ) -> Result<Vec<TableStateMachine<State, Input>>, Box<dyn std::error::Error>> {
+        self.check_errors()?;
+        let starts = initials
+            .into_iter()
+            .map(|initial_state| (initial_state.clone(), initial_state))
+            .collect();
+        self.finish(starts)
+    }
+
+    /// Reports the transitions rejected by the duplicate policy, if any.
+    fn check_errors(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Box::new(StateMachineError::BatchInsert(std::mem::take(
+                &mut self.errors,
+            ))))
+        }
+    }
+
+    /// Builds one state machine per pair of the initial and the current states,
+    /// all of which share the rows of the table.
+    fn finish(
+        self,
+        starts: Vec<(State, State)>,
+    ) -> Result<Vec<TableStateMachine<State, Input>>, Box<dyn std::error::Error>> {
+        let describe = self.describe.unwrap_or(|_| String::new());
+        let table = Arc::new(self.table);
+        let any_input = Arc::new(self.any_input);
+        let any_state = Arc::new(self.any_state);
+        let ignored = Arc::new(self.ignored);
+        let labels = Arc::new(self.labels);
+        let forbidden = Arc::new(self.forbidden);
+        let observers = Arc::new(self.observers);
+        let tags = Arc::new(self.tags);
+        let mut machines = Vec::with_capacity(starts.len());
+        for (initial_state, current_state) in starts {
+            if let Some(declared) = &self.declared {
+                for state in &[&initial_state, &current_state] {
+                    if !declared.contains(*state) {
+                        return Err(Box::new(StateMachineError::UndeclaredState(
+                            describe(state).into(),
+                        )));
+                    }
+                }
+            }
+            let sm = TableStateMachine {
+                initial_state,
+                current_state: RefCell::new(StateWrapper::new(current_state)),
+                table: Arc::clone(&table),
+                any_input: Arc::clone(&any_input),
+                any_state: Arc::clone(&any_state),
+                ignored: Arc::clone(&ignored),
+                labels: Arc::clone(&labels),
+                forbidden: Arc::clone(&forbidden),
+                observers: Arc::clone(&observers),
+                tags: Arc::clone(&tags),
+            };
+            // The totality depends on the table only, so it is checked on the first machine.
+            if machines.is_empty() {
+                if let Some(check_total) = self.total {
+                    check_total(&sm.coverage())?;
+                }
+            }
+            if self.require_reachable_current {
+                let current_state = &sm.current_state.borrow().0;
+                if !sm.reachable_states().contains(current_state) {
+                    return Err(Box::new(StateMachineError::UnreachableState(
+                        describe(current_state).into(),
+                    )));
+                }
+            }
+            machines.push(sm);
+        }
+        Ok(machines)
+    }
+}
+
+impl<State, Input> Default for TableStateMachineBuilder<State, Input> {
+    fn default() -> Self {
+        TableStateMachineBuilder {
+            initial_state: None,
+            current_state: None,
+            table: HashMap::new(),
+            any_input: HashMap::new(),
+            any_state: HashMap::new(),
+            ignored: HashSet::new(),
+            labels: HashMap::new(),
+            forbidden: HashSet::new(),
+            observers: Vec::new(),
+            tags: HashMap::new(),
+            total: None,
+            duplicate_policy: DuplicatePolicy::Overwrite,
+            rows: 0,
+            errors: Vec::new(),
+            declared: None,
+            require_reachable_current: false,
+            describe: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::Cell,
+        collections::{HashMap, HashSet},
+        io::Write,
+        panic::{self, AssertUnwindSafe},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::{
+        with_shared_transition, BasicStateMachineBuilder, DuplicatePolicy, StateMachineBuilder,
+        TableStateMachineBuilder,
+    };
+    use crate::machine::{
+        dedupe::IdempotentOutcome,
+        error::{InsertError, ParseInputError, StateMachineError},
+        table::Enumerable,
+        time::{Clock, ManualClock, OccupancyBasis},
+        StateMachine,
+    };
+
+    #[allow(dead_code)]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+    enum Stations {
+        Shibuya,
+        IkejiriOhashi,
+        Sangendyaya,
+        KomazawaDaigaku,
+        Sakurashinmachi,
+        Yoga,
+        FutakoTamagawa,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+    enum Train {
+        Local,
+        Express,
+    }
+
+    #[test]
+    fn test_build() {
+        // sets only initial state
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
+                (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
+                (Stations::KomazawaDaigaku, Train::Local) => Stations::Sakurashinmachi,
+                (Stations::Sakurashinmachi, Train::Local) => Stations::Yoga,
+                _ => unreachable!(),
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!(Stations::Shibuya, sm.current_state());
+
+        // sets current state after initializing initial state
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .current_state(Stations::Sangendyaya)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
+                (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
+                (Stations::KomazawaDaigaku, Train::Local) => Stations::Sakurashinmachi,
+                (Stations::Sakurashinmachi, Train::Local) => Stations::Yoga,
+                _ => unreachable!(),
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!(Stations::Sangendyaya, sm.current_state());
+    }
+
+    #[test]
+    fn test_fail_initial_state() {
+        let sm = BasicStateMachineBuilder::start()
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
+                (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
+                (Stations::KomazawaDaigaku, Train::Local) => Stations::Sakurashinmachi,
+                (Stations::Sakurashinmachi, Train::Local) => Stations::Yoga,
+                _ => unreachable!(),
+            })
+            .build();
+
+        assert!(sm.is_err());
+    }
+
+    #[test]
+    fn test_initial_state_with() {
+        let evaluated = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&evaluated);
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state_with(move || {
+                *counter.lock().unwrap() += 1;
+                Stations::Shibuya
+            })
+            .current_state(Stations::Sangendyaya)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                _ => unreachable!(),
+            })
+            .build()
+            .unwrap();
+        assert_eq!(1, *evaluated.lock().unwrap());
+        assert_eq!(Stations::Sangendyaya, sm.current_state());
+        assert_eq!(Stations::Shibuya, sm.reset());
+        assert_eq!(1, *evaluated.lock().unwrap());
+
+        // not evaluated if the builder fails
+        let counter = Arc::clone(&evaluated);
+        let sm =
+            BasicStateMachineBuilder::<Stations, Train, fn(&Stations, Train) -> Stations>::start()
+                .initial_state_with(move || {
+                    *counter.lock().unwrap() += 1;
+                    Stations::Shibuya
+                })
+                .build();
+        assert!(sm.is_err());
+        assert_eq!(1, *evaluated.lock().unwrap());
+
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .initial_state_with(|| Stations::Shibuya)
+            .transition(|station, _: Train| *station)
+            .build();
+        match sm.map(|_| ()).unwrap_err().downcast_ref() {
+            Some(StateMachineError::ConflictingFields(a, b)) => {
+                assert_eq!(("initial_state", "initial_state_with"), (*a, *b))
+            }
+            _ => panic!("the conflict must be reported"),
+        }
+    }
+
+    #[test]
+    fn test_build_many() {
+        let machines = BasicStateMachineBuilder::start()
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
+                (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
+                (Stations::KomazawaDaigaku, Train::Local) => Stations::Sakurashinmachi,
+                (Stations::Sakurashinmachi, Train::Local) => Stations::Yoga,
+                _ => unreachable!(),
+            })
+            .build_many(
+                (0..1000)
+                    .map(|i| {
+                        if i % 2 == 0 {
+                            Stations::Shibuya
+                        } else {
+                            Stations::Sangendyaya
+                        }
+                    })
+                    .collect(),
+            )
+            .unwrap();
+
+        assert_eq!(1000, machines.len());
+
+        machines[0].consume(Train::Express);
+        machines[1].consume(Train::Express);
+        machines[2].consume(Train::Local);
+
+        assert_eq!(Stations::Sangendyaya, machines[0].current_state());
+        assert_eq!(Stations::FutakoTamagawa, machines[1].current_state());
+        assert_eq!(Stations::IkejiriOhashi, machines[2].current_state());
+        assert_eq!(Stations::Sangendyaya, machines[3].current_state());
+        assert!(machines[4..]
+            .iter()
+            .step_by(2)
+            .all(|sm| sm.current_state() == Stations::Shibuya));
+
+        // each machine resets to its own initial state
+        assert_eq!(Stations::Sangendyaya, machines[1].reset());
+        assert_eq!(Stations::Shibuya, machines[2].reset());
+    }
+
+    #[test]
+    fn test_build_many_validates_once() {
+        let machines = BasicStateMachineBuilder::start()
+            .transition(|count: &i32, step: i32| count + step)
+            .metadata(0, "zero")
+            .states(vec![0, 1])
+            .build_many(vec![0, 1])
+            .unwrap();
+        // the validated parts are shared, not rebuilt per machine
+        assert!(Arc::ptr_eq(&machines[0].metadata, &machines[1].metadata));
+
+        // the shared checks fail even without any machine to build
+        let result = BasicStateMachineBuilder::start()
+            .transition(|count: &i32, step: i32| count + step)
+            .on_terminal(|_| {})
+            .build_many(vec![]);
+        assert!(matches!(
+            result.map(|_| ()).unwrap_err().downcast_ref(),
+            Some(StateMachineError::MissingField("terminal_states"))
+        ));
+
+        // the declared states are still checked per machine
+        let result = BasicStateMachineBuilder::start()
+            .transition(|count: &i32, step: i32| count + step)
+            .states(vec![0, 1])
+            .build_many(vec![0, 2]);
+        assert!(matches!(
+            result.map(|_| ()).unwrap_err().downcast_ref(),
+            Some(StateMachineError::UndeclaredState(_))
+        ));
+    }
+
+    #[test]
+    fn test_build_many_table() {
+        let machines = TableStateMachineBuilder::start()
+            .add_transition(Stations::Shibuya, Train::Local, Stations::IkejiriOhashi)
+            .add_transition(Stations::Shibuya, Train::Express, Stations::Sangendyaya)
+            .add_transition(
+                Stations::Sangendyaya,
+                Train::Express,
+                Stations::FutakoTamagawa,
+            )
+            .build_many(
+                (0..1000)
+                    .map(|i| {
+                        if i % 2 == 0 {
+                            Stations::Shibuya
+                        } else {
+                            Stations::Sangendyaya
+                        }
+                    })
+                    .collect(),
+            )
+            .unwrap();
+
+        assert_eq!(1000, machines.len());
+        assert_eq!(1000, Arc::strong_count(&machines[0].table));
+
+        machines[0].consume(Train::Express);
+        machines[1].consume(Train::Express);
+        assert_eq!(Stations::Sangendyaya, machines[0].current_state());
+        assert_eq!(Stations::FutakoTamagawa, machines[1].current_state());
+        assert_eq!(Stations::Shibuya, machines[2].current_state());
+        assert_eq!(Stations::Sangendyaya, machines[1].reset());
+
+        // The rows shared with the others are copied on compaction.
+        let mut machines = machines.into_iter();
+        let compact = machines.next().unwrap().compact();
+        assert_eq!(Stations::FutakoTamagawa, compact.consume(Train::Express));
+        assert_eq!(999, Arc::strong_count(&machines.next().unwrap().table));
+
+        assert!(TableStateMachineBuilder::start()
+            .states(vec![Stations::Shibuya])
+            .add_transition(Stations::Shibuya, Train::Local, Stations::Shibuya)
+            .build_many(vec![Stations::Shibuya, Stations::Yoga])
+            .is_err());
+    }
+
+    #[test]
+    fn test_build_ref() {
+        let calls = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&calls);
+        let builder = BasicStateMachineBuilder::start()
+            .initial_state_with(move || {
+                *counter.lock().unwrap() += 1;
+                Stations::Shibuya
+            })
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
+                _ => unreachable!(),
+            })
+            .with_history();
+
+        let first = builder.build_ref().unwrap();
+        let second = builder
+            .clone()
+            .current_state(Stations::IkejiriOhashi)
+            .build_ref()
+            .unwrap();
+        let third = builder
+            .current_state(Stations::Sangendyaya)
+            .build()
+            .unwrap();
+        assert_eq!(1, *calls.lock().unwrap());
+
+        first.consume(Train::Local);
+        second.consume(Train::Local);
+        third.consume(Train::Local);
+
+        assert_eq!(Stations::IkejiriOhashi, first.current_state());
+        assert_eq!(Stations::Sangendyaya, second.current_state());
+        assert_eq!(Stations::KomazawaDaigaku, third.current_state());
+        assert_eq!(
+            vec![Stations::Shibuya, Stations::IkejiriOhashi],
+            first.history()
+        );
+        assert_eq!(Stations::Shibuya, third.reset());
+        assert_eq!(Stations::Sangendyaya, second.current_state());
+    }
+
+    #[test]
+    fn test_into_table() {
+        let builder = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .current_state(Stations::IkejiriOhashi)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
+                (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
+                _ => unreachable!(),
+            });
+
+        match builder.clone().states(vec![Stations::Shibuya]).into_table() {
+            Err(StateMachineError::MissingField(field)) => assert_eq!("alphabet", field),
+            _ => panic!("the missing alphabet must be reported"),
+        }
+        match builder.clone().alphabet(vec![Train::Local]).into_table() {
+            Err(StateMachineError::MissingField(field)) => assert_eq!("states", field),
+            _ => panic!("the missing states must be reported"),
+        }
+
+        let states = vec![
+            Stations::Shibuya,
+            Stations::IkejiriOhashi,
+            Stations::Sangendyaya,
+            Stations::KomazawaDaigaku,
+            Stations::FutakoTamagawa,
+        ];
+        let sm = builder.clone().build().unwrap();
+        let table = builder
+            .states(states.clone())
+            .alphabet(vec![Train::Local, Train::Express])
+            .into_table()
+            .unwrap();
+
+        assert_eq!(Stations::IkejiriOhashi, table.current_state());
+        assert_eq!(Stations::Shibuya, table.reset());
+        // the defined pairs behave the same and the undefined ones stay
+        for state in states {
+            sm.set(state);
+            table.set(state);
+            for train in [Train::Local, Train::Express] {
+                let expected =
+                    panic::catch_unwind(AssertUnwindSafe(|| sm.peek(train))).unwrap_or(state);
+                assert_eq!(expected, table.peek(train));
+            }
+        }
+    }
+
+    #[test]
+    fn test_into_table_silences_panics() {
+        let panics = Arc::new(Mutex::new(0));
+        let this = std::thread::current().id();
+        let prev = {
+            let _lock = super::PANIC_HOOK.lock().unwrap();
+            let prev = Arc::new(panic::take_hook());
+            let counted = Arc::clone(&panics);
+            let other = Arc::clone(&prev);
+            panic::set_hook(Box::new(move |info| {
+                if std::thread::current().id() == this {
+                    *counted.lock().unwrap() += 1;
+                } else {
+                    other(info)
+                }
+            }));
+            prev
+        };
+
+        let table = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                _ => unreachable!(),
+            })
+            .states(vec![Stations::Shibuya, Stations::IkejiriOhashi])
+            .alphabet(vec![Train::Local, Train::Express])
+            .into_table()
+            .unwrap();
+        assert_eq!(Stations::IkejiriOhashi, table.consume(Train::Local));
+        // the three undefined pairs didn't reach the hook
+        assert_eq!(0, *panics.lock().unwrap());
+        // the hook is restored afterward
+        assert!(panic::catch_unwind(|| panic!("after the conversion")).is_err());
+        assert_eq!(1, *panics.lock().unwrap());
+
+        let _lock = super::PANIC_HOOK.lock().unwrap();
+        drop(panic::take_hook());
+        panic::set_hook(Box::new(move |info| prev(info)));
+    }
+
+    /// The scenario shared by the static and the dynamic machines.
+    fn assert_local_line(sm: &impl StateMachine<Stations, Train>) {
+        assert_eq!(Stations::Shibuya, sm.current_state());
+        assert_eq!(Stations::IkejiriOhashi, sm.peek(Train::Local));
+        assert_eq!(Stations::IkejiriOhashi, sm.consume(Train::Local));
+        assert_eq!(Stations::Sangendyaya, sm.consume(Train::Local));
+        assert_eq!(Stations::FutakoTamagawa, sm.consume(Train::Express));
+        assert_eq!(Stations::Shibuya, sm.reset());
+        sm.set(Stations::Sangendyaya);
+        assert_eq!(Stations::KomazawaDaigaku, sm.consume(Train::Local));
+    }
+
+    #[test]
+    fn test_dynamic() {
+        type Transition = fn(&Stations, Train) -> Stations;
+
+        fn builder() -> BasicStateMachineBuilder<Stations, Train, Transition> {
+            BasicStateMachineBuilder::<_, _, Transition>::start()
+                .initial_state(Stations::Shibuya)
+                .transition(|station, train| match (station, train) {
+                    (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                    (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                    (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
+                    (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
+                    _ => unreachable!(),
+                })
+                .with_history()
+        }
+
+        let sm = builder().build().unwrap();
+        assert_local_line(&sm);
+        let dynamic = builder().dynamic().unwrap();
+        assert_local_line(&dynamic);
+        assert_eq!(sm.history(), dynamic.history());
+    }
+
+    #[test]
+    fn test_fail_build_many() {
+        let machines =
+            BasicStateMachineBuilder::<Stations, Train, fn(&Stations, Train) -> Stations>::start()
+                .build_many(vec![Stations::Shibuya]);
+
+        assert!(machines.is_err());
+    }
+
+    #[test]
+    fn test_build_table() {
+        let sm = TableStateMachineBuilder::start()
+            .initial_state("Off")
+            .add_transition("Off", "Press", "On")
+            .add_transition("On", "Press", "Off")
+            .build()
+            .unwrap();
+
+        assert_eq!("Off", sm.current_state());
+        assert_eq!("On", sm.consume("Press"));
+
+        let sm = TableStateMachineBuilder::start()
+            .initial_state("Running")
+            .add_transition("Running", "Pause", "Paused")
+            .add_any_input("Maintenance", "Maintenance")
+            .add_any_state("EmergencyStop", "Stopped")
+            .build()
+            .unwrap();
+
+        assert_eq!("Stopped", sm.consume("EmergencyStop"));
+        sm.set("Maintenance");
+        assert_eq!("Maintenance", sm.consume("EmergencyStop"));
+
+        let sm = TableStateMachineBuilder::start()
+            .initial_state("Off")
+            .add_transition("Off", "Press", "On")
+            .ignore("Off", vec!["Release", "Hold"])
+            .build()
+            .unwrap();
+
+        assert_eq!("Off", sm.consume("Hold"));
+        assert!(sm.is_ignored(&"Off", &"Release"));
+        assert!(sm.self_loops().is_empty());
+
+        // the ignored inputs are rows subject to the duplicate policy
+        let sm = TableStateMachineBuilder::start()
+            .initial_state("Off")
+            .ignore("Off", vec!["Press"])
+            .add_transition("Off", "Press", "On")
+            .build()
+            .unwrap();
+        assert!(!sm.is_ignored(&"Off", &"Press"));
+        assert_eq!("On", sm.consume("Press"));
+
+        let result = TableStateMachineBuilder::start()
+            .initial_state("Off")
+            .duplicate_policy(DuplicatePolicy::Reject)
+            .add_transition("Off", "Press", "On")
+            .ignore("Off", vec!["Press"])
+            .build();
+        assert!(result.is_err());
+
+        let sm = TableStateMachineBuilder::<&str, &str>::start()
+            .add_transition("Off", "Press", "On")
+            .build();
+
+        assert!(sm.is_err());
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Door {
+        Open,
+        Closed,
+        Locked,
+    }
+
+    impl Enumerable for Door {
+        fn all() -> Vec<Self> {
+            vec![Door::Open, Door::Closed, Door::Locked]
+        }
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Action {
+        Push,
+        Lock,
+    }
+
+    impl Enumerable for Action {
+        fn all() -> Vec<Self> {
+            vec![Action::Push, Action::Lock]
+        }
+    }
+
+    #[test]
+    fn test_total_table() {
+        let sm = TableStateMachineBuilder::start()
+            .initial_state(Door::Open)
+            .add_transition(Door::Open, Action::Push, Door::Closed)
+            .add_transition(Door::Closed, Action::Lock, Door::Locked)
+            .forbid(Door::Closed, vec![Action::Lock])
+            .total()
+            .build();
+
+        match sm.map(|_| ()).unwrap_err().downcast_ref() {
+            Some(StateMachineError::IncompleteCoverage {
+                undecided,
+                conflicting,
+                shadowed,
+            }) => {
+                assert_eq!(
+                    vec![
+                        "(Open, Lock)",
+                        "(Closed, Push)",
+                        "(Locked, Push)",
+                        "(Locked, Lock)"
+                    ],
+                    *undecided
+                );
+                assert_eq!(vec!["(Closed, Lock)"], *conflicting);
+                assert!(shadowed.is_empty());
+            }
+            _ => panic!("the coverage is incomplete"),
+        }
+
+        let sm = TableStateMachineBuilder::start()
+            .initial_state(Door::Open)
+            .add_transition(Door::Open, Action::Push, Door::Closed)
+            .add_transition(Door::Closed, Action::Lock, Door::Locked)
+            .add_any_state(Action::Push, Door::Open)
+            .ignore(Door::Open, vec![Action::Lock])
+            .forbid(Door::Locked, vec![Action::Lock])
+            .total()
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            "| state | Push | Lock |\n\
+             |---|---|---|\n\
+             | Open | -> Closed | ignore |\n\
+             | Closed | -> Open | -> Locked |\n\
+             | Locked | -> Open | forbid |\n",
+            sm.coverage_manifest()
+        );
+        assert_eq!(Door::Open, sm.try_consume(Action::Lock).unwrap());
+        assert_eq!(Door::Closed, sm.try_consume(Action::Push).unwrap());
+        assert_eq!(Door::Locked, sm.try_consume(Action::Lock).unwrap());
+        assert!(matches!(
+            sm.try_consume(Action::Lock),
+            Err(StateMachineError::ForbiddenTransition)
+        ));
+        assert_eq!(Door::Locked, sm.consume(Action::Lock));
+        assert_eq!(Door::Open, sm.consume(Action::Push));
+
+        // Every pair from `Locked` is decided by a transition or the forbid,
+        // so the any-input rule never applies.
+        let sm = TableStateMachineBuilder::start()
+            .initial_state(Door::Open)
+            .add_transition(Door::Open, Action::Push, Door::Closed)
+            .add_transition(Door::Closed, Action::Lock, Door::Locked)
+            .add_transition(Door::Locked, Action::Push, Door::Open)
+            .add_any_state(Action::Push, Door::Open)
+            .add_any_input(Door::Locked, Door::Closed)
+            .ignore(Door::Open, vec![Action::Lock])
+            .forbid(Door::Locked, vec![Action::Lock])
+            .total()
+            .build();
+        match sm.map(|_| ()).unwrap_err().downcast_ref() {
+            Some(StateMachineError::IncompleteCoverage {
+                undecided,
+                conflicting,
+                shadowed,
+            }) => {
+                assert!(undecided.is_empty());
+                assert!(conflicting.is_empty());
+                assert_eq!(vec!["AnyInput(Locked)"], *shadowed);
+            }
+            _ => panic!("the any-input rule is shadowed"),
+        }
+    }
+
+    #[test]
+    fn test_extend_transitions() {
+        let rows = (0..10_000).map(|n| (n, n % 3, n + 1)).chain(vec![
+            (0, 0, 100),
+            (1, 1, 1),
+            (9_999, 0, 0),
+        ]);
+        let result = TableStateMachineBuilder::start()
+            .initial_state(0)
+            .duplicate_policy(DuplicatePolicy::Reject)
+            .extend_transitions(rows)
+            .build();
+        match result.map(|_| ()).unwrap_err().downcast_ref() {
+            Some(StateMachineError::BatchInsert(errors)) => assert_eq!(
+                &vec![
+                    (10_000, InsertError::Duplicate),
+                    (10_001, InsertError::Duplicate),
+                    (10_002, InsertError::Duplicate),
+                ],
+                errors
+            ),
+            _ => panic!("the duplicates must be reported"),
+        }
+
+        // the first ones are kept
+        let sm = TableStateMachineBuilder::start()
+            .initial_state(0)
+            .duplicate_policy(DuplicatePolicy::KeepFirst)
+            .extend_transitions(vec![(0, "next", 1), (1, "next", 2), (0, "next", 100)])
+            .build()
+            .unwrap();
+        assert_eq!(1, sm.consume("next"));
+
+        // the last ones win by default
+        let sm = TableStateMachineBuilder::start()
+            .initial_state(0)
+            .add_transition(0, "next", 1)
+            .extend_transitions(vec![(1, "next", 2), (0, "next", 100)])
+            .build()
+            .unwrap();
+        assert_eq!(100, sm.consume("next"));
+    }
+
+    #[test]
+    fn test_table_current_state_override() {
+        let builder = || {
+            TableStateMachineBuilder::start()
+                .initial_state(Stations::Shibuya)
+                .add_transition(Stations::Shibuya, Train::Local, Stations::IkejiriOhashi)
+                .add_transition(Stations::IkejiriOhashi, Train::Local, Stations::Sangendyaya)
+                .add_transition(Stations::Yoga, Train::Local, Stations::FutakoTamagawa)
+                .states(vec![
+                    Stations::Shibuya,
+                    Stations::IkejiriOhashi,
+                    Stations::Sangendyaya,
+                    Stations::Yoga,
+                    Stations::FutakoTamagawa,
+                ])
+        };
+
+        let undeclared = builder().current_state(Stations::KomazawaDaigaku).build();
+        match undeclared.map(|_| ()).unwrap_err().downcast_ref() {
+            Some(StateMachineError::UndeclaredState(state)) => {
+                assert_eq!("KomazawaDaigaku", state)
+            }
+            _ => panic!("the undeclared state must be reported"),
+        }
+
+        // `Yoga` is declared, so it is allowed unless the reachability is required
+        assert!(builder().current_state(Stations::Yoga).build().is_ok());
+        let unreachable = builder()
+            .current_state(Stations::Yoga)
+            .require_reachable_current()
+            .build();
+        match unreachable.map(|_| ()).unwrap_err().downcast_ref() {
+            Some(StateMachineError::UnreachableState(state)) => assert_eq!("Yoga", state),
+            _ => panic!("the unreachable state must be reported"),
+        }
+
+        let sm = builder()
+            .current_state(Stations::Sangendyaya)
+            .require_reachable_current()
+            .build()
+            .unwrap();
+        assert_eq!(Stations::Sangendyaya, sm.current_state());
+    }
+
+    #[test]
+    fn test_declared_states() {
+        let builder = || {
+            BasicStateMachineBuilder::start()
+                .initial_state(Stations::Shibuya)
+                .transition(|station, train| match (station, train) {
+                    (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                    _ => unreachable!(),
+                })
+                .states(vec![Stations::Shibuya, Stations::IkejiriOhashi])
+        };
+
+        match builder()
+            .current_state(Stations::Yoga)
+            .build()
+            .map(|_| ())
+            .unwrap_err()
+            .downcast_ref()
+        {
+            Some(StateMachineError::UndeclaredState(state)) => assert_eq!("Yoga", state),
+            _ => panic!("the undeclared state must be reported"),
+        }
+        let sm = builder()
+            .current_state(Stations::IkejiriOhashi)
+            .build()
+            .unwrap();
+        assert_eq!(Stations::IkejiriOhashi, sm.current_state());
+    }
+
+    #[test]
+    fn test_on_enter_state() {
+        let entered = Arc::new(Mutex::new(Vec::new()));
+        let first = Arc::clone(&entered);
+        let second = Arc::clone(&entered);
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
+                _ => unreachable!(),
+            })
+            .on_enter_state(Stations::Sangendyaya, move |station| {
+                first.lock().unwrap().push(("first", *station));
+            })
+            .on_enter_state(Stations::Sangendyaya, move |station| {
+                second.lock().unwrap().push(("second", *station));
+            })
+            .build()
+            .unwrap();
+
+        sm.consume(Train::Local);
+        assert!(entered.lock().unwrap().is_empty());
+        sm.consume(Train::Local);
+        assert_eq!(
+            vec![
+                ("first", Stations::Sangendyaya),
+                ("second", Stations::Sangendyaya)
+            ],
+            *entered.lock().unwrap()
+        );
+        sm.consume(Train::Local);
+        assert_eq!(2, entered.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_update_notifies_observers() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::clone(&log);
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|state: &i32, input: i32| state + input)
+            .build()
+            .unwrap();
+        sm.add_observer_with_priority(0, move |prev: &i32, next: &i32| {
+            observed.lock().unwrap().push((*prev, *next))
+        });
+
+        sm.update(|state| *state += 5);
+        sm.consume(1);
+        assert_eq!(vec![(0, 5), (5, 6)], *log.lock().unwrap());
+        // `set` and `reset` aren't notified
+        sm.set(10);
+        sm.reset();
+        assert_eq!(2, log.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_update_deferred_in_atomic_batch() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::clone(&log);
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|balance: &i32, amount: i32| balance + amount)
+            .add_named_guard("non-negative", |balance: &i32, amount: &i32| {
+                balance + amount >= 0
+            })
+            .build()
+            .unwrap();
+        sm.add_observer_with_priority(0, move |prev: &i32, next: &i32| {
+            observed.lock().unwrap().push((*prev, *next))
+        });
+        let with_updates = |inputs: Vec<i32>| {
+            let log = Arc::clone(&log);
+            let sm = &sm;
+            inputs.into_iter().inspect(move |_| {
+                sm.update(|balance| *balance += 100);
+                // nothing is notified until the batch is committed
+                assert!(log.lock().unwrap().is_empty());
+            })
+        };
+
+        assert!(sm.consume_all_atomic(with_updates(vec![1, -1000])).is_err());
+        assert_eq!(0, sm.current_state());
+        assert!(log.lock().unwrap().is_empty());
+
+        assert_eq!(
+            203,
+            sm.consume_all_atomic(with_updates(vec![1, 2])).unwrap()
+        );
+        assert_eq!(
+            vec![(0, 100), (100, 101), (101, 201), (201, 203)],
+            *log.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_hook_ordering() {
+        #[derive(Clone, Debug, PartialEq)]
+        enum Recorder {
+            Idle,
+            Recording,
+        }
+
+        enum Input {
+            Toggle,
+        }
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let flush_fails = Arc::new(Mutex::new(false));
+        let (guard_log, transition_log, exit_log, enter_log, sink_log, observer_log) = (
+            Arc::clone(&log),
+            Arc::clone(&log),
+            Arc::clone(&log),
+            Arc::clone(&log),
+            Arc::clone(&log),
+            Arc::clone(&log),
+        );
+        let fails = Arc::clone(&flush_fails);
+
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Recorder::Idle)
+            .add_named_guard("always", move |_, _| {
+                guard_log.lock().unwrap().push("guard");
+                true
+            })
+            .transition(move |state, input| {
+                transition_log.lock().unwrap().push("transition");
+                match (state, input) {
+                    (Recorder::Idle, Input::Toggle) => Recorder::Recording,
+                    (Recorder::Recording, Input::Toggle) => Recorder::Idle,
+                }
+            })
+            .on_exit_state(Recorder::Recording, move |_| {
+                exit_log.lock().unwrap().push("exit");
+                if *fails.lock().unwrap() {
+                    Err("failed to flush the buffer".to_string())
+                } else {
+                    Ok(())
+                }
+            })
+            .on_enter_state(Recorder::Idle, move |_| {
+                enter_log.lock().unwrap().push("enter");
+            })
+            .metric_sink(move |_, _| sink_log.lock().unwrap().push("sink"))
+            .with_history()
+            .build()
+            .unwrap();
+        sm.add_observer_with_priority(0, move |_, _| observer_log.lock().unwrap().push("observer"));
+
+        sm.consume(Input::Toggle);
+        assert_eq!(
+            vec!["guard", "transition", "sink", "observer"],
+            *log.lock().unwrap()
+        );
+        log.lock().unwrap().clear();
+
+        // the veto path
+        *flush_fails.lock().unwrap() = true;
+        match sm.try_consume(Input::Toggle) {
+            Err(StateMachineError::ExitVetoed { reason }) => {
+                assert_eq!("failed to flush the buffer", reason)
+            }
+            _ => panic!("the transition must be vetoed"),
+        }
+        assert_eq!(vec!["guard", "transition", "exit"], *log.lock().unwrap());
+        assert_eq!(Recorder::Recording, sm.current_state());
+        assert_eq!(vec![Recorder::Idle, Recorder::Recording], sm.history());
+        log.lock().unwrap().clear();
+
+        *flush_fails.lock().unwrap() = false;
+        assert_eq!(Recorder::Idle, sm.try_consume(Input::Toggle).unwrap());
+        assert_eq!(
+            vec!["guard", "transition", "exit", "enter", "sink", "observer"],
+            *log.lock().unwrap()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "`set` is called on the state machine while `update` is in progress")]
+    fn test_strict_borrow() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station, _: Train| *station)
+            .strict_borrow()
+            .build()
+            .unwrap();
+
+        sm.consume(Train::Local);
+        sm.update(|_| sm.set(Stations::Sangendyaya));
+    }
+
+    #[test]
+    fn test_occupancy() {
+        let clock = Arc::new(ManualClock::new());
+        let build = |basis, keep_on_reset| {
+            let builder = BasicStateMachineBuilder::start()
+                .initial_state(Stations::Shibuya)
+                .transition(|station, _: Train| match station {
+                    Stations::Shibuya => Stations::IkejiriOhashi,
+                    Stations::IkejiriOhashi => Stations::Sangendyaya,
+                    Stations::Sangendyaya => Stations::Shibuya,
+                    _ => unreachable!(),
+                })
+                .clock(Arc::clone(&clock))
+                .track_occupancy(basis);
+            if keep_on_reset {
+                builder.keep_occupancy_on_reset().build().unwrap()
+            } else {
+                builder.build().unwrap()
+            }
+        };
+
+        let sm = build(OccupancyBasis::DwellTime, false);
+        assert!(sm.occupancy().is_empty());
+        assert!(sm.most_visited(1).is_empty());
+        clock.advance(Duration::from_secs(1));
+        sm.consume(Train::Local);
+        clock.advance(Duration::from_secs(2));
+        sm.consume(Train::Local);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(
+            vec![
+                (Stations::Sangendyaya, 0.625),
+                (Stations::IkejiriOhashi, 0.25),
+                (Stations::Shibuya, 0.125),
+            ],
+            sm.occupancy()
+        );
+        assert_eq!(
+            vec![Stations::Sangendyaya, Stations::IkejiriOhashi],
+            sm.most_visited(2)
+        );
+        // the reset starts over by default
+        sm.reset();
+        assert!(sm.occupancy().is_empty());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(vec![(Stations::Shibuya, 1.0)], sm.occupancy());
+
+        let sm = build(OccupancyBasis::DwellTime, true);
+        clock.advance(Duration::from_secs(3));
+        sm.reset();
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(vec![(Stations::Shibuya, 1.0)], sm.occupancy());
+        sm.consume(Train::Local);
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(
+            vec![(Stations::IkejiriOhashi, 0.6), (Stations::Shibuya, 0.4)],
+            sm.occupancy()
+        );
+
+        let sm = build(OccupancyBasis::Visits, false);
+        for _ in 0..5 {
+            clock.advance(Duration::from_secs(1));
+            sm.consume(Train::Local);
+        }
+        // the tie is broken by the order of the first visits, and the initial state
+        // is counted only on returning to it
+        assert_eq!(
+            vec![
+                (Stations::IkejiriOhashi, 0.4),
+                (Stations::Sangendyaya, 0.4),
+                (Stations::Shibuya, 0.2),
+            ],
+            sm.occupancy()
+        );
+        sm.reset();
+        assert!(sm.occupancy().is_empty());
+    }
+
+    #[test]
+    fn test_state_entered_at() {
+        let clock = Arc::new(ManualClock::new());
+        let built_at = clock.now();
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                _ => unreachable!(),
+            })
+            .clock(Arc::clone(&clock))
+            .build()
+            .unwrap();
+        assert_eq!(built_at, sm.state_entered_at());
+
+        clock.advance(Duration::from_secs(1));
+        sm.consume(Train::Local);
+        let first = sm.state_entered_at();
+        assert_eq!(built_at + Duration::from_secs(1), first);
+
+        clock.advance(Duration::from_secs(2));
+        sm.consume(Train::Local);
+        assert_eq!(first + Duration::from_secs(2), sm.state_entered_at());
+
+        clock.advance(Duration::from_secs(3));
+        sm.reset();
+        assert_eq!(clock.now(), sm.state_entered_at());
+        clock.advance(Duration::from_secs(4));
+        sm.set(Stations::Sangendyaya);
+        assert_eq!(clock.now(), sm.state_entered_at());
+    }
+
+    #[test]
+    fn test_timing_by_state() {
+        let clock = Arc::new(ManualClock::new());
+        let ticking = Arc::clone(&clock);
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(move |station, train| {
+                let next = match (station, train) {
+                    (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                    (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                    (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
+                    (Stations::KomazawaDaigaku, Train::Local) => Stations::Sakurashinmachi,
+                    _ => unreachable!(),
+                };
+                // computing `Sangendyaya` is ten times as expensive as the others.
+                match next {
+                    Stations::Sangendyaya => ticking.advance(Duration::from_millis(100)),
+                    _ => ticking.advance(Duration::from_millis(10)),
+                }
+                next
+            })
+            .clock(Arc::clone(&clock))
+            .with_timing()
+            .build()
+            .unwrap();
+
+        assert!(sm.timing_by_state().is_empty());
+        sm.consume(Train::Local);
+        sm.consume(Train::Local);
+        sm.consume(Train::Local);
+        sm.set(Stations::IkejiriOhashi);
+        sm.consume(Train::Local);
+
+        let timings = sm.timing_by_state();
+        assert_eq!(
+            Some(&Duration::from_millis(10)),
+            timings.get(&Stations::IkejiriOhashi)
+        );
+        assert_eq!(
+            Some(&Duration::from_millis(200)),
+            timings.get(&Stations::Sangendyaya)
+        );
+        assert!(timings[&Stations::Sangendyaya] > timings[&Stations::KomazawaDaigaku]);
+    }
+
+    #[test]
+    fn test_slowest_transitions() {
+        let clock = Arc::new(ManualClock::new());
+        let ticking = Arc::clone(&clock);
+        let slow = Arc::new(Mutex::new(Vec::new()));
+        let reported = Arc::clone(&slow);
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(move |station, train| {
+                let (next, cost) = match (station, train) {
+                    (Stations::Shibuya, Train::Local) => (Stations::IkejiriOhashi, 10),
+                    (Stations::IkejiriOhashi, Train::Local) => (Stations::Sangendyaya, 300),
+                    (Stations::Sangendyaya, Train::Local) => (Stations::KomazawaDaigaku, 50),
+                    (Stations::Shibuya, Train::Express) => (Stations::Sangendyaya, 120),
+                    _ => unreachable!(),
+                };
+                ticking.advance(Duration::from_millis(cost));
+                next
+            })
+            .clock(Arc::clone(&clock))
+            .with_metrics()
+            .on_slow(Duration::from_millis(100), move |prev, next, elapsed| {
+                reported.lock().unwrap().push((*prev, *next, elapsed))
+            })
+            .build()
+            .unwrap();
+
+        assert!(sm.slowest_transitions(3).is_empty());
+        sm.consume(Train::Local);
+        sm.consume(Train::Local);
+        sm.consume(Train::Local);
+        // peeking isn't measured.
+        sm.reset();
+        sm.peek(Train::Local);
+        sm.consume(Train::Express);
+
+        assert_eq!(
+            vec![
+                (
+                    (Stations::IkejiriOhashi, Stations::Sangendyaya),
+                    Duration::from_millis(300)
+                ),
+                (
+                    (Stations::Shibuya, Stations::Sangendyaya),
+                    Duration::from_millis(120)
+                ),
+                (
+                    (Stations::Sangendyaya, Stations::KomazawaDaigaku),
+                    Duration::from_millis(50)
+                ),
+            ],
+            sm.slowest_transitions(3)
+        );
+        assert_eq!(1, sm.slowest_transitions(1).len());
+        assert_eq!(4, sm.slowest_transitions(10).len());
+        assert_eq!(
+            vec![
+                (
+                    Stations::IkejiriOhashi,
+                    Stations::Sangendyaya,
+                    Duration::from_millis(300)
+                ),
+                (
+                    Stations::Shibuya,
+                    Stations::Sangendyaya,
+                    Duration::from_millis(120)
+                ),
+            ],
+            *slow.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_vetoed_transition_is_not_measured() {
+        let clock = Arc::new(ManualClock::new());
+        let ticking = Arc::clone(&clock);
+        let slow = Arc::new(Mutex::new(Vec::new()));
+        let reported = Arc::clone(&slow);
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(move |station, train| {
+                ticking.advance(Duration::from_millis(200));
+                match (station, train) {
+                    (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                    (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
+                    _ => unreachable!(),
+                }
+            })
+            .clock(Arc::clone(&clock))
+            .with_timing()
+            .with_metrics()
+            .on_slow(Duration::from_millis(100), move |prev, next, _| {
+                reported.lock().unwrap().push((*prev, *next))
+            })
+            .on_exit_state(Stations::Shibuya, |_| {
+                Err("the doors are still open".to_string())
+            })
+            .build()
+            .unwrap();
+
+        assert!(matches!(
+            sm.try_consume(Train::Express),
+            Err(StateMachineError::ExitVetoed { .. })
+        ));
+        assert!(sm.timing_by_state().is_empty());
+        assert!(sm.slowest_transitions(10).is_empty());
+        assert!(slow.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_status() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                _ => panic!("no such train"),
+            })
+            .track_last_input()
+            .build()
+            .unwrap();
+
+        let status = sm.status();
+        assert_eq!(Stations::Shibuya, status.state);
+        assert_eq!(None, status.last_input);
+        assert_eq!(0, status.transition_count);
+        // the time in the state isn't tracked
+        assert_eq!(None, status.time_in_state);
+
+        sm.consume(Train::Local);
+        sm.set(Stations::Shibuya);
+        sm.consume(Train::Local);
+        let status = sm.status();
+        assert_eq!(Some(Train::Local), status.last_input);
+        // `set` isn't a consumed transition
+        assert_eq!(2, status.transition_count);
+        assert!(!status.is_faulted);
+
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| sm.consume(Train::Express))).is_err());
+        let status = sm.status();
+        assert_eq!(Stations::IkejiriOhashi, status.state);
+        assert_eq!(2, status.transition_count);
+        assert!(status.is_faulted);
+
+        // the rolled back transitions aren't counted
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| {
+            sm.consume_all_atomic(vec![Train::Local, Train::Express])
+        }))
+        .is_err());
+        let status = sm.status();
+        assert_eq!(Stations::IkejiriOhashi, status.state);
+        assert_eq!(2, status.transition_count);
+
+        sm.consume(Train::Local);
+        let status = sm.status();
+        assert_eq!(3, status.transition_count);
+        assert!(!status.is_faulted);
+    }
+
+    #[test]
+    fn test_track_time_in_state() {
+        let clock = Arc::new(ManualClock::new());
+        let build = |timed: bool| {
+            let builder = BasicStateMachineBuilder::start()
+                .initial_state(0)
+                .transition(|count: &i32, step: i32| count + step)
+                .clock(Arc::clone(&clock));
+            if timed {
+                builder.track_time_in_state().build().unwrap()
+            } else {
+                builder.build().unwrap()
+            }
+        };
+
+        let untimed = build(false);
+        let timed = build(true);
+        clock.advance(Duration::from_secs(3));
+        // the clock alone doesn't opt in
+        assert_eq!(None, untimed.status().time_in_state);
+        assert_eq!(Some(Duration::from_secs(3)), timed.status().time_in_state);
+
+        timed.consume(1);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(Some(Duration::from_secs(2)), timed.status().time_in_state);
+    }
+
+    #[test]
+    fn test_min_dwell() {
+        let clock = Arc::new(ManualClock::new());
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                _ => unreachable!(),
+            })
+            .clock(Arc::clone(&clock))
+            .min_dwell(Duration::from_secs(30))
+            .build()
+            .unwrap();
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(Stations::Shibuya, sm.consume(Train::Local));
+        match sm.try_consume(Train::Local) {
+            Err(StateMachineError::DwellTooShort { remaining }) => {
+                assert_eq!(Duration::from_secs(20), remaining)
+            }
+            _ => panic!("the early input must be blocked"),
+        }
+
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(Stations::IkejiriOhashi, sm.consume(Train::Local));
+        // The dwell time starts over in the new state.
+        clock.advance(Duration::from_secs(29));
+        assert_eq!(Stations::IkejiriOhashi, sm.consume(Train::Local));
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(Stations::Sangendyaya, sm.consume(Train::Local));
+    }
+
+    #[test]
+    fn test_consume_with_skips_discarded_input() {
+        let clock = Arc::new(ManualClock::new());
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                _ => unreachable!(),
+            })
+            .clock(Arc::clone(&clock))
+            .min_dwell(Duration::from_secs(30))
+            .build()
+            .unwrap();
+        let made = Cell::new(0);
+        let make = |_: &Stations| {
+            made.set(made.get() + 1);
+            Train::Local
+        };
+
+        // the input would be discarded before the dwell time passes
+        assert_eq!(Stations::Shibuya, sm.consume_with(make));
+        assert_eq!(0, made.get());
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(Stations::IkejiriOhashi, sm.consume_with(make));
+        assert_eq!(1, made.get());
+    }
+
+    /// A large input which can't be cloned.
+    struct Event {
+        payload: Vec<u8>,
+    }
+
+    #[test]
+    fn test_consume_all_ref() {
+        let events: Vec<_> = (1..=4)
+            .map(|n| Event {
+                payload: vec![0; n * 1_000],
+            })
+            .collect();
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|total: &usize, event: &Event| total + event.payload.len())
+            .with_history()
+            .build()
+            .unwrap();
+
+        assert_eq!(1_000, sm.consume_ref(&events[0]));
+        assert_eq!(10_000, sm.consume_all_ref(&events[1..]));
+        assert_eq!(vec![0, 1_000, 3_000, 6_000, 10_000], sm.history());
+        // The inputs are still owned by the caller.
+        assert_eq!(4, events.len());
+    }
 
-    fn start() -> Self {
-        Self::default()
+    #[test]
+    fn test_contextual_panics() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::IkejiriOhashi, Train::Local) => panic!("the line is closed"),
+                (station, train) => unreachable!("{:?} doesn't stop at {:?}", train, station),
+            })
+            .contextual_panics("den-en-toshi")
+            .build()
+            .unwrap();
+        let message = |payload: Box<dyn std::any::Any + Send>| {
+            *payload
+                .downcast::<String>()
+                .expect("the enriched message is formatted")
+        };
+
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| sm.consume(Train::Express)));
+        assert_eq!(
+            "the state machine `den-en-toshi` panicked in the transition from Shibuya by Express: \
+             internal error: entered unreachable code: Express doesn't stop at Shibuya",
+            message(payload.unwrap_err())
+        );
+
+        assert_eq!(Stations::IkejiriOhashi, sm.consume(Train::Local));
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| sm.peek(Train::Local)));
+        assert_eq!(
+            "the state machine `den-en-toshi` panicked in the transition from IkejiriOhashi by Local: \
+             the line is closed",
+            message(payload.unwrap_err())
+        );
+        assert_eq!(Stations::IkejiriOhashi, sm.current_state());
     }
 
-    fn initial_state(mut self, state: State) -> Self {
-        self.initial_state = Some(state);
-        self
+    #[test]
+    fn test_consume_closed() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::Shibuya, Train::Express) => Stations::Yoga,
+                (station, _) => *station,
+            })
+            .epsilon(|station| match station {
+                Stations::IkejiriOhashi => Some(Stations::Sangendyaya),
+                Stations::Sangendyaya => Some(Stations::KomazawaDaigaku),
+                Stations::Yoga => Some(Stations::FutakoTamagawa),
+                Stations::FutakoTamagawa => Some(Stations::Yoga),
+                _ => None,
+            })
+            .with_history()
+            .build()
+            .unwrap();
+        let observed = Arc::new(Mutex::new(Vec::new()));
+        {
+            let observed = Arc::clone(&observed);
+            sm.add_observer_with_priority(0, move |prev: &Stations, next: &Stations| {
+                observed.lock().unwrap().push((*prev, *next))
+            });
+        }
+
+        assert_eq!(Stations::KomazawaDaigaku, sm.consume_closed(Train::Local));
+        assert_eq!(
+            vec![
+                (Stations::Shibuya, Stations::IkejiriOhashi),
+                (Stations::IkejiriOhashi, Stations::Sangendyaya),
+                (Stations::Sangendyaya, Stations::KomazawaDaigaku),
+            ],
+            *observed.lock().unwrap()
+        );
+        assert_eq!(
+            vec![
+                Stations::Shibuya,
+                Stations::IkejiriOhashi,
+                Stations::Sangendyaya,
+                Stations::KomazawaDaigaku,
+            ],
+            sm.history()
+        );
+
+        // The cycle between Yoga and FutakoTamagawa stops before revisiting Yoga.
+        sm.set(Stations::Shibuya);
+        assert_eq!(Stations::FutakoTamagawa, sm.consume_closed(Train::Express));
     }
 
-    fn current_state(mut self, state: State) -> Self {
-        self.current_state = Some(state);
-        self
+    #[test]
+    fn test_consume_closed_rejected_or_paused() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (station, _) => *station,
+            })
+            .add_named_guard("local only", |_, train| *train == Train::Local)
+            // It fires from the current state too, unless no input is applied.
+            .epsilon(|station| match station {
+                Stations::Shibuya | Stations::IkejiriOhashi => Some(Stations::Sangendyaya),
+                _ => None,
+            })
+            .with_history()
+            .build()
+            .unwrap();
+
+        assert_eq!(Stations::Shibuya, sm.consume_closed(Train::Express));
+        assert_eq!(vec![Stations::Shibuya], sm.history());
+
+        sm.pause();
+        assert_eq!(Stations::Shibuya, sm.consume_closed(Train::Local));
+        assert_eq!(vec![Stations::Shibuya], sm.history());
+        // the buffered input is consumed without the epsilon transitions
+        assert_eq!(Stations::IkejiriOhashi, sm.resume());
+        assert_eq!(
+            vec![Stations::Shibuya, Stations::IkejiriOhashi],
+            sm.history()
+        );
+
+        assert_eq!(Stations::IkejiriOhashi, sm.consume_closed(Train::Express));
+        assert_eq!(Stations::Sangendyaya, sm.consume_closed(Train::Local));
     }
 
-    fn transition(mut self, next: Transition) -> Self {
-        self.transition = Some(next);
-        self
+    #[test]
+    fn test_consume_checked_post() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                (_, Train::Express) => Stations::FutakoTamagawa,
+                _ => unreachable!(),
+            })
+            .post_condition(|station| *station != Stations::FutakoTamagawa)
+            .with_history()
+            .build()
+            .unwrap();
+        let observed = Arc::new(Mutex::new(Vec::new()));
+        {
+            let observed = Arc::clone(&observed);
+            sm.add_observer_with_priority(0, move |_, next: &Stations| {
+                observed.lock().unwrap().push(*next)
+            });
+        }
+
+        assert_eq!(
+            Stations::IkejiriOhashi,
+            sm.consume_checked_post(Train::Local).unwrap()
+        );
+        assert!(matches!(
+            sm.consume_checked_post(Train::Express),
+            Err(StateMachineError::PostConditionFailed)
+        ));
+        // The transition into the forbidden state left no trace.
+        assert_eq!(Stations::IkejiriOhashi, sm.current_state());
+        assert_eq!(
+            vec![Stations::Shibuya, Stations::IkejiriOhashi],
+            sm.history()
+        );
+        assert_eq!(vec![Stations::IkejiriOhashi], *observed.lock().unwrap());
+
+        assert_eq!(
+            Stations::Sangendyaya,
+            sm.consume_checked_post(Train::Local).unwrap()
+        );
     }
 
-    fn build(self) -> Result<Self::Output, Box<dyn std::error::Error>> {
-        match (self.initial_state, self.transition) {
-            (Some(initial_state), Some(transition)) => Ok(BasicStateMachine {
-                initial_state: initial_state.clone(),
-                current_state: {
-                    // If `current_state` in this builder is still `None`,
-                    // sets `initial_state` as the current state forcibly.
-                    let current_state = self.current_state;
-                    match current_state {
-                        Some(state) => RefCell::new(StateWrapper::new(state)),
-                        None => RefCell::new(StateWrapper::new(initial_state)),
-                    }
-                },
-                transition,
-                _maker: self._marker,
-            }),
-            (None, _) => Err(Box::new(StateMachineError::MissingField(
-                "initial_state".to_string(),
-            ))),
-            (_, None) => Err(Box::new(StateMachineError::MissingField(
-                "transition".to_string(),
-            ))),
+    #[test]
+    fn test_consume_monotonic() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
+                (Stations::KomazawaDaigaku, Train::Local) => Stations::Sakurashinmachi,
+                (_, Train::Express) => Stations::Shibuya,
+                _ => unreachable!(),
+            })
+            .state_order(vec![
+                Stations::Shibuya,
+                Stations::IkejiriOhashi,
+                Stations::Sangendyaya,
+            ])
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            Stations::IkejiriOhashi,
+            sm.consume_monotonic(Train::Local).unwrap()
+        );
+        assert_eq!(
+            Stations::Sangendyaya,
+            sm.consume_monotonic(Train::Local).unwrap()
+        );
+        match sm.consume_monotonic(Train::Express) {
+            Err(StateMachineError::Regression { from, to }) => {
+                assert_eq!(("Sangendyaya", "Shibuya"), (from.as_str(), to.as_str()))
+            }
+            _ => panic!("the backward transition must be rejected"),
         }
+        assert_eq!(Stations::Sangendyaya, sm.current_state());
+
+        // The states out of the order are free to transit.
+        assert_eq!(
+            Stations::KomazawaDaigaku,
+            sm.consume_monotonic(Train::Local).unwrap()
+        );
+        assert_eq!(
+            Stations::Shibuya,
+            sm.consume_monotonic(Train::Express).unwrap()
+        );
     }
-}
 
-impl<State, Input, Transition> Default for BasicStateMachineBuilder<State, Input, Transition>
-where
-    Transition: Fn(&State, Input) -> State,
-    State: Clone,
-{
-    fn default() -> Self {
-        BasicStateMachineBuilder {
-            initial_state: None,
-            current_state: None,
-            transition: None,
-            _marker: PhantomData::<Input>::default(),
+    #[test]
+    fn test_consume_validated() {
+        let transited = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&transited);
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(move |station, train: Option<Train>| {
+                *counter.lock().unwrap() += 1;
+                match (station, train) {
+                    (Stations::Shibuya, Some(Train::Local)) => Stations::IkejiriOhashi,
+                    _ => unreachable!(),
+                }
+            })
+            .validate_input(|train: &Option<Train>| match train {
+                Some(_) => Ok(()),
+                None => Err("no train is specified".to_string()),
+            })
+            .with_history()
+            .build()
+            .unwrap();
+
+        match sm.consume_validated(None) {
+            Err(StateMachineError::InvalidInput { reason }) => {
+                assert_eq!("no train is specified", reason)
+            }
+            _ => panic!("the input must be rejected"),
         }
+        assert_eq!(0, *transited.lock().unwrap());
+        assert_eq!(Stations::Shibuya, sm.current_state());
+        assert_eq!(vec![Stations::Shibuya], sm.history());
+
+        assert_eq!(
+            Stations::IkejiriOhashi,
+            sm.consume_validated(Some(Train::Local)).unwrap()
+        );
+        assert_eq!(1, *transited.lock().unwrap());
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::{BasicStateMachineBuilder, StateMachineBuilder};
-    use crate::machine::StateMachine;
+    #[test]
+    fn test_consume_checked() {
+        let evaluated = Arc::new(Mutex::new(Vec::new()));
+        let first = Arc::clone(&evaluated);
+        let second = Arc::clone(&evaluated);
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
+                _ => unreachable!(),
+            })
+            .add_named_guard("in service", move |_, _| {
+                first.lock().unwrap().push("in service");
+                true
+            })
+            .add_named_guard("local only", move |_, train| {
+                second.lock().unwrap().push("local only");
+                matches!(train, Train::Local)
+            })
+            .build()
+            .unwrap();
 
-    #[allow(dead_code)]
-    #[derive(Copy, Clone, Debug, PartialEq)]
-    enum Stations {
-        Shibuya,
-        IkejiriOhashi,
-        Sangendyaya,
-        KomazawaDaigaku,
-        Sakurashinmachi,
-        Yoga,
-        FutakoTamagawa,
+        match sm.consume_checked(Train::Express) {
+            Err(StateMachineError::GuardFailed { name }) => assert_eq!("local only", name),
+            _ => panic!("the transition must be blocked"),
+        }
+        assert_eq!(vec!["in service", "local only"], *evaluated.lock().unwrap());
+        assert_eq!(Stations::Shibuya, sm.current_state());
+        // the infallible path also leaves the state untouched
+        assert_eq!(Stations::Shibuya, sm.consume(Train::Express));
+
+        assert_eq!(
+            Stations::IkejiriOhashi,
+            sm.consume_checked(Train::Local).unwrap()
+        );
     }
 
-    #[allow(dead_code)]
-    enum Train {
-        Local,
-        Express,
+    #[test]
+    fn test_guard_cache() {
+        let evaluated = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&evaluated);
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(50)
+            .transition(|balance, amount| balance - amount)
+            .add_named_guard("sufficient", move |balance: &i32, amount: &i32| {
+                *counter.lock().unwrap() += 1;
+                balance >= amount
+            })
+            .guard_cache()
+            .build()
+            .unwrap();
+
+        for _ in 0..3 {
+            match sm.try_consume(100) {
+                Err(StateMachineError::GuardFailed { name }) => assert_eq!("sufficient", name),
+                _ => panic!("the transition must be blocked"),
+            }
+        }
+        assert_eq!(1, *evaluated.lock().unwrap());
+        assert_eq!(50, sm.current_state());
+
+        // another input misses the memo
+        assert_eq!(20, sm.try_consume(30).unwrap());
+        assert_eq!(2, *evaluated.lock().unwrap());
+        // the memo for the previous state is dropped
+        assert!(sm.try_consume(100).is_err());
+        assert!(sm.try_consume(100).is_err());
+        assert_eq!(3, *evaluated.lock().unwrap());
     }
 
     #[test]
-    fn test_build() {
-        // sets only initial state
+    fn test_consume_str() {
         let sm = BasicStateMachineBuilder::start()
             .initial_state(Stations::Shibuya)
             .transition(|station, train| match (station, train) {
                 (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
                 (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
+                (Stations::IkejiriOhashi, _) => Stations::Sangendyaya,
+                _ => unreachable!(),
+            })
+            .parse_input_with(|raw| match raw {
+                "local" => Ok(Train::Local),
+                "express" => Ok(Train::Express),
+                _ => Err(ParseInputError::new("unknown train")),
+            })
+            .add_named_guard("local only", |_, train| matches!(train, Train::Local))
+            .build()
+            .unwrap();
+
+        assert_eq!(Stations::IkejiriOhashi, sm.consume_str("local").unwrap());
+
+        match sm.consume_str("bullet") {
+            Err(StateMachineError::UnparsableInput { raw, error }) => {
+                assert_eq!("bullet", raw);
+                assert_eq!(ParseInputError::new("unknown train"), error);
+            }
+            _ => panic!("the raw input must be rejected by the parser"),
+        }
+        match sm.consume_str("express") {
+            Err(StateMachineError::GuardFailed { name }) => assert_eq!("local only", name),
+            _ => panic!("the transition must be blocked"),
+        }
+        assert_eq!(Stations::IkejiriOhashi, sm.current_state());
+    }
+
+    #[test]
+    fn test_no_reset_from() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station, train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
                 (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
                 (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
-                (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
-                (Stations::KomazawaDaigaku, Train::Local) => Stations::Sakurashinmachi,
-                (Stations::Sakurashinmachi, Train::Local) => Stations::Yoga,
                 _ => unreachable!(),
             })
+            .no_reset_from(vec![Stations::IkejiriOhashi])
+            .no_reset_from(vec![Stations::Sangendyaya])
             .build()
             .unwrap();
 
+        sm.consume(Train::Local);
+        assert!(matches!(
+            sm.try_reset(),
+            Err(StateMachineError::ResetForbidden)
+        ));
+        assert_eq!(Stations::IkejiriOhashi, sm.current_state());
+        sm.consume(Train::Local);
+        assert!(sm.try_reset().is_err());
+        sm.consume(Train::Local);
+        assert_eq!(Stations::Shibuya, sm.try_reset().unwrap());
         assert_eq!(Stations::Shibuya, sm.current_state());
+    }
 
-        // sets current state after initializing initial state
+    #[test]
+    fn test_metric_sink() {
+        let counts = Arc::new(Mutex::new(HashMap::new()));
+        let sink = Arc::clone(&counts);
         let sm = BasicStateMachineBuilder::start()
             .initial_state(Stations::Shibuya)
-            .current_state(Stations::Sangendyaya)
             .transition(|station, train| match (station, train) {
                 (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
-                (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
                 (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
-                (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
                 (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
-                (Stations::KomazawaDaigaku, Train::Local) => Stations::Sakurashinmachi,
-                (Stations::Sakurashinmachi, Train::Local) => Stations::Yoga,
                 _ => unreachable!(),
             })
+            .metric_sink(move |from, to| {
+                *sink.lock().unwrap().entry((*from, *to)).or_insert(0) += 1;
+            })
             .build()
             .unwrap();
 
-        assert_eq!(Stations::Sangendyaya, sm.current_state());
+        for _ in 0..3 {
+            sm.consume(Train::Local);
+            sm.consume(Train::Local);
+            sm.consume(Train::Express);
+            sm.reset();
+        }
+        sm.set(Stations::IkejiriOhashi);
+
+        let counts = counts.lock().unwrap();
+        assert_eq!(3, counts.len());
+        assert_eq!(
+            Some(&3),
+            counts.get(&(Stations::Shibuya, Stations::IkejiriOhashi))
+        );
+        assert_eq!(
+            Some(&3),
+            counts.get(&(Stations::IkejiriOhashi, Stations::Sangendyaya))
+        );
+        assert_eq!(
+            Some(&3),
+            counts.get(&(Stations::Sangendyaya, Stations::FutakoTamagawa))
+        );
     }
 
     #[test]
-    fn test_fail_initial_state() {
+    fn test_state_frequencies() {
+        #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+        enum Switch {
+            On,
+            Off,
+        }
+
         let sm = BasicStateMachineBuilder::start()
-            .transition(|station, train| match (station, train) {
+            .initial_state(Switch::Off)
+            .transition(|switch, toggle: bool| match (switch, toggle) {
+                (Switch::Off, true) => Switch::On,
+                (Switch::On, true) => Switch::Off,
+                (switch, false) => *switch,
+            })
+            .with_state_frequencies()
+            .build()
+            .unwrap();
+
+        assert!(sm.state_frequencies().is_empty());
+        for toggle in [true, true, true, false, true, false, false] {
+            sm.consume(toggle);
+        }
+        // set and reset aren't counted
+        sm.set(Switch::On);
+        sm.reset();
+
+        let frequencies = sm.state_frequencies();
+        assert_eq!(2, frequencies.len());
+        assert_eq!(3, frequencies[&Switch::On]);
+        assert_eq!(4, frequencies[&Switch::Off]);
+    }
+
+    #[test]
+    fn test_with_shared_transition() {
+        let machines = with_shared_transition(
+            vec![
+                Stations::Shibuya,
+                Stations::IkejiriOhashi,
+                Stations::Sangendyaya,
+            ],
+            |station: &Stations, train: Train| match (station, train) {
                 (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
                 (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
                 (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
                 (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
                 (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
-                (Stations::KomazawaDaigaku, Train::Local) => Stations::Sakurashinmachi,
-                (Stations::Sakurashinmachi, Train::Local) => Stations::Yoga,
+                _ => unreachable!(),
+            },
+        );
+
+        assert_eq!(Stations::Sangendyaya, machines[0].consume(Train::Express));
+        assert_eq!(Stations::Sangendyaya, machines[1].consume(Train::Local));
+        assert_eq!(
+            Stations::FutakoTamagawa,
+            machines[2].consume(Train::Express)
+        );
+        assert_eq!(
+            Stations::FutakoTamagawa,
+            machines[0].consume(Train::Express)
+        );
+        assert_eq!(Stations::Sangendyaya, machines[1].current_state());
+        assert_eq!(Stations::IkejiriOhashi, machines[1].reset());
+    }
+
+    #[derive(Clone)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace_to() {
+        let buffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state("Off")
+            .transition(|state, input| match (*state, input) {
+                ("Off", "Press") => "On",
+                ("On", "Press") => "Off",
                 _ => unreachable!(),
             })
-            .build();
+            .trace_to(buffer.clone())
+            .build()
+            .unwrap();
 
-        assert!(sm.is_err());
+        sm.consume("Press");
+        sm.consume("Press");
+        sm.peek("Press");
+
+        assert_eq!(
+            "Off --Press--> On\nOn --Press--> Off\n",
+            String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_log_filter() {
+        let buffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state("Idle")
+            .transition(|state, input| match (*state, input) {
+                ("Idle", "start") => "Running",
+                ("Running", "fail") => "Error",
+                ("Error", "retry") => "Running",
+                ("Running", "stop") => "Idle",
+                _ => unreachable!(),
+            })
+            .trace_to(buffer.clone())
+            .log_filter(|_, to| *to == "Error")
+            .build()
+            .unwrap();
+
+        for input in ["start", "fail", "retry", "stop", "start", "fail"] {
+            sm.consume(input);
+        }
+
+        assert_eq!(
+            "Running --fail--> Error\nRunning --fail--> Error\n",
+            String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_trajectory_hash() {
+        let run = |inputs: &[Train]| {
+            let sm = BasicStateMachineBuilder::start()
+                .initial_state(Stations::Shibuya)
+                .transition(|station, train| match (station, train) {
+                    (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                    (Stations::Shibuya, Train::Express) => Stations::FutakoTamagawa,
+                    (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                    (Stations::IkejiriOhashi, Train::Express) => Stations::FutakoTamagawa,
+                    (Stations::FutakoTamagawa, _) => Stations::Shibuya,
+                    _ => Stations::FutakoTamagawa,
+                })
+                .track_trajectory_hash()
+                .build()
+                .unwrap();
+            for train in inputs {
+                sm.consume(*train);
+            }
+            sm.trajectory_hash()
+        };
+
+        let local = [Train::Local, Train::Local, Train::Express];
+        assert_eq!(run(&local), run(&local));
+        assert_ne!(run(&local), run(&[Train::Express, Train::Local]));
+        assert_ne!(run(&local), run(&[Train::Local, Train::Express]));
+        assert_ne!(run(&[]), run(&local));
+    }
+
+    #[test]
+    fn test_dedupe_keys() {
+        let build = |keep_on_reset: bool| {
+            let builder = BasicStateMachineBuilder::start()
+                .initial_state(0)
+                .transition(|count: &i32, step: i32| count + step)
+                .dedupe_keys(2);
+            if keep_on_reset {
+                builder.keep_dedupe_keys_on_reset().build().unwrap()
+            } else {
+                builder.build().unwrap()
+            }
+        };
+
+        let sm = build(false);
+        assert_eq!(IdempotentOutcome::Applied(1), sm.consume_idempotent(10, 1));
+        assert_eq!(
+            IdempotentOutcome::AlreadyApplied(1),
+            sm.consume_idempotent(10, 1)
+        );
+        assert_eq!(IdempotentOutcome::Applied(2), sm.consume_idempotent(20, 1));
+        assert_eq!(IdempotentOutcome::Applied(3), sm.consume_idempotent(30, 1));
+        // The key 10 is evicted as the oldest one.
+        assert_eq!(IdempotentOutcome::Applied(4), sm.consume_idempotent(10, 1));
+        assert_eq!(
+            IdempotentOutcome::AlreadyApplied(4),
+            sm.consume_idempotent(30, 1)
+        );
+
+        sm.reset();
+        assert_eq!(IdempotentOutcome::Applied(1), sm.consume_idempotent(30, 1));
+
+        let sm = build(true);
+        assert_eq!(IdempotentOutcome::Applied(1), sm.consume_idempotent(30, 1));
+        sm.reset();
+        assert_eq!(
+            IdempotentOutcome::AlreadyApplied(0),
+            sm.consume_idempotent(30, 1)
+        );
+    }
+
+    #[test]
+    fn test_consume_idempotent_retry_after_rejection() {
+        let limit = Arc::new(Mutex::new(1));
+        let sm = {
+            let limit = Arc::clone(&limit);
+            BasicStateMachineBuilder::start()
+                .initial_state(0)
+                .transition(|count: &i32, step: i32| count + step)
+                .add_named_guard("within limit", move |_: &i32, step: &i32| {
+                    *step <= *limit.lock().unwrap()
+                })
+                .dedupe_keys(4)
+                .build()
+                .unwrap()
+        };
+
+        assert_eq!(IdempotentOutcome::Rejected(0), sm.consume_idempotent(10, 2));
+        *limit.lock().unwrap() = 2;
+        assert_eq!(IdempotentOutcome::Applied(2), sm.consume_idempotent(10, 2));
+        assert_eq!(
+            IdempotentOutcome::AlreadyApplied(2),
+            sm.consume_idempotent(10, 2)
+        );
+    }
+
+    #[test]
+    fn test_consume_all_atomic() {
+        let transitions = Arc::new(Mutex::new(Vec::new()));
+        let entered = Arc::new(Mutex::new(0));
+        let sm = {
+            let transitions = Arc::clone(&transitions);
+            let entered = Arc::clone(&entered);
+            BasicStateMachineBuilder::start()
+                .initial_state(0)
+                .transition(|balance: &i32, amount: i32| balance + amount)
+                .add_named_guard("non-negative", |balance: &i32, amount: &i32| {
+                    balance + amount >= 0
+                })
+                .metric_sink(move |prev: &i32, next: &i32| {
+                    transitions.lock().unwrap().push((*prev, *next))
+                })
+                .on_enter_state(10, move |_| *entered.lock().unwrap() += 1)
+                .with_history()
+                .with_state_frequencies()
+                .build()
+                .unwrap()
+        };
+
+        assert_eq!(10, sm.consume_all_atomic(vec![5, 5]).unwrap());
+        assert_eq!(vec![(0, 5), (5, 10)], *transitions.lock().unwrap());
+        assert_eq!(1, *entered.lock().unwrap());
+        let generation = sm.generation();
+        let frequencies = sm.state_frequencies();
+
+        let err = sm.consume_all_atomic(vec![-10, 10, -20, 1, 2]).unwrap_err();
+        assert_eq!(2, err.index);
+        assert!(matches!(err.error, StateMachineError::GuardFailed { .. }));
+        assert_eq!(vec![1, 2], err.remaining);
+        assert_eq!(10, err.state);
+        assert_eq!(10, sm.current_state());
+        assert_eq!(vec![0, 5, 10], sm.history());
+        assert_eq!(generation, sm.generation());
+        assert_eq!(frequencies, sm.state_frequencies());
+        // The observers never see the rolled back transitions.
+        assert_eq!(vec![(0, 5), (5, 10)], *transitions.lock().unwrap());
+        assert_eq!(1, *entered.lock().unwrap());
+
+        assert_eq!(10, sm.consume(0));
+        assert_eq!((10, 10), *transitions.lock().unwrap().last().unwrap());
+        assert_eq!(2, *entered.lock().unwrap());
+    }
+
+    #[test]
+    fn test_consume_all_atomic_rollback() {
+        let buffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+        let reached = Arc::new(Mutex::new(0));
+        let terminal_log = Arc::clone(&reached);
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|balance: &i32, amount: i32| {
+                assert!(amount != 0, "zero amount");
+                balance + amount
+            })
+            .add_named_guard("non-negative", |balance: &i32, amount: &i32| {
+                balance + amount >= 0
+            })
+            .terminal_states(vec![100])
+            .on_terminal(move |_| *terminal_log.lock().unwrap() += 1)
+            .trace_to(buffer.clone())
+            .history_limit(2)
+            .track_visited()
+            .guard_cache()
+            .build()
+            .unwrap();
+        let traced = || String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+
+        assert!(sm.consume_all_atomic(vec![50, 50, -200]).is_err());
+        assert_eq!(vec![0], sm.history());
+        assert_eq!(HashSet::from([0]), sm.visited_states());
+        assert_eq!(0, *reached.lock().unwrap());
+        assert_eq!("", traced());
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| sm.consume_all_atomic(vec![3, 0])));
+        assert!(result.is_err());
+        assert_eq!(0, sm.current_state());
+        assert_eq!(0, sm.generation());
+        assert_eq!(vec![0], sm.history());
+        assert_eq!(HashSet::from([0]), sm.visited_states());
+        assert_eq!("", traced());
+
+        assert_eq!(5, sm.consume_all_atomic(vec![2, 3]).unwrap());
+        assert_eq!(vec![2, 5], sm.history());
+        assert_eq!("0 --2--> 2\n2 --3--> 5\n", traced());
+        assert_eq!(100, sm.consume_all_atomic(vec![95]).unwrap());
+        assert_eq!(1, *reached.lock().unwrap());
+    }
+
+    #[test]
+    fn test_on_terminal() {
+        let reached = Arc::new(Mutex::new(Vec::new()));
+        let sm = {
+            let reached = Arc::clone(&reached);
+            BasicStateMachineBuilder::start()
+                .initial_state(Stations::Shibuya)
+                .transition(|station, train| match (station, train) {
+                    (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                    (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                    (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
+                    (Stations::Sangendyaya, Train::Local) => Stations::Sangendyaya,
+                    (Stations::FutakoTamagawa, _) => Stations::Sangendyaya,
+                    _ => unreachable!(),
+                })
+                .terminal_states(vec![Stations::Sangendyaya, Stations::FutakoTamagawa])
+                .on_terminal(move |station| reached.lock().unwrap().push(*station))
+                .build()
+                .unwrap()
+        };
+
+        sm.consume(Train::Local);
+        assert!(reached.lock().unwrap().is_empty());
+        sm.consume(Train::Local);
+        sm.consume(Train::Local);
+        sm.consume(Train::Express);
+        sm.consume(Train::Local);
+        assert_eq!(vec![Stations::Sangendyaya], *reached.lock().unwrap());
+
+        sm.reset();
+        sm.consume(Train::Local);
+        sm.consume(Train::Local);
+        assert_eq!(
+            vec![Stations::Sangendyaya, Stations::Sangendyaya],
+            *reached.lock().unwrap()
+        );
+
+        let result = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station, _: Train| *station)
+            .on_terminal(|_| {})
+            .build();
+        assert!(result.is_err());
     }
 }
@@ -0,0 +1,323 @@
+use std::{cell::RefCell, hash::Hash, sync::Arc};
+
+use super::{
+    error::StateMachineError,
+    table::{TableStateMachine, TransitionContext, TransitionObserver},
+    StateMachine, StateWrapper,
+};
+
+/// The table state machine resolving transitions by binary search over sorted `Vec`s
+/// instead of hashing. It is converted from [`TableStateMachine`] via
+/// [`TableStateMachine::compact()`], and behaves the same as the original one.
+///
+/// It is a middle ground between the hash table and a dense array: the lookups are
+/// cache-friendly and need no hashing, while the states and the inputs can still be
+/// arbitrary types as long as they are [`Ord`]. It pays off mostly on tiny tables with
+/// cheap keys; see `benches/compact.rs` for where each backend wins.
+///
+/// It is `Send` if the state and the input are `Send`, but never `Sync`.
+pub struct CompactTableStateMachine<State, Input> {
+    initial_state: State,
+    current_state: RefCell<StateWrapper<State>>,
+    /// Sorted by the pairs of the state and the input.
+    table: Vec<(State, Input, State)>,
+    /// Sorted by the states.
+    any_input: Vec<(State, State)>,
+    /// Sorted by the inputs.
+    any_state: Vec<(Input, State)>,
+    /// Sorted by the pairs of the state and the input.
+    forbidden: Vec<(State, Input)>,
+    /// Sorted by the pairs of the state and the input.
+    labels: Vec<(State, Input, String)>,
+    /// Sorted by the pairs of the state and the input.
+    ignored: Vec<(State, Input)>,
+    observers: Arc<Vec<TransitionObserver<State, Input>>>,
+}
+
+impl<State, Input> CompactTableStateMachine<State, Input>
+where
+    State: Clone + Ord,
+    Input: Ord,
+{
+    /// Resolves the transition according to the same precedence order as
+    /// [`TableStateMachine`]: forbidden pairs, exact transitions, any-input rules and
+    /// any-state rules.
+    fn lookup(&self, state: &State, input: &Input) -> Option<&State> {
+        if self.is_forbidden(state, input) {
+            return None;
+        }
+        let exact = self
+            .table
+            .binary_search_by(|(from, on, _)| from.cmp(state).then_with(|| on.cmp(input)))
+            .ok()
+            .map(|index| &self.table[index].2);
+        exact
+            .or_else(|| find(&self.any_input, state))
+            .or_else(|| find(&self.any_state, input))
+    }
+
+    fn next(&self, input: &Input) -> Option<State> {
+        self.lookup(&self.current_state.borrow().0, input).cloned()
+    }
+
+    fn is_forbidden(&self, state: &State, input: &Input) -> bool {
+        contains(&self.forbidden, state, input)
+    }
+
+    /// Transits like [`TableStateMachine::try_consume()`].
+    pub fn try_consume(&self, input: Input) -> Result<State, StateMachineError> {
+        if self.is_forbidden(&self.current_state.borrow().0, &input) {
+            return Err(StateMachineError::ForbiddenTransition);
+        }
+        Ok(self.consume(input))
+    }
+
+    /// Returns the label of the transition from `from` on `input`
+    /// like [`TableStateMachine::label_of()`].
+    pub fn label_of(&self, from: &State, input: &Input) -> Option<&str> {
+        self.labels
+            .binary_search_by(|(state, on, _)| state.cmp(from).then_with(|| on.cmp(input)))
+            .ok()
+            .map(|index| self.labels[index].2.as_str())
+    }
+}
+
+/// Returns `true` if `pairs` sorted by the pairs contains the pair of `state` and `input`.
+fn contains<State: Ord, Input: Ord>(
+    pairs: &[(State, Input)],
+    state: &State,
+    input: &Input,
+) -> bool {
+    pairs
+        .binary_search_by(|(from, on)| from.cmp(state).then_with(|| on.cmp(input)))
+        .is_ok()
+}
+
+/// Finds the value of `key` in `entries` sorted by the keys.
+fn find<'a, K: Ord, V>(entries: &'a [(K, V)], key: &K) -> Option<&'a V> {
+    entries
+        .binary_search_by(|(k, _)| k.cmp(key))
+        .ok()
+        .map(|index| &entries[index].1)
+}
+
+impl<State, Input> StateMachine<State, Input> for CompactTableStateMachine<State, Input>
+where
+    State: Clone + Ord,
+    Input: Ord,
+{
+    fn current_state(&self) -> State {
+        self.current_state.borrow().get()
+    }
+
+    fn consume(&self, input: Input) -> State {
+        let new_state = match self.next(&input) {
+            Some(new_state) => new_state,
+            None => return self.current_state(),
+        };
+        let prev = self.current_state.borrow_mut().set(new_state.clone());
+        if !self.observers.is_empty() && !contains(&self.ignored, &prev, &input) {
+            let context = TransitionContext {
+                from: &prev,
+                input: &input,
+                to: &new_state,
+                label: self.label_of(&prev, &input),
+            };
+            for observer in self.observers.iter() {
+                observer(&context);
+            }
+        }
+        new_state
+    }
+
+    fn peek(&self, input: Input) -> State {
+        self.next(&input).unwrap_or_else(|| self.current_state())
+    }
+
+    fn reset(&self) -> State {
+        self.current_state
+            .borrow_mut()
+            .set(self.initial_state.clone());
+        self.initial_state.clone()
+    }
+
+    fn set(&self, new_state: State) {
+        self.current_state.borrow_mut().set(new_state);
+    }
+}
+
+impl<State, Input> TableStateMachine<State, Input>
+where
+    State: Clone + Ord + Hash,
+    Input: Clone + Ord + Hash,
+{
+    /// Converts the state machine into [`CompactTableStateMachine`] keeping its current state,
+    /// the forbidden and the ignored pairs, the labels and the observers.
+    /// The rows are copied only if they are still shared with other machines
+    /// built by [`crate::machine::builder::TableStateMachineBuilder::build_many()`].
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{builder::TableStateMachineBuilder, StateMachine};
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "Press", "On")
+    ///     .add_transition("On", "Press", "Off")
+    ///     .build()
+    ///     .unwrap()
+    ///     .compact();
+    ///
+    /// assert_eq!("On", sm.consume("Press"));
+    /// assert_eq!("On", sm.consume("Pull"));
+    /// ```
+    pub fn compact(self) -> CompactTableStateMachine<State, Input> {
+        let mut table: Vec<(State, Input, State)> = unshare(self.table)
+            .into_iter()
+            .map(|((from, input), to)| (from, input, to))
+            .collect();
+        table.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+        let mut any_input: Vec<(State, State)> = unshare(self.any_input).into_iter().collect();
+        any_input.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut any_state: Vec<(Input, State)> = unshare(self.any_state).into_iter().collect();
+        any_state.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut forbidden: Vec<(State, Input)> = unshare(self.forbidden).into_iter().collect();
+        forbidden.sort();
+        let mut labels: Vec<(State, Input, String)> = unshare(self.labels)
+            .into_iter()
+            .map(|((from, input), label)| (from, input, label))
+            .collect();
+        labels.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+        let mut ignored: Vec<(State, Input)> = unshare(self.ignored).into_iter().collect();
+        ignored.sort();
+        CompactTableStateMachine {
+            initial_state: self.initial_state,
+            current_state: self.current_state,
+            table,
+            any_input,
+            any_state,
+            forbidden,
+            labels,
+            ignored,
+            observers: self.observers,
+        }
+    }
+}
+
+/// Takes the rows out of the `Arc`, cloning them only if another machine still shares them.
+fn unshare<T: Clone>(rows: Arc<T>) -> T {
+    Arc::try_unwrap(rows).unwrap_or_else(|rows| (*rows).clone())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use crate::machine::{
+        builder::TableStateMachineBuilder, table::TableStateMachine, StateMachine,
+    };
+
+    fn table() -> TableStateMachine<&'static str, &'static str> {
+        TableStateMachineBuilder::start()
+            .initial_state("Cart")
+            .add_transition("Payment", "Confirm", "Paid")
+            .add_transition("Payment", "Cancel", "Cart")
+            .add_transition("Maintenance", "Abort", "Paid")
+            .add_any_input("Maintenance", "Maintenance")
+            .add_any_state("Abort", "Cart")
+            .add_any_state("Cancel", "Cancelled")
+            .add_transition_labeled("Cart", "Checkout", "Payment", "proceed to payment")
+            .ignore("Paid", vec!["Checkout"])
+            .forbid("Paid", vec!["Cancel"])
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_same_as_hash_backend() {
+        let states = [
+            "Cart",
+            "Payment",
+            "Paid",
+            "Maintenance",
+            "Cancelled",
+            "Unknown",
+        ];
+        let inputs = ["Checkout", "Confirm", "Cancel", "Abort", "Unknown"];
+        let hash = table();
+        let compact = table().compact();
+
+        for state in states {
+            hash.set(state);
+            compact.set(state);
+            for input in inputs {
+                assert_eq!(
+                    hash.peek(input),
+                    compact.peek(input),
+                    "{} on {}",
+                    state,
+                    input
+                );
+                assert_eq!(
+                    hash.try_consume(input).is_ok(),
+                    compact.try_consume(input).is_ok(),
+                    "{} on {}",
+                    state,
+                    input
+                );
+                assert_eq!(
+                    hash.label_of(&state, &input),
+                    compact.label_of(&state, &input)
+                );
+                hash.set(state);
+                compact.set(state);
+            }
+        }
+        assert_eq!(
+            Some("proceed to payment"),
+            compact.label_of(&"Cart", &"Checkout")
+        );
+
+        assert_eq!("Cart", compact.reset());
+        assert_eq!("Payment", compact.consume("Checkout"));
+        assert_eq!("Paid", compact.consume("Confirm"));
+        assert_eq!("Cart", compact.consume("Abort"));
+    }
+
+    #[test]
+    fn test_keep_observers() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sm = {
+            let log = Arc::clone(&log);
+            TableStateMachineBuilder::start()
+                .initial_state("Cart")
+                .add_transition_labeled("Cart", "Checkout", "Payment", "proceed to payment")
+                .ignore("Payment", vec!["Checkout"])
+                .on_transition(move |context| {
+                    log.lock()
+                        .unwrap()
+                        .push((*context.to, context.label.map(String::from)))
+                })
+                .build()
+                .unwrap()
+                .compact()
+        };
+
+        sm.consume("Checkout");
+        // the ignored input isn't notified
+        sm.consume("Checkout");
+        assert_eq!(
+            vec![("Payment", Some("proceed to payment".to_string()))],
+            *log.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_keep_current_state() {
+        let sm = table();
+        sm.consume("Checkout");
+        let sm = sm.compact();
+        assert_eq!("Payment", sm.current_state());
+        assert_eq!("Cart", sm.reset());
+    }
+}
@@ -0,0 +1,191 @@
+use std::cell::Cell;
+
+use super::StateMachine;
+
+/// The transition table known at compile time. Each entry is `(state, input, next)`.
+/// It is validated by [`ConstTable::new()`] in const context, so an invalid table is
+/// a compile error. Looking it up doesn't allocate or hash.
+///
+/// # Example
+/// ```compile_fail
+/// use statemachine_rs::machine::const_table::ConstTable;
+///
+/// // the transition for the pair of `0` and `0` is defined twice
+/// const TABLE: ConstTable<2> = ConstTable::new([(0, 0, 1), (0, 0, 0)], 2, 1);
+/// let _ = TABLE;
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConstTable<const N: usize> {
+    transitions: [(u8, u8, u8); N],
+}
+
+impl<const N: usize> ConstTable<N> {
+    /// Creates the table from `transitions` for the machine whose states are
+    /// `0..states` and whose inputs are `0..inputs`.
+    ///
+    /// # Panics
+    /// Panics if a state or an input is out of the bounds, or the transition for
+    /// the same pair of the state and the input is defined twice.
+    /// In const context, it is a compile error.
+    pub const fn new(transitions: [(u8, u8, u8); N], states: u8, inputs: u8) -> Self {
+        let mut i = 0;
+        while i < N {
+            let (state, input, next) = transitions[i];
+            if state >= states || next >= states {
+                panic!("the state is out of the bounds");
+            }
+            if input >= inputs {
+                panic!("the input is out of the bounds");
+            }
+            let mut j = 0;
+            while j < i {
+                if transitions[j].0 == state && transitions[j].1 == input {
+                    panic!("the transition is defined twice");
+                }
+                j += 1;
+            }
+            i += 1;
+        }
+        ConstTable { transitions }
+    }
+
+    /// Returns the next state of `state` on `input`, if defined.
+    pub const fn lookup(&self, state: u8, input: u8) -> Option<u8> {
+        let mut i = 0;
+        while i < N {
+            let (from, on, next) = self.transitions[i];
+            if from == state && on == input {
+                return Some(next);
+            }
+            i += 1;
+        }
+        None
+    }
+}
+
+/// The state machine driven by [`ConstTable`]. States and inputs are `u8`s,
+/// e.g. the discriminants of fieldless enums. Like
+/// [`crate::machine::table::TableStateMachine`], it retains in its current state
+/// if no transition is defined for the input.
+///
+/// It is always `Send` but never `Sync`, since the current state is kept in a `Cell`.
+///
+/// # Example
+/// ```
+/// use statemachine_rs::machine::{
+///     const_table::{ConstTable, ConstTableStateMachine},
+///     StateMachine,
+/// };
+///
+/// const OFF: u8 = 0;
+/// const ON: u8 = 1;
+/// const PRESS: u8 = 0;
+///
+/// const TABLE: ConstTable<2> = ConstTable::new([(OFF, PRESS, ON), (ON, PRESS, OFF)], 2, 1);
+///
+/// let sm = ConstTableStateMachine::new(TABLE, OFF);
+/// assert_eq!(ON, sm.consume(PRESS));
+/// assert_eq!(OFF, sm.consume(PRESS));
+/// ```
+pub struct ConstTableStateMachine<const N: usize> {
+    table: ConstTable<N>,
+    initial_state: u8,
+    current_state: Cell<u8>,
+}
+
+impl<const N: usize> ConstTableStateMachine<N> {
+    /// Creates a new state machine starting from `initial_state`.
+    pub const fn new(table: ConstTable<N>, initial_state: u8) -> Self {
+        ConstTableStateMachine {
+            table,
+            initial_state,
+            current_state: Cell::new(initial_state),
+        }
+    }
+}
+
+impl<const N: usize> StateMachine<u8, u8> for ConstTableStateMachine<N> {
+    fn current_state(&self) -> u8 {
+        self.current_state.get()
+    }
+
+    fn consume(&self, input: u8) -> u8 {
+        let new_state = self.peek(input);
+        self.current_state.set(new_state);
+        new_state
+    }
+
+    fn peek(&self, input: u8) -> u8 {
+        let current_state = self.current_state.get();
+        self.table
+            .lookup(current_state, input)
+            .unwrap_or(current_state)
+    }
+
+    fn reset(&self) -> u8 {
+        self.current_state.set(self.initial_state);
+        self.initial_state
+    }
+
+    fn set(&self, new_state: u8) {
+        self.current_state.set(new_state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ConstTable, ConstTableStateMachine};
+    use crate::machine::{builder::TableStateMachineBuilder, StateMachine};
+
+    const SHIBUYA: u8 = 0;
+    const IKEJIRI_OHASHI: u8 = 1;
+    const SANGENDYAYA: u8 = 2;
+    const FUTAKO_TAMAGAWA: u8 = 3;
+
+    const LOCAL: u8 = 0;
+    const EXPRESS: u8 = 1;
+
+    const TRANSITIONS: [(u8, u8, u8); 4] = [
+        (SHIBUYA, LOCAL, IKEJIRI_OHASHI),
+        (SHIBUYA, EXPRESS, SANGENDYAYA),
+        (IKEJIRI_OHASHI, LOCAL, SANGENDYAYA),
+        (SANGENDYAYA, EXPRESS, FUTAKO_TAMAGAWA),
+    ];
+    const TABLE: ConstTable<4> = ConstTable::new(TRANSITIONS, 4, 2);
+
+    #[test]
+    fn test_same_as_table_state_machine() {
+        let sm = ConstTableStateMachine::new(TABLE, SHIBUYA);
+        let table = TableStateMachineBuilder::start()
+            .initial_state(SHIBUYA)
+            .extend_transitions(TRANSITIONS)
+            .build()
+            .unwrap();
+
+        for state in SHIBUYA..=FUTAKO_TAMAGAWA {
+            sm.set(state);
+            table.set(state);
+            for input in [LOCAL, EXPRESS] {
+                assert_eq!(table.peek(input), sm.peek(input));
+            }
+        }
+
+        assert_eq!(SHIBUYA, sm.reset());
+        assert_eq!(IKEJIRI_OHASHI, sm.consume(LOCAL));
+        assert_eq!(SANGENDYAYA, sm.consume(LOCAL));
+        assert_eq!(SANGENDYAYA, sm.consume(LOCAL));
+        assert_eq!(FUTAKO_TAMAGAWA, sm.consume(EXPRESS));
+    }
+
+    #[test]
+    #[should_panic(expected = "the transition is defined twice")]
+    fn test_duplicate_at_runtime() {
+        ConstTable::new([(0, 0, 1), (0, 0, 0)], 2, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "the input is out of the bounds")]
+    fn test_out_of_bounds_at_runtime() {
+        ConstTable::new([(0, 1, 1)], 2, 1);
+    }
+}
@@ -0,0 +1,103 @@
+//! Tests for `Cow<'static, str>` states, which are mostly `&'static str` labels
+//! with occasional owned dynamic ones.
+
+use std::borrow::Cow;
+
+use super::{
+    builder::{BasicStateMachineBuilder, StateMachineBuilder, TableStateMachineBuilder},
+    table::TableStateMachine,
+    StateMachine,
+};
+
+type Label = Cow<'static, str>;
+
+fn owned(label: &str) -> Label {
+    Cow::Owned(label.to_string())
+}
+
+fn table(initial_state: Label) -> TableStateMachine<Label, Label> {
+    TableStateMachineBuilder::start()
+        .initial_state(initial_state)
+        .add_transition(Cow::Borrowed("Off"), Cow::Borrowed("press"), owned("On"))
+        .add_transition(
+            Cow::Borrowed("On"),
+            Cow::Borrowed("press"),
+            Cow::Borrowed("Off"),
+        )
+        .add_any_state(owned("fail"), Cow::Borrowed("Broken"))
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn test_basic_machine() {
+    let sm = BasicStateMachineBuilder::start()
+        .initial_state(Cow::Borrowed("Idle"))
+        .transition(|state: &Label, job: &str| match (state.as_ref(), job) {
+            ("Idle", "") => Cow::Borrowed("Idle"),
+            (_, job) => owned(&format!("Running {}", job)),
+        })
+        .tag(Cow::Borrowed("Running build"), "busy")
+        .with_history()
+        .build()
+        .unwrap();
+
+    // cloning the borrowed variant doesn't allocate a `String`
+    assert!(matches!(sm.current_state(), Cow::Borrowed("Idle")));
+    assert_eq!("Running build", sm.consume("build"));
+    assert!(matches!(sm.current_state(), Cow::Owned(_)));
+    // the owned state finds the tag attached to the borrowed one
+    assert!(sm.has_tag("busy"));
+    assert_eq!("Running build", sm.current_state_ref().as_ref());
+    assert_eq!(vec!["Idle", "Running build"], sm.history());
+}
+
+#[test]
+fn test_table_machine() {
+    let sm = table(Cow::Borrowed("Off"));
+
+    // the owned and the borrowed variants hash and compare identically
+    assert_eq!(owned("On"), sm.consume(owned("press")));
+    assert_eq!(Cow::Borrowed("Off"), sm.consume(Cow::Borrowed("press")));
+    assert_eq!("On", sm.consume_by("press"));
+    assert_eq!("Broken", sm.consume_by("fail"));
+    assert_eq!("Broken", sm.consume_by("press"));
+}
+
+#[test]
+fn test_export() {
+    let borrowed = table(Cow::Borrowed("Off"));
+    let owned = table(owned("Off"));
+
+    assert_eq!(borrowed.to_dot(), owned.to_dot());
+    assert_eq!(borrowed.to_mermaid(), owned.to_mermaid());
+    let states = [Cow::Borrowed("Off"), Cow::Borrowed("On")];
+    let inputs = [Cow::Borrowed("press"), Cow::Borrowed("fail")];
+    assert_eq!(
+        borrowed.to_csv_matrix(&states, &inputs),
+        owned.to_csv_matrix(&states, &inputs)
+    );
+    assert!(borrowed.diff(&owned).is_empty());
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+    let sm = table(Cow::Borrowed("Off"));
+    sm.consume_by("press");
+
+    // restores the machine from the snapshot of its current state
+    let snapshot = sm.current_state();
+    let restored = TableStateMachineBuilder::start()
+        .initial_state(Cow::Borrowed("Off"))
+        .current_state(snapshot)
+        .add_transition(Cow::Borrowed("Off"), Cow::Borrowed("press"), owned("On"))
+        .add_transition(
+            Cow::Borrowed("On"),
+            Cow::Borrowed("press"),
+            Cow::Borrowed("Off"),
+        )
+        .build()
+        .unwrap();
+    assert_eq!(sm.current_state(), restored.current_state());
+    assert_eq!(sm.consume_by("press"), restored.consume_by("press"));
+}
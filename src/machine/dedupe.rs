@@ -0,0 +1,65 @@
+use std::collections::{HashSet, VecDeque};
+
+/// The outcome of [`crate::machine::BasicStateMachine::consume_idempotent()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IdempotentOutcome<State> {
+    /// The input is consumed and the machine transited to the state.
+    Applied(State),
+    /// The key has been seen recently, so the input is ignored.
+    /// It holds the current state.
+    AlreadyApplied(State),
+    /// The input is rejected, e.g. by a guard or the validator, so the key isn't recorded
+    /// and a retry with the same key is applied. It holds the current state.
+    Rejected(State),
+}
+
+/// The recently seen deduplication keys, bounded by the capacity.
+/// The oldest key is evicted first when it is full.
+pub(crate) struct DedupeKeys {
+    capacity: usize,
+    keep_on_reset: bool,
+    seen: HashSet<u64>,
+    order: VecDeque<u64>,
+}
+
+impl DedupeKeys {
+    pub(crate) fn new(capacity: usize, keep_on_reset: bool) -> Self {
+        DedupeKeys {
+            capacity,
+            keep_on_reset,
+            seen: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Returns `true` if `key` has been seen recently.
+    pub(crate) fn contains(&self, key: u64) -> bool {
+        self.seen.contains(&key)
+    }
+
+    /// Records `key` and returns `true` if it hasn't been seen recently.
+    pub(crate) fn insert(&mut self, key: u64) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+        if self.seen.contains(&key) {
+            return false;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.order.push_back(key);
+        self.seen.insert(key);
+        true
+    }
+
+    /// Forgets every key unless they are kept on reset.
+    pub(crate) fn reset(&mut self) {
+        if !self.keep_on_reset {
+            self.seen.clear();
+            self.order.clear();
+        }
+    }
+}
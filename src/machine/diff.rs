@@ -0,0 +1,476 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    hash::Hash,
+};
+
+use super::table::TableStateMachine;
+
+/// The difference between the definitions of two table machines.
+/// It is created by [`TableStateMachine::diff()`].
+/// Swapping the arguments of `diff` inverts added/removed ones.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DefinitionDiff<State, Input> {
+    /// The states which only appear in the other definition.
+    pub added_states: Vec<State>,
+    /// The states which only appear in this definition.
+    pub removed_states: Vec<State>,
+    /// The transitions `(from, input, to)` which are only defined in the other definition.
+    pub added_transitions: Vec<(State, Input, State)>,
+    /// The transitions `(from, input, to)` which are only defined in this definition.
+    pub removed_transitions: Vec<(State, Input, State)>,
+    /// The transitions `(from, input, old target, new target)` whose targets are changed.
+    pub retargeted_transitions: Vec<(State, Input, State, State)>,
+    /// The any-input rules `(from, to)` which are only defined in the other definition.
+    pub added_any_input: Vec<(State, State)>,
+    /// The any-input rules `(from, to)` which are only defined in this definition.
+    pub removed_any_input: Vec<(State, State)>,
+    /// The any-input rules `(from, old target, new target)` whose targets are changed.
+    pub retargeted_any_input: Vec<(State, State, State)>,
+    /// The any-state rules `(input, to)` which are only defined in the other definition.
+    pub added_any_state: Vec<(Input, State)>,
+    /// The any-state rules `(input, to)` which are only defined in this definition.
+    pub removed_any_state: Vec<(Input, State)>,
+    /// The any-state rules `(input, old target, new target)` whose targets are changed.
+    pub retargeted_any_state: Vec<(Input, State, State)>,
+    /// The pairs `(state, input)` which are only ignored in the other definition.
+    pub added_ignored: Vec<(State, Input)>,
+    /// The pairs `(state, input)` which are only ignored in this definition.
+    pub removed_ignored: Vec<(State, Input)>,
+    /// The pairs `(state, input)` which are only forbidden in the other definition.
+    pub added_forbidden: Vec<(State, Input)>,
+    /// The pairs `(state, input)` which are only forbidden in this definition.
+    pub removed_forbidden: Vec<(State, Input)>,
+    /// The labels `(from, input, old label, new label)` which are changed,
+    /// where `None` means that the transition has no label in that definition.
+    pub relabeled: Vec<(State, Input, Option<String>, Option<String>)>,
+    /// The pair of the old and the new initial state if it is changed.
+    pub initial_state: Option<(State, State)>,
+}
+
+impl<State, Input> DefinitionDiff<State, Input> {
+    /// Returns `true` if the two definitions are the same.
+    pub fn is_empty(&self) -> bool {
+        self.added_states.is_empty()
+            && self.removed_states.is_empty()
+            && self.added_transitions.is_empty()
+            && self.removed_transitions.is_empty()
+            && self.retargeted_transitions.is_empty()
+            && self.added_any_input.is_empty()
+            && self.removed_any_input.is_empty()
+            && self.retargeted_any_input.is_empty()
+            && self.added_any_state.is_empty()
+            && self.removed_any_state.is_empty()
+            && self.retargeted_any_state.is_empty()
+            && self.added_ignored.is_empty()
+            && self.removed_ignored.is_empty()
+            && self.added_forbidden.is_empty()
+            && self.removed_forbidden.is_empty()
+            && self.relabeled.is_empty()
+            && self.initial_state.is_none()
+    }
+}
+
+/// The entries only in the new map, the ones only in the old map and the ones
+/// whose values are changed as `(key, old value, new value)`.
+type MapDiff<K, V> = (Vec<(K, V)>, Vec<(K, V)>, Vec<(K, V, V)>);
+
+fn diff_maps<K, V>(old: &HashMap<K, V>, new: &HashMap<K, V>) -> MapDiff<K, V>
+where
+    K: Clone + Eq + Hash,
+    V: Clone + PartialEq,
+{
+    let mut removed = Vec::new();
+    let mut changed = Vec::new();
+    for (key, value) in old {
+        match new.get(key) {
+            None => removed.push((key.clone(), value.clone())),
+            Some(new_value) if new_value != value => {
+                changed.push((key.clone(), value.clone(), new_value.clone()))
+            }
+            Some(_) => {}
+        }
+    }
+    let added = new
+        .iter()
+        .filter(|(key, _)| !old.contains_key(*key))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    (added, removed, changed)
+}
+
+/// The elements only in the new set and the ones only in the old set.
+fn diff_sets<T>(old: &HashSet<T>, new: &HashSet<T>) -> (Vec<T>, Vec<T>)
+where
+    T: Clone + Eq + Hash,
+{
+    (
+        new.difference(old).cloned().collect(),
+        old.difference(new).cloned().collect(),
+    )
+}
+
+impl<State, Input> TableStateMachine<State, Input>
+where
+    State: Clone + Eq + Hash,
+    Input: Clone + Eq + Hash,
+{
+    /// Compares the definition of this machine with `other`'s and lists what changed:
+    /// states, transitions (added, removed and retargeted), the any-input and the any-state
+    /// rules, the ignored and the forbidden pairs, the labels and the initial state.
+    /// The ignored pairs are compared as such rather than as the self-loops they resolve to.
+    /// The current states of the machines are not compared.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::builder::TableStateMachineBuilder;
+    ///
+    /// let v1 = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "Press", "On")
+    ///     .build()
+    ///     .unwrap();
+    /// let v2 = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "Press", "On")
+    ///     .add_transition("On", "Press", "Off")
+    ///     .add_any_state("Unplug", "Off")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// let diff = v1.diff(&v2);
+    /// assert_eq!(vec![("On", "Press", "Off")], diff.added_transitions);
+    /// assert_eq!(vec![("Unplug", "Off")], diff.added_any_state);
+    /// assert_eq!(
+    ///     "+ * --Unplug--> Off\n+ On --Press--> Off\n",
+    ///     diff.to_string()
+    /// );
+    /// ```
+    pub fn diff(&self, other: &Self) -> DefinitionDiff<State, Input> {
+        let (states, other_states) = (self.states(), other.states());
+        let (added_states, removed_states) = diff_sets(&states, &other_states);
+
+        let (added, removed, retargeted) = diff_maps(&self.transitions(), &other.transitions());
+        let (added_any_input, removed_any_input, retargeted_any_input) =
+            diff_maps(&self.any_input, &other.any_input);
+        let (added_any_state, removed_any_state, retargeted_any_state) =
+            diff_maps(&self.any_state, &other.any_state);
+        let (added_ignored, removed_ignored) = diff_sets(&self.ignored, &other.ignored);
+        let (added_forbidden, removed_forbidden) = diff_sets(&self.forbidden, &other.forbidden);
+
+        let relabeled = self
+            .labels
+            .keys()
+            .chain(
+                other
+                    .labels
+                    .keys()
+                    .filter(|key| !self.labels.contains_key(*key)),
+            )
+            .filter_map(|key| {
+                let (old, new) = (self.labels.get(key), other.labels.get(key));
+                if old == new {
+                    None
+                } else {
+                    let (from, input) = key.clone();
+                    Some((from, input, old.cloned(), new.cloned()))
+                }
+            })
+            .collect();
+
+        let initial_state = if self.initial_state != other.initial_state {
+            Some((self.initial_state.clone(), other.initial_state.clone()))
+        } else {
+            None
+        };
+
+        DefinitionDiff {
+            added_states,
+            removed_states,
+            added_transitions: added
+                .into_iter()
+                .map(|((from, input), to)| (from, input, to))
+                .collect(),
+            removed_transitions: removed
+                .into_iter()
+                .map(|((from, input), to)| (from, input, to))
+                .collect(),
+            retargeted_transitions: retargeted
+                .into_iter()
+                .map(|((from, input), old, new)| (from, input, old, new))
+                .collect(),
+            added_any_input,
+            removed_any_input,
+            retargeted_any_input,
+            added_any_state,
+            removed_any_state,
+            retargeted_any_state,
+            added_ignored,
+            removed_ignored,
+            added_forbidden,
+            removed_forbidden,
+            relabeled,
+            initial_state,
+        }
+    }
+
+    /// Returns the transitions added explicitly, leaving out the self-loops of the ignored pairs.
+    fn transitions(&self) -> HashMap<(State, Input), State> {
+        self.table
+            .iter()
+            .filter(|(key, _)| !self.ignored.contains(*key))
+            .map(|(key, to)| (key.clone(), to.clone()))
+            .collect()
+    }
+}
+
+impl<State, Input> Display for DefinitionDiff<State, Input>
+where
+    State: Display,
+    Input: Display,
+{
+    /// Formats the difference in a unified-diff-like style. The any-input and the any-state
+    /// rules are written with `*` in place of the input and the state respectively.
+    /// The states are sorted by name and then the changes of the edges are sorted by
+    /// the edge, so that the output is stable and the changes of one edge stay together.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn edge(from: impl Display, input: impl Display) -> String {
+            format!("{} --{}-->", from, input)
+        }
+
+        if let Some((old, new)) = &self.initial_state {
+            writeln!(f, "- initial {}", old)?;
+            writeln!(f, "+ initial {}", new)?;
+        }
+        // Each line is keyed by what it is about, and the lines on the same key keep
+        // the order of the categories below.
+        let mut states: Vec<(String, String)> = self
+            .removed_states
+            .iter()
+            .map(|state| (state.to_string(), format!("- state {}", state)))
+            .chain(
+                self.added_states
+                    .iter()
+                    .map(|state| (state.to_string(), format!("+ state {}", state))),
+            )
+            .collect();
+        states.sort();
+
+        let mut edges: Vec<(String, String)> = Vec::new();
+        let mut push = |edge: String, line: String| edges.push((edge, line));
+        for (from, input, to) in &self.removed_transitions {
+            push(edge(from, input), format!("- {} {}", edge(from, input), to));
+        }
+        for (from, input, to) in &self.added_transitions {
+            push(edge(from, input), format!("+ {} {}", edge(from, input), to));
+        }
+        for (from, input, old, new) in &self.retargeted_transitions {
+            let edge = edge(from, input);
+            push(edge.clone(), format!("~ {} {} => {}", edge, old, new));
+        }
+        for (from, to) in &self.removed_any_input {
+            push(edge(from, "*"), format!("- {} {}", edge(from, "*"), to));
+        }
+        for (from, to) in &self.added_any_input {
+            push(edge(from, "*"), format!("+ {} {}", edge(from, "*"), to));
+        }
+        for (from, old, new) in &self.retargeted_any_input {
+            let edge = edge(from, "*");
+            push(edge.clone(), format!("~ {} {} => {}", edge, old, new));
+        }
+        for (input, to) in &self.removed_any_state {
+            push(edge("*", input), format!("- {} {}", edge("*", input), to));
+        }
+        for (input, to) in &self.added_any_state {
+            push(edge("*", input), format!("+ {} {}", edge("*", input), to));
+        }
+        for (input, old, new) in &self.retargeted_any_state {
+            let edge = edge("*", input);
+            push(edge.clone(), format!("~ {} {} => {}", edge, old, new));
+        }
+        for (state, input) in &self.removed_ignored {
+            push(
+                edge(state, input),
+                format!("- {} (ignored)", edge(state, input)),
+            );
+        }
+        for (state, input) in &self.added_ignored {
+            push(
+                edge(state, input),
+                format!("+ {} (ignored)", edge(state, input)),
+            );
+        }
+        for (state, input) in &self.removed_forbidden {
+            push(
+                edge(state, input),
+                format!("- {} (forbidden)", edge(state, input)),
+            );
+        }
+        for (state, input) in &self.added_forbidden {
+            push(
+                edge(state, input),
+                format!("+ {} (forbidden)", edge(state, input)),
+            );
+        }
+        for (from, input, old, new) in &self.relabeled {
+            let edge = edge(from, input);
+            let line = match (old, new) {
+                (Some(old), Some(new)) => format!("~ {} label {:?} => {:?}", edge, old, new),
+                (Some(old), None) => format!("- {} label {:?}", edge, old),
+                (None, Some(new)) => format!("+ {} label {:?}", edge, new),
+                (None, None) => continue,
+            };
+            push(edge, line);
+        }
+        // The stable sort keeps the order above among the lines on the same edge.
+        edges.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (_, line) in states.iter().chain(edges.iter()) {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::machine::{builder::TableStateMachineBuilder, table::TableStateMachine};
+
+    fn v1() -> TableStateMachine<&'static str, &'static str> {
+        TableStateMachineBuilder::start()
+            .initial_state("Draft")
+            .add_transition("Draft", "Submit", "Review")
+            .add_transition("Review", "Approve", "Published")
+            .add_transition("Review", "Reject", "Draft")
+            .add_transition("Published", "Archive", "Archived")
+            .build()
+            .unwrap()
+    }
+
+    fn v2() -> TableStateMachine<&'static str, &'static str> {
+        TableStateMachineBuilder::start()
+            .initial_state("New")
+            .add_transition("New", "Edit", "Draft")
+            .add_transition("Draft", "Submit", "Review")
+            .add_transition("Review", "Approve", "Published")
+            .add_transition("Review", "Reject", "Rejected")
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_diff() {
+        let diff = v1().diff(&v2());
+
+        assert_eq!(2, diff.added_states.len());
+        assert!(diff.added_states.contains(&"New"));
+        assert!(diff.added_states.contains(&"Rejected"));
+        assert_eq!(vec!["Archived"], diff.removed_states);
+        assert_eq!(vec![("New", "Edit", "Draft")], diff.added_transitions);
+        assert_eq!(
+            vec![("Published", "Archive", "Archived")],
+            diff.removed_transitions
+        );
+        assert_eq!(
+            vec![("Review", "Reject", "Draft", "Rejected")],
+            diff.retargeted_transitions
+        );
+        assert_eq!(Some(("Draft", "New")), diff.initial_state);
+        assert_eq!(
+            "- initial Draft
++ initial New
+- state Archived
++ state New
++ state Rejected
++ New --Edit--> Draft
+- Published --Archive--> Archived
+~ Review --Reject--> Draft => Rejected
+",
+            diff.to_string()
+        );
+    }
+
+    #[test]
+    fn test_diff_is_symmetric() {
+        let forward = v1().diff(&v2());
+        let backward = v2().diff(&v1());
+
+        assert_eq!(forward.added_transitions, backward.removed_transitions);
+        assert_eq!(forward.removed_transitions, backward.added_transitions);
+        assert_eq!(forward.removed_states, backward.added_states);
+        assert_eq!(
+            vec![("Review", "Reject", "Rejected", "Draft")],
+            backward.retargeted_transitions
+        );
+        assert_eq!(Some(("New", "Draft")), backward.initial_state);
+        assert!(v1().diff(&v1()).is_empty());
+    }
+
+    #[test]
+    fn test_diff_effective_behaviour() {
+        let old = TableStateMachineBuilder::start()
+            .initial_state("Idle")
+            .add_transition_labeled("Idle", "Start", "Running", "start")
+            .add_transition("Running", "Pause", "Running")
+            .add_transition_labeled("Running", "Stop", "Idle", "stop")
+            .add_any_input("Broken", "Idle")
+            .add_any_state("Reset", "Idle")
+            .forbid("Idle", vec!["Stop"])
+            .build()
+            .unwrap();
+        let new = TableStateMachineBuilder::start()
+            .initial_state("Idle")
+            .add_transition_labeled("Idle", "Start", "Running", "launch")
+            .add_transition("Running", "Stop", "Idle")
+            .ignore("Running", vec!["Pause"])
+            .add_any_input("Broken", "Broken")
+            .add_any_state("Reset", "Idle")
+            .add_any_state("Kill", "Broken")
+            .forbid("Running", vec!["Start"])
+            .build()
+            .unwrap();
+
+        let diff = old.diff(&new);
+        assert!(diff.added_transitions.is_empty());
+        assert_eq!(
+            vec![("Running", "Pause", "Running")],
+            diff.removed_transitions
+        );
+        assert!(diff.retargeted_transitions.is_empty());
+        assert_eq!(
+            vec![("Broken", "Idle", "Broken")],
+            diff.retargeted_any_input
+        );
+        assert_eq!(vec![("Kill", "Broken")], diff.added_any_state);
+        assert_eq!(vec![("Running", "Pause")], diff.added_ignored);
+        assert_eq!(vec![("Running", "Start")], diff.added_forbidden);
+        assert_eq!(vec![("Idle", "Stop")], diff.removed_forbidden);
+        assert_eq!(2, diff.relabeled.len());
+        assert!(diff.relabeled.contains(&(
+            "Idle",
+            "Start",
+            Some("start".to_string()),
+            Some("launch".to_string())
+        )));
+        assert!(diff
+            .relabeled
+            .contains(&("Running", "Stop", Some("stop".to_string()), None)));
+        assert_eq!(
+            r#"+ * --Kill--> Broken
+~ Broken --*--> Idle => Broken
+~ Idle --Start--> label "start" => "launch"
+- Idle --Stop--> (forbidden)
+- Running --Pause--> Running
++ Running --Pause--> (ignored)
++ Running --Start--> (forbidden)
+- Running --Stop--> label "stop"
+"#,
+            diff.to_string()
+        );
+
+        let backward = new.diff(&old);
+        assert_eq!(diff.added_ignored, backward.removed_ignored);
+        assert_eq!(diff.added_any_state, backward.removed_any_state);
+        assert!(new.diff(&new).is_empty());
+    }
+}
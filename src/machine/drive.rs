@@ -0,0 +1,58 @@
+use super::{error::StateMachineError, BasicStateMachine};
+
+/// An iterator which consumes inputs one by one on the state machine.
+/// It is created by [`BasicStateMachine::drive()`].
+///
+/// It remembers the epoch (see [`BasicStateMachine::epoch()`]) at the time it was created,
+/// and yields [`StateMachineError::StaleIterator`] once and ends if the state machine has
+/// been changed outside the transition since then.
+pub struct Drive<'a, State, Input, Transition, I, M = ()>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+{
+    sm: &'a BasicStateMachine<State, Input, Transition, M>,
+    inputs: I,
+    epoch: u64,
+    stale: bool,
+}
+
+impl<'a, State, Input, Transition, I, M> Drive<'a, State, Input, Transition, I, M>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+{
+    pub(crate) fn new(sm: &'a BasicStateMachine<State, Input, Transition, M>, inputs: I) -> Self {
+        Drive {
+            epoch: sm.epoch(),
+            sm,
+            inputs,
+            stale: false,
+        }
+    }
+}
+
+impl<State, Input, Transition, I, M> Iterator for Drive<'_, State, Input, Transition, I, M>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+    I: Iterator<Item = Input>,
+{
+    type Item = Result<State, StateMachineError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.stale {
+            return None;
+        }
+        let actual = self.sm.epoch();
+        if actual != self.epoch {
+            self.stale = true;
+            return Some(Err(StateMachineError::StaleIterator {
+                expected: self.epoch,
+                actual,
+            }));
+        }
+        let input = self.inputs.next()?;
+        Some(self.sm.try_consume(input))
+    }
+}
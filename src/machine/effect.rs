@@ -0,0 +1,153 @@
+use std::{cell::RefCell, marker::PhantomData};
+
+use super::StateWrapper;
+
+/// A state machine whose transition returns a command alongside the next state, like
+/// the Elm architecture. The command describes the side effect the caller should perform,
+/// so that the state change is cleanly separated from the side effect itself.
+/// [`EffectfulStateMachine::consume()`] updates the current state and hands the command
+/// back to the caller.
+///
+/// It is `Send` if the state, the input, the command and the transition are `Send`,
+/// but never `Sync`.
+///
+/// # Example
+/// ```
+/// use statemachine_rs::machine::effect::EffectfulStateMachine;
+///
+/// #[derive(Clone, Debug, PartialEq)]
+/// enum ButtonState {
+///     On,
+///     Off,
+/// }
+///
+/// enum Input {
+///     Press,
+/// }
+///
+/// #[derive(Debug, PartialEq)]
+/// enum Cmd {
+///     Beep,
+///     None,
+/// }
+///
+/// let sm = EffectfulStateMachine::new(ButtonState::Off, |state, input| match (state, input) {
+///     (ButtonState::On, Input::Press) => (ButtonState::Off, Cmd::None),
+///     (ButtonState::Off, Input::Press) => (ButtonState::On, Cmd::Beep),
+/// });
+///
+/// assert_eq!(Cmd::Beep, sm.consume(Input::Press));
+/// assert_eq!(ButtonState::On, sm.current_state());
+/// ```
+pub struct EffectfulStateMachine<State, Input, Cmd, Transition>
+where
+    Transition: Fn(&State, Input) -> (State, Cmd),
+    State: Clone,
+{
+    initial_state: State,
+    current_state: RefCell<StateWrapper<State>>,
+    transition: Transition,
+    _marker: PhantomData<(Input, Cmd)>,
+}
+
+impl<State, Input, Cmd, Transition> EffectfulStateMachine<State, Input, Cmd, Transition>
+where
+    Transition: Fn(&State, Input) -> (State, Cmd),
+    State: Clone,
+{
+    /// Creates a new state machine starting from `initial_state`.
+    pub fn new(initial_state: State, transition: Transition) -> Self {
+        EffectfulStateMachine {
+            current_state: RefCell::new(StateWrapper::new(initial_state.clone())),
+            initial_state,
+            transition,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the current state of the state machine.
+    pub fn current_state(&self) -> State {
+        self.current_state.borrow().get()
+    }
+
+    /// Transits to the next state according to `input` and returns
+    /// the command which the caller should perform.
+    pub fn consume(&self, input: Input) -> Cmd {
+        let (new_state, cmd) = (self.transition)(&self.current_state.borrow().0, input);
+        self.current_state.borrow_mut().set(new_state);
+        cmd
+    }
+
+    /// Returns the next state and the command according to `input`
+    /// but the state machine retains in its current state.
+    pub fn peek(&self, input: Input) -> (State, Cmd) {
+        (self.transition)(&self.current_state.borrow().0, input)
+    }
+
+    /// Resets the current state to the initial state. No commands are returned.
+    pub fn reset(&self) -> State {
+        self.current_state
+            .borrow_mut()
+            .set(self.initial_state.clone());
+        self.initial_state.clone()
+    }
+
+    /// Set a new state forcibly to the current state. No commands are returned.
+    pub fn set(&self, new_state: State) {
+        self.current_state.borrow_mut().set(new_state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::EffectfulStateMachine;
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Page {
+        Idle,
+        Loading,
+        Loaded(String),
+    }
+
+    enum Msg {
+        Open(String),
+        Received(String),
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Cmd {
+        Fetch(String),
+        Nothing,
+    }
+
+    #[test]
+    fn test_consume_returns_cmd() {
+        let sm = EffectfulStateMachine::new(Page::Idle, |page, msg| match (page, msg) {
+            (Page::Idle, Msg::Open(url)) => (Page::Loading, Cmd::Fetch(url)),
+            (Page::Loading, Msg::Received(body)) => (Page::Loaded(body), Cmd::Nothing),
+            (page, _) => (page.clone(), Cmd::Nothing),
+        });
+
+        // the caller interprets the command and feeds the result back
+        let mut msg = Msg::Open("https://example.com".to_string());
+        let mut fetched = Vec::new();
+        while let Cmd::Fetch(url) = sm.consume(msg) {
+            msg = Msg::Received(format!("body of {}", url));
+            fetched.push(url);
+        }
+
+        assert_eq!(vec!["https://example.com".to_string()], fetched);
+        assert_eq!(
+            Page::Loaded("body of https://example.com".to_string()),
+            sm.current_state()
+        );
+        assert_eq!(
+            (
+                Page::Loaded("body of https://example.com".to_string()),
+                Cmd::Nothing
+            ),
+            sm.peek(Msg::Open("https://example.org".to_string()))
+        );
+        assert_eq!(Page::Idle, sm.reset());
+    }
+}
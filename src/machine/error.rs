@@ -1,20 +1,442 @@
+use std::borrow::Cow;
 use std::error::Error;
 use std::fmt::Display;
+use std::time::Duration;
 
+/// The error of building and driving state machines.
+///
+/// The field names are `&'static str` and the state names and the guard names are
+/// `Cow<'static, str>`, so that constructing and displaying the errors on the hot path
+/// doesn't allocate unless a name is computed at runtime.
+///
+/// New variants may be added as the state machines gain features, so matching on it
+/// needs a wildcard arm.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum StateMachineError {
-    MissingField(String),
+    /// The field of the builder is required but not set, e.g. `initial_state`.
+    MissingField(&'static str),
+    /// The fields of the builder exclude each other but both are set,
+    /// e.g. `initial_state` and `initial_state_with`.
+    ConflictingFields(&'static str, &'static str),
+    /// The state isn't one of the declared states.
+    /// See [`crate::machine::builder::BasicStateMachineBuilder::states()`].
+    UndeclaredState(Cow<'static, str>),
+    /// The current state isn't reachable from the initial state. See
+    /// [`crate::machine::builder::TableStateMachineBuilder::require_reachable_current()`].
+    UnreachableState(Cow<'static, str>),
+    /// The input is rejected by the validator.
+    /// See [`crate::machine::builder::BasicStateMachineBuilder::validate_input()`].
+    InvalidInput { reason: String },
+    /// The raw input can't be parsed via [`crate::machine::BasicStateMachine::consume_str()`].
+    UnparsableInput { raw: String, error: ParseInputError },
+    /// The exit hook of the current state vetoes the transition.
+    /// See [`crate::machine::builder::BasicStateMachineBuilder::on_exit_state()`].
+    ExitVetoed { reason: String },
+    /// The guard named `name` blocks the transition.
+    /// See [`crate::machine::builder::BasicStateMachineBuilder::add_named_guard()`].
+    GuardFailed { name: Cow<'static, str> },
+    /// The current state has to be kept for `remaining` more before the transition.
+    /// See [`crate::machine::builder::BasicStateMachineBuilder::min_dwell()`].
+    DwellTooShort { remaining: Duration },
+    /// The pair of the current state and the input is marked forbidden in the table.
+    /// See [`crate::machine::table::TableStateMachine::try_consume()`].
+    ForbiddenTransition,
+    /// The transition moves back in the state order.
+    /// See [`crate::machine::BasicStateMachine::consume_monotonic()`].
+    Regression { from: String, to: String },
+    /// The next state fails a post-condition and is rolled back.
+    /// See [`crate::machine::BasicStateMachine::consume_checked_post()`].
+    PostConditionFailed,
+    /// Some pairs of the state and the input have no transition or more than one,
+    /// or some wildcard rules never apply.
+    /// See [`crate::machine::builder::TableStateMachineBuilder::total()`].
+    IncompleteCoverage {
+        undecided: Vec<String>,
+        conflicting: Vec<String>,
+        shadowed: Vec<String>,
+    },
+    /// The machine has moved on since the generation the caller expected.
+    /// See [`crate::machine::BasicStateMachine::consume_at_generation()`].
+    StaleGeneration { expected: u64, actual: u64 },
+    /// The reset is forbidden from the current state.
+    /// See [`crate::machine::BasicStateMachine::try_reset()`].
+    ResetForbidden,
+    /// The snapshot is taken at an older epoch than the machine is at.
+    /// See [`crate::machine::BasicStateMachine::restore()`].
+    StaleSnapshot { snapshot: u64, actual: u64 },
+    /// The machine is changed outside of the iterator created by [`crate::machine::BasicStateMachine::drive()`].
+    StaleIterator { expected: u64, actual: u64 },
+    /// Another transaction is already open. See [`crate::machine::BasicStateMachine::begin()`].
+    TransactionInProgress,
+    /// The input isn't consumed in time. See
+    /// [`crate::machine::asynchronous::AsyncStateMachine::consume_timeout()`].
+    Timeout,
+    /// The rows rejected on inserting transitions with their indices. See
+    /// [`crate::machine::builder::TableStateMachineBuilder::extend_transitions()`].
+    BatchInsert(Vec<(usize, InsertError)>),
+}
+
+impl StateMachineError {
+    /// Creates [`StateMachineError::MissingField`] in a const context.
+    pub const fn missing_field(field_name: &'static str) -> Self {
+        StateMachineError::MissingField(field_name)
+    }
+
+    /// Creates [`StateMachineError::ConflictingFields`] in a const context.
+    pub const fn conflicting_fields(
+        field_name: &'static str,
+        other_field_name: &'static str,
+    ) -> Self {
+        StateMachineError::ConflictingFields(field_name, other_field_name)
+    }
+
+    /// Creates [`StateMachineError::UndeclaredState`] for a statically known state name
+    /// in a const context.
+    pub const fn undeclared_state(state: &'static str) -> Self {
+        StateMachineError::UndeclaredState(Cow::Borrowed(state))
+    }
+
+    /// Creates [`StateMachineError::UnreachableState`] for a statically known state name
+    /// in a const context.
+    pub const fn unreachable_state(state: &'static str) -> Self {
+        StateMachineError::UnreachableState(Cow::Borrowed(state))
+    }
+
+    /// Creates [`StateMachineError::GuardFailed`] for a statically known guard name
+    /// in a const context.
+    pub const fn guard_failed(name: &'static str) -> Self {
+        StateMachineError::GuardFailed {
+            name: Cow::Borrowed(name),
+        }
+    }
+}
+
+/// The reason why a raw input can't be parsed into an input.
+/// See [`crate::machine::builder::BasicStateMachineBuilder::parse_input_with()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseInputError {
+    pub reason: String,
+}
+
+impl ParseInputError {
+    pub fn new<R>(reason: R) -> Self
+    where
+        R: Into<String>,
+    {
+        ParseInputError {
+            reason: reason.into(),
+        }
+    }
+}
+
+impl Display for ParseInputError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+impl Error for ParseInputError {}
+
+/// The reason why a row is rejected on inserting transitions.
+/// See [`crate::machine::builder::TableStateMachineBuilder::extend_transitions()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InsertError {
+    /// The transition for the same pair of the state and the input is already defined
+    /// and [`crate::machine::builder::DuplicatePolicy::Reject`] is set.
+    Duplicate,
+}
+
+impl Display for InsertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InsertError::Duplicate => f.write_str("the transition is already defined"),
+        }
+    }
 }
 
 impl Display for StateMachineError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            StateMachineError::MissingField(field_name) => f.write_str(&format!(
+            StateMachineError::MissingField(field_name) => write!(
+                f,
                 "Failed to build the builder because {} field is uninitialized.",
                 field_name
-            )),
+            ),
+            StateMachineError::ConflictingFields(field_name, other_field_name) => write!(
+                f,
+                "Failed to build the builder because {} and {} fields are both initialized.",
+                field_name, other_field_name
+            ),
+            StateMachineError::UndeclaredState(state) => {
+                write!(f, "The state {} is not declared.", state)
+            }
+            StateMachineError::UnreachableState(state) => write!(
+                f,
+                "The state {} is not reachable from the initial state.",
+                state
+            ),
+            StateMachineError::InvalidInput { reason } => {
+                write!(f, "The input is invalid: {}", reason)
+            }
+            StateMachineError::UnparsableInput { raw, error } => {
+                write!(f, "Failed to parse {:?} into an input: {}", raw, error)
+            }
+            StateMachineError::ExitVetoed { reason } => {
+                write!(f, "The transition is vetoed by an exit hook: {}", reason)
+            }
+            StateMachineError::GuardFailed { name } => {
+                write!(f, "The transition is blocked by the guard: {}", name)
+            }
+            StateMachineError::DwellTooShort { remaining } => write!(
+                f,
+                "The current state must be kept for {:?} more before the transition.",
+                remaining
+            ),
+            StateMachineError::ForbiddenTransition => {
+                f.write_str("The transition is forbidden from the current state.")
+            }
+            StateMachineError::Regression { from, to } => write!(
+                f,
+                "The transition from {} to {} regresses in the state order.",
+                from, to
+            ),
+            StateMachineError::PostConditionFailed => {
+                f.write_str("The next state fails the post-condition, so it is rolled back.")
+            }
+            StateMachineError::IncompleteCoverage {
+                undecided,
+                conflicting,
+                shadowed,
+            } => {
+                f.write_str("The transitions are not decided exactly once:")?;
+                for (kind, pairs) in [
+                    ("undecided", undecided),
+                    ("conflicting", conflicting),
+                    ("shadowed", shadowed),
+                ] {
+                    if !pairs.is_empty() {
+                        write!(f, " {}", kind)?;
+                        for pair in pairs {
+                            write!(f, " {}", pair)?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            StateMachineError::StaleGeneration { expected, actual } => write!(
+                f,
+                "The generation is stale: expected {} but actually {}",
+                expected, actual
+            ),
+            StateMachineError::StaleSnapshot { snapshot, actual } => write!(
+                f,
+                "The snapshot is stale: taken at epoch {} but the machine is at epoch {}",
+                snapshot, actual
+            ),
+            StateMachineError::StaleIterator { expected, actual } => write!(
+                f,
+                "The iterator is stale: created at epoch {} but the machine is at epoch {}",
+                expected, actual
+            ),
+            StateMachineError::Timeout => f.write_str("The operation is timed out."),
+            StateMachineError::TransactionInProgress => {
+                f.write_str("Another transaction is already in progress.")
+            }
+            StateMachineError::ResetForbidden => {
+                f.write_str("The reset is forbidden from the current state.")
+            }
+            StateMachineError::BatchInsert(errors) => {
+                write!(f, "Failed to insert {} rows:", errors.len())?;
+                for (row, error) in errors {
+                    write!(f, " (row {}: {})", row, error)?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
 impl Error for StateMachineError {}
+
+/// The error of [`crate::machine::BasicStateMachine::consume_all_atomic()`].
+/// The state machine is already restored to the state before the batch when it is returned.
+#[derive(Debug)]
+pub struct AtomicBatchError<State, Input> {
+    /// The index of the failing input in the batch.
+    pub index: usize,
+    /// The reason why the failing input is rejected.
+    pub error: StateMachineError,
+    /// The inputs after the failing one, which are never consumed.
+    pub remaining: Vec<Input>,
+    /// The state the machine is restored to.
+    pub state: State,
+}
+
+impl<State, Input> Display for AtomicBatchError<State, Input> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The batch is rolled back at the input {}: {}",
+            self.index, self.error
+        )
+    }
+}
+
+impl<State, Input> Error for AtomicBatchError<State, Input>
+where
+    State: std::fmt::Debug,
+    Input: std::fmt::Debug,
+{
+}
+
+#[cfg(test)]
+mod test {
+    use std::{borrow::Cow, time::Duration};
+
+    use super::{AtomicBatchError, InsertError, ParseInputError, StateMachineError};
+
+    // The names can be built in const contexts without allocating.
+    const MISSING: StateMachineError = StateMachineError::missing_field("initial_state");
+    const GUARD: StateMachineError = StateMachineError::guard_failed("local only");
+
+    #[test]
+    fn test_static_names() {
+        assert!(matches!(
+            MISSING,
+            StateMachineError::MissingField("initial_state")
+        ));
+        assert!(matches!(
+            GUARD,
+            StateMachineError::GuardFailed {
+                name: Cow::Borrowed("local only")
+            }
+        ));
+        assert!(matches!(
+            StateMachineError::undeclared_state("Yoga"),
+            StateMachineError::UndeclaredState(Cow::Borrowed("Yoga"))
+        ));
+    }
+
+    #[test]
+    fn test_display() {
+        let cases = vec![
+            (
+                MISSING,
+                "Failed to build the builder because initial_state field is uninitialized.",
+            ),
+            (
+                StateMachineError::conflicting_fields("initial_state", "initial_state_with"),
+                "Failed to build the builder because initial_state and initial_state_with fields are both initialized.",
+            ),
+            (
+                StateMachineError::undeclared_state("Yoga"),
+                "The state Yoga is not declared.",
+            ),
+            (
+                StateMachineError::UnreachableState(Cow::Owned("Yoga".to_string())),
+                "The state Yoga is not reachable from the initial state.",
+            ),
+            (
+                StateMachineError::InvalidInput {
+                    reason: "empty".to_string(),
+                },
+                "The input is invalid: empty",
+            ),
+            (
+                StateMachineError::UnparsableInput {
+                    raw: "jump".to_string(),
+                    error: ParseInputError::new("unknown command"),
+                },
+                "Failed to parse \"jump\" into an input: unknown command",
+            ),
+            (
+                StateMachineError::ExitVetoed {
+                    reason: "busy".to_string(),
+                },
+                "The transition is vetoed by an exit hook: busy",
+            ),
+            (GUARD, "The transition is blocked by the guard: local only"),
+            (
+                StateMachineError::DwellTooShort {
+                    remaining: Duration::from_millis(1500),
+                },
+                "The current state must be kept for 1.5s more before the transition.",
+            ),
+            (
+                StateMachineError::ForbiddenTransition,
+                "The transition is forbidden from the current state.",
+            ),
+            (
+                StateMachineError::Regression {
+                    from: "Shipped".to_string(),
+                    to: "Paid".to_string(),
+                },
+                "The transition from Shipped to Paid regresses in the state order.",
+            ),
+            (
+                StateMachineError::PostConditionFailed,
+                "The next state fails the post-condition, so it is rolled back.",
+            ),
+            (
+                StateMachineError::IncompleteCoverage {
+                    undecided: vec!["(Off, Press)".to_string(), "(On, Press)".to_string()],
+                    conflicting: vec!["(On, Break)".to_string()],
+                    shadowed: vec!["AnyState(Break)".to_string()],
+                },
+                "The transitions are not decided exactly once: undecided (Off, Press) (On, Press) conflicting (On, Break) shadowed AnyState(Break)",
+            ),
+            (
+                StateMachineError::StaleGeneration {
+                    expected: 1,
+                    actual: 2,
+                },
+                "The generation is stale: expected 1 but actually 2",
+            ),
+            (
+                StateMachineError::StaleSnapshot {
+                    snapshot: 1,
+                    actual: 2,
+                },
+                "The snapshot is stale: taken at epoch 1 but the machine is at epoch 2",
+            ),
+            (
+                StateMachineError::StaleIterator {
+                    expected: 1,
+                    actual: 2,
+                },
+                "The iterator is stale: created at epoch 1 but the machine is at epoch 2",
+            ),
+            (
+                StateMachineError::ResetForbidden,
+                "The reset is forbidden from the current state.",
+            ),
+            (
+                StateMachineError::TransactionInProgress,
+                "Another transaction is already in progress.",
+            ),
+            (StateMachineError::Timeout, "The operation is timed out."),
+            (
+                StateMachineError::BatchInsert(vec![
+                    (0, InsertError::Duplicate),
+                    (2, InsertError::Duplicate),
+                ]),
+                "Failed to insert 2 rows: (row 0: the transition is already defined) (row 2: the transition is already defined)",
+            ),
+        ];
+        for (error, expected) in cases {
+            assert_eq!(expected, error.to_string());
+        }
+
+        let error = AtomicBatchError {
+            index: 1,
+            error: StateMachineError::Timeout,
+            remaining: vec![3],
+            state: 0,
+        };
+        assert_eq!(
+            "The batch is rolled back at the input 1: The operation is timed out.",
+            error.to_string()
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use std::{cell::RefCell, marker::PhantomData};
+
+use super::StateWrapper;
+
+/// A state machine whose transition emits domain events alongside the next state.
+/// It is useful for event-sourcing integration: [`EventSourcedStateMachine::consume()`]
+/// updates the current state and hands the emitted events back to the caller.
+///
+/// It is `Send` if the state, the input, the events and the transition are `Send`,
+/// but never `Sync`.
+///
+/// # Example
+/// ```
+/// use statemachine_rs::machine::event_sourced::EventSourcedStateMachine;
+///
+/// #[derive(Clone, Debug, PartialEq)]
+/// enum ButtonState {
+///     On,
+///     Off,
+/// }
+///
+/// enum Input {
+///     Press,
+/// }
+///
+/// #[derive(Debug, PartialEq)]
+/// enum Event {
+///     TurnedOn,
+///     TurnedOff,
+/// }
+///
+/// let sm = EventSourcedStateMachine::new(ButtonState::Off, |state, input| match (state, input) {
+///     (ButtonState::On, Input::Press) => (ButtonState::Off, vec![Event::TurnedOff]),
+///     (ButtonState::Off, Input::Press) => (ButtonState::On, vec![Event::TurnedOn]),
+/// });
+///
+/// assert_eq!(vec![Event::TurnedOn], sm.consume(Input::Press));
+/// assert_eq!(ButtonState::On, sm.current_state());
+/// ```
+pub struct EventSourcedStateMachine<State, Input, Event, Transition>
+where
+    Transition: Fn(&State, Input) -> (State, Vec<Event>),
+    State: Clone,
+{
+    initial_state: State,
+    current_state: RefCell<StateWrapper<State>>,
+    transition: Transition,
+    _marker: PhantomData<(Input, Event)>,
+}
+
+impl<State, Input, Event, Transition> EventSourcedStateMachine<State, Input, Event, Transition>
+where
+    Transition: Fn(&State, Input) -> (State, Vec<Event>),
+    State: Clone,
+{
+    /// Creates a new state machine starting from `initial_state`.
+    pub fn new(initial_state: State, transition: Transition) -> Self {
+        EventSourcedStateMachine {
+            current_state: RefCell::new(StateWrapper::new(initial_state.clone())),
+            initial_state,
+            transition,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the current state of the state machine.
+    pub fn current_state(&self) -> State {
+        self.current_state.borrow().get()
+    }
+
+    /// Transits to the next state according to `input` and returns
+    /// the events emitted by the transition in the order they were emitted.
+    pub fn consume(&self, input: Input) -> Vec<Event> {
+        let (new_state, events) = (self.transition)(&self.current_state.borrow().0, input);
+        self.current_state.borrow_mut().set(new_state);
+        events
+    }
+
+    /// Resets the current state to the initial state. No events are emitted.
+    pub fn reset(&self) -> State {
+        self.current_state
+            .borrow_mut()
+            .set(self.initial_state.clone());
+        self.initial_state.clone()
+    }
+
+    /// Set a new state forcibly to the current state. No events are emitted.
+    pub fn set(&self, new_state: State) {
+        self.current_state.borrow_mut().set(new_state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::EventSourcedStateMachine;
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Account {
+        Open { balance: u32 },
+        Closed,
+    }
+
+    enum Command {
+        Withdraw(u32),
+        Close,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Withdrawn(u32),
+        Overdrawn,
+        Closed,
+    }
+
+    #[test]
+    fn test_consume_returns_events_in_order() {
+        let sm = EventSourcedStateMachine::new(
+            Account::Open { balance: 100 },
+            |account, command| match (account, command) {
+                (Account::Open { balance }, Command::Withdraw(amount)) if *balance >= amount => (
+                    Account::Open {
+                        balance: balance - amount,
+                    },
+                    vec![Event::Withdrawn(amount)],
+                ),
+                (Account::Open { .. }, Command::Withdraw(amount)) => (
+                    Account::Closed,
+                    vec![Event::Withdrawn(amount), Event::Overdrawn],
+                ),
+                (Account::Open { .. }, Command::Close) => (Account::Closed, vec![Event::Closed]),
+                (Account::Closed, _) => (Account::Closed, vec![]),
+            },
+        );
+
+        assert_eq!(
+            vec![Event::Withdrawn(30)],
+            sm.consume(Command::Withdraw(30))
+        );
+        assert_eq!(Account::Open { balance: 70 }, sm.current_state());
+        assert_eq!(
+            vec![Event::Withdrawn(80), Event::Overdrawn],
+            sm.consume(Command::Withdraw(80))
+        );
+        assert_eq!(Account::Closed, sm.current_state());
+        assert!(sm.consume(Command::Close).is_empty());
+        assert_eq!(Account::Open { balance: 100 }, sm.reset());
+    }
+}
@@ -0,0 +1,744 @@
+use std::{collections::HashMap, fmt::Display, hash::Hash};
+
+use super::table::TableStateMachine;
+
+/// Returns the label of the node of particular state, or `None` to label it with its name.
+type NodeLabel<'a, State> = &'a dyn Fn(&State) -> Option<String>;
+
+/// Escapes `value` to be embedded in a double-quoted DOT string.
+fn escape_dot(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Escapes `value` to be embedded in a Mermaid label.
+fn escape_mermaid(value: &str) -> String {
+    // Escapes character by character, so that the escapes themselves aren't escaped again.
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '#' => escaped.push_str("#35;"),
+            '"' => escaped.push_str("#quot;"),
+            ';' => escaped.push_str("#59;"),
+            '\n' => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Quotes `value` as a TypeScript string literal.
+fn quote_typescript(value: &str) -> String {
+    format!(
+        "\"{}\"",
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+/// Escapes `value` to be a CSV field. It is quoted only if needed.
+fn escape_csv(value: &str) -> String {
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+impl<State, Input> TableStateMachine<State, Input>
+where
+    State: Clone + Eq + Hash + Display,
+    Input: Eq + Hash + Display,
+{
+    /// Exports the transition table as a Graphviz DOT digraph.
+    /// Edges are labeled with their inputs (and the labels of transitions, if any).
+    /// Any-input rules are labeled with `*`, any-state rules start from the `*` node,
+    /// and ignored inputs are drawn as dashed self-loops.
+    /// The edges are sorted so that the output is stable.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::builder::TableStateMachineBuilder;
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "Press", "On")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert!(sm.to_dot().contains(r#""Off" -> "On" [label="Press"];"#));
+    /// ```
+    pub fn to_dot(&self) -> String {
+        self.dot(None, true, None)
+    }
+
+    /// Exports the transition table like [`TableStateMachine::to_dot()`] with
+    /// the current state at the time of the call filled with color.
+    pub fn to_dot_with_state(&self) -> String {
+        self.dot(Some(&self.current_state.borrow().0), true, None)
+    }
+
+    /// Exports the transition table like [`TableStateMachine::to_dot()`] without
+    /// the self-loops of the ignored inputs, which tend to clutter large diagrams.
+    pub fn to_dot_without_ignored(&self) -> String {
+        self.dot(None, false, None)
+    }
+
+    /// Exports the transition table like [`TableStateMachine::to_dot()`] with the nodes
+    /// labeled by `node_label`, e.g. with a field of the metadata attached via
+    /// [`crate::machine::builder::BasicStateMachineBuilder::metadata()`].
+    /// The states for which it returns `None` are labeled with their names.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::builder::{BasicStateMachineBuilder, StateMachineBuilder};
+    ///
+    /// struct Appearance {
+    ///     caption: &'static str,
+    ///     color: &'static str,
+    /// }
+    ///
+    /// let builder = BasicStateMachineBuilder::start()
+    ///     .initial_state(false)
+    ///     .transition(|state: &bool, _: &str| !state)
+    ///     .states(vec![false, true])
+    ///     .alphabet(vec!["Press"])
+    ///     .metadata(true, Appearance { caption: "Button is on", color: "green" });
+    /// let sm = builder.build_ref().unwrap();
+    /// let table = builder.into_table().unwrap();
+    ///
+    /// let dot = table.to_dot_labeled(|state| sm.metadata_for(state).map(|m| m.caption));
+    /// assert!(dot.contains(r#""true" [label="Button is on"];"#));
+    /// assert!(dot.contains(r#""false" -> "true" [label="Press"];"#));
+    /// ```
+    pub fn to_dot_labeled<F, L>(&self, node_label: F) -> String
+    where
+        F: Fn(&State) -> Option<L>,
+        L: Display,
+    {
+        self.dot(
+            None,
+            true,
+            Some(&|state: &State| node_label(state).map(|label| label.to_string())),
+        )
+    }
+
+    /// Exports the transition table as a Mermaid state diagram.
+    /// Each state is declared with an id (`s0`, `s1`, ...) in the order of their names,
+    /// so that arbitrary names can be rendered.
+    /// Any-input rules are labeled with `*`, any-state rules start from the `*` state,
+    /// and ignored inputs are labeled with `(ignored)`.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::builder::TableStateMachineBuilder;
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "Press", "On")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert!(sm.to_mermaid().contains("s0 --> s1 : Press"));
+    /// ```
+    pub fn to_mermaid(&self) -> String {
+        self.mermaid(None, true, None)
+    }
+
+    /// Exports the transition table like [`TableStateMachine::to_mermaid()`] with
+    /// the current state at the time of the call styled by the `current` class.
+    pub fn to_mermaid_with_state(&self) -> String {
+        self.mermaid(Some(&self.current_state.borrow().0), true, None)
+    }
+
+    /// Exports the transition table like [`TableStateMachine::to_mermaid()`] without
+    /// the self-loops of the ignored inputs.
+    pub fn to_mermaid_without_ignored(&self) -> String {
+        self.mermaid(None, false, None)
+    }
+
+    /// Exports the transition table like [`TableStateMachine::to_mermaid()`] with
+    /// the states labeled by `node_label` like [`TableStateMachine::to_dot_labeled()`].
+    pub fn to_mermaid_labeled<F, L>(&self, node_label: F) -> String
+    where
+        F: Fn(&State) -> Option<L>,
+        L: Display,
+    {
+        self.mermaid(
+            None,
+            true,
+            Some(&|state: &State| node_label(state).map(|label| label.to_string())),
+        )
+    }
+
+    /// Returns the labels of the nodes by the names of their states.
+    fn node_labels(&self, node_label: Option<NodeLabel<'_, State>>) -> HashMap<String, String> {
+        let node_label = match node_label {
+            Some(node_label) => node_label,
+            None => return HashMap::new(),
+        };
+        self.states()
+            .iter()
+            .filter_map(|state| node_label(state).map(|label| (state.to_string(), label)))
+            .collect()
+    }
+
+    /// Exports the transition table as a CSV adjacency matrix for spreadsheet tools.
+    /// The header row lists `inputs`, and each row lists the targets from the state
+    /// in `states` on each input in the same order. The targets are resolved with
+    /// the wildcard rules as [`crate::machine::StateMachine::consume()`] does,
+    /// and the cell is empty if no transition is defined.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::builder::TableStateMachineBuilder;
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "Press", "On")
+    ///     .add_transition("On", "Press", "Off")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(
+    ///     "state,Press\nOff,On\nOn,Off\n",
+    ///     sm.to_csv_matrix(&["Off", "On"], &["Press"])
+    /// );
+    /// ```
+    pub fn to_csv_matrix(&self, states: &[State], inputs: &[Input]) -> String {
+        let mut csv = String::from("state");
+        for input in inputs {
+            csv.push(',');
+            csv.push_str(&escape_csv(&input.to_string()));
+        }
+        csv.push('\n');
+        for state in states {
+            csv.push_str(&escape_csv(&state.to_string()));
+            for input in inputs {
+                csv.push(',');
+                if let Some(to) = self.lookup_by(state, input) {
+                    csv.push_str(&escape_csv(&to.to_string()));
+                }
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+
+    /// Generates the Rust source of a `match`-based transition function equivalent to
+    /// the transition table, which can be pasted into a codebase and passed to
+    /// [`crate::machine::builder::StateMachineBuilder::transition()`].
+    /// States and inputs are written as the variants of `state_enum_name` and
+    /// `input_enum_name` named by their [`Display`] representations.
+    /// The arms follow the precedence order of the table: exact transitions,
+    /// any-input rules, any-state rules and finally staying in the current state.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::builder::TableStateMachineBuilder;
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "Press", "On")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert!(sm
+    ///     .to_rust_source("Light", "Switch")
+    ///     .contains("(Light::Off, Switch::Press) => Light::On,"));
+    /// ```
+    pub fn to_rust_source(&self, state_enum_name: &str, input_enum_name: &str) -> String {
+        let state = |state: &State| format!("{}::{}", state_enum_name, state);
+        let input = |input: &Input| format!("{}::{}", input_enum_name, input);
+        let mut exact: Vec<String> = self
+            .table
+            .iter()
+            .map(|((from, on), to)| format!("({}, {}) => {},", state(from), input(on), state(to)))
+            .collect();
+        let mut any_input: Vec<String> = self
+            .any_input
+            .iter()
+            .map(|(from, to)| format!("({}, _) => {},", state(from), state(to)))
+            .collect();
+        let mut any_state: Vec<String> = self
+            .any_state
+            .iter()
+            .map(|(on, to)| format!("(_, {}) => {},", input(on), state(to)))
+            .collect();
+        exact.sort();
+        any_input.sort();
+        any_state.sort();
+
+        let mut source = format!(
+            "fn transition(state: &{}, input: {}) -> {} {{\n",
+            state_enum_name, input_enum_name, state_enum_name
+        );
+        source.push_str("    match (state, input) {\n");
+        for arm in exact.iter().chain(&any_input).chain(&any_state) {
+            source.push_str("        ");
+            source.push_str(arm);
+            source.push('\n');
+        }
+        source.push_str("        (state, _) => state.clone(),\n");
+        source.push_str("    }\n");
+        source.push_str("}\n");
+        source
+    }
+
+    /// Exports the states and the inputs as TypeScript string literal unions named
+    /// `State` and `Input`, and the transitions as the `transitions` lookup object
+    /// from each state to the targets on each input, for frontends to stay in sync with
+    /// the definition. The targets are resolved with the wildcard rules as
+    /// [`crate::machine::StateMachine::consume()`] does, and only the defined ones are listed.
+    /// States and inputs are named by their [`Display`] representations and sorted.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::builder::TableStateMachineBuilder;
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "Press", "On")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert!(sm
+    ///     .to_typescript()
+    ///     .starts_with("export type State = \"Off\" | \"On\";\n"));
+    /// ```
+    pub fn to_typescript(&self) -> String {
+        let states = self.states();
+        let mut states: Vec<(String, &State)> = states
+            .iter()
+            .map(|state| (state.to_string(), state))
+            .collect();
+        states.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut inputs: Vec<(String, &Input)> = self
+            .table
+            .keys()
+            .map(|(_, input)| input)
+            .chain(self.any_state.keys())
+            .map(|input| (input.to_string(), input))
+            .collect();
+        inputs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        inputs.dedup_by(|(a, _), (b, _)| a == b);
+        let union = |names: Vec<String>| {
+            if names.is_empty() {
+                "never".to_string()
+            } else {
+                names.join(" | ")
+            }
+        };
+
+        let mut source = format!(
+            "export type State = {};\n",
+            union(
+                states
+                    .iter()
+                    .map(|(name, _)| quote_typescript(name))
+                    .collect()
+            )
+        );
+        source.push_str(&format!(
+            "export type Input = {};\n",
+            union(
+                inputs
+                    .iter()
+                    .map(|(name, _)| quote_typescript(name))
+                    .collect()
+            )
+        ));
+        source.push_str(
+            "export const transitions: Record<State, Partial<Record<Input, State>>> = {\n",
+        );
+        for (name, state) in &states {
+            let row: Vec<String> = inputs
+                .iter()
+                .filter_map(|(on, input)| {
+                    self.lookup_by(state, *input).map(|to| {
+                        format!(
+                            "{}: {}",
+                            quote_typescript(on),
+                            quote_typescript(&to.to_string())
+                        )
+                    })
+                })
+                .collect();
+            if row.is_empty() {
+                source.push_str(&format!("  {}: {{}},\n", quote_typescript(name)));
+            } else {
+                source.push_str(&format!(
+                    "  {}: {{ {} }},\n",
+                    quote_typescript(name),
+                    row.join(", ")
+                ));
+            }
+        }
+        source.push_str("};\n");
+        source
+    }
+
+    fn edge_label(&self, key: &(State, Input)) -> String {
+        match self.labels.get(key) {
+            Some(label) => format!("{} / {}", key.1, label),
+            None => key.1.to_string(),
+        }
+    }
+
+    /// Returns the rows of the table, skipping the ignored inputs unless `include_ignored`.
+    fn rows(&self, include_ignored: bool) -> impl Iterator<Item = (&(State, Input), &State)> {
+        self.table
+            .iter()
+            .filter(move |(key, _)| include_ignored || !self.ignored.contains(*key))
+    }
+
+    fn dot(
+        &self,
+        current: Option<&State>,
+        include_ignored: bool,
+        node_label: Option<NodeLabel<'_, State>>,
+    ) -> String {
+        let mut edges: Vec<String> = self
+            .rows(include_ignored)
+            .map(|(key, to)| {
+                let style = if self.ignored.contains(key) {
+                    ", style=dashed"
+                } else {
+                    ""
+                };
+                format!(
+                    "    \"{}\" -> \"{}\" [label=\"{}\"{}];",
+                    escape_dot(&key.0.to_string()),
+                    escape_dot(&to.to_string()),
+                    escape_dot(&self.edge_label(key)),
+                    style
+                )
+            })
+            .chain(self.any_input.iter().map(|(from, to)| {
+                format!(
+                    "    \"{}\" -> \"{}\" [label=\"*\"];",
+                    escape_dot(&from.to_string()),
+                    escape_dot(&to.to_string())
+                )
+            }))
+            .chain(self.any_state.iter().map(|(input, to)| {
+                format!(
+                    "    \"*\" -> \"{}\" [label=\"{}\"];",
+                    escape_dot(&to.to_string()),
+                    escape_dot(&input.to_string())
+                )
+            }))
+            .collect();
+        edges.sort();
+
+        let mut dot = String::from("digraph {\n");
+        dot.push_str("    __start__ [shape=point];\n");
+        dot.push_str(&format!(
+            "    __start__ -> \"{}\";\n",
+            escape_dot(&self.initial_state.to_string())
+        ));
+        if let Some(current) = current {
+            dot.push_str(&format!(
+                "    \"{}\" [style=filled, fillcolor=yellow];\n",
+                escape_dot(&current.to_string())
+            ));
+        }
+        let mut labels: Vec<_> = self.node_labels(node_label).into_iter().collect();
+        labels.sort();
+        for (name, label) in labels {
+            dot.push_str(&format!(
+                "    \"{}\" [label=\"{}\"];\n",
+                escape_dot(&name),
+                escape_dot(&label)
+            ));
+        }
+        for edge in edges {
+            dot.push_str(&edge);
+            dot.push('\n');
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn mermaid(
+        &self,
+        current: Option<&State>,
+        include_ignored: bool,
+        node_label: Option<NodeLabel<'_, State>>,
+    ) -> String {
+        let mut names: Vec<String> = self
+            .table
+            .iter()
+            .flat_map(|((from, _), to)| vec![from, to])
+            .chain(self.any_input.iter().flat_map(|(from, to)| vec![from, to]))
+            .chain(self.any_state.values())
+            .chain(std::iter::once(&self.initial_state))
+            .chain(current)
+            .map(|state| state.to_string())
+            .collect();
+        names.sort();
+        names.dedup();
+        let id = |state: &State| {
+            let name = state.to_string();
+            format!("s{}", names.iter().position(|n| *n == name).unwrap_or(0))
+        };
+
+        let mut edges: Vec<String> = self
+            .rows(include_ignored)
+            .map(|(key, to)| {
+                let label = if self.ignored.contains(key) {
+                    format!("{} (ignored)", self.edge_label(key))
+                } else {
+                    self.edge_label(key)
+                };
+                format!(
+                    "    {} --> {} : {}",
+                    id(&key.0),
+                    id(to),
+                    escape_mermaid(&label)
+                )
+            })
+            .chain(
+                self.any_input
+                    .iter()
+                    .map(|(from, to)| format!("    {} --> {} : *", id(from), id(to))),
+            )
+            .chain(self.any_state.iter().map(|(input, to)| {
+                format!(
+                    "    any --> {} : {}",
+                    id(to),
+                    escape_mermaid(&input.to_string())
+                )
+            }))
+            .collect();
+        edges.sort();
+
+        let labels = self.node_labels(node_label);
+        let mut mermaid = String::from("stateDiagram-v2\n");
+        for (i, name) in names.iter().enumerate() {
+            mermaid.push_str(&format!(
+                "    state \"{}\" as s{}\n",
+                escape_mermaid(labels.get(name).unwrap_or(name)),
+                i
+            ));
+        }
+        if !self.any_state.is_empty() {
+            mermaid.push_str("    state \"*\" as any\n");
+        }
+        mermaid.push_str(&format!("    [*] --> {}\n", id(&self.initial_state)));
+        for edge in edges {
+            mermaid.push_str(&edge);
+            mermaid.push('\n');
+        }
+        if let Some(current) = current {
+            mermaid.push_str("    classDef current fill:#ff0\n");
+            mermaid.push_str(&format!("    class {} current\n", id(current)));
+        }
+        mermaid
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::machine::{builder::TableStateMachineBuilder, StateMachine};
+
+    #[test]
+    fn test_to_dot() {
+        let sm = TableStateMachineBuilder::start()
+            .initial_state("Cart")
+            .add_transition("Cart", "Checkout", "Payment")
+            .add_transition_labeled(
+                "Payment",
+                "Confirm",
+                "Paid",
+                "customer confirmed \"payment\"",
+            )
+            .add_any_input("Maintenance", "Maintenance")
+            .add_any_state("Abort", "Cart")
+            .ignore("Paid", vec!["Checkout"])
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            Some("customer confirmed \"payment\""),
+            sm.label_of(&"Payment", &"Confirm")
+        );
+        assert_eq!(None, sm.label_of(&"Cart", &"Checkout"));
+        assert_eq!(
+            r#"digraph {
+    __start__ [shape=point];
+    __start__ -> "Cart";
+    "*" -> "Cart" [label="Abort"];
+    "Cart" -> "Payment" [label="Checkout"];
+    "Maintenance" -> "Maintenance" [label="*"];
+    "Paid" -> "Paid" [label="Checkout", style=dashed];
+    "Payment" -> "Paid" [label="Confirm / customer confirmed \"payment\""];
+}
+"#,
+            sm.to_dot()
+        );
+        assert!(!sm.to_dot_without_ignored().contains(r#""Paid" -> "Paid""#));
+        assert!(!sm.to_mermaid_without_ignored().contains("(ignored)"));
+        assert!(sm.to_mermaid().contains("(ignored)"));
+    }
+
+    #[test]
+    fn test_export_with_state() {
+        let sm = TableStateMachineBuilder::start()
+            .initial_state("Off")
+            .add_transition("Off", "Press", "On")
+            .add_transition_labeled("On", "Press", "Off", "power saving")
+            .add_transition("On", "Break", "Broken")
+            .add_any_state("Repair", "Off")
+            .build()
+            .unwrap();
+        sm.consume("Press");
+
+        assert_eq!(
+            r#"digraph {
+    __start__ [shape=point];
+    __start__ -> "Off";
+    "On" [style=filled, fillcolor=yellow];
+    "*" -> "Off" [label="Repair"];
+    "Off" -> "On" [label="Press"];
+    "On" -> "Broken" [label="Break"];
+    "On" -> "Off" [label="Press / power saving"];
+}
+"#,
+            sm.to_dot_with_state()
+        );
+        assert_eq!(
+            r#"stateDiagram-v2
+    state "Broken" as s0
+    state "Off" as s1
+    state "On" as s2
+    state "*" as any
+    [*] --> s1
+    any --> s1 : Repair
+    s1 --> s2 : Press
+    s2 --> s0 : Break
+    s2 --> s1 : Press / power saving
+    classDef current fill:#ff0
+    class s2 current
+"#,
+            sm.to_mermaid_with_state()
+        );
+
+        // the current state equals the initial state
+        sm.reset();
+        assert!(sm
+            .to_dot_with_state()
+            .contains("\"Off\" [style=filled, fillcolor=yellow];"));
+        assert!(sm.to_mermaid_with_state().ends_with("class s1 current\n"));
+    }
+
+    #[test]
+    fn test_export_labeled() {
+        let sm = TableStateMachineBuilder::start()
+            .initial_state("Off")
+            .add_transition("Off", "Press", "On")
+            .add_transition("On", "Press", "Off")
+            .build()
+            .unwrap();
+        let caption = |state: &&str| match *state {
+            "On" => Some("Button is \"on\""),
+            _ => None,
+        };
+
+        assert_eq!(
+            r#"digraph {
+    __start__ [shape=point];
+    __start__ -> "Off";
+    "On" [label="Button is \"on\""];
+    "Off" -> "On" [label="Press"];
+    "On" -> "Off" [label="Press"];
+}
+"#,
+            sm.to_dot_labeled(caption)
+        );
+        assert_eq!(
+            r#"stateDiagram-v2
+    state "Off" as s0
+    state "Button is #quot;on#quot;" as s1
+    [*] --> s0
+    s0 --> s1 : Press
+    s1 --> s0 : Press
+"#,
+            sm.to_mermaid_labeled(caption)
+        );
+    }
+
+    #[test]
+    fn test_to_rust_source() {
+        let sm = TableStateMachineBuilder::start()
+            .initial_state("Off")
+            .add_transition("Off", "Press", "On")
+            .add_transition("On", "Press", "Off")
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            r#"fn transition(state: &Light, input: Switch) -> Light {
+    match (state, input) {
+        (Light::Off, Switch::Press) => Light::On,
+        (Light::On, Switch::Press) => Light::Off,
+        (state, _) => state.clone(),
+    }
+}
+"#,
+            sm.to_rust_source("Light", "Switch")
+        );
+    }
+
+    #[test]
+    fn test_to_csv_matrix() {
+        let sm = TableStateMachineBuilder::start()
+            .initial_state("Cart")
+            .add_transition("Cart", "Checkout", "Payment")
+            .add_transition("Payment", "Confirm", "Paid")
+            .add_transition("Payment", "Pay, later", "Invoice \"due\"")
+            .add_any_state("Abort", "Cart")
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            concat!(
+                "state,Checkout,Confirm,\"Pay, later\",Abort\n",
+                "Cart,Payment,,,Cart\n",
+                "Payment,,Paid,\"Invoice \"\"due\"\"\",Cart\n",
+                "Paid,,,,Cart\n",
+            ),
+            sm.to_csv_matrix(
+                &["Cart", "Payment", "Paid"],
+                &["Checkout", "Confirm", "Pay, later", "Abort"]
+            )
+        );
+    }
+
+    #[test]
+    fn test_to_typescript() {
+        let sm = TableStateMachineBuilder::start()
+            .initial_state("Off")
+            .add_transition("Off", "Press", "On")
+            .add_transition("On", "Press", "Off")
+            .add_transition("On", "Break", "Broken")
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            r#"export type State = "Broken" | "Off" | "On";
+export type Input = "Break" | "Press";
+export const transitions: Record<State, Partial<Record<Input, State>>> = {
+  "Broken": {},
+  "Off": { "Press": "On" },
+  "On": { "Break": "Broken", "Press": "Off" },
+};
+"#,
+            sm.to_typescript()
+        );
+    }
+}
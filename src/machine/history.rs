@@ -0,0 +1,280 @@
+use std::{
+    cell::{Ref, RefCell},
+    collections::VecDeque,
+};
+
+/// The recorded history of the state machine.
+///
+/// If the limit is set, it is a ring buffer pre-allocated with the capacity of the limit,
+/// so recording a state pops the oldest one from the front in O(1) without allocating.
+/// Otherwise it grows like `Vec`.
+#[derive(Clone)]
+pub(crate) struct History<State> {
+    buffer: VecDeque<State>,
+    limit: Option<usize>,
+    checkpoint: Option<Checkpoint<State>>,
+}
+
+/// The changes since [`History::checkpoint()`], which are enough to rewind the buffer
+/// without cloning it.
+#[derive(Clone)]
+struct Checkpoint<State> {
+    /// The number of the states pushed since the checkpoint.
+    pushed: usize,
+    /// The states evicted from the front since the checkpoint, the oldest first.
+    evicted: Vec<State>,
+}
+
+impl<State> History<State> {
+    /// Creates the history starting from `state`.
+    pub(crate) fn new(state: State, limit: Option<usize>) -> Self {
+        let mut buffer = VecDeque::with_capacity(limit.unwrap_or(1).max(1));
+        buffer.push_back(state);
+        let mut history = History {
+            buffer,
+            limit: None,
+            checkpoint: None,
+        };
+        history.set_limit(limit);
+        history
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Returns the `index`-th oldest state.
+    pub(crate) fn get(&self, index: usize) -> Option<&State> {
+        self.buffer.get(index)
+    }
+
+    pub(crate) fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Sets the limit and drops the oldest states beyond it, if any.
+    pub(crate) fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+        if let Some(limit) = limit {
+            if self.buffer.len() > limit {
+                self.buffer.drain(..self.buffer.len() - limit);
+            }
+        }
+    }
+
+    /// Records `state` as the newest one.
+    pub(crate) fn push(&mut self, state: State) {
+        match self.limit {
+            Some(0) => return,
+            Some(limit) => {
+                if self.buffer.len() >= limit {
+                    let evicted = self.buffer.pop_front();
+                    if let (Some(checkpoint), Some(evicted)) = (&mut self.checkpoint, evicted) {
+                        checkpoint.evicted.push(evicted);
+                    }
+                }
+                self.buffer.push_back(state);
+            }
+            None => self.buffer.push_back(state),
+        }
+        if let Some(checkpoint) = &mut self.checkpoint {
+            checkpoint.pushed += 1;
+        }
+    }
+
+    /// Starts tracking the pushes, so that [`History::rewind()`] can undo them.
+    pub(crate) fn checkpoint(&mut self) {
+        self.checkpoint = Some(Checkpoint {
+            pushed: 0,
+            evicted: Vec::new(),
+        });
+    }
+
+    /// Undoes the pushes since the checkpoint, restoring the evicted states, and
+    /// stops tracking them.
+    pub(crate) fn rewind(&mut self) {
+        if let Some(checkpoint) = self.checkpoint.take() {
+            for _ in 0..checkpoint.pushed {
+                self.buffer.pop_back();
+            }
+            for state in checkpoint.evicted.into_iter().rev() {
+                self.buffer.push_front(state);
+            }
+        }
+    }
+
+    /// Stops tracking the pushes, keeping them.
+    pub(crate) fn release(&mut self) {
+        self.checkpoint = None;
+    }
+
+    /// Replaces the whole history with `state` and releases the buffer beyond the limit.
+    pub(crate) fn reset_to(&mut self, state: State) {
+        *self = History::new(state, self.limit);
+    }
+}
+
+/// Lifts the limit of the history while it is alive. When dropped, the limit is restored
+/// and the history is trimmed back to it, even if the batch panics.
+/// See [`crate::machine::BasicStateMachine::consume_all_unlimited()`].
+pub(crate) struct UnlimitedHistory<'a, State> {
+    history: Option<&'a RefCell<History<State>>>,
+    limit: Option<usize>,
+}
+
+impl<'a, State> UnlimitedHistory<'a, State> {
+    pub(crate) fn lift(history: Option<&'a RefCell<History<State>>>) -> Self {
+        let limit = history.and_then(|history| {
+            let mut history = history.borrow_mut();
+            let limit = history.limit();
+            history.set_limit(None);
+            limit
+        });
+        UnlimitedHistory { history, limit }
+    }
+}
+
+impl<State> Drop for UnlimitedHistory<'_, State> {
+    fn drop(&mut self) {
+        if let Some(history) = self.history {
+            history.borrow_mut().set_limit(self.limit);
+        }
+    }
+}
+
+impl<State> From<Vec<State>> for History<State> {
+    fn from(buffer: Vec<State>) -> Self {
+        History {
+            buffer: buffer.into(),
+            limit: None,
+            checkpoint: None,
+        }
+    }
+}
+
+/// An iterator over the recorded history of the state machine.
+/// It is created by [`crate::machine::BasicStateMachine::history_iter()`].
+///
+/// The iterator holds the borrow of the history while it is alive,
+/// so it clones the states one by one from the oldest to the newest
+/// instead of cloning the whole buffer.
+/// Note that consuming inputs on the state machine while the iterator is
+/// alive causes a panic.
+pub struct HistoryIter<'a, State> {
+    history: Option<Ref<'a, History<State>>>,
+    index: usize,
+}
+
+impl<'a, State> HistoryIter<'a, State> {
+    pub(crate) fn new(history: Option<Ref<'a, History<State>>>) -> Self {
+        HistoryIter { history, index: 0 }
+    }
+}
+
+impl<State> Iterator for HistoryIter<'_, State>
+where
+    State: Clone,
+{
+    type Item = State;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let state = self.history.as_ref()?.get(self.index)?.clone();
+        self.index += 1;
+        Some(state)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.history.as_ref().map_or(0, |history| history.len());
+        let remaining = len.saturating_sub(self.index);
+        (remaining, Some(remaining))
+    }
+}
+
+impl<State> ExactSizeIterator for HistoryIter<'_, State> where State: Clone {}
+
+#[cfg(test)]
+mod test {
+    use super::History;
+    use crate::machine::{
+        builder::{BasicStateMachineBuilder, StateMachineBuilder},
+        StateMachine,
+    };
+
+    fn states(history: &History<u32>) -> Vec<u32> {
+        (0..history.len())
+            .map(|index| *history.get(index).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut history = History::new(0, Some(3));
+        history.push(1);
+        history.checkpoint();
+        history.push(2);
+        history.push(3);
+        history.push(4);
+        assert_eq!(vec![2, 3, 4], states(&history));
+        history.rewind();
+        assert_eq!(vec![0, 1], states(&history));
+
+        history.checkpoint();
+        history.push(2);
+        history.release();
+        history.rewind();
+        assert_eq!(vec![0, 1, 2], states(&history));
+    }
+
+    #[test]
+    fn test_wrap_around() {
+        let mut history = History::new(0, Some(3));
+        history.push(1);
+        history.push(2);
+        assert_eq!(vec![0, 1, 2], states(&history));
+        history.push(3);
+        history.push(4);
+        assert_eq!(vec![2, 3, 4], states(&history));
+        assert_eq!(None, history.get(3));
+
+        for state in 5..100 {
+            history.push(state);
+        }
+        assert_eq!(vec![97, 98, 99], states(&history));
+
+        // lifting the limit keeps the order
+        history.set_limit(None);
+        history.push(100);
+        assert_eq!(vec![97, 98, 99, 100], states(&history));
+        history.set_limit(Some(2));
+        assert_eq!(vec![99, 100], states(&history));
+        history.push(101);
+        assert_eq!(vec![100, 101], states(&history));
+
+        history.reset_to(7);
+        assert_eq!(vec![7], states(&history));
+        assert_eq!(Some(2), history.limit());
+    }
+
+    #[test]
+    fn test_overflow() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|state: &u32, input: u32| state + input)
+            .history_limit(3)
+            .build()
+            .unwrap();
+        for _ in 0..10 {
+            sm.consume(1);
+        }
+
+        assert_eq!(vec![8, 9, 10], sm.history());
+        assert_eq!(vec![8, 9, 10], sm.history_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_zero_limit() {
+        let mut history = History::new(0, Some(0));
+        history.push(1);
+        assert_eq!(0, history.len());
+    }
+}
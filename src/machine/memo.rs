@@ -0,0 +1,73 @@
+use std::{borrow::Cow, cell::RefCell, collections::HashMap, hash::Hash};
+
+use super::Guard;
+
+/// The function which looks up the memo or evaluates the guards on a miss.
+/// It is captured where `State: Eq + Clone` and `Input: Eq + Hash + Clone` are available
+/// like [`super::stats::Count`], so that the machine itself doesn't have to require them.
+pub(crate) type Evaluate<State, Input> = fn(
+    &GuardCache<State, Input>,
+    &[(Cow<'static, str>, Guard<State, Input>)],
+    &State,
+    &Input,
+) -> Option<usize>;
+
+/// The state the memo is valid for and the index of the first failing guard for each input,
+/// or `None` if all the guards hold.
+type Memo<State, Input> = (State, HashMap<Input, Option<usize>>);
+
+/// The memo of the guard results. See [`super::builder::BasicStateMachineBuilder::guard_cache()`].
+pub(crate) struct GuardCache<State, Input> {
+    entries: RefCell<Option<Memo<State, Input>>>,
+    evaluate: Evaluate<State, Input>,
+}
+
+impl<State, Input> GuardCache<State, Input> {
+    pub(crate) fn new(evaluate: Evaluate<State, Input>) -> Self {
+        GuardCache {
+            entries: RefCell::new(None),
+            evaluate,
+        }
+    }
+
+    /// Returns the index of the first failing guard for `state` and `input`,
+    /// evaluating the guards only if it isn't memoized yet.
+    pub(crate) fn failing_guard(
+        &self,
+        guards: &[(Cow<'static, str>, Guard<State, Input>)],
+        state: &State,
+        input: &Input,
+    ) -> Option<usize> {
+        (self.evaluate)(self, guards, state, input)
+    }
+
+    /// Forgets every memoized result.
+    pub(crate) fn clear(&self) {
+        self.entries.borrow_mut().take();
+    }
+}
+
+pub(crate) fn evaluate<State, Input>(
+    cache: &GuardCache<State, Input>,
+    guards: &[(Cow<'static, str>, Guard<State, Input>)],
+    state: &State,
+    input: &Input,
+) -> Option<usize>
+where
+    State: Eq + Clone,
+    Input: Eq + Hash + Clone,
+{
+    let mut entries = cache.entries.borrow_mut();
+    // The memo is dropped as soon as the state changes, so it never outgrows the inputs
+    // tried on the current state.
+    if !matches!(&*entries, Some((cached, _)) if cached == state) {
+        *entries = Some((state.clone(), HashMap::new()));
+    }
+    let memo = &mut entries.as_mut().expect("the memo is just set").1;
+    if let Some(failing) = memo.get(input) {
+        return *failing;
+    }
+    let failing = guards.iter().position(|(_, guard)| !guard(state, input));
+    memo.insert(input.clone(), failing);
+    failing
+}
@@ -1,7 +1,139 @@
-use std::{cell::RefCell, marker::PhantomData};
+use std::{
+    any::Any,
+    borrow::Cow,
+    cell::{Cell, Ref, RefCell},
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Debug,
+    hash::Hash,
+    marker::PhantomData,
+    mem,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
 
+pub mod actor;
+pub mod asynchronous;
+mod atomic;
+#[cfg(test)]
+mod auto_traits;
 pub mod builder;
+pub mod compact;
+pub mod const_table;
+#[cfg(test)]
+mod cow;
+pub mod dedupe;
+pub mod diff;
+pub mod drive;
+pub mod effect;
 pub mod error;
+pub mod event_sourced;
+mod export;
+pub mod history;
+mod memo;
+pub mod observer;
+pub mod orchestrator;
+pub mod pure;
+mod queue;
+pub mod shared;
+pub mod snapshot;
+pub mod state_ref;
+mod stats;
+mod strict;
+pub mod table;
+pub mod testing;
+pub mod time;
+mod trace;
+pub mod transaction;
+pub mod watch;
+
+use atomic::AtomicBatch;
+use dedupe::{DedupeKeys, IdempotentOutcome};
+use drive::Drive;
+use error::{AtomicBatchError, ParseInputError, StateMachineError};
+use history::{History, HistoryIter, UnlimitedHistory};
+use memo::GuardCache;
+use observer::{ObserverHandle, Observers};
+use queue::InputQueue;
+use snapshot::{FullSnapshot, MachineStatus, Snapshot, StalePolicy};
+use state_ref::StateRef;
+use stats::{Frequencies, Telemetry, Trajectory, Visited};
+use strict::{ActiveOperation, OperationGuard};
+use time::{Clock, Occupancy, Timings, TransitionMetrics};
+use trace::{PanicContext, Tracer};
+use transaction::Transaction;
+use watch::{WatchFor, Watcher};
+
+/// The boxed transition function. The machine with this transition can replace
+/// its transition with another closure via [`BasicStateMachine::set_transition()`].
+pub type BoxedTransition<State, Input> = Box<dyn Fn(&State, Input) -> State>;
+
+/// The type-erased transition function of [`DynStateMachine`].
+/// Unlike [`BoxedTransition`], it can be sent to and shared among threads.
+pub type DynTransition<State, Input> = Box<dyn Fn(&State, Input) -> State + Send + Sync>;
+
+/// The state machine whose transition is type-erased. It is built via
+/// [`builder::BasicStateMachineBuilder::dynamic()`].
+///
+/// Every closure has its own type, so each [`BasicStateMachine`] built with a distinct closure
+/// is compiled separately. All the `DynStateMachine`s for the same `State` and `Input` share
+/// one compiled copy instead, which reduces the compile time and the binary size when
+/// there are many machines. The cost is a dynamic dispatch (and the lost inlining)
+/// on every call of the transition. See `benches/dynamic.rs` to measure it.
+pub type DynStateMachine<State, Input, M = ()> =
+    BasicStateMachine<State, Input, DynTransition<State, Input>, M>;
+
+/// The validator for inputs. See [`builder::BasicStateMachineBuilder::validate_input()`].
+pub(crate) type InputValidator<Input> = Arc<dyn Fn(&Input) -> Result<(), String> + Send + Sync>;
+
+/// The parser of raw inputs. See [`builder::BasicStateMachineBuilder::parse_input_with()`].
+pub(crate) type InputParser<Input> =
+    Arc<dyn Fn(&str) -> Result<Input, ParseInputError> + Send + Sync>;
+
+/// The changes buffered by [`BasicStateMachine::consume_all_atomic()`], as the previous
+/// and the next states with how they are notified.
+pub(crate) type Deferred<State> = Vec<(State, State, Deferral)>;
+
+/// How a change buffered in [`Deferred`] is notified once the batch is committed.
+pub(crate) enum Deferral {
+    /// A transition, with the described source state and input to be traced, if any.
+    /// It notifies the tracer, the watchers, the enter hooks, the metric sinks and the observers.
+    Transition(Option<(String, String)>),
+    /// An in-place change via [`BasicStateMachine::update()`].
+    /// It notifies the watchers and the observers only.
+    Update,
+}
+
+/// The predicate which must hold for the transition.
+/// See [`builder::BasicStateMachineBuilder::add_named_guard()`].
+pub(crate) type Guard<State, Input> = Arc<dyn Fn(&State, &Input) -> bool + Send + Sync>;
+
+/// The predicate on a state. See [`builder::BasicStateMachineBuilder::no_reset_from()`].
+pub(crate) type StatePredicate<State> = Arc<dyn Fn(&State) -> bool + Send + Sync>;
+
+/// The transition without input. See [`builder::BasicStateMachineBuilder::epsilon()`].
+pub(crate) type EpsilonStep<State> = Arc<dyn Fn(&State) -> Option<State> + Send + Sync>;
+
+/// The callback which is called with the previous and the next states.
+/// See [`builder::BasicStateMachineBuilder::metric_sink()`].
+pub(crate) type MetricSink<State> = Arc<dyn Fn(&State, &State) + Send + Sync>;
+
+/// The predicate on the pair of the previous and the next states.
+/// See [`builder::BasicStateMachineBuilder::log_filter()`].
+pub(crate) type TransitionFilter<State> = Arc<dyn Fn(&State, &State) -> bool + Send + Sync>;
+
+/// The callback which is called with the previous and the next states and the duration of
+/// the slow transition. See [`builder::BasicStateMachineBuilder::on_slow()`].
+pub(crate) type SlowHook<State> = Arc<dyn Fn(&State, &State, Duration) + Send + Sync>;
+
+/// The callback which is called with a state. See [`builder::BasicStateMachineBuilder::on_enter_state()`].
+pub(crate) type Hook<State> = Arc<dyn Fn(&State) + Send + Sync>;
+
+/// The callback which can veto the transition. See [`builder::BasicStateMachineBuilder::on_exit_state()`].
+pub(crate) type ExitHook<State> = Arc<dyn Fn(&State) -> Result<(), String> + Send + Sync>;
+
+/// The hook which is told of the stale snapshot restored anyway.
+/// See [`builder::BasicStateMachineBuilder::on_stale_snapshot()`].
+pub(crate) type StaleSnapshotHook = Arc<dyn Fn(&StateMachineError) + Send + Sync>;
 
 /// The trait is representing the basic operation for the state machine.
 /// It includes getting its current state, transition to the next state,
@@ -171,33 +303,221 @@ pub trait StateMachine<State, Input> {
     /// assert_eq!(ButtonState::Disable, sm.consume(Input::Press));
     /// ```
     fn set(&self, new_state: State);
+    /// Transits to the next state like [`StateMachine::consume()`] and returns
+    /// the pair of the previous state and the new state, so that the caller can
+    /// roll back manually via [`StateMachine::set()`].
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// #[derive(Clone, Debug, PartialEq)]
+    /// enum ButtonState {
+    ///     On,
+    ///     Off,
+    /// }
+    ///
+    /// enum Input {
+    ///     Press,
+    /// }
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(ButtonState::Off)
+    ///     .transition(|state, input| match (state, input) {
+    ///         (ButtonState::On, Input::Press) => ButtonState::Off,
+    ///         (ButtonState::Off, Input::Press) => ButtonState::On,
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// let (prev, new) = sm.consume_with_prev(Input::Press);
+    /// assert_eq!((ButtonState::Off, ButtonState::On), (prev.clone(), new));
+    /// sm.set(prev);
+    /// assert_eq!(ButtonState::Off, sm.current_state());
+    /// ```
+    fn consume_with_prev(&self, input: Input) -> (State, State) {
+        let prev = self.current_state();
+        let new = self.consume(input);
+        (prev, new)
+    }
+
+    /// Transits to the next state like [`StateMachine::consume()`] and returns the response
+    /// computed by `respond` from the previous state and the new state, e.g. to answer
+    /// a request modeled as the input. The machines shared among threads call `respond`
+    /// before any other input is consumed, so the response is consistent with the transition.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(10)
+    ///     .transition(|balance: &u32, amount: u32| balance.saturating_sub(amount))
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// let withdrawn = sm.consume_respond(15, |prev, new| prev - new);
+    /// assert_eq!(10, withdrawn);
+    /// assert_eq!(0, sm.current_state());
+    /// ```
+    fn consume_respond<R, F>(&self, input: Input, respond: F) -> R
+    where
+        F: FnOnce(&State, &State) -> R,
+    {
+        let (prev, new) = self.consume_with_prev(input);
+        respond(&prev, &new)
+    }
+
+    /// Consumes `inputs` in order, collapsing each run of equal consecutive inputs
+    /// into one, and returns the resulting state. It saves the transition computation
+    /// for a noisy input stream. Note that it changes the result unless consuming
+    /// the same input twice in a row is the same as consuming it once.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|state, input: i32| state * 10 + input)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(121, sm.consume_all_dedup(vec![1, 1, 2, 2, 2, 1]));
+    /// ```
+    fn consume_all_dedup<I>(&self, inputs: I) -> State
+    where
+        I: IntoIterator<Item = Input>,
+        Input: PartialEq,
+    {
+        let mut inputs = inputs.into_iter().peekable();
+        while let Some(input) = inputs.next() {
+            if inputs.peek() != Some(&input) {
+                self.consume(input);
+            }
+        }
+        self.current_state()
+    }
+    /// Returns the type name of the states via [`std::any::type_name()`],
+    /// e.g. to tell apart many heterogeneous machines in the logs.
+    /// The name is for diagnostics only and its exact format isn't guaranteed.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// #[derive(Clone, Debug, PartialEq)]
+    /// enum ButtonState {
+    ///     On,
+    ///     Off,
+    /// }
+    ///
+    /// enum Input {
+    ///     Press,
+    /// }
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(ButtonState::Off)
+    ///     .transition(|state, input| match (state, input) {
+    ///         (ButtonState::On, Input::Press) => ButtonState::Off,
+    ///         (ButtonState::Off, Input::Press) => ButtonState::On,
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert!(sm.state_type_name().ends_with("ButtonState"));
+    /// assert!(sm.input_type_name().ends_with("Input"));
+    /// ```
+    fn state_type_name(&self) -> &'static str {
+        std::any::type_name::<State>()
+    }
+    /// Returns the type name of the inputs via [`std::any::type_name()`].
+    /// See [`StateMachine::state_type_name()`].
+    fn input_type_name(&self) -> &'static str {
+        std::any::type_name::<Input>()
+    }
+}
+
+/// The result of previewing a transition for particular input.
+/// See [`BasicStateMachine::peek_edge()`] and [`table::TableStateMachine::peek_edge()`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum PeekResult<State> {
+    /// The input is valid from the current state and leads to the state.
+    Valid(State),
+    /// No transition is defined for the input from the current state.
+    Invalid,
 }
 
 /// [`StateWrapper`] is a struct for interior mutability.
 /// It enables to acquire the control of switching mutable/imutable
 /// with [`std::cell::RefCell`].
-pub(crate) struct StateWrapper<State: Clone>(State);
+pub(crate) struct StateWrapper<State>(State);
 
-impl<State> StateWrapper<State>
-where
-    State: Clone,
-{
+impl<State> StateWrapper<State> {
     pub fn new(state: State) -> Self {
         StateWrapper(state)
     }
 
-    pub fn get(&self) -> State {
+    pub fn get(&self) -> State
+    where
+        State: Clone,
+    {
         self.0.clone()
     }
 
-    pub fn set(&mut self, state: State) {
-        self.0 = state;
+    /// Swaps the state with `state` without cloning and returns the previous one.
+    pub fn set(&mut self, state: State) -> State {
+        mem::replace(&mut self.0, state)
     }
 }
 
 /// The basic state machine implementation.
 /// It holds `initial_state`, `current_state`, `transition` function.
-pub struct BasicStateMachine<State, Input, Transition>
+///
+/// # Threads
+/// It is `Send` if the state, the input and the transition are `Send`, so it can be moved
+/// to another thread, e.g. via [`actor::MachineHandle`]. It is never `Sync`, since
+/// the current state is kept in a `RefCell`, so it can't be shared among threads via `Arc`:
+///
+/// ```compile_fail
+/// use std::{sync::Arc, thread};
+/// use statemachine_rs::machine::{
+///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+/// };
+///
+/// let sm = Arc::new(
+///     BasicStateMachineBuilder::start()
+///         .initial_state(0)
+///         .transition(|count: &u32, step: u32| count + step)
+///         .build()
+///         .unwrap(),
+/// );
+/// let shared = Arc::clone(&sm);
+/// // error[E0277]: `RefCell<..>` cannot be shared between threads safely
+/// thread::spawn(move || shared.consume(1)).join().unwrap();
+/// ```
+///
+/// Use [`shared::SharedStateMachine`] instead to share the state machine, or
+/// [`actor::MachineHandle`] to send the inputs to it from several threads:
+///
+/// ```
+/// use std::{sync::Arc, thread};
+/// use statemachine_rs::machine::{shared::SharedStateMachine, StateMachine};
+///
+/// let sm = Arc::new(SharedStateMachine::new(0, |count: &u32, step: u32| count + step));
+/// let shared = Arc::clone(&sm);
+/// thread::spawn(move || shared.consume(1)).join().unwrap();
+/// assert_eq!(1, sm.current_state());
+/// ```
+pub struct BasicStateMachine<State, Input, Transition, M = ()>
 where
     Transition: Fn(&State, Input) -> State,
     State: Clone,
@@ -212,12 +532,142 @@ where
     /// `transition` is the definition of state transition.
     /// See an example of [`StateMachine::consume()`], you can grasp how
     /// to define the transition.
-    transition: Transition,
+    /// It can be replaced via [`BasicStateMachine::set_transition()`].
+    transition: RefCell<Transition>,
+    /// `metadata` holds arbitrary data attached to each state via
+    /// [`builder::BasicStateMachineBuilder::metadata()`].
+    /// It is shared among the machines built by [`builder::StateMachineBuilder::build_many()`].
+    metadata: Arc<HashMap<State, M>>,
+    /// `tags` holds the tags attached to each state via
+    /// [`builder::BasicStateMachineBuilder::tag()`].
+    tags: Arc<HashMap<State, Vec<String>>>,
+    /// `history` records every state the machine has been in.
+    /// It is `None` unless [`builder::BasicStateMachineBuilder::with_history()`] is called.
+    /// If [`builder::BasicStateMachineBuilder::history_limit()`] is called, only the newest states
+    /// within the limit are kept in a pre-allocated ring buffer.
+    history: Option<RefCell<History<State>>>,
+    /// `input_validator` validates inputs before they reach the transition.
+    /// It is set via [`builder::BasicStateMachineBuilder::validate_input()`].
+    input_validator: Option<InputValidator<Input>>,
+    /// `guards` are the named predicates which must hold for the transition, in order.
+    /// See [`builder::BasicStateMachineBuilder::add_named_guard()`].
+    guards: Vec<(Cow<'static, str>, Guard<State, Input>)>,
+    /// `guard_cache` memoizes the results of `guards` for the current state.
+    /// It is `None` unless [`builder::BasicStateMachineBuilder::guard_cache()`] is called.
+    guard_cache: Option<GuardCache<State, Input>>,
+    /// `reset_forbidden` are the predicates on the current state which forbid resetting.
+    /// See [`builder::BasicStateMachineBuilder::no_reset_from()`].
+    reset_forbidden: Vec<StatePredicate<State>>,
+    /// `min_dwell` is how long the machine must stay in the current state before transiting.
+    /// See [`builder::BasicStateMachineBuilder::min_dwell()`].
+    min_dwell: Option<Duration>,
+    /// `state_order` is the order of the states which the transitions mustn't regress.
+    /// See [`builder::BasicStateMachineBuilder::state_order()`].
+    state_order: Vec<State>,
+    /// `post_conditions` are the predicates which the next state must satisfy.
+    /// See [`builder::BasicStateMachineBuilder::post_condition()`].
+    post_conditions: Vec<StatePredicate<State>>,
+    /// `epsilon` is the transition without input followed by [`BasicStateMachine::consume_closed()`].
+    /// See [`builder::BasicStateMachineBuilder::epsilon()`].
+    epsilon: Option<EpsilonStep<State>>,
+    /// `metric_sinks` are called with the pairs of the previous and the next states on consuming.
+    /// See [`builder::BasicStateMachineBuilder::metric_sink()`].
+    metric_sinks: Vec<MetricSink<State>>,
+    /// `observers` are called with the pairs of the previous and the next states on consuming,
+    /// after the enter hooks and the metric sinks. See [`BasicStateMachine::add_observer_with_priority()`].
+    observers: Observers<State>,
+    /// `enter_hooks` are called after the machine transits to a new state via `consume`.
+    /// See [`builder::BasicStateMachineBuilder::on_enter_state()`].
+    enter_hooks: Vec<Hook<State>>,
+    /// `exit_hooks` are called before the machine leaves the current state via `consume`.
+    /// See [`builder::BasicStateMachineBuilder::on_exit_state()`].
+    exit_hooks: Vec<ExitHook<State>>,
+    /// `terminal` tells whether the state is terminal.
+    /// See [`builder::BasicStateMachineBuilder::terminal_states()`].
+    terminal: Option<StatePredicate<State>>,
+    /// `terminal_hooks` are called when the machine reaches a terminal state via `consume`.
+    /// See [`builder::BasicStateMachineBuilder::on_terminal()`].
+    terminal_hooks: Vec<Hook<State>>,
+    /// `terminal_reached` is `true` after the terminal hooks are called
+    /// until the machine leaves the terminal states.
+    terminal_reached: Cell<bool>,
+    /// `clock` is the source of "now" for the time-aware features.
+    /// See [`builder::BasicStateMachineBuilder::clock()`].
+    clock: Arc<dyn Clock + Send + Sync>,
+    /// `entered_at` is the time when the machine entered the current state.
+    /// See [`BasicStateMachine::state_entered_at()`].
+    entered_at: Cell<Instant>,
+    /// `timings` accumulates the durations of transitions by their resulting states.
+    /// It is `None` unless [`builder::BasicStateMachineBuilder::with_timing()`] is called.
+    timings: Option<Timings<State>>,
+    /// `metrics` keeps the longest duration of the transitions for each pair of the states.
+    /// It is `None` unless [`builder::BasicStateMachineBuilder::with_metrics()`] is called.
+    metrics: Option<TransitionMetrics<State>>,
+    /// `slow_hooks` are called when a transition takes longer than their thresholds.
+    /// See [`builder::BasicStateMachineBuilder::on_slow()`].
+    slow_hooks: Vec<(Duration, SlowHook<State>)>,
+    /// `frequencies` counts the transitions by their resulting states.
+    /// It is `None` unless [`builder::BasicStateMachineBuilder::with_state_frequencies()`] is called.
+    frequencies: Option<Frequencies<State>>,
+    /// `visited` holds the states the machine has ever been in.
+    /// It is `None` unless [`builder::BasicStateMachineBuilder::track_visited()`] is called.
+    visited: Option<Visited<State>>,
+    /// `trajectory` holds the running hash of the states the machine has been in.
+    /// It is `None` unless [`builder::BasicStateMachineBuilder::track_trajectory_hash()`] is called.
+    trajectory: Option<Trajectory<State>>,
+    /// `occupancy` weighs the states the machine has been in.
+    /// It is `None` unless [`builder::BasicStateMachineBuilder::track_occupancy()`] is called.
+    occupancy: Option<Occupancy<State>>,
+    /// `tracer` writes a line per transition.
+    /// It is `None` unless [`builder::BasicStateMachineBuilder::trace_to()`] is called.
+    tracer: Option<Tracer<State, Input>>,
+    /// `panic_context` enriches the panics in `transition`.
+    /// It is `None` unless [`builder::BasicStateMachineBuilder::contextual_panics()`] is called.
+    panic_context: Option<PanicContext<State, Input>>,
+    /// `input_parser` parses the raw inputs of [`BasicStateMachine::consume_str()`].
+    /// It is `None` unless [`builder::BasicStateMachineBuilder::parse_input_with()`] is called.
+    input_parser: Option<InputParser<Input>>,
+    /// `generation` is bumped every time the current state is changed.
+    /// See [`BasicStateMachine::consume_at_generation()`].
+    generation: Cell<u64>,
+    /// `telemetry` counts the consumed transitions and keeps the last input for
+    /// [`BasicStateMachine::status()`].
+    telemetry: Telemetry<Input>,
+    /// `epoch` is bumped every time the current state is changed outside the transition.
+    /// See [`BasicStateMachine::epoch()`].
+    epoch: Cell<u64>,
+    /// `stale_snapshot_policy` decides how to restore a stale snapshot.
+    /// See [`builder::BasicStateMachineBuilder::stale_snapshot_policy()`].
+    stale_snapshot_policy: StalePolicy,
+    /// `stale_snapshot_hook` is told of the stale snapshot restored under [`StalePolicy::Warn`].
+    /// See [`builder::BasicStateMachineBuilder::on_stale_snapshot()`].
+    stale_snapshot_hook: Option<StaleSnapshotHook>,
+    /// `watchers` are notified every time the current state is changed.
+    /// See [`BasicStateMachine::watch_for()`].
+    watchers: RefCell<Vec<Weak<Mutex<Watcher<State>>>>>,
+    /// `strict` tracks the operation in progress to detect overlapping operations.
+    /// It is `None` unless [`builder::BasicStateMachineBuilder::strict_borrow()`] is called.
+    strict: Option<ActiveOperation>,
+    /// `dedupe` holds the recently seen deduplication keys.
+    /// It is `None` unless [`builder::BasicStateMachineBuilder::dedupe_keys()`] is called.
+    dedupe: Option<RefCell<DedupeKeys>>,
+    /// `pending` buffers the inputs consumed while the machine is paused.
+    /// It is `None` unless the machine is paused via [`BasicStateMachine::pause()`].
+    pending: RefCell<Option<VecDeque<Input>>>,
+    /// `deferred` buffers the transitions to notify the observers and the tracer of later.
+    /// It is `None` unless [`BasicStateMachine::consume_all_atomic()`] is in progress.
+    deferred: RefCell<Option<Deferred<State>>>,
+    /// `queue` holds the inputs waiting for [`BasicStateMachine::drain_highest()`].
+    /// See [`BasicStateMachine::enqueue()`].
+    queue: RefCell<InputQueue<Input>>,
+    /// `in_transaction` is `true` while a transaction is open.
+    /// See [`BasicStateMachine::begin()`].
+    in_transaction: Cell<bool>,
     _maker: PhantomData<Input>,
 }
 
-impl<State, Input, Transition> StateMachine<State, Input>
-    for BasicStateMachine<State, Input, Transition>
+impl<State, Input, Transition, M> StateMachine<State, Input>
+    for BasicStateMachine<State, Input, Transition, M>
 where
     Transition: Fn(&State, Input) -> State,
     State: Clone,
@@ -226,126 +676,2203 @@ where
         self.current_state.borrow().get()
     }
 
+    /// If the input is rejected by the validator set via
+    /// [`builder::BasicStateMachineBuilder::validate_input()`] or a guard set via
+    /// [`builder::BasicStateMachineBuilder::add_named_guard()`] doesn't hold,
+    /// the state machine retains in its current state.
+    /// Use [`BasicStateMachine::try_consume()`] to know the reason.
     fn consume(&self, input: Input) -> State {
-        let new_state = (self.transition)(&self.current_state.borrow().0, input);
-        self.current_state.borrow_mut().set(new_state);
-        self.current_state()
+        self.try_consume(input)
+            .unwrap_or_else(|_| self.current_state())
+    }
+
+    /// It passes the previous state taken out of the machine instead of a clone of it.
+    /// If the input is rejected, both states are the current state.
+    fn consume_with_prev(&self, input: Input) -> (State, State) {
+        self.try_consume_checked_with_prev(input, |_, _| Ok(()))
+            .unwrap_or_else(|_| {
+                let current_state = self.current_state();
+                (current_state.clone(), current_state)
+            })
+    }
+
+    /// It passes the previous state taken out of the machine instead of a clone of it.
+    /// If the input is rejected, both states are the current state.
+    fn consume_respond<R, F>(&self, input: Input, respond: F) -> R
+    where
+        F: FnOnce(&State, &State) -> R,
+    {
+        match self.try_consume_checked_with_prev(input, |_, _| Ok(())) {
+            Ok((prev, new_state)) => respond(&prev, &new_state),
+            Err(_) => {
+                let current_state = self.current_state();
+                respond(&current_state, &current_state)
+            }
+        }
     }
 
     fn peek(&self, input: Input) -> State {
-        (self.transition)(&self.current_state.borrow().0, input)
+        self.apply(&self.current_state.borrow().0, input)
     }
 
+    /// It clones the initial state twice: one for the current state and one for the return value.
     fn reset(&self) -> State {
+        let _operation = self.enter("reset");
+        self.bump_epoch();
+        if let Some(dedupe) = &self.dedupe {
+            dedupe.borrow_mut().reset();
+        }
+        if let Some(trajectory) = &self.trajectory {
+            trajectory.restart();
+        }
+        self.record(&self.initial_state);
+        if let Some(occupancy) = &self.occupancy {
+            occupancy.reset(&self.initial_state, self.entered_at.get());
+        }
         self.current_state
             .borrow_mut()
             .set(self.initial_state.clone());
-        self.current_state()
+        self.initial_state.clone()
     }
 
     fn set(&self, new_state: State) {
-        self.current_state.borrow_mut().set(new_state)
+        let _operation = self.enter("set");
+        self.bump_epoch();
+        self.record(&new_state);
+        self.current_state.borrow_mut().set(new_state);
     }
 }
 
-#[cfg(test)]
-mod test {
-    use std::{cell::RefCell, marker::PhantomData};
+impl<State, Input, Transition, M> BasicStateMachine<State, Input, Transition, M>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+{
+    /// Transits to the next state like [`StateMachine::consume()`], but returns
+    /// [`StateMachineError::InvalidInput`] if the input is rejected by the validator,
+    /// [`StateMachineError::GuardFailed`] if a guard doesn't hold,
+    /// [`StateMachineError::DwellTooShort`] if the current state was entered too recently and
+    /// [`StateMachineError::ExitVetoed`] if an exit hook vetoes the transition.
+    /// In those cases, the current state and the history are untouched and
+    /// no enter hooks are called.
+    ///
+    /// # Ordering
+    /// The transition is processed in the following order.
+    ///
+    /// 1. The input validator checks the input.
+    /// 2. The guards are evaluated in the order of registration, and then the minimum dwell
+    ///    time set via [`builder::BasicStateMachineBuilder::min_dwell()`] is checked.
+    /// 3. The transition function computes the next state.
+    /// 4. The exit hooks of the current state are called. Each of them can veto the transition.
+    /// 5. The current state is swapped to the next state (and recorded to the history, timed
+    ///    and traced).
+    /// 6. The enter hooks of the next state are called.
+    /// 7. The terminal hooks are called if the next state is terminal.
+    /// 8. The metric sinks and then the observers are called with the previous state and
+    ///    the next state.
+    ///
+    /// The exit hooks receive the borrow of the current state instead of its clone,
+    /// so transiting the state machine from inside them panics.
+    ///
+    /// If none of the validator, the guards, the hooks, the timings and the tracer is set,
+    /// it takes the fast path which borrows the current state mutably only once
+    /// around the transition function. Reading the state machine from inside the
+    /// transition function panics in that case.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|balance, amount| balance + amount)
+    ///     .validate_input(|amount: &i32| {
+    ///         if *amount < 0 {
+    ///             Err("negative amount".to_string())
+    ///         } else {
+    ///             Ok(())
+    ///         }
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(10, sm.try_consume(10).unwrap());
+    /// assert!(sm.try_consume(-5).is_err());
+    /// assert_eq!(10, sm.current_state());
+    /// ```
+    pub fn try_consume(&self, input: Input) -> Result<State, StateMachineError> {
+        self.try_consume_checked(input, |_, _| Ok(()))
+    }
 
-    use super::StateMachine;
-    use super::{BasicStateMachine, StateWrapper};
+    /// Parses `raw` with the parser set via [`builder::BasicStateMachineBuilder::parse_input_with()`]
+    /// and then transits like [`BasicStateMachine::try_consume()`], e.g. on a command typed
+    /// in a console. It returns [`StateMachineError::UnparsableInput`] if `raw` can't be parsed
+    /// or no parser is set, and the errors of `try_consume` if the transition fails.
+    ///
+    /// # Example
+    /// ```
+    /// use std::convert::TryFrom;
+    ///
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder,
+    ///     error::StateMachineError,
+    /// };
+    ///
+    /// enum Command {
+    ///     Up,
+    ///     Down,
+    /// }
+    ///
+    /// impl TryFrom<&str> for Command {
+    ///     type Error = String;
+    ///
+    ///     fn try_from(raw: &str) -> Result<Self, Self::Error> {
+    ///         match raw {
+    ///             "up" => Ok(Command::Up),
+    ///             "down" => Ok(Command::Down),
+    ///             _ => Err(format!("unknown command {}", raw)),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|floor: &i32, command| match command {
+    ///         Command::Up => floor + 1,
+    ///         Command::Down => floor - 1,
+    ///     })
+    ///     .parse_input()
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(1, sm.consume_str("up").unwrap());
+    /// assert!(matches!(
+    ///     sm.consume_str("jump"),
+    ///     Err(StateMachineError::UnparsableInput { .. })
+    /// ));
+    /// ```
+    pub fn consume_str(&self, raw: &str) -> Result<State, StateMachineError> {
+        let parsed = match &self.input_parser {
+            Some(parser) => parser(raw),
+            None => Err(ParseInputError::new("no input parser is set")),
+        };
+        let input = parsed.map_err(|error| StateMachineError::UnparsableInput {
+            raw: raw.to_string(),
+            error,
+        })?;
+        self.try_consume(input)
+    }
 
-    #[derive(Copy, Clone, Debug, PartialEq)]
-    enum Stations {
-        Shibuya,
-        IkejiriOhashi,
-        Sangendyaya,
-        KomazawaDaigaku,
-        Sakurashinmachi,
-        Yoga,
-        FutakoTamagawa,
+    /// Transits like [`BasicStateMachine::try_consume()`], but `check` can reject
+    /// the transition from the current state to the next state right after the transition
+    /// function computes it.
+    fn try_consume_checked<C>(&self, input: Input, check: C) -> Result<State, StateMachineError>
+    where
+        C: FnOnce(&State, &State) -> Result<(), StateMachineError>,
+    {
+        self.try_consume_checked_with_prev(input, check)
+            .map(|(_, new_state)| new_state)
     }
 
-    enum Train {
-        Local,
-        Express,
+    /// Transits like [`BasicStateMachine::try_consume_checked()`] and returns the previous
+    /// state taken out of the machine with the new state, so that the previous state
+    /// isn't cloned. While the machine is paused, both are the current state.
+    fn try_consume_checked_with_prev<C>(
+        &self,
+        input: Input,
+        check: C,
+    ) -> Result<(State, State), StateMachineError>
+    where
+        C: FnOnce(&State, &State) -> Result<(), StateMachineError>,
+    {
+        let _operation = self.enter("consume");
+        if let Some(pending) = self.pending.borrow_mut().as_mut() {
+            pending.push_back(input);
+            let current_state = self.current_state();
+            return Ok((current_state.clone(), current_state));
+        }
+        if self.is_plain() {
+            let mut current_state = self.current_state.borrow_mut();
+            let observed = self.telemetry.observe(&input);
+            let new_state = self.transit(&current_state.0, input);
+            check(&current_state.0, &new_state)?;
+            self.record_transition(&new_state, observed);
+            let prev = current_state.set(new_state.clone());
+            drop(current_state);
+            if !self.defer(&prev, &new_state, || Deferral::Transition(None)) {
+                self.observe(&prev, &new_state);
+            }
+            return Ok((prev, new_state));
+        }
+        if let Some(validator) = &self.input_validator {
+            validator(&input).map_err(|reason| StateMachineError::InvalidInput { reason })?;
+        }
+        if let Some(index) = self.failing_guard(&self.current_state.borrow().0, &input) {
+            return Err(StateMachineError::GuardFailed {
+                name: self.guards[index].0.clone(),
+            });
+        }
+        if let Some(remaining) = self.dwell_remaining() {
+            return Err(StateMachineError::DwellTooShort { remaining });
+        }
+        let trace = self.tracer.as_ref().map(|tracer| {
+            (
+                tracer.describe_state(&self.current_state.borrow().0),
+                tracer.describe_input(&input),
+            )
+        });
+        let observed = self.telemetry.observe(&input);
+        let started_at = self.clock.now();
+        let new_state = self.transit(&self.current_state.borrow().0, input);
+        let elapsed = self.clock.now() - started_at;
+        check(&self.current_state.borrow().0, &new_state)?;
+        for hook in &self.exit_hooks {
+            hook(&self.current_state.borrow().0)
+                .map_err(|reason| StateMachineError::ExitVetoed { reason })?;
+        }
+        let trace = trace.filter(|_| {
+            self.tracer
+                .as_ref()
+                .is_some_and(|tracer| tracer.accepts(&self.current_state.borrow().0, &new_state))
+        });
+        self.record_transition(&new_state, observed);
+        let prev = self.current_state.borrow_mut().set(new_state.clone());
+        self.committed(&prev, &new_state, elapsed, trace);
+        Ok((prev, new_state))
     }
 
-    #[test]
-    fn test_current_state() {
-        let sm = BasicStateMachine {
-            initial_state: Stations::Shibuya,
-            current_state: RefCell::new(StateWrapper::new(Stations::Shibuya)),
-            transition: |station, train| match (station, train) {
-                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
-                _ => unreachable!(),
-            },
-            _maker: PhantomData::<Train>::default(),
-        };
+    /// Returns how long the machine has to stay in the current state yet, or `None` if
+    /// the minimum dwell time has passed or isn't set.
+    fn dwell_remaining(&self) -> Option<Duration> {
+        let min_dwell = self.min_dwell?;
+        let dwelled = self.clock.now() - self.entered_at.get();
+        if dwelled < min_dwell {
+            Some(min_dwell - dwelled)
+        } else {
+            None
+        }
+    }
 
-        assert_eq!(Stations::Shibuya, sm.current_state());
+    /// Measures the transition from `prev` to `next` which has just been swapped in,
+    /// and then writes its trace, calls the enter hooks and notifies the observers unless
+    /// they are deferred. `elapsed` is how long the transition function took.
+    fn committed(
+        &self,
+        prev: &State,
+        next: &State,
+        elapsed: Duration,
+        mut trace: Option<(String, String)>,
+    ) {
+        if let Some(timings) = &self.timings {
+            timings.record(next, elapsed);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.record(prev, next, elapsed);
+        }
+        for (threshold, hook) in &self.slow_hooks {
+            if elapsed > *threshold {
+                hook(prev, next, elapsed);
+            }
+        }
+        if !self.defer(prev, next, || Deferral::Transition(trace.take())) {
+            if let (Some(tracer), Some((from, input))) = (&self.tracer, trace) {
+                tracer.write(&from, &input, next);
+            }
+            self.entered(next);
+            self.observe(prev, next);
+        }
     }
 
-    #[test]
-    fn test_consume() {
-        let sm = BasicStateMachine {
-            initial_state: Stations::Shibuya,
-            current_state: RefCell::new(StateWrapper::new(Stations::Shibuya)),
-            transition: |station, train| match (station, train) {
-                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
-                (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
-                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
-                (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
-                (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
-                (Stations::KomazawaDaigaku, Train::Local) => Stations::Sakurashinmachi,
-                (Stations::Sakurashinmachi, Train::Local) => Stations::Yoga,
-                _ => unreachable!(),
-            },
-            _maker: PhantomData::<Train>::default(),
-        };
+    /// Transits like [`BasicStateMachine::try_consume()`], but returns
+    /// [`StateMachineError::Regression`] with the offending states if the next state is
+    /// earlier than the current state in the order set via
+    /// [`builder::BasicStateMachineBuilder::state_order()`]. The order is checked right after
+    /// the transition function, so the rejected transition isn't timed, hooked nor observed.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder,
+    ///     error::StateMachineError, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state("Draft")
+    ///     .transition(|_: &&str, next: &'static str| next)
+    ///     .state_order(vec!["Draft", "Review", "Published"])
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!("Review", sm.consume_monotonic("Review").unwrap());
+    /// assert!(matches!(
+    ///     sm.consume_monotonic("Draft"),
+    ///     Err(StateMachineError::Regression { .. })
+    /// ));
+    /// assert_eq!("Review", sm.current_state());
+    /// ```
+    pub fn consume_monotonic(&self, input: Input) -> Result<State, StateMachineError>
+    where
+        State: PartialEq + Debug,
+    {
+        self.try_consume_checked(input, |current, next| {
+            let rank = |state: &State| self.state_order.iter().position(|s| s == state);
+            match (rank(current), rank(next)) {
+                (Some(from), Some(to)) if to < from => Err(StateMachineError::Regression {
+                    from: format!("{:?}", current),
+                    to: format!("{:?}", next),
+                }),
+                _ => Ok(()),
+            }
+        })
+    }
 
-        assert_eq!(Stations::IkejiriOhashi, sm.consume(Train::Local));
+    /// Transits like [`BasicStateMachine::try_consume()`], but returns
+    /// [`StateMachineError::PostConditionFailed`] and stays in the current state if
+    /// the next state fails any of the conditions set via
+    /// [`builder::BasicStateMachineBuilder::post_condition()`]. The conditions are checked right
+    /// after the transition function, so the rolled back transition is never committed
+    /// to the history nor notified to the observers.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder,
+    ///     error::StateMachineError, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(10)
+    ///     .transition(|balance: &i32, amount: i32| balance - amount)
+    ///     .post_condition(|balance: &i32| *balance >= 0)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(3, sm.consume_checked_post(7).unwrap());
+    /// assert!(matches!(
+    ///     sm.consume_checked_post(5),
+    ///     Err(StateMachineError::PostConditionFailed)
+    /// ));
+    /// assert_eq!(3, sm.current_state());
+    /// ```
+    pub fn consume_checked_post(&self, input: Input) -> Result<State, StateMachineError> {
+        self.try_consume_checked(input, |_, next| {
+            if self.post_conditions.iter().all(|condition| condition(next)) {
+                Ok(())
+            } else {
+                Err(StateMachineError::PostConditionFailed)
+            }
+        })
     }
 
-    #[test]
-    fn test_peek() {
-        let sm = BasicStateMachine {
-            initial_state: Stations::Sangendyaya,
-            current_state: RefCell::new(StateWrapper::new(Stations::Sangendyaya)),
-            transition: |station, train| match (station, train) {
-                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
-                (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
-                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
-                (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
-                (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
-                (Stations::KomazawaDaigaku, Train::Local) => Stations::Sakurashinmachi,
-                (Stations::Sakurashinmachi, Train::Local) => Stations::Yoga,
-                _ => unreachable!(),
-            },
-            _maker: PhantomData::<Train>::default(),
+    /// Consumes `input` like [`StateMachine::consume()`] and then follows the epsilon
+    /// transition set via [`builder::BasicStateMachineBuilder::epsilon()`] until it no longer
+    /// fires, and returns the stable state. If no epsilon transition is set, it is the same
+    /// as [`StateMachine::consume()`].
+    ///
+    /// The input is consumed like [`BasicStateMachine::try_consume()`], and the epsilon
+    /// transitions are followed only if it is applied. If it is rejected, e.g. by a guard,
+    /// the current state is returned as is. While the machine is paused, the input is
+    /// buffered and no epsilon step is taken, and [`BasicStateMachine::resume()`] later
+    /// consumes it like [`StateMachine::consume()`] without following them.
+    ///
+    /// Each epsilon step is a transition without input: like
+    /// [`transaction::Transaction::commit()`], it is notified to the observers, recorded to
+    /// the history and calls the enter hooks, but no validator, guards nor exit hooks are
+    /// involved. The notifications are deferred in [`BasicStateMachine::consume_all_atomic()`]
+    /// as the ones of the input are. The steps stop before revisiting any state reached
+    /// since the input, so a cycle of epsilon transitions never loops forever.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|state: &u32, input: u32| state + input)
+    ///     // Rounds up to the next multiple of 5 one step at a time.
+    ///     .epsilon(|state: &u32| if state % 5 != 0 { Some(state + 1) } else { None })
+    ///     .with_history()
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(5, sm.consume_closed(3));
+    /// assert_eq!(vec![0, 3, 4, 5], sm.history());
+    /// ```
+    pub fn consume_closed(&self, input: Input) -> State
+    where
+        State: PartialEq,
+    {
+        let mut state = match self.try_consume_checked_with_prev(input, |_, _| Ok(())) {
+            Ok((_, state)) => state,
+            Err(_) => return self.current_state(),
         };
-
-        assert_eq!(Stations::FutakoTamagawa, sm.peek(Train::Express));
-        assert_eq!(Stations::Sangendyaya, sm.current_state());
+        let step = match &self.epsilon {
+            Some(step) if !self.is_paused() => step,
+            _ => return state,
+        };
+        let _operation = self.enter("consume");
+        let mut visited = vec![state.clone()];
+        while let Some(next) = step(&state) {
+            if visited.contains(&next) {
+                break;
+            }
+            self.record_transition(&next, None);
+            self.current_state.borrow_mut().set(next.clone());
+            if !self.defer(&state, &next, || Deferral::Transition(None)) {
+                self.entered(&next);
+                self.observe(&state, &next);
+            }
+            visited.push(next.clone());
+            state = next;
+        }
+        state
     }
 
-    #[test]
-    fn test_reset() {
-        let sm = BasicStateMachine {
-            initial_state: Stations::Shibuya,
-            current_state: RefCell::new(StateWrapper::new(Stations::Sangendyaya)),
-            transition: |station, train| match (station, train) {
-                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
-                (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
-                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
-                (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
-                (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
+    /// Consumes `input` like [`StateMachine::consume()`] unless `key` has been seen recently,
+    /// so that the same logical event delivered twice is applied only once.
+    /// The recently seen keys are kept up to the capacity set via
+    /// [`builder::BasicStateMachineBuilder::dedupe_keys()`] and the oldest one is evicted first.
+    /// They are forgotten on [`StateMachine::reset()`] unless
+    /// [`builder::BasicStateMachineBuilder::keep_dedupe_keys_on_reset()`] is called.
+    /// If the deduplication isn't enabled, every input is applied.
+    ///
+    /// The input is applied like [`BasicStateMachine::try_consume()`] and the key is recorded
+    /// only once it succeeds, so an input rejected by a guard or the validator yields
+    /// [`dedupe::IdempotentOutcome::Rejected`] and can be retried with the same key.
+    /// While the machine is paused the input is queued and the key is recorded.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder,
+    ///     dedupe::IdempotentOutcome,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|state: &i32, input: i32| state + input)
+    ///     .dedupe_keys(16)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(IdempotentOutcome::Applied(5), sm.consume_idempotent(1, 5));
+    /// assert_eq!(IdempotentOutcome::AlreadyApplied(5), sm.consume_idempotent(1, 5));
+    /// ```
+    pub fn consume_idempotent(&self, key: u64, input: Input) -> IdempotentOutcome<State> {
+        if let Some(dedupe) = &self.dedupe {
+            if dedupe.borrow().contains(key) {
+                return IdempotentOutcome::AlreadyApplied(self.current_state());
+            }
+        }
+        match self.try_consume(input) {
+            Ok(state) => {
+                if let Some(dedupe) = &self.dedupe {
+                    dedupe.borrow_mut().insert(key);
+                }
+                IdempotentOutcome::Applied(state)
+            }
+            Err(_) => IdempotentOutcome::Rejected(self.current_state()),
+        }
+    }
+
+    /// Validates `input` with the validator set via
+    /// [`builder::BasicStateMachineBuilder::validate_input()`] and then transits to the next state.
+    /// If the input is rejected, it returns [`StateMachineError::InvalidInput`] without
+    /// calling the transition function, so the state machine retains in its current state.
+    /// It is the same as [`BasicStateMachine::try_consume()`]; see it for the whole ordering.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder,
+    ///     error::StateMachineError, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state("idle")
+    ///     .transition(|state, command: &str| match (*state, command) {
+    ///         ("idle", "start") => "running",
+    ///         ("running", "stop") => "idle",
+    ///         _ => state,
+    ///     })
+    ///     .validate_input(|command: &&str| {
+    ///         if command.is_empty() {
+    ///             Err("empty command".to_string())
+    ///         } else {
+    ///             Ok(())
+    ///         }
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert!(matches!(
+    ///     sm.consume_validated(""),
+    ///     Err(StateMachineError::InvalidInput { .. })
+    /// ));
+    /// assert_eq!("idle", sm.current_state());
+    /// assert_eq!("running", sm.consume_validated("start").unwrap());
+    /// ```
+    pub fn consume_validated(&self, input: Input) -> Result<State, StateMachineError> {
+        self.try_consume(input)
+    }
+
+    /// Evaluates the guards set via [`builder::BasicStateMachineBuilder::add_named_guard()`]
+    /// in order and then transits to the next state. If a guard doesn't hold, it returns
+    /// [`StateMachineError::GuardFailed`] with the name of the first failing guard and
+    /// the rest of the guards aren't evaluated.
+    /// It is the same as [`BasicStateMachine::try_consume()`]; see it for the whole ordering.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder,
+    ///     error::StateMachineError, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|balance, amount| balance - amount)
+    ///     .add_named_guard("positive", |_, amount: &i32| *amount > 0)
+    ///     .add_named_guard("sufficient", |balance: &i32, amount: &i32| balance >= amount)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// match sm.consume_checked(10) {
+    ///     Err(StateMachineError::GuardFailed { name }) => assert_eq!("sufficient", name),
+    ///     _ => unreachable!(),
+    /// }
+    /// assert_eq!(0, sm.current_state());
+    /// ```
+    pub fn consume_checked(&self, input: Input) -> Result<State, StateMachineError> {
+        self.try_consume(input)
+    }
+
+    /// Builds the input lazily by `make` with read access to the current state and
+    /// then transits to the next state like [`StateMachine::consume()`].
+    /// It is useful when the input is expensive to construct: check the current state
+    /// cheaply beforehand (e.g. via [`BasicStateMachine::has_tag()`]) and skip calling
+    /// this method entirely if the machine won't use the input.
+    ///
+    /// Unlike [`crate::machine::table::TableStateMachine::consume_with()`], the machine can't
+    /// tell which inputs the transition function accepts, so `make` is skipped only where
+    /// any input would be discarded: while the minimum dwell time set via
+    /// [`builder::BasicStateMachineBuilder::min_dwell()`] hasn't passed. The state machine
+    /// retains in its current state then. While paused, the input is built to be queued.
+    ///
+    /// # Ordering
+    /// `make` runs after the minimum dwell time is checked and before the input validator
+    /// and the guards, since they take the input.
+    /// The current state is borrowed only while `make` is running, so the validator,
+    /// the guards and the transition see the same state as `make` did.
+    /// See [`BasicStateMachine::try_consume()`] for the rest of the ordering.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(1)
+    ///     .transition(|state, input: Vec<i32>| state + input.len() as i32)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(2, sm.consume_with(|state| vec![0; *state as usize]));
+    /// assert_eq!(4, sm.consume_with(|state| vec![0; *state as usize]));
+    /// ```
+    pub fn consume_with(&self, make: impl FnOnce(&State) -> Input) -> State {
+        if self.pending.borrow().is_none() && self.dwell_remaining().is_some() {
+            return self.current_state();
+        }
+        let input = make(&self.current_state.borrow().0);
+        self.consume(input)
+    }
+
+    /// Returns the future which resolves once the state machine reaches `target`
+    /// via [`StateMachine::consume()`], [`StateMachine::set()`], [`StateMachine::reset()`]
+    /// or [`BasicStateMachine::update()`]. If the current state is already `target`,
+    /// it resolves immediately. It works with any async runtime.
+    ///
+    /// # Example
+    /// ```
+    /// use std::{
+    ///     future::Future,
+    ///     pin::Pin,
+    ///     sync::Arc,
+    ///     task::{Context, Poll, Wake},
+    /// };
+    ///
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// struct NoopWaker;
+    ///
+    /// impl Wake for NoopWaker {
+    ///     fn wake(self: Arc<Self>) {}
+    /// }
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .transition(|state, _: ()| if *state == "Off" { "On" } else { "Off" })
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// let waker = Arc::new(NoopWaker).into();
+    /// let mut cx = Context::from_waker(&waker);
+    /// let mut on = sm.watch_for("On");
+    /// assert_eq!(Poll::Pending, Pin::new(&mut on).poll(&mut cx));
+    /// sm.consume(());
+    /// assert_eq!(Poll::Ready(()), Pin::new(&mut on).poll(&mut cx));
+    /// ```
+    pub fn watch_for(&self, target: State) -> WatchFor<State>
+    where
+        State: PartialEq + Send + 'static,
+    {
+        let reached = self.current_state.borrow().0 == target;
+        let watch = WatchFor::new(move |state| *state == target, reached);
+        if !reached {
+            self.watchers.borrow_mut().push(watch.watcher());
+        }
+        watch
+    }
+
+    /// Returns the snapshot of the initial state and the current state.
+    /// See [`Snapshot`] for how to restore it.
+    pub fn snapshot(&self) -> Snapshot<State> {
+        Snapshot {
+            initial_state: self.initial_state.clone(),
+            current_state: self.current_state(),
+            epoch: self.epoch(),
+        }
+    }
+
+    /// Restores the current state from `snapshot`. The initial state in the snapshot is ignored.
+    ///
+    /// If the state machine has been changed outside the transition (see
+    /// [`BasicStateMachine::epoch()`]) since the snapshot was taken, the snapshot is stale and
+    /// applying it may silently mix the timelines. In that case, it returns
+    /// [`StateMachineError::StaleSnapshot`] without touching anything, or restores it anyway and
+    /// reports the error to the hook set via [`builder::BasicStateMachineBuilder::on_stale_snapshot()`],
+    /// according to [`builder::BasicStateMachineBuilder::stale_snapshot_policy()`].
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|state: &i32, input: i32| state + input)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// let snapshot = sm.snapshot();
+    /// sm.consume(1);
+    /// assert!(sm.restore(snapshot.clone()).is_ok());
+    /// assert_eq!(0, sm.current_state());
+    /// // the snapshot predates the restore
+    /// assert!(sm.restore(snapshot).is_err());
+    /// ```
+    pub fn restore(&self, snapshot: Snapshot<State>) -> Result<(), StateMachineError> {
+        let actual = self.epoch();
+        if snapshot.epoch < actual {
+            let error = StateMachineError::StaleSnapshot {
+                snapshot: snapshot.epoch,
+                actual,
+            };
+            match self.stale_snapshot_policy {
+                StalePolicy::Reject => return Err(error),
+                StalePolicy::Warn => {
+                    if let Some(hook) = &self.stale_snapshot_hook {
+                        hook(&error);
+                    }
+                }
+            }
+        }
+        let _operation = self.enter("restore");
+        self.bump_epoch();
+        self.record(&snapshot.current_state);
+        self.current_state.borrow_mut().set(snapshot.current_state);
+        Ok(())
+    }
+
+    /// Returns the time when the machine entered the current state according to the clock
+    /// set via [`builder::BasicStateMachineBuilder::clock()`], e.g. to show a timeout.
+    /// It is stamped on building and every time the current state is changed, including
+    /// [`StateMachine::set()`], [`StateMachine::reset()`] and [`BasicStateMachine::update()`].
+    ///
+    /// # Example
+    /// ```
+    /// use std::{sync::Arc, time::Duration};
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder,
+    ///     time::{Clock, ManualClock}, StateMachine,
+    /// };
+    ///
+    /// let clock = Arc::new(ManualClock::new());
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|state: &i32, input: i32| state + input)
+    ///     .clock(Arc::clone(&clock))
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// clock.advance(Duration::from_secs(5));
+    /// assert_eq!(Duration::from_secs(5), clock.now() - sm.state_entered_at());
+    /// sm.consume(1);
+    /// assert_eq!(clock.now(), sm.state_entered_at());
+    /// ```
+    pub fn state_entered_at(&self) -> Instant {
+        self.entered_at.get()
+    }
+
+    /// Returns the current state, the last input, the number of the consumed transitions,
+    /// whether the last transition panicked and the time elapsed since the machine entered
+    /// the current state at once.
+    ///
+    /// The last input is reported only if
+    /// [`builder::BasicStateMachineBuilder::track_last_input()`] is called, and the time in
+    /// the state only if [`builder::BasicStateMachineBuilder::track_time_in_state()`] is called.
+    /// The time is measured by the clock set via [`builder::BasicStateMachineBuilder::clock()`].
+    ///
+    /// # Example
+    /// ```
+    /// use std::{sync::Arc, time::Duration};
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, time::ManualClock,
+    ///     StateMachine,
+    /// };
+    ///
+    /// let clock = Arc::new(ManualClock::new());
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|count: &u32, step: u32| count + step)
+    ///     .clock(Arc::clone(&clock))
+    ///     .track_last_input()
+    ///     .track_time_in_state()
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// sm.consume(2);
+    /// sm.set(5);
+    /// clock.advance(Duration::from_secs(3));
+    /// let status = sm.status();
+    /// assert_eq!(5, status.state);
+    /// assert_eq!(Some(2), status.last_input);
+    /// assert_eq!(1, status.transition_count);
+    /// assert!(!status.is_faulted);
+    /// assert_eq!(Some(Duration::from_secs(3)), status.time_in_state);
+    /// ```
+    pub fn status(&self) -> MachineStatus<State, Input> {
+        let current = self.current_state.borrow();
+        MachineStatus {
+            state: current.0.clone(),
+            last_input: self.telemetry.last_input(),
+            transition_count: self.telemetry.transitions(),
+            is_faulted: self.telemetry.is_faulted(),
+            time_in_state: if self.telemetry.is_timed() {
+                Some(self.clock.now() - self.entered_at.get())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Returns the epoch of the state machine. It starts from `0` and is bumped every time
+    /// the current state is changed outside the transition, i.e. via [`StateMachine::set()`],
+    /// [`StateMachine::reset()`], [`BasicStateMachine::update()`] or
+    /// [`BasicStateMachine::restore()`]. Unlike [`BasicStateMachine::generation()`],
+    /// consuming inputs doesn't bump it, so it detects the snapshots and the iterators
+    /// which outlive such an external change.
+    pub fn epoch(&self) -> u64 {
+        self.epoch.get()
+    }
+
+    /// Returns an iterator which consumes `inputs` one by one via
+    /// [`BasicStateMachine::try_consume()`] and yields the results.
+    /// If the state machine is changed outside the transition while the iterator is alive
+    /// (see [`BasicStateMachine::epoch()`]), it yields [`StateMachineError::StaleIterator`]
+    /// once instead of consuming the next input, and ends.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|state: &i32, input: i32| state + input)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// let mut drive = sm.drive(vec![1, 2, 3]);
+    /// assert_eq!(1, drive.next().unwrap().unwrap());
+    /// sm.reset();
+    /// assert!(drive.next().unwrap().is_err());
+    /// assert!(drive.next().is_none());
+    /// ```
+    pub fn drive<I>(&self, inputs: I) -> Drive<'_, State, Input, Transition, I::IntoIter, M>
+    where
+        I: IntoIterator<Item = Input>,
+    {
+        Drive::new(self, inputs.into_iter())
+    }
+
+    /// Returns the generation of the state machine. It starts from `0` and is bumped
+    /// every time the current state is changed via [`StateMachine::consume()`],
+    /// [`StateMachine::set()`], [`StateMachine::reset()`] or [`BasicStateMachine::update()`].
+    /// Rejected transitions don't bump it.
+    pub fn generation(&self) -> u64 {
+        self.generation.get()
+    }
+
+    /// Transits to the next state like [`BasicStateMachine::try_consume()`] only if
+    /// the generation of the state machine is still `expected`, i.e. nobody has changed
+    /// the current state since the caller observed it. Returns the new state and
+    /// the new generation, or [`StateMachineError::StaleGeneration`] otherwise.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder,
+    ///     error::StateMachineError, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|state, input| state + input)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// let observed = sm.generation();
+    /// sm.consume(1);
+    /// assert!(matches!(
+    ///     sm.consume_at_generation(10, observed),
+    ///     Err(StateMachineError::StaleGeneration { .. })
+    /// ));
+    /// assert_eq!((11, 2), sm.consume_at_generation(10, sm.generation()).unwrap());
+    /// ```
+    pub fn consume_at_generation(
+        &self,
+        input: Input,
+        expected: u64,
+    ) -> Result<(State, u64), StateMachineError> {
+        let actual = self.generation();
+        if actual != expected {
+            return Err(StateMachineError::StaleGeneration { expected, actual });
+        }
+        let new_state = self.try_consume(input)?;
+        Ok((new_state, self.generation()))
+    }
+
+    /// Returns the guarded reference to the current state. Unlike
+    /// [`StateMachine::current_state()`], it doesn't clone the state, so it is
+    /// useful to inspect a large state.
+    ///
+    /// Note that transiting the state machine while the returned [`StateRef`] is alive
+    /// panics. See [`StateRef`] for details.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// #[derive(Clone)]
+    /// struct Document {
+    ///     body: Vec<u8>,
+    ///     revision: u32,
+    /// }
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(Document { body: vec![0; 1024], revision: 0 })
+    ///     .transition(|doc: &Document, body: Vec<u8>| Document {
+    ///         body,
+    ///         revision: doc.revision + 1,
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// {
+    ///     let doc = sm.current_state_ref();
+    ///     assert_eq!(0, doc.revision);
+    /// }
+    /// sm.consume(vec![1; 1024]);
+    /// assert_eq!(1, sm.current_state_ref().revision);
+    /// ```
+    pub fn current_state_ref(&self) -> StateRef<'_, State> {
+        StateRef::new(Ref::map(self.current_state.borrow(), |wrapper| &wrapper.0))
+    }
+
+    /// Replaces the transition function with `transition` at runtime.
+    /// Subsequent transitions use the new one. It is mainly useful for the machine whose
+    /// transition is boxed (see [`BoxedTransition`]) since each closure has its own type.
+    ///
+    /// Note that calling this method from inside the transition function itself
+    /// (e.g. via a reference to the machine captured by the closure) panics,
+    /// because the transition function is borrowed while it is running.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, BoxedTransition,
+    ///     StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(Box::new(|state: &i32, input: i32| state + input) as BoxedTransition<i32, i32>)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(1, sm.consume(1));
+    /// sm.set_transition(Box::new(|state, input| state * input));
+    /// assert_eq!(3, sm.consume(3));
+    /// ```
+    pub fn set_transition(&self, transition: Transition) {
+        *self.transition.borrow_mut() = transition;
+    }
+
+    /// Mutates the current state in place via `f` without cloning it.
+    /// Like [`StateMachine::set()`], it bypasses the transition function, the validator and
+    /// the hooks. The updated state is recorded to the history if it is enabled.
+    /// The observers registered via [`BasicStateMachine::add_observer_with_priority()`] are
+    /// called with the states before and after `f`, so the state is cloned once before `f`
+    /// if any observer is registered. Inside [`BasicStateMachine::consume_all_atomic()`]
+    /// the notification is deferred like the transitions, so it is dropped if the batch
+    /// is rolled back.
+    ///
+    /// Unlike `update`, [`StateMachine::set()`] and [`StateMachine::reset()`] don't notify
+    /// the observers: they are the way to roll back or restore the machine by hand
+    /// (see [`StateMachine::consume_with_prev()`]), which the observers shouldn't see as
+    /// a change of the state. `update` changes the data held by the state instead, which
+    /// the observers can't tell otherwise.
+    ///
+    /// Note that accessing the state machine from inside `f`
+    /// (e.g. via a reference to the machine captured by the closure) panics,
+    /// because the current state is mutably borrowed while `f` is running.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// #[derive(Clone, Debug, PartialEq)]
+    /// struct Job {
+    ///     running: bool,
+    ///     retries: u32,
+    /// }
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(Job { running: false, retries: 0 })
+    ///     .transition(|job: &Job, run: bool| Job { running: run, ..job.clone() })
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// sm.update(|job| job.retries += 1);
+    /// assert_eq!(Job { running: false, retries: 1 }, sm.current_state());
+    /// ```
+    pub fn update(&self, f: impl FnOnce(&mut State)) {
+        let operation = self.enter("update");
+        self.bump_epoch();
+        // The deferred update is also replayed to the watchers, so it is buffered
+        // even without any observer.
+        let prev = if self.observers.len() > 0 || self.deferred.borrow().is_some() {
+            Some(self.current_state())
+        } else {
+            None
+        };
+        f(&mut self.current_state.borrow_mut().0);
+        self.record(&self.current_state.borrow().0);
+        drop(operation);
+        if let Some(prev) = prev {
+            let next = self.current_state();
+            if !self.defer(&prev, &next, || Deferral::Update) {
+                self.observers.notify(&prev, &next);
+            }
+        }
+    }
+
+    /// Resets the current state to the initial state like [`StateMachine::reset()`], but returns
+    /// [`StateMachineError::ResetForbidden`] without touching anything if the current state is
+    /// protected via [`builder::BasicStateMachineBuilder::no_reset_from()`].
+    /// Note that [`StateMachine::reset()`] itself ignores the protection.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state("Cart")
+    ///     .transition(|state: &&str, input: &str| match (*state, input) {
+    ///         ("Cart", "checkout") => "Paying",
+    ///         ("Paying", "paid") => "Done",
+    ///         _ => unreachable!(),
+    ///     })
+    ///     .no_reset_from(vec!["Paying"])
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// sm.consume("checkout");
+    /// assert!(sm.try_reset().is_err());
+    /// sm.consume("paid");
+    /// assert_eq!("Cart", sm.try_reset().unwrap());
+    /// ```
+    pub fn try_reset(&self) -> Result<State, StateMachineError> {
+        let forbidden = {
+            let current_state = &self.current_state.borrow().0;
+            self.reset_forbidden
+                .iter()
+                .any(|forbidden| forbidden(current_state))
+        };
+        if forbidden {
+            return Err(StateMachineError::ResetForbidden);
+        }
+        Ok(self.reset())
+    }
+
+    /// Pauses the state machine. While it is paused, the inputs given to
+    /// [`StateMachine::consume()`] and [`BasicStateMachine::try_consume()`] are buffered
+    /// instead of being applied, and the current state is returned as is.
+    /// The buffered inputs are applied in order on [`BasicStateMachine::resume()`].
+    /// Pausing a paused machine does nothing.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|state: &i32, input: i32| state + input)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// sm.pause();
+    /// assert_eq!(0, sm.consume(1));
+    /// assert_eq!(0, sm.consume(2));
+    /// assert_eq!(vec![1, 2], sm.pending_inputs());
+    /// assert_eq!(3, sm.resume());
+    /// ```
+    pub fn pause(&self) {
+        let mut pending = self.pending.borrow_mut();
+        if pending.is_none() {
+            *pending = Some(VecDeque::new());
+        }
+    }
+
+    /// Resumes the state machine paused via [`BasicStateMachine::pause()`] and consumes
+    /// the buffered inputs in order, returning the resulting state.
+    /// It does nothing but return the current state if the machine isn't paused.
+    pub fn resume(&self) -> State {
+        let pending = self.pending.borrow_mut().take();
+        for input in pending.into_iter().flatten() {
+            self.consume(input);
+        }
+        self.current_state()
+    }
+
+    /// Returns `true` if the state machine is paused via [`BasicStateMachine::pause()`].
+    pub fn is_paused(&self) -> bool {
+        self.pending.borrow().is_some()
+    }
+
+    /// Returns the inputs buffered while the state machine is paused, from the oldest.
+    /// It returns an empty `Vec` if the machine isn't paused.
+    pub fn pending_inputs(&self) -> Vec<Input>
+    where
+        Input: Clone,
+    {
+        self.pending.borrow().iter().flatten().cloned().collect()
+    }
+
+    /// Discards the inputs buffered while the state machine is paused without applying them.
+    /// The machine stays paused.
+    pub fn clear_pending(&self) {
+        if let Some(pending) = self.pending.borrow_mut().as_mut() {
+            pending.clear();
+        }
+    }
+
+    /// Registers `observer` which is called with the previous and the next states on every
+    /// transition via [`StateMachine::consume()`], once the state is swapped and the enter
+    /// hooks have run, right after the metric sinks set via
+    /// [`builder::BasicStateMachineBuilder::metric_sink()`]. The observers are called by ascending
+    /// `priority` and then by registration order. They are also called on
+    /// [`BasicStateMachine::update()`], but not on [`StateMachine::set()`] nor
+    /// [`StateMachine::reset()`].
+    ///
+    /// The returned handle detaches the observer via [`BasicStateMachine::remove_observer()`]
+    /// or [`ObserverHandle::detach()`]. Detaching an observer during a notification pass,
+    /// e.g. from inside another observer, takes effect from the next pass.
+    ///
+    /// # Example
+    /// ```
+    /// use std::sync::{Arc, Mutex};
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|state: &i32, input: i32| state + input)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// let log = Arc::new(Mutex::new(Vec::new()));
+    /// let late = Arc::clone(&log);
+    /// sm.add_observer_with_priority(10, move |_, next: &i32| late.lock().unwrap().push(("late", *next)));
+    /// let early = Arc::clone(&log);
+    /// let handle = sm.add_observer_with_priority(-10, move |_, next: &i32| {
+    ///     early.lock().unwrap().push(("early", *next))
+    /// });
+    ///
+    /// sm.consume(1);
+    /// assert!(sm.remove_observer(&handle));
+    /// sm.consume(1);
+    /// assert_eq!(vec![("early", 1), ("late", 1), ("late", 2)], *log.lock().unwrap());
+    /// assert_eq!(1, sm.observer_count());
+    /// ```
+    pub fn add_observer_with_priority<F>(&self, priority: i32, observer: F) -> ObserverHandle
+    where
+        F: Fn(&State, &State) + Send + Sync + 'static,
+    {
+        self.observers.add(priority, Arc::new(observer))
+    }
+
+    /// Detaches the observer registered via [`BasicStateMachine::add_observer_with_priority()`].
+    /// It returns `false` if the observer is already detached.
+    pub fn remove_observer(&self, handle: &ObserverHandle) -> bool {
+        handle.detach()
+    }
+
+    /// Returns the number of the observers registered via
+    /// [`BasicStateMachine::add_observer_with_priority()`] and not detached yet.
+    pub fn observer_count(&self) -> usize {
+        self.observers.len()
+    }
+
+    /// Registers the observers of `other`, e.g. the machine this one is forked from,
+    /// in this state machine with the same priorities, including the collecting observers.
+    /// The observers are shared via `Arc`, not deep-copied: both machines call the same
+    /// closure, so whatever it captures, e.g. a counter, is shared rather than duplicated.
+    /// The observers already detached from `other` are skipped.
+    ///
+    /// It returns the handles of the copies, which are registered on their own:
+    /// detaching a copy leaves the original and vice versa.
+    pub fn copy_observers_from(&self, other: &Self) -> Vec<ObserverHandle> {
+        self.observers.extend_from(&other.observers)
+    }
+
+    /// Registers `observer` which returns a value computed from the previous and the next
+    /// states, e.g. a derived notification. It is ordered and detached in the same way as
+    /// [`BasicStateMachine::add_observer_with_priority()`], and its outputs are returned by
+    /// [`BasicStateMachine::consume_collecting()`] or dropped otherwise.
+    pub fn add_collecting_observer<T, F>(&self, priority: i32, observer: F) -> ObserverHandle
+    where
+        F: Fn(&State, &State) -> T + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        self.observers.add_collector(
+            priority,
+            Arc::new(move |prev: &State, next: &State| {
+                Box::new(observer(prev, next)) as Box<dyn Any + Send>
+            }),
+        )
+    }
+
+    /// Consumes `input` like [`StateMachine::consume()`] and returns the new state with
+    /// the outputs of the observers registered via
+    /// [`BasicStateMachine::add_collecting_observer()`] in the notification order.
+    /// The outputs of other types than `T` are skipped.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|state: &i32, input: i32| state + input)
+    ///     .build()
+    ///     .unwrap();
+    /// sm.add_collecting_observer(0, |_, next: &i32| next * 10);
+    ///
+    /// assert_eq!((2, vec![20]), sm.consume_collecting::<i32>(2));
+    /// ```
+    pub fn consume_collecting<T: 'static>(&self, input: Input) -> (State, Vec<T>) {
+        let (state, outputs) = self.observers.collect(|| self.consume(input));
+        let outputs = outputs
+            .into_iter()
+            .filter_map(|output| output.downcast().ok())
+            .map(|output| *output)
+            .collect();
+        (state, outputs)
+    }
+
+    /// Begins a transaction. The inputs consumed through the returned [`Transaction`] are
+    /// applied to a shadow copy of the state, and the state machine keeps the state at this
+    /// point until [`Transaction::commit()`] is called. Dropping the transaction or calling
+    /// [`Transaction::rollback()`] discards the shadow state.
+    ///
+    /// Only one transaction can be open at a time, so it returns
+    /// [`StateMachineError::TransactionInProgress`] while another one is open.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|state: &i32, input: i32| state + input)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// let mut transaction = sm.begin().unwrap();
+    /// transaction.consume(1).unwrap();
+    /// transaction.consume(2).unwrap();
+    /// assert_eq!(0, sm.current_state());
+    /// assert!(sm.begin().is_err());
+    /// assert_eq!(3, transaction.commit().unwrap());
+    /// assert_eq!(3, sm.current_state());
+    /// ```
+    pub fn begin(&self) -> Result<Transaction<'_, State, Input, Transition, M>, StateMachineError> {
+        if self.in_transaction.replace(true) {
+            return Err(StateMachineError::TransactionInProgress);
+        }
+        Ok(Transaction::new(self))
+    }
+
+    /// Queues `input` with `priority` for [`BasicStateMachine::drain_highest()`]
+    /// without consuming it. The higher priority is drained first and the inputs
+    /// with the same priority are drained in the order of enqueuing.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(String::new())
+    ///     .transition(|log: &String, event: &str| format!("{}{}", log, event))
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// sm.enqueue("a", 1);
+    /// sm.enqueue("b", 3);
+    /// sm.enqueue("c", 2);
+    /// assert_eq!(3, sm.queued_len());
+    /// assert_eq!(Some("b".to_string()), sm.drain_highest());
+    /// assert_eq!(Some("bc".to_string()), sm.drain_highest());
+    /// assert_eq!(Some("bca".to_string()), sm.drain_highest());
+    /// assert_eq!(None, sm.drain_highest());
+    /// ```
+    pub fn enqueue(&self, input: Input, priority: u32) {
+        self.queue.borrow_mut().push(input, priority);
+    }
+
+    /// Pops the input with the highest priority queued via [`BasicStateMachine::enqueue()`]
+    /// and consumes it like [`StateMachine::consume()`], returning the new state.
+    /// It returns `None` if the queue is empty. Calling it in a loop models the ticks of
+    /// an event loop.
+    pub fn drain_highest(&self) -> Option<State> {
+        let input = self.queue.borrow_mut().pop()?;
+        Some(self.consume(input))
+    }
+
+    /// Returns the number of the inputs queued via [`BasicStateMachine::enqueue()`].
+    pub fn queued_len(&self) -> usize {
+        self.queue.borrow().len()
+    }
+
+    /// Returns the states the machine has been in, from the oldest to the newest.
+    /// The first entry is the state at the time the machine was built.
+    /// It returns an empty `Vec` unless the history is enabled via
+    /// [`builder::BasicStateMachineBuilder::with_history()`].
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(1)
+    ///     .transition(|state, input| match (state, input) {
+    ///         (1, "next") => 2,
+    ///         (2, "next") => 3,
+    ///         _ => unreachable!(),
+    ///     })
+    ///     .with_history()
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// sm.consume("next");
+    /// sm.consume("next");
+    /// assert_eq!(vec![1, 2, 3], sm.history());
+    /// ```
+    pub fn history(&self) -> Vec<State> {
+        self.history_iter().collect()
+    }
+
+    /// Splits the history at the first occurrence of `state` into the states before it and
+    /// the states from it, e.g. to analyze what happened before and after an incident.
+    /// It returns `None` if `state` never appears in the history.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state("Idle")
+    ///     .transition(|_, input: &'static str| input)
+    ///     .with_history()
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// sm.consume("Running");
+    /// sm.consume("Failed");
+    /// sm.consume("Idle");
+    /// assert_eq!(
+    ///     Some((vec!["Idle", "Running"], vec!["Failed", "Idle"])),
+    ///     sm.split_history_at(&"Failed")
+    /// );
+    /// assert_eq!(None, sm.split_history_at(&"Stopped"));
+    /// ```
+    pub fn split_history_at(&self, state: &State) -> Option<(Vec<State>, Vec<State>)>
+    where
+        State: PartialEq,
+    {
+        let mut before = self.history();
+        let index = before.iter().position(|s| s == state)?;
+        let from = before.split_off(index);
+        Some((before, from))
+    }
+
+    /// Consumes `inputs` one by one via [`StateMachine::consume()`], keeping every
+    /// state in the history regardless of [`builder::BasicStateMachineBuilder::history_limit()`]
+    /// during the batch, e.g. to debug a burst of inputs. The limit is restored afterward
+    /// and the history is trimmed back to it, dropping the oldest states,
+    /// even if the transition panics in the middle of the batch.
+    ///
+    /// Note that the history grows by one state per input during the batch, so the memory
+    /// held by the history is proportional to the length of `inputs` until the batch ends.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|state: &i32, input: i32| state + input)
+    ///     .history_limit(2)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(6, sm.consume_all_unlimited(vec![1, 2, 3]));
+    /// assert_eq!(vec![3, 6], sm.history());
+    /// ```
+    pub fn consume_all_unlimited(&self, inputs: impl IntoIterator<Item = Input>) -> State {
+        let _unlimited = UnlimitedHistory::lift(self.history.as_ref());
+        for input in inputs {
+            self.consume(input);
+        }
+        self.current_state()
+    }
+
+    /// Consumes `inputs` one by one via [`BasicStateMachine::try_consume()`] as a whole.
+    /// If any input fails or the batch panics, everything is restored to the time before
+    /// the batch: the current state, the history, the generation, the epoch, the entry time,
+    /// the timings, the frequencies, the visited states and the terminal flag, and the guard
+    /// cache is cleared. The returned [`AtomicBatchError`] tells the index of the failing
+    /// input and the inputs after it, which are never consumed.
+    ///
+    /// The tracer, the watchers, the enter hooks, the metric sinks and the observers are
+    /// buffered during the batch and notified of every transition in order only if the whole
+    /// batch succeeds, so they never observe the rolled back transitions.
+    /// Note that the exit hooks, which can veto the transition, are called as the batch goes.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|balance: &i32, amount: i32| balance + amount)
+    ///     .add_named_guard("non-negative", |balance: &i32, amount: &i32| balance + amount >= 0)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(5, sm.consume_all_atomic(vec![10, -5]).unwrap());
+    ///
+    /// let err = sm.consume_all_atomic(vec![5, -20, 3]).unwrap_err();
+    /// assert_eq!(1, err.index);
+    /// assert_eq!(vec![3], err.remaining);
+    /// assert_eq!(5, sm.current_state());
+    /// ```
+    pub fn consume_all_atomic(
+        &self,
+        inputs: impl IntoIterator<Item = Input>,
+    ) -> Result<State, AtomicBatchError<State, Input>> {
+        let batch = AtomicBatch::begin(self);
+        let mut inputs = inputs.into_iter().enumerate();
+        let failure = inputs
+            .find_map(|(index, input)| self.try_consume(input).err().map(|error| (index, error)));
+
+        if let Some((index, error)) = failure {
+            drop(batch);
+            return Err(AtomicBatchError {
+                index,
+                error,
+                remaining: inputs.map(|(_, input)| input).collect(),
+                state: self.current_state(),
+            });
+        }
+
+        for (prev, next, deferral) in batch.commit() {
+            watch::notify(&mut self.watchers.borrow_mut(), &next);
+            match deferral {
+                Deferral::Transition(trace) => {
+                    if let (Some(tracer), Some((from, input))) = (&self.tracer, trace) {
+                        tracer.write(&from, &input, &next);
+                    }
+                    self.entered(&next);
+                    self.observe(&prev, &next);
+                }
+                Deferral::Update => self.observers.notify(&prev, &next),
+            }
+        }
+        Ok(self.current_state())
+    }
+
+    /// Simulates consuming `inputs` one by one from the current state and returns every
+    /// intermediate state, without changing the current state. Nothing observable is
+    /// touched: the history, the generation, the timings and the frequencies aren't updated
+    /// and no hooks are called.
+    /// The simulation stops at the first input rejected by the validator or a guard,
+    /// so the returned `Vec` is shorter than `inputs` in that case.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|state, input| state + input)
+    ///     .add_named_guard("small", |_, input: &i32| *input < 10)
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(vec![1, 3, 6], sm.peek_chain(vec![1, 2, 3]));
+    /// assert_eq!(vec![1], sm.peek_chain(vec![1, 20, 3]));
+    /// assert_eq!(0, sm.current_state());
+    /// ```
+    pub fn peek_chain(&self, inputs: impl IntoIterator<Item = Input>) -> Vec<State> {
+        let mut states: Vec<State> = Vec::new();
+        for input in inputs {
+            let next = {
+                let current = self.current_state.borrow();
+                let current_state = states.last().unwrap_or(&current.0);
+                if let Some(validator) = &self.input_validator {
+                    if validator(&input).is_err() {
+                        break;
+                    }
+                }
+                if !self
+                    .guards
+                    .iter()
+                    .all(|(_, guard)| guard(current_state, &input))
+                {
+                    break;
+                }
+                self.apply(current_state, input)
+            };
+            states.push(next);
+        }
+        states
+    }
+
+    /// Returns whether `input` is valid and the state it leads to without
+    /// changing the current state. Since the transition is a total function,
+    /// it always returns [`PeekResult::Valid`].
+    pub fn peek_edge(&self, input: &Input) -> PeekResult<State>
+    where
+        Input: Clone,
+    {
+        PeekResult::Valid(self.peek(input.clone()))
+    }
+
+    /// Returns an iterator over the history without cloning the whole buffer.
+    /// See [`HistoryIter`] for the caveat about borrowing.
+    pub fn history_iter(&self) -> HistoryIter<'_, State> {
+        HistoryIter::new(self.history.as_ref().map(|history| history.borrow()))
+    }
+
+    /// Coalesces the whole history into the current state, i.e. the history is replaced with
+    /// the current state alone and its buffer is released, so that long-running workflows can
+    /// keep the memory bounded. The current state and the initial state are kept as they are.
+    /// It does nothing unless the history is enabled.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|state: &i32, input: i32| state + input)
+    ///     .with_history()
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// sm.consume(1);
+    /// sm.consume(2);
+    /// sm.coalesce();
+    /// assert_eq!(vec![3], sm.history());
+    /// assert_eq!(0, sm.reset());
+    /// ```
+    pub fn coalesce(&self) {
+        if let Some(history) = &self.history {
+            history.borrow_mut().reset_to(self.current_state());
+        }
+    }
+
+    /// Returns the cumulative durations of the transition function bucketed by
+    /// the resulting states. It surfaces which states are expensive to compute.
+    /// It returns an empty map unless the timing is enabled via
+    /// [`builder::BasicStateMachineBuilder::with_timing()`].
+    pub fn timing_by_state(&self) -> HashMap<State, Duration> {
+        self.timings
+            .as_ref()
+            .map(|timings| timings.get())
+            .unwrap_or_default()
+    }
+
+    /// Returns up to `n` pairs of the previous and the next states whose transitions took
+    /// the longest, from the slowest, with the longest duration of each pair.
+    /// The duration is measured around the transition function by the clock set via
+    /// [`builder::BasicStateMachineBuilder::clock()`], so the peeks aren't included.
+    /// It returns an empty `Vec` unless the metrics are enabled via
+    /// [`builder::BasicStateMachineBuilder::with_metrics()`].
+    pub fn slowest_transitions(&self, n: usize) -> Vec<((State, State), Duration)> {
+        let mut slowest: Vec<_> = self
+            .metrics
+            .as_ref()
+            .map(|metrics| metrics.get())
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        slowest.sort_by(|(_, a), (_, b)| b.cmp(a));
+        slowest.truncate(n);
+        slowest
+    }
+
+    /// Returns how many transitions via [`StateMachine::consume()`] resulted in each state.
+    /// The transitions rejected by the validator or vetoed by the exit hooks, and
+    /// the states set via [`StateMachine::set()`] or [`StateMachine::reset()`] aren't counted.
+    /// It returns an empty map unless the counting is enabled via
+    /// [`builder::BasicStateMachineBuilder::with_state_frequencies()`].
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(false)
+    ///     .transition(|state: &bool, _: ()| !state)
+    ///     .with_state_frequencies()
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// sm.consume(());
+    /// sm.consume(());
+    /// sm.consume(());
+    /// let frequencies = sm.state_frequencies();
+    /// assert_eq!(2, frequencies[&true]);
+    /// assert_eq!(1, frequencies[&false]);
+    /// ```
+    pub fn state_frequencies(&self) -> HashMap<State, usize> {
+        self.frequencies
+            .as_ref()
+            .map(|frequencies| frequencies.get())
+            .unwrap_or_default()
+    }
+
+    /// Returns the fraction of the time spent in, or of the transitions resulting in,
+    /// each state according to the basis given to
+    /// [`builder::BasicStateMachineBuilder::track_occupancy()`], in the descending order.
+    /// The fractions sum up to `1.0`. Unlike [`BasicStateMachine::state_frequencies()`],
+    /// the states set via [`StateMachine::set()`] are also counted. The states of the same
+    /// fraction are in the order in which the machine first occupied them.
+    /// With [`time::OccupancyBasis::Visits`], the initial state isn't counted until
+    /// the machine returns to it.
+    /// It returns an empty `Vec` if nothing is weighed yet, e.g. no transition happened or
+    /// no time passed, or unless the tracking is enabled.
+    ///
+    /// # Example
+    /// ```
+    /// use std::{sync::Arc, time::Duration};
+    ///
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder,
+    ///     time::{Clock, ManualClock, OccupancyBasis},
+    ///     StateMachine,
+    /// };
+    ///
+    /// let clock = Arc::new(ManualClock::new());
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(false)
+    ///     .transition(|state: &bool, _: ()| !state)
+    ///     .clock(Arc::clone(&clock))
+    ///     .track_occupancy(OccupancyBasis::DwellTime)
+    ///     .build()
+    ///     .unwrap();
+    /// assert!(sm.occupancy().is_empty());
+    ///
+    /// clock.advance(Duration::from_secs(1));
+    /// sm.consume(());
+    /// clock.advance(Duration::from_secs(3));
+    /// assert_eq!(vec![(true, 0.75), (false, 0.25)], sm.occupancy());
+    /// ```
+    pub fn occupancy(&self) -> Vec<(State, f64)> {
+        self.occupancy
+            .as_ref()
+            .map(|occupancy| occupancy.get(self.clock.now()))
+            .unwrap_or_default()
+    }
+
+    /// Returns at most `n` states occupying the machine most, in the descending order.
+    /// See [`BasicStateMachine::occupancy()`].
+    pub fn most_visited(&self, n: usize) -> Vec<State> {
+        self.occupancy()
+            .into_iter()
+            .take(n)
+            .map(|(state, _)| state)
+            .collect()
+    }
+
+    /// Returns the states the machine has ever been in, including the current state
+    /// at the time it was built and the states set via [`StateMachine::set()`].
+    /// It returns an empty set unless the tracking is enabled via
+    /// [`builder::BasicStateMachineBuilder::track_visited()`].
+    pub fn visited_states(&self) -> HashSet<State> {
+        self.visited
+            .as_ref()
+            .map(|visited| visited.get())
+            .unwrap_or_default()
+    }
+
+    /// Returns the running hash of the states the machine has been in, in order,
+    /// starting from the current state at the time it was built. Every change of the
+    /// current state folds the new state into it, and [`StateMachine::reset()`] starts it over.
+    /// So two runs consuming the same inputs from the same state produce the same hash.
+    /// It returns `0` unless the tracking is enabled via
+    /// [`builder::BasicStateMachineBuilder::track_trajectory_hash()`].
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|count: &u32, step: u32| count + step)
+    ///     .track_trajectory_hash()
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// sm.consume(1);
+    /// sm.consume(2);
+    /// let hash = sm.trajectory_hash();
+    /// sm.reset();
+    /// sm.consume(1);
+    /// sm.consume(2);
+    /// assert_eq!(hash, sm.trajectory_hash());
+    /// ```
+    pub fn trajectory_hash(&self) -> u64 {
+        self.trajectory
+            .as_ref()
+            .map(|trajectory| trajectory.get())
+            .unwrap_or_default()
+    }
+
+    /// Takes the snapshot of the state machine like [`BasicStateMachine::snapshot()`],
+    /// including the transition count, the generation, the visited states and the time
+    /// elapsed since the machine entered the current state.
+    /// It can be restored via [`builder::BasicStateMachineBuilder::restore_full()`].
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let builder = BasicStateMachineBuilder::start()
+    ///     .transition(|state: &i32, input: i32| state + input)
+    ///     .track_visited();
+    /// let sm = builder.clone().initial_state(0).build().unwrap();
+    /// sm.consume(1);
+    /// sm.consume(2);
+    ///
+    /// let snapshot = sm.full_snapshot();
+    /// assert_eq!(2, snapshot.transition_count);
+    ///
+    /// let restored = builder.restore_full(snapshot).build().unwrap();
+    /// assert_eq!(3, restored.current_state());
+    /// assert_eq!(2, restored.generation());
+    /// assert_eq!(sm.visited_states(), restored.visited_states());
+    /// ```
+    pub fn full_snapshot(&self) -> FullSnapshot<State> {
+        FullSnapshot {
+            initial_state: self.initial_state.clone(),
+            current_state: self.current_state(),
+            epoch: self.epoch.get(),
+            transition_count: self.telemetry.transitions(),
+            generation: self.generation.get(),
+            visited: self.visited_states(),
+            entered_ms_ago: (self.clock.now() - self.entered_at.get()).as_millis() as u64,
+        }
+    }
+
+    /// Calls the transition function, enriching its panic if
+    /// [`builder::BasicStateMachineBuilder::contextual_panics()`] is called.
+    pub(crate) fn apply(&self, state: &State, input: Input) -> State {
+        let transition = self.transition.borrow();
+        match &self.panic_context {
+            Some(context) => context.call(state, input, &*transition),
+            None => transition(state, input),
+        }
+    }
+
+    /// Returns the index of the first guard which doesn't hold, consulting the memo
+    /// if [`builder::BasicStateMachineBuilder::guard_cache()`] is called.
+    fn failing_guard(&self, state: &State, input: &Input) -> Option<usize> {
+        match &self.guard_cache {
+            Some(cache) => cache.failing_guard(&self.guards, state, input),
+            None => self
+                .guards
+                .iter()
+                .position(|(_, guard)| !guard(state, input)),
+        }
+    }
+
+    /// Returns `true` if nothing but the transition has to be called on consuming,
+    /// so that [`BasicStateMachine::try_consume()`] can take the fast path.
+    fn is_plain(&self) -> bool {
+        self.input_validator.is_none()
+            && self.guards.is_empty()
+            && self.min_dwell.is_none()
+            && self.exit_hooks.is_empty()
+            && self.enter_hooks.is_empty()
+            && self.terminal_hooks.is_empty()
+            && self.timings.is_none()
+            && self.metrics.is_none()
+            && self.slow_hooks.is_empty()
+            && self.tracer.is_none()
+    }
+
+    fn bump_epoch(&self) {
+        self.epoch.set(self.epoch.get() + 1);
+    }
+
+    /// Marks `operation` as in progress if the strict borrow mode is enabled.
+    fn enter(&self, operation: &'static str) -> Option<OperationGuard<'_>> {
+        self.strict.as_ref().map(|strict| strict.enter(operation))
+    }
+
+    /// Bumps the generation, stamps the entry time, notifies the watchers and
+    /// records `state` to the history if it is enabled.
+    /// It is called every time the current state is changed.
+    fn record(&self, state: &State) {
+        self.generation.set(self.generation.get() + 1);
+        self.telemetry.set_faulted(false);
+        let now = self.clock.now();
+        self.entered_at.set(now);
+        if let Some(occupancy) = &self.occupancy {
+            occupancy.record(state, now);
+        }
+        if self.deferred.borrow().is_none() {
+            watch::notify(&mut self.watchers.borrow_mut(), state);
+            if !self.is_terminal(state) {
+                self.terminal_reached.set(false);
+            }
+        }
+        if let Some(visited) = &self.visited {
+            visited.record(state);
+        }
+        if let Some(trajectory) = &self.trajectory {
+            trajectory.record(state);
+        }
+        if let Some(history) = &self.history {
+            history.borrow_mut().push(state.clone());
+        }
+    }
+
+    /// Records the transition to `state` like [`BasicStateMachine::record()`], and counts it
+    /// by the resulting state and for [`BasicStateMachine::status()`] with its `input`.
+    fn record_transition(&self, state: &State, input: Option<Input>) {
+        self.record(state);
+        if let Some(frequencies) = &self.frequencies {
+            frequencies.record(state);
+        }
+        self.telemetry.record(input);
+    }
+
+    /// Calls the transition function on consuming. The machine is marked as faulted
+    /// while it runs, so that the panic in it is reported by [`BasicStateMachine::status()`].
+    fn transit(&self, state: &State, input: Input) -> State {
+        self.telemetry.set_faulted(true);
+        let next = self.apply(state, input);
+        self.telemetry.set_faulted(false);
+        next
+    }
+
+    /// Calls the enter hooks and then the terminal hooks if the machine has just reached
+    /// a terminal state. It is called after the machine transits to `state` via `consume`.
+    fn entered(&self, state: &State) {
+        for hook in &self.enter_hooks {
+            hook(state);
+        }
+        if !self.is_terminal(state) {
+            self.terminal_reached.set(false);
+        } else if !self.terminal_reached.replace(true) {
+            for hook in &self.terminal_hooks {
+                hook(state);
+            }
+        }
+    }
+
+    fn is_terminal(&self, state: &State) -> bool {
+        self.terminal
+            .as_ref()
+            .is_some_and(|terminal| terminal(state))
+    }
+
+    /// Calls the metric sinks and then the observers with `prev` and `next`.
+    fn observe(&self, prev: &State, next: &State) {
+        for sink in &self.metric_sinks {
+            sink(prev, next);
+        }
+        self.observers.notify(prev, next);
+    }
+
+    /// Buffers the pair of `prev` and `next` with the deferral made by `deferral` and returns
+    /// `true` if the notifications are deferred by [`BasicStateMachine::consume_all_atomic()`].
+    fn defer(&self, prev: &State, next: &State, deferral: impl FnOnce() -> Deferral) -> bool {
+        match self.deferred.borrow_mut().as_mut() {
+            Some(deferred) => {
+                deferred.push((prev.clone(), next.clone(), deferral()));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The state machines whose transition takes the input by reference, built from
+/// a transition like `|state: &State, input: &Input| ...`. They consume the inputs
+/// borrowed for `'a` without cloning them nor taking their ownership.
+impl<'a, State, Input, Transition, M> BasicStateMachine<State, &'a Input, Transition, M>
+where
+    Transition: Fn(&State, &'a Input) -> State,
+    State: Clone,
+{
+    /// Transits to the next state by the borrowed `input` like [`StateMachine::consume()`].
+    pub fn consume_ref(&self, input: &'a Input) -> State {
+        self.consume(input)
+    }
+
+    /// Consumes the borrowed `inputs` one by one like [`BasicStateMachine::consume_ref()`]
+    /// and returns the resulting state.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// let words = vec!["state".to_string(), "machine".to_string()];
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(0)
+    ///     .transition(|length: &usize, word: &String| length + word.len())
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(12, sm.consume_all_ref(&words));
+    /// ```
+    pub fn consume_all_ref(&self, inputs: &'a [Input]) -> State {
+        for input in inputs {
+            self.consume(input);
+        }
+        self.current_state()
+    }
+}
+
+impl<State, Input, Transition, M> BasicStateMachine<State, Input, Transition, M>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone + Eq + Hash,
+{
+    /// Returns the metadata attached to the current state via
+    /// [`builder::BasicStateMachineBuilder::metadata()`].
+    /// It returns `None` if no metadata is attached to the state.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+    /// enum ButtonState {
+    ///     On,
+    ///     Off,
+    /// }
+    ///
+    /// enum Input {
+    ///     Press,
+    /// }
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(ButtonState::Off)
+    ///     .transition(|state, input| match (state, input) {
+    ///         (ButtonState::On, Input::Press) => ButtonState::Off,
+    ///         (ButtonState::Off, Input::Press) => ButtonState::On,
+    ///     })
+    ///     .metadata(ButtonState::On, "Button is on")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(None, sm.current_metadata());
+    /// sm.consume(Input::Press);
+    /// assert_eq!(Some(&"Button is on"), sm.current_metadata());
+    /// ```
+    pub fn current_metadata(&self) -> Option<&M> {
+        self.metadata_for(&self.current_state.borrow().0)
+    }
+
+    /// Returns the metadata attached to `state`.
+    /// It returns `None` if no metadata is attached to the state.
+    pub fn metadata_for(&self, state: &State) -> Option<&M> {
+        self.metadata.get(state)
+    }
+
+    /// Returns the tags attached to the current state.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, StateMachine,
+    /// };
+    ///
+    /// #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+    /// enum ButtonState {
+    ///     On,
+    ///     Off,
+    /// }
+    ///
+    /// enum Input {
+    ///     Press,
+    /// }
+    ///
+    /// let sm = BasicStateMachineBuilder::start()
+    ///     .initial_state(ButtonState::Off)
+    ///     .transition(|state, input| match (state, input) {
+    ///         (ButtonState::On, Input::Press) => ButtonState::Off,
+    ///         (ButtonState::Off, Input::Press) => ButtonState::On,
+    ///     })
+    ///     .tag(ButtonState::On, "billable")
+    ///     .tag(ButtonState::On, "user-visible")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert!(sm.current_tags().is_empty());
+    /// sm.consume(Input::Press);
+    /// assert_eq!(&["billable", "user-visible"], sm.current_tags());
+    /// ```
+    pub fn current_tags(&self) -> &[String] {
+        self.tags
+            .get(&self.current_state.borrow().0)
+            .map(|tags| tags.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Returns `true` if the current state has `tag`.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.current_tags().iter().any(|t| t == tag)
+    }
+
+    /// Returns every state which has `tag`. The order of the states is unspecified.
+    pub fn states_with_tag(&self, tag: &str) -> Vec<State> {
+        self.tags
+            .iter()
+            .filter(|(_, tags)| tags.iter().any(|t| t == tag))
+            .map(|(state, _)| state.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::StateMachine;
+    use super::{BoxedTransition, PeekResult};
+    use crate::machine::builder::{BasicStateMachineBuilder, StateMachineBuilder};
+    use crate::machine::error::StateMachineError;
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum ButtonState {
+        On,
+        Off,
+    }
+
+    enum Input {
+        Press,
+    }
+
+    #[test]
+    fn test_type_names() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(ButtonState::Off)
+            .transition(|state, input| match (state, input) {
+                (ButtonState::On, Input::Press) => ButtonState::Off,
+                (ButtonState::Off, Input::Press) => ButtonState::On,
+            })
+            .build()
+            .unwrap();
+        assert_eq!(ButtonState::On, sm.consume(Input::Press));
+
+        assert_eq!(
+            "statemachine_rs::machine::test::ButtonState",
+            sm.state_type_name()
+        );
+        assert_eq!(
+            "statemachine_rs::machine::test::Input",
+            sm.input_type_name()
+        );
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    enum Stations {
+        Shibuya,
+        IkejiriOhashi,
+        Sangendyaya,
+        KomazawaDaigaku,
+        Sakurashinmachi,
+        Yoga,
+        FutakoTamagawa,
+    }
+
+    #[derive(Clone)]
+    enum Train {
+        Local,
+        Express,
+    }
+
+    #[test]
+    fn test_current_state() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station: &Stations, train: Train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                _ => unreachable!(),
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!(Stations::Shibuya, sm.current_state());
+    }
+
+    #[test]
+    fn test_consume() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station: &Stations, train: Train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
+                (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
                 (Stations::KomazawaDaigaku, Train::Local) => Stations::Sakurashinmachi,
                 (Stations::Sakurashinmachi, Train::Local) => Stations::Yoga,
                 _ => unreachable!(),
-            },
-            _maker: PhantomData::<Train>::default(),
-        };
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!(Stations::IkejiriOhashi, sm.consume(Train::Local));
+    }
+
+    #[test]
+    fn test_peek() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Sangendyaya)
+            .transition(|station: &Stations, train: Train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
+                (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
+                (Stations::KomazawaDaigaku, Train::Local) => Stations::Sakurashinmachi,
+                (Stations::Sakurashinmachi, Train::Local) => Stations::Yoga,
+                _ => unreachable!(),
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!(Stations::FutakoTamagawa, sm.peek(Train::Express));
+        assert_eq!(Stations::Sangendyaya, sm.current_state());
+    }
+
+    #[test]
+    fn test_reset() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .current_state(Stations::Sangendyaya)
+            .transition(|station: &Stations, train: Train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
+                (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
+                (Stations::KomazawaDaigaku, Train::Local) => Stations::Sakurashinmachi,
+                (Stations::Sakurashinmachi, Train::Local) => Stations::Yoga,
+                _ => unreachable!(),
+            })
+            .build()
+            .unwrap();
 
         assert_eq!(Stations::FutakoTamagawa, sm.consume(Train::Express));
         assert_eq!(Stations::Shibuya, sm.reset());
@@ -353,18 +2880,634 @@ mod test {
 
     #[test]
     fn test_set() {
-        let sm = BasicStateMachine {
-            initial_state: Stations::Shibuya,
-            current_state: RefCell::new(StateWrapper::new(Stations::Shibuya)),
-            transition: |station, train| match (station, train) {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station: &Stations, train: Train| match (station, train) {
                 (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
                 _ => unreachable!(),
-            },
-            _maker: PhantomData::<Train>::default(),
-        };
+            })
+            .build()
+            .unwrap();
 
         assert_eq!(Stations::Shibuya, sm.current_state());
         sm.set(Stations::Yoga);
         assert_eq!(Stations::Yoga, sm.current_state())
     }
+
+    #[test]
+    fn test_metadata() {
+        #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+        enum ButtonState {
+            On,
+            Off,
+        }
+
+        enum Input {
+            Press,
+        }
+
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(ButtonState::Off)
+            .transition(|state: &ButtonState, input| match (state, input) {
+                (ButtonState::On, Input::Press) => ButtonState::Off,
+                (ButtonState::Off, Input::Press) => ButtonState::On,
+            })
+            .metadata(ButtonState::Off, "Off")
+            .metadata(ButtonState::On, "Button is on")
+            // replaces the one attached to the same state before
+            .metadata(ButtonState::Off, "Button is off")
+            .build()
+            .unwrap();
+
+        assert_eq!(Some(&"Button is off"), sm.current_metadata());
+        sm.consume(Input::Press);
+        assert_eq!(Some(&"Button is on"), sm.current_metadata());
+        sm.consume(Input::Press);
+        assert_eq!(Some(&"Button is off"), sm.current_metadata());
+        assert_eq!(Some(&"Button is on"), sm.metadata_for(&ButtonState::On));
+    }
+
+    #[test]
+    fn test_history_iter() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|state: &i32, input: i32| state + input)
+            .with_history()
+            .build()
+            .unwrap();
+
+        for input in 1..=4 {
+            sm.consume(input);
+        }
+
+        // history: 0, 1, 3, 6, 10
+        assert_eq!(20, sm.history_iter().sum::<i32>());
+        assert_eq!(3, sm.history_iter().filter(|state| state % 2 == 0).count());
+        assert_eq!(vec![0, 1, 3, 6, 10], sm.history());
+    }
+
+    #[test]
+    fn test_split_history_at() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|state: &i32, input: i32| state + input)
+            .with_history()
+            .build()
+            .unwrap();
+
+        for input in 1..=4 {
+            sm.consume(input);
+        }
+
+        // history: 0, 1, 3, 6, 10
+        assert_eq!(Some((vec![0, 1], vec![3, 6, 10])), sm.split_history_at(&3));
+        assert_eq!(
+            Some((vec![], vec![0, 1, 3, 6, 10])),
+            sm.split_history_at(&0)
+        );
+        assert_eq!(None, sm.split_history_at(&2));
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|state: &i32, input: i32| state + input)
+            .with_history()
+            .build()
+            .unwrap();
+
+        for input in 1..=1000 {
+            sm.consume(input);
+        }
+        assert_eq!(1001, sm.history_iter().count());
+
+        sm.coalesce();
+        assert_eq!(vec![500500], sm.history());
+        assert_eq!(500500, sm.current_state());
+
+        sm.consume(1);
+        assert_eq!(vec![500500, 500501], sm.history());
+        assert_eq!(0, sm.reset());
+    }
+
+    #[test]
+    fn test_drive() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|state: &i32, input: i32| state + input)
+            .with_history()
+            .build()
+            .unwrap();
+
+        let drive = sm.drive(1..=3);
+        assert_eq!(vec![1, 3, 6], drive.map(Result::unwrap).collect::<Vec<_>>());
+
+        let mut drive = sm.drive(1..=3);
+        assert_eq!(7, drive.next().unwrap().unwrap());
+        sm.update(|state| *state = 0);
+        match drive.next() {
+            Some(Err(StateMachineError::StaleIterator { expected, actual })) => {
+                assert_eq!((0, 1), (expected, actual))
+            }
+            _ => panic!("the stale iterator must be reported"),
+        }
+        assert!(drive.next().is_none());
+        assert_eq!(0, sm.current_state());
+    }
+
+    #[test]
+    fn test_pending_inputs() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|state: &i32, input: i32| state + input)
+            .with_history()
+            .build()
+            .unwrap();
+        assert!(sm.pending_inputs().is_empty());
+
+        sm.pause();
+        assert!(sm.is_paused());
+        assert_eq!(0, sm.consume(1));
+        assert_eq!(0, sm.try_consume(2).unwrap());
+        assert_eq!(vec![1, 2], sm.pending_inputs());
+
+        sm.clear_pending();
+        assert!(sm.pending_inputs().is_empty());
+        assert!(sm.is_paused());
+
+        assert_eq!(0, sm.resume());
+        assert!(!sm.is_paused());
+        assert_eq!(vec![0], sm.history());
+        assert_eq!(0, sm.generation());
+    }
+
+    #[test]
+    fn test_consume_all_unlimited() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|state: &i32, input: i32| state + input)
+            .history_limit(2)
+            .build()
+            .unwrap();
+
+        let mut lengths = Vec::new();
+        let inputs = (1..=4).inspect(|_| lengths.push(sm.history().len()));
+        assert_eq!(10, sm.consume_all_unlimited(inputs));
+        // the history kept every state during the batch
+        assert_eq!(vec![1, 2, 3, 4], lengths);
+        assert_eq!(vec![6, 10], sm.history());
+
+        // the limit is restored
+        sm.consume(5);
+        assert_eq!(vec![10, 15], sm.history());
+    }
+
+    #[test]
+    fn test_consume_all_unlimited_panic() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|state: &i32, input: i32| {
+                assert!(input > 0, "no negative input");
+                state + input
+            })
+            .history_limit(2)
+            .build()
+            .unwrap();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            sm.consume_all_unlimited(vec![1, 2, 3, -1])
+        }));
+        assert!(result.is_err());
+        // the limit is restored even though the batch panicked
+        assert_eq!(vec![3, 6], sm.history());
+        sm.consume(4);
+        assert_eq!(vec![6, 10], sm.history());
+    }
+
+    #[test]
+    fn test_tags() {
+        #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+        enum Order {
+            Draft,
+            Paid,
+            Shipped,
+        }
+
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Order::Draft)
+            .transition(|state: &Order, _: ()| match state {
+                Order::Draft => Order::Paid,
+                Order::Paid => Order::Shipped,
+                Order::Shipped => Order::Shipped,
+            })
+            .tag(Order::Paid, "billable")
+            .tag(Order::Shipped, "billable")
+            .tag(Order::Shipped, "terminal")
+            .build()
+            .unwrap();
+
+        assert!(sm.current_tags().is_empty());
+        assert!(!sm.has_tag("billable"));
+
+        sm.consume(());
+        assert_eq!(&["billable"], sm.current_tags());
+        assert!(sm.has_tag("billable"));
+        assert!(!sm.has_tag("terminal"));
+
+        sm.consume(());
+        assert_eq!(&["billable", "terminal"], sm.current_tags());
+        assert!(sm.has_tag("terminal"));
+
+        let billable = sm.states_with_tag("billable");
+        assert_eq!(2, billable.len());
+        assert!(billable.contains(&Order::Paid));
+        assert!(billable.contains(&Order::Shipped));
+        assert_eq!(vec![Order::Shipped], sm.states_with_tag("terminal"));
+        assert!(sm.states_with_tag("user-visible").is_empty());
+    }
+
+    #[test]
+    fn test_peek_edge() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station: &Stations, train: Train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
+                _ => unreachable!(),
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            PeekResult::Valid(Stations::Sangendyaya),
+            sm.peek_edge(&Train::Express)
+        );
+        assert_eq!(Stations::Shibuya, sm.current_state());
+    }
+
+    #[test]
+    fn test_validate_input() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|balance: &i32, amount: i32| balance + amount)
+            .with_history()
+            .validate_input(|amount: &i32| {
+                if *amount < 0 {
+                    Err(format!("{} is a negative amount", amount))
+                } else {
+                    Ok(())
+                }
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!(10, sm.try_consume(10).unwrap());
+        match sm.try_consume(-5) {
+            Err(StateMachineError::InvalidInput { reason }) => {
+                assert_eq!("-5 is a negative amount", reason)
+            }
+            _ => panic!("the input must be rejected"),
+        }
+        assert_eq!(10, sm.current_state());
+        // the infallible path also leaves the state untouched
+        assert_eq!(10, sm.consume(-5));
+        assert_eq!(vec![0, 10], sm.history());
+    }
+
+    #[test]
+    fn test_consume_respond_takes_prev_out() {
+        #[derive(Debug)]
+        struct Counted(u32, Arc<AtomicUsize>);
+
+        impl Clone for Counted {
+            fn clone(&self) -> Self {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                Counted(self.0, Arc::clone(&self.1))
+            }
+        }
+
+        let clones = Arc::new(AtomicUsize::new(0));
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Counted(10, Arc::clone(&clones)))
+            .transition(|balance: &Counted, amount: u32| {
+                Counted(balance.0.saturating_sub(amount), Arc::clone(&balance.1))
+            })
+            .build()
+            .unwrap();
+        let built = clones.load(Ordering::SeqCst);
+
+        // Only the new state is cloned into the machine.
+        assert_eq!(4, sm.consume_respond(4, |prev, new| prev.0 - new.0));
+        assert_eq!(built + 1, clones.load(Ordering::SeqCst));
+        let (prev, new) = sm.consume_with_prev(1);
+        assert_eq!((6, 5), (prev.0, new.0));
+        assert_eq!(built + 2, clones.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_consume_with_prev() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station: &Stations, train: Train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                _ => unreachable!(),
+            })
+            .build()
+            .unwrap();
+
+        let before = sm.current_state();
+        assert_eq!(
+            (before, Stations::IkejiriOhashi),
+            sm.consume_with_prev(Train::Local)
+        );
+        let before = sm.current_state();
+        let (prev, new) = sm.consume_with_prev(Train::Local);
+        assert_eq!(before, prev);
+        assert_eq!(Stations::Sangendyaya, new);
+
+        // rolls back manually
+        sm.set(prev);
+        assert_eq!(Stations::IkejiriOhashi, sm.current_state());
+    }
+
+    #[test]
+    fn test_set_transition() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(
+                Box::new(|station: &Stations, train| match (station, train) {
+                    (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                    (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                    _ => unreachable!(),
+                }) as BoxedTransition<Stations, Train>,
+            )
+            .build()
+            .unwrap();
+
+        assert_eq!(Stations::IkejiriOhashi, sm.consume(Train::Local));
+
+        // the express service starts
+        sm.set_transition(Box::new(|station, train| match (station, train) {
+            (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+            (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
+            (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
+            _ => unreachable!(),
+        }));
+
+        assert_eq!(Stations::Sangendyaya, sm.consume(Train::Local));
+        assert_eq!(Stations::FutakoTamagawa, sm.consume(Train::Express));
+    }
+
+    #[test]
+    fn test_current_state_ref() {
+        struct Timetable {
+            stations: Vec<Stations>,
+            delay: u32,
+            clones: Arc<AtomicUsize>,
+        }
+
+        impl Clone for Timetable {
+            fn clone(&self) -> Self {
+                self.clones.fetch_add(1, Ordering::SeqCst);
+                Timetable {
+                    stations: self.stations.clone(),
+                    delay: self.delay,
+                    clones: Arc::clone(&self.clones),
+                }
+            }
+        }
+
+        let clones = Arc::new(AtomicUsize::new(0));
+        let timetable = Timetable {
+            stations: vec![Stations::Shibuya; 10_000],
+            delay: 3,
+            clones: Arc::clone(&clones),
+        };
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(timetable.clone())
+            .current_state(timetable)
+            .transition(|timetable: &Timetable, delay: u32| Timetable {
+                stations: timetable.stations.clone(),
+                delay,
+                clones: Arc::clone(&timetable.clones),
+            })
+            .build()
+            .unwrap();
+        clones.store(0, Ordering::SeqCst);
+
+        {
+            let timetable = sm.current_state_ref();
+            assert_eq!(3, timetable.delay);
+            assert_eq!(10_000, timetable.stations.len());
+        }
+        assert_eq!(0, clones.load(Ordering::SeqCst));
+
+        // the guard is released, so the machine can transit
+        sm.try_consume(5).unwrap();
+        let clones_after_consume = clones.load(Ordering::SeqCst);
+        assert_eq!(5, sm.current_state_ref().delay);
+        assert_eq!(clones_after_consume, clones.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_update() {
+        #[derive(Clone, Debug, PartialEq)]
+        struct Ride {
+            station: Stations,
+            delay: u32,
+        }
+
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Ride {
+                station: Stations::Shibuya,
+                delay: 0,
+            })
+            .transition(|ride: &Ride, train: Train| match (ride.station, train) {
+                (Stations::Shibuya, Train::Local) => Ride {
+                    station: Stations::IkejiriOhashi,
+                    ..ride.clone()
+                },
+                _ => unreachable!(),
+            })
+            .with_history()
+            .build()
+            .unwrap();
+
+        sm.update(|ride| ride.delay += 2);
+        assert_eq!(2, sm.current_state_ref().delay);
+        // the transition sees the updated state
+        assert_eq!(
+            Ride {
+                station: Stations::IkejiriOhashi,
+                delay: 2,
+            },
+            sm.consume(Train::Local)
+        );
+        assert_eq!(
+            vec![0, 2, 2],
+            sm.history_iter().map(|ride| ride.delay).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_clone_counts() {
+        struct Counted {
+            value: u32,
+            clones: Arc<AtomicUsize>,
+        }
+
+        impl Clone for Counted {
+            fn clone(&self) -> Self {
+                self.clones.fetch_add(1, Ordering::SeqCst);
+                Counted {
+                    value: self.value,
+                    clones: Arc::clone(&self.clones),
+                }
+            }
+        }
+
+        let clones = Arc::new(AtomicUsize::new(0));
+        let counted = |value| Counted {
+            value,
+            clones: Arc::clone(&clones),
+        };
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(counted(0))
+            .transition(|state: &Counted, input: u32| Counted {
+                value: state.value + input,
+                clones: Arc::clone(&state.clones),
+            })
+            .build()
+            .unwrap();
+        let clones_of = |f: &dyn Fn()| {
+            let before = clones.load(Ordering::SeqCst);
+            f();
+            clones.load(Ordering::SeqCst) - before
+        };
+
+        // one for the return value
+        assert_eq!(1, clones_of(&|| assert_eq!(0, sm.current_state().value)));
+        assert_eq!(0, clones_of(&|| assert_eq!(2, sm.peek(2).value)));
+        // one for the return value, the computed state is moved into the machine
+        assert_eq!(1, clones_of(&|| assert_eq!(2, sm.consume(2).value)));
+        assert_eq!(0, clones_of(&|| sm.set(counted(10))));
+        assert_eq!(0, clones_of(&|| sm.update(|state| state.value += 1)));
+        assert_eq!(
+            0,
+            clones_of(&|| assert_eq!(11, sm.current_state_ref().value))
+        );
+        // one for the current state and one for the return value
+        assert_eq!(2, clones_of(&|| assert_eq!(0, sm.reset().value)));
+    }
+
+    #[test]
+    fn test_consume_at_generation() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station: &Stations, train: Train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                _ => unreachable!(),
+            })
+            .build()
+            .unwrap();
+
+        let observed = sm.generation();
+        assert_eq!(0, observed);
+        // another task transits the machine in the meantime
+        assert_eq!(Stations::IkejiriOhashi, sm.consume(Train::Local));
+        match sm.consume_at_generation(Train::Local, observed) {
+            Err(StateMachineError::StaleGeneration { expected, actual }) => {
+                assert_eq!((0, 1), (expected, actual))
+            }
+            _ => panic!("the stale generation must be rejected"),
+        }
+        assert_eq!(Stations::IkejiriOhashi, sm.current_state());
+
+        let fresh = sm.generation();
+        assert_eq!(
+            (Stations::Sangendyaya, 2),
+            sm.consume_at_generation(Train::Local, fresh).unwrap()
+        );
+        sm.set(Stations::Shibuya);
+        sm.reset();
+        assert_eq!(4, sm.generation());
+    }
+
+    #[test]
+    fn test_peek_chain() {
+        let entered = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&entered);
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(|station: &Stations, train: Train| match (station, train) {
+                (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+                (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
+                (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+                (Stations::Sangendyaya, Train::Local) => Stations::KomazawaDaigaku,
+                (Stations::Sangendyaya, Train::Express) => Stations::FutakoTamagawa,
+                _ => unreachable!(),
+            })
+            .with_history()
+            .on_enter_state(Stations::Sangendyaya, move |_| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                Stations::IkejiriOhashi,
+                Stations::Sangendyaya,
+                Stations::FutakoTamagawa
+            ],
+            sm.peek_chain(vec![Train::Local, Train::Local, Train::Express])
+        );
+        assert!(sm.peek_chain(Vec::new()).is_empty());
+        assert_eq!(Stations::Shibuya, sm.current_state());
+        assert_eq!(0, sm.generation());
+        assert_eq!(vec![Stations::Shibuya], sm.history());
+        assert_eq!(0, entered.load(Ordering::SeqCst));
+
+        // the simulation starts from the current state
+        sm.consume(Train::Express);
+        assert_eq!(
+            vec![Stations::KomazawaDaigaku],
+            sm.peek_chain(vec![Train::Local])
+        );
+        assert_eq!(1, sm.generation());
+    }
+
+    #[test]
+    fn test_consume_all_dedup() {
+        #[derive(PartialEq)]
+        enum Button {
+            Press,
+            Release,
+        }
+
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(false)
+            .transition(|on: &bool, button: Button| match button {
+                Button::Press => !on,
+                Button::Release => *on,
+            })
+            .build()
+            .unwrap();
+
+        assert!(sm.consume_all_dedup(vec![Button::Press, Button::Press, Button::Press]));
+        assert_eq!(1, sm.generation());
+        assert!(!sm.consume_all_dedup(vec![
+            Button::Release,
+            Button::Press,
+            Button::Press,
+            Button::Release,
+            Button::Release,
+        ]));
+        assert_eq!(4, sm.generation());
+        assert!(!sm.consume_all_dedup(Vec::new()));
+        assert_eq!(4, sm.generation());
+    }
 }
@@ -0,0 +1,299 @@
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// The callback which is called with the previous and the next states.
+/// See [`crate::machine::BasicStateMachine::add_observer_with_priority()`].
+pub(crate) type Observer<State> = Arc<dyn Fn(&State, &State) + Send + Sync>;
+
+/// The observer which returns a value collected by
+/// [`crate::machine::BasicStateMachine::consume_collecting()`].
+pub(crate) type Collector<State> = Arc<dyn Fn(&State, &State) -> Box<dyn Any + Send> + Send + Sync>;
+
+/// The handle of the observer registered via
+/// [`crate::machine::BasicStateMachine::add_observer_with_priority()`].
+#[derive(Clone, Debug)]
+pub struct ObserverHandle {
+    id: u64,
+    detached: Arc<AtomicBool>,
+}
+
+impl ObserverHandle {
+    /// Detaches the observer like [`crate::machine::BasicStateMachine::remove_observer()`].
+    /// It can be called from inside the callbacks, where the state machine isn't reachable.
+    /// It returns `false` if the observer is already detached.
+    pub fn detach(&self) -> bool {
+        !self.detached.swap(true, Ordering::SeqCst)
+    }
+
+    /// Returns the id of the observer, which is unique in the state machine.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+enum Callback<State> {
+    Observe(Observer<State>),
+    Collect(Collector<State>),
+}
+
+impl<State> Clone for Callback<State> {
+    fn clone(&self) -> Self {
+        match self {
+            Callback::Observe(observer) => Callback::Observe(Arc::clone(observer)),
+            Callback::Collect(collector) => Callback::Collect(Arc::clone(collector)),
+        }
+    }
+}
+
+struct Entry<State> {
+    priority: i32,
+    handle: ObserverHandle,
+    callback: Callback<State>,
+}
+
+/// The observers ordered by ascending priority and then by registration order.
+pub(crate) struct Observers<State> {
+    entries: RefCell<Vec<Entry<State>>>,
+    next_id: Cell<u64>,
+    /// The outputs of the collecting observers, buffered only while
+    /// [`Observers::collect()`] runs.
+    collected: RefCell<Option<Vec<Box<dyn Any + Send>>>>,
+}
+
+impl<State> Observers<State> {
+    pub(crate) fn new() -> Self {
+        Observers {
+            entries: RefCell::new(Vec::new()),
+            next_id: Cell::new(0),
+            collected: RefCell::new(None),
+        }
+    }
+
+    pub(crate) fn add(&self, priority: i32, observer: Observer<State>) -> ObserverHandle {
+        self.insert(priority, Callback::Observe(observer))
+    }
+
+    pub(crate) fn add_collector(
+        &self,
+        priority: i32,
+        collector: Collector<State>,
+    ) -> ObserverHandle {
+        self.insert(priority, Callback::Collect(collector))
+    }
+
+    fn insert(&self, priority: i32, callback: Callback<State>) -> ObserverHandle {
+        let handle = ObserverHandle {
+            id: self.next_id.get(),
+            detached: Arc::new(AtomicBool::new(false)),
+        };
+        self.next_id.set(handle.id + 1);
+        let mut entries = self.entries.borrow_mut();
+        // Inserting after the entries with the same priority keeps the registration order.
+        let index = entries.partition_point(|entry| entry.priority <= priority);
+        entries.insert(
+            index,
+            Entry {
+                priority,
+                handle: handle.clone(),
+                callback,
+            },
+        );
+        handle
+    }
+
+    /// Registers the observers of `other` not detached yet, keeping their priorities and
+    /// order and sharing their callbacks. It returns the handles of the new registrations.
+    pub(crate) fn extend_from(&self, other: &Observers<State>) -> Vec<ObserverHandle> {
+        let entries: Vec<_> = other
+            .entries
+            .borrow()
+            .iter()
+            .filter(|entry| !entry.handle.detached.load(Ordering::SeqCst))
+            .map(|entry| (entry.priority, entry.callback.clone()))
+            .collect();
+        entries
+            .into_iter()
+            .map(|(priority, callback)| self.insert(priority, callback))
+            .collect()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries
+            .borrow()
+            .iter()
+            .filter(|entry| !entry.handle.detached.load(Ordering::SeqCst))
+            .count()
+    }
+
+    /// Calls the observers registered at this point in order. The observers detached
+    /// during the pass are still called in it and dropped at the next pass.
+    pub(crate) fn notify(&self, prev: &State, next: &State) {
+        let observers = {
+            let mut entries = self.entries.borrow_mut();
+            if entries.is_empty() {
+                return;
+            }
+            entries.retain(|entry| !entry.handle.detached.load(Ordering::SeqCst));
+            entries
+                .iter()
+                .map(|entry| entry.callback.clone())
+                .collect::<Vec<_>>()
+        };
+        for observer in observers {
+            match observer {
+                Callback::Observe(observer) => observer(prev, next),
+                Callback::Collect(collector) => {
+                    let output = collector(prev, next);
+                    if let Some(collected) = self.collected.borrow_mut().as_mut() {
+                        collected.push(output);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs `f` and returns its result with the outputs of the collecting observers
+    /// called meanwhile. Outside of it the outputs are dropped.
+    pub(crate) fn collect<R>(&self, f: impl FnOnce() -> R) -> (R, Vec<Box<dyn Any + Send>>) {
+        let outer = self.collected.replace(Some(Vec::new()));
+        let result = f();
+        let collected = self.collected.replace(outer).unwrap_or_default();
+        (result, collected)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use crate::machine::{
+        builder::{BasicStateMachineBuilder, StateMachineBuilder},
+        observer::ObserverHandle,
+        StateMachine,
+    };
+
+    #[test]
+    fn test_priority_and_removal() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|state: &i32, input: i32| state + input)
+            .build()
+            .unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let target: Arc<Mutex<Option<ObserverHandle>>> = Arc::new(Mutex::new(None));
+
+        let second = {
+            let log = Arc::clone(&log);
+            sm.add_observer_with_priority(5, move |_, next: &i32| {
+                log.lock().unwrap().push(("second", *next))
+            })
+        };
+        {
+            let log = Arc::clone(&log);
+            let target = Arc::clone(&target);
+            sm.add_observer_with_priority(-1, move |_, next: &i32| {
+                log.lock().unwrap().push(("first", *next));
+                if let Some(handle) = target.lock().unwrap().take() {
+                    assert!(handle.detach());
+                }
+            });
+        }
+        let third = {
+            let log = Arc::clone(&log);
+            sm.add_observer_with_priority(5, move |_, next: &i32| {
+                log.lock().unwrap().push(("third", *next))
+            })
+        };
+        assert_eq!(3, sm.observer_count());
+
+        sm.consume(1);
+        assert_eq!(
+            vec![("first", 1), ("second", 1), ("third", 1)],
+            *log.lock().unwrap()
+        );
+
+        // The first observer detaches the third one, which still fires in this pass.
+        log.lock().unwrap().clear();
+        *target.lock().unwrap() = Some(third.clone());
+        sm.consume(1);
+        assert_eq!(
+            vec![("first", 2), ("second", 2), ("third", 2)],
+            *log.lock().unwrap()
+        );
+        assert_eq!(2, sm.observer_count());
+
+        log.lock().unwrap().clear();
+        sm.consume(1);
+        assert_eq!(vec![("first", 3), ("second", 3)], *log.lock().unwrap());
+
+        assert!(sm.remove_observer(&second));
+        assert!(!sm.remove_observer(&second));
+        assert!(!sm.remove_observer(&third));
+        assert_eq!(1, sm.observer_count());
+    }
+
+    #[test]
+    fn test_copy_observers_from() {
+        let builder = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|state: &i32, input: i32| state + input);
+        let parent = builder.clone().build().unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        {
+            let log = Arc::clone(&log);
+            parent.add_observer_with_priority(0, move |_, next: &i32| {
+                log.lock().unwrap().push(*next)
+            });
+        }
+        let detached = parent.add_observer_with_priority(0, |_, _| panic!("detached"));
+        assert!(detached.detach());
+
+        let fork = builder.initial_state(100).build().unwrap();
+        let handles = fork.copy_observers_from(&parent);
+        assert_eq!(1, handles.len());
+        assert_eq!(1, fork.observer_count());
+        // The closure is shared rather than cloned, so is the log it captures.
+        assert_eq!(2, Arc::strong_count(&log));
+
+        parent.consume(1);
+        fork.consume(1);
+        assert_eq!(vec![1, 101], *log.lock().unwrap());
+
+        // The copies are registered on their own, so detaching one leaves the other.
+        assert!(fork.remove_observer(&handles[0]));
+        fork.consume(1);
+        parent.consume(1);
+        assert_eq!(vec![1, 101, 2], *log.lock().unwrap());
+    }
+
+    #[test]
+    fn test_consume_collecting() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|state: &i32, input: i32| state + input)
+            .build()
+            .unwrap();
+        sm.add_collecting_observer(0, |prev: &i32, next: &i32| {
+            format!("moved from {} to {}", prev, next)
+        });
+        sm.add_collecting_observer(-1, |_, next: &i32| format!("now at {}", next));
+        sm.add_collecting_observer(1, |_, next: &i32| *next > 2);
+
+        assert_eq!(
+            (
+                1,
+                vec!["now at 1".to_string(), "moved from 0 to 1".to_string()]
+            ),
+            sm.consume_collecting::<String>(1)
+        );
+        // The outputs of plain consumes are dropped.
+        sm.consume(1);
+        assert_eq!((5, vec![true]), sm.consume_collecting::<bool>(3));
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{self, Debug, Display},
+    hash::Hash,
+    marker::PhantomData,
+    sync::{Mutex, MutexGuard},
+};
+
+use super::StateMachine;
+
+/// The error of [`MachineMap`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OrchestratorError<State> {
+    /// No machine is registered with the key.
+    UnknownKey,
+    /// The key is already taken by another machine.
+    DuplicateKey,
+    /// The machine would move into `state`, which already holds `max` machines.
+    StateAtCapacity { state: State, max: usize },
+}
+
+impl<State> Display for OrchestratorError<State>
+where
+    State: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrchestratorError::UnknownKey => f.write_str("No machine is registered with the key."),
+            OrchestratorError::DuplicateKey => {
+                f.write_str("Another machine is already registered with the key.")
+            }
+            OrchestratorError::StateAtCapacity { state, max } => write!(
+                f,
+                "The state {:?} already holds the maximum of {} machines.",
+                state, max
+            ),
+        }
+    }
+}
+
+impl<State> Error for OrchestratorError<State> where State: Debug {}
+
+struct Inner<Key, State, M> {
+    machines: HashMap<Key, M>,
+    /// The maximum number of the machines in each limited state.
+    limits: HashMap<State, usize>,
+    /// The number of the machines in each state. The states without machines are removed.
+    occupancy: HashMap<State, usize>,
+}
+
+impl<Key, State, M> Inner<Key, State, M>
+where
+    State: Eq + Hash,
+{
+    /// Returns the error if `state` can't take one more machine.
+    fn check_capacity(&self, state: &State) -> Result<(), OrchestratorError<State>>
+    where
+        State: Clone,
+    {
+        match self.limits.get(state) {
+            Some(max) if self.occupancy.get(state).copied().unwrap_or(0) >= *max => {
+                Err(OrchestratorError::StateAtCapacity {
+                    state: state.clone(),
+                    max: *max,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn enter(&mut self, state: State) {
+        *self.occupancy.entry(state).or_insert(0) += 1;
+    }
+
+    fn leave(&mut self, state: &State) {
+        if let Some(count) = self.occupancy.get_mut(state) {
+            *count -= 1;
+            if *count == 0 {
+                self.occupancy.remove(state);
+            }
+        }
+    }
+}
+
+/// The state machines keyed by `Key`, e.g. one per session, which can be shared among
+/// threads. The machines are kept behind a single mutex, so every operation on the map
+/// sees the machines and the number of the machines in each state consistently.
+///
+/// Some states can be limited to hold at most particular number of machines at the same
+/// time via [`MachineMap::limit_state()`], e.g. the states holding a scarce resource.
+///
+/// # Example
+/// ```
+/// use statemachine_rs::machine::{
+///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder,
+///     orchestrator::{MachineMap, OrchestratorError},
+/// };
+///
+/// let machine = || {
+///     BasicStateMachineBuilder::start()
+///         .initial_state("Idle")
+///         .transition(|_: &&str, next: &'static str| next)
+///         .build()
+///         .unwrap()
+/// };
+/// let sessions = MachineMap::new();
+/// sessions.limit_state("Downloading", 1);
+/// sessions.insert(1, machine()).unwrap();
+/// sessions.insert(2, machine()).unwrap();
+///
+/// assert_eq!(Ok("Downloading"), sessions.consume(&1, "Downloading"));
+/// assert_eq!(
+///     Err(OrchestratorError::StateAtCapacity { state: "Downloading", max: 1 }),
+///     sessions.consume(&2, "Downloading")
+/// );
+/// ```
+pub struct MachineMap<Key, State, Input, M> {
+    inner: Mutex<Inner<Key, State, M>>,
+    _marker: PhantomData<fn(Input)>,
+}
+
+impl<Key, State, Input, M> Default for MachineMap<Key, State, Input, M> {
+    fn default() -> Self {
+        MachineMap {
+            inner: Mutex::new(Inner {
+                machines: HashMap::new(),
+                limits: HashMap::new(),
+                occupancy: HashMap::new(),
+            }),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<Key, State, Input, M> MachineMap<Key, State, Input, M>
+where
+    Key: Eq + Hash,
+    State: Clone + Eq + Hash,
+    M: StateMachine<State, Input>,
+{
+    /// Creates an empty map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<Key, State, M>> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Limits `state` to hold at most `max` machines at the same time. The machines already
+    /// in the state stay there even beyond `max`, but no more machine can move into it
+    /// until the number falls below `max`.
+    pub fn limit_state(&self, state: State, max: usize) {
+        self.lock().limits.insert(state, max);
+    }
+
+    /// Registers `machine` with `key`. It is rejected if `key` is already taken or
+    /// the current state of `machine` is at capacity.
+    pub fn insert(&self, key: Key, machine: M) -> Result<(), OrchestratorError<State>> {
+        let mut inner = self.lock();
+        if inner.machines.contains_key(&key) {
+            return Err(OrchestratorError::DuplicateKey);
+        }
+        let state = machine.current_state();
+        inner.check_capacity(&state)?;
+        inner.enter(state);
+        inner.machines.insert(key, machine);
+        Ok(())
+    }
+
+    /// Unregisters the machine with `key` and returns it, releasing its state.
+    pub fn remove(&self, key: &Key) -> Option<M> {
+        let mut inner = self.lock();
+        let machine = inner.machines.remove(key)?;
+        inner.leave(&machine.current_state());
+        Some(machine)
+    }
+
+    /// Returns the current state of the machine with `key`.
+    pub fn current_state(&self, key: &Key) -> Option<State> {
+        self.lock()
+            .machines
+            .get(key)
+            .map(|machine| machine.current_state())
+    }
+
+    /// Returns the number of the machines in `state`.
+    pub fn count_in(&self, state: &State) -> usize {
+        self.lock().occupancy.get(state).copied().unwrap_or(0)
+    }
+
+    /// Consumes `input` on the machine with `key` and returns its new state.
+    /// If the machine would move into a state at capacity, it is rejected with
+    /// [`OrchestratorError::StateAtCapacity`] before the input is consumed, so the machine
+    /// stays put. Leaving a state releases its capacity right away.
+    ///
+    /// The next state is predicted via [`StateMachine::peek()`], so the input is cloned
+    /// once. The machines whose `consume` may move somewhere else than `peek` predicts
+    /// may exceed the limits.
+    pub fn consume(&self, key: &Key, input: Input) -> Result<State, OrchestratorError<State>>
+    where
+        Input: Clone,
+    {
+        let mut inner = self.lock();
+        let machine = inner
+            .machines
+            .get(key)
+            .ok_or(OrchestratorError::UnknownKey)?;
+        let prev = machine.current_state();
+        let next = machine.peek(input.clone());
+        if next != prev {
+            inner.check_capacity(&next)?;
+        }
+        let next = machine.consume(input);
+        if next != prev {
+            inner.leave(&prev);
+            inner.enter(next.clone());
+        }
+        Ok(next)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, thread};
+
+    use super::{MachineMap, OrchestratorError};
+    use crate::machine::{
+        builder::{BasicStateMachineBuilder, StateMachineBuilder},
+        StateMachine,
+    };
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Session {
+        Idle,
+        Downloading,
+        Done,
+    }
+
+    #[derive(Copy, Clone)]
+    enum Event {
+        Start,
+        Finish,
+    }
+
+    fn session() -> impl StateMachine<Session, Event> + Send {
+        BasicStateMachineBuilder::start()
+            .initial_state(Session::Idle)
+            .transition(|session, event| match (session, event) {
+                (Session::Idle, Event::Start) => Session::Downloading,
+                (Session::Downloading, Event::Finish) => Session::Done,
+                (session, _) => *session,
+            })
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_limit_state() {
+        let sessions = MachineMap::new();
+        sessions.limit_state(Session::Downloading, 3);
+        for key in 1..=4 {
+            sessions.insert(key, session()).unwrap();
+        }
+        for key in 1..=3 {
+            assert_eq!(
+                Ok(Session::Downloading),
+                sessions.consume(&key, Event::Start)
+            );
+        }
+
+        assert_eq!(
+            Err(OrchestratorError::StateAtCapacity {
+                state: Session::Downloading,
+                max: 3
+            }),
+            sessions.consume(&4, Event::Start)
+        );
+        assert_eq!(Some(Session::Idle), sessions.current_state(&4));
+
+        assert_eq!(Ok(Session::Done), sessions.consume(&1, Event::Finish));
+        assert_eq!(Ok(Session::Downloading), sessions.consume(&4, Event::Start));
+        assert_eq!(3, sessions.count_in(&Session::Downloading));
+
+        // Removing a machine releases its state as well.
+        assert!(sessions.remove(&2).is_some());
+        assert_eq!(2, sessions.count_in(&Session::Downloading));
+        assert_eq!(
+            Err(OrchestratorError::UnknownKey),
+            sessions.consume(&2, Event::Start)
+        );
+        assert_eq!(
+            Err(OrchestratorError::DuplicateKey),
+            sessions.insert(3, session())
+        );
+    }
+
+    #[test]
+    fn test_limit_state_concurrently() {
+        let sessions = Arc::new(MachineMap::new());
+        sessions.limit_state(Session::Downloading, 3);
+        for key in 0..64 {
+            sessions.insert(key, session()).unwrap();
+        }
+
+        let workers: Vec<_> = (0..8)
+            .map(|worker| {
+                let sessions = Arc::clone(&sessions);
+                thread::spawn(move || {
+                    (0..8)
+                        .filter(|n| sessions.consume(&(worker * 8 + n), Event::Start).is_ok())
+                        .count()
+                })
+            })
+            .collect();
+        let admitted: usize = workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .sum();
+
+        assert_eq!(3, admitted);
+        assert_eq!(3, sessions.count_in(&Session::Downloading));
+        assert_eq!(61, sessions.count_in(&Session::Idle));
+    }
+}
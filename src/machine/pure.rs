@@ -0,0 +1,127 @@
+//! The pure transition runners without any state machine, for one-shot passes over inputs.
+//!
+//! They take the same transition function as
+//! [`crate::machine::builder::StateMachineBuilder::transition()`], so the same closure can
+//! drive both a [`crate::machine::BasicStateMachine`] and a pure pass by passing it
+//! by reference.
+
+/// Runs `transition` from `initial` over `inputs` and yields the state after each input,
+/// like [`Iterator::scan()`]. The initial state isn't yielded.
+/// It is lazy: no transition runs until the iterator is advanced.
+///
+/// # Example
+/// ```
+/// use statemachine_rs::machine::{
+///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, pure,
+/// };
+///
+/// let transition = |state: &i32, input: i32| state * 10 + input;
+/// assert_eq!(vec![1, 12, 123], pure::run(0, &transition, vec![1, 2, 3]).collect::<Vec<_>>());
+///
+/// let sm = BasicStateMachineBuilder::start()
+///     .initial_state(0)
+///     .transition(&transition)
+///     .build()
+///     .unwrap();
+/// assert_eq!(123, sm.consume_all_unlimited(vec![1, 2, 3]));
+/// ```
+pub fn run<State, Input, Transition>(
+    initial: State,
+    transition: Transition,
+    inputs: impl IntoIterator<Item = Input>,
+) -> impl Iterator<Item = State>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+{
+    inputs.into_iter().scan(initial, move |state, input| {
+        *state = transition(state, input);
+        Some(state.clone())
+    })
+}
+
+/// Runs `transition` from `initial` over `inputs` and returns the final state.
+/// Unlike [`run()`], it never clones the states.
+///
+/// # Example
+/// ```
+/// use statemachine_rs::machine::pure;
+///
+/// let words = vec!["state", "machine"];
+/// assert_eq!(12, pure::fold_final(0, |length: &usize, word: &str| length + word.len(), words));
+/// ```
+pub fn fold_final<State, Input, Transition>(
+    initial: State,
+    transition: Transition,
+    inputs: impl IntoIterator<Item = Input>,
+) -> State
+where
+    Transition: Fn(&State, Input) -> State,
+{
+    inputs
+        .into_iter()
+        .fold(initial, |state, input| transition(&state, input))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fold_final, run};
+    use crate::machine::builder::{BasicStateMachineBuilder, StateMachineBuilder};
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Stations {
+        Shibuya,
+        IkejiriOhashi,
+        Sangendyaya,
+    }
+
+    #[derive(Copy, Clone)]
+    enum Train {
+        Local,
+        Express,
+    }
+
+    fn transition(station: &Stations, train: Train) -> Stations {
+        match (station, train) {
+            (Stations::Shibuya, Train::Local) => Stations::IkejiriOhashi,
+            (Stations::Shibuya, Train::Express) => Stations::Sangendyaya,
+            (Stations::IkejiriOhashi, Train::Local) => Stations::Sangendyaya,
+            (Stations::IkejiriOhashi, Train::Express) => Stations::Sangendyaya,
+            (Stations::Sangendyaya, _) => Stations::Shibuya,
+        }
+    }
+
+    #[test]
+    fn test_equivalent_to_machine() {
+        let inputs: Vec<_> = (0..100)
+            .map(|n| {
+                if n % 3 == 0 {
+                    Train::Express
+                } else {
+                    Train::Local
+                }
+            })
+            .collect();
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .transition(transition)
+            .with_history()
+            .build()
+            .unwrap();
+        let last = sm.consume_all_unlimited(inputs.clone());
+
+        let states: Vec<_> = run(Stations::Shibuya, transition, inputs.clone()).collect();
+        assert_eq!(sm.history()[1..], states[..]);
+        assert_eq!(last, fold_final(Stations::Shibuya, transition, inputs));
+        assert_eq!(Some(&last), states.last());
+    }
+
+    #[test]
+    fn test_empty_inputs() {
+        assert_eq!(0, run(Stations::Shibuya, transition, Vec::new()).count());
+        assert_eq!(
+            Stations::Shibuya,
+            fold_final(Stations::Shibuya, transition, Vec::new())
+        );
+    }
+}
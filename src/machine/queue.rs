@@ -0,0 +1,81 @@
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+/// The input queued with its priority. The higher priority comes first and
+/// the inputs with the same priority come in the order of enqueuing.
+struct Queued<Input> {
+    priority: u32,
+    sequence: u64,
+    input: Input,
+}
+
+impl<Input> PartialEq for Queued<Input> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<Input> Eq for Queued<Input> {}
+
+impl<Input> PartialOrd for Queued<Input> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Input> Ord for Queued<Input> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+/// The priority queue of inputs owned by the state machine.
+/// See [`crate::machine::BasicStateMachine::enqueue()`].
+pub(crate) struct InputQueue<Input> {
+    heap: BinaryHeap<Queued<Input>>,
+    sequence: u64,
+}
+
+impl<Input> InputQueue<Input> {
+    pub(crate) fn new() -> Self {
+        InputQueue {
+            heap: BinaryHeap::new(),
+            sequence: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub(crate) fn push(&mut self, input: Input, priority: u32) {
+        self.heap.push(Queued {
+            priority,
+            sequence: self.sequence,
+            input,
+        });
+        self.sequence += 1;
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<Input> {
+        self.heap.pop().map(|queued| queued.input)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::InputQueue;
+
+    #[test]
+    fn test_fifo_within_priority() {
+        let mut queue = InputQueue::new();
+        queue.push("a", 1);
+        queue.push("b", 2);
+        queue.push("c", 1);
+        queue.push("d", 2);
+        assert_eq!(4, queue.len());
+        let drained = std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>();
+        assert_eq!(vec!["b", "d", "a", "c"], drained);
+    }
+}
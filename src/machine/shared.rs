@@ -0,0 +1,754 @@
+use std::{
+    collections::VecDeque,
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock,
+    },
+    thread,
+};
+
+use super::{snapshot::MachineStatus, StateMachine};
+
+/// The value published to the readers without locking.
+///
+/// The value is held as a raw pointer from [`Arc::into_raw()`] and readers clone the `Arc`
+/// from it. A reader announces itself on one of the two counters selected by `epoch` before
+/// loading the pointer, and the writer doesn't release the replaced value until both counters
+/// have drained in turn, so that no reader which might have loaded it is left.
+struct Published<T> {
+    ptr: AtomicPtr<T>,
+    epoch: AtomicUsize,
+    readers: [AtomicUsize; 2],
+    writer: Mutex<()>,
+}
+
+impl<T> Published<T> {
+    fn new(value: Arc<T>) -> Self {
+        Published {
+            ptr: AtomicPtr::new(Arc::into_raw(value) as *mut T),
+            epoch: AtomicUsize::new(0),
+            readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Returns the published value. It never blocks.
+    fn load(&self) -> Arc<T> {
+        let readers = &self.readers[self.epoch.load(Ordering::SeqCst) & 1];
+        readers.fetch_add(1, Ordering::SeqCst);
+        let ptr = self.ptr.load(Ordering::SeqCst);
+        // SAFETY: `ptr` comes from `Arc::into_raw()` and the writer keeps the value alive
+        // until this reader leaves the counter, so the strong count is still positive here.
+        let value = unsafe {
+            Arc::increment_strong_count(ptr);
+            Arc::from_raw(ptr)
+        };
+        readers.fetch_sub(1, Ordering::SeqCst);
+        value
+    }
+
+    /// Publishes the value computed from the current one by `f`, serialized with
+    /// the other writers, and returns it.
+    fn update(&self, f: impl FnOnce(&T) -> T) -> Arc<T> {
+        let _writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let new = Arc::new(f(&self.load()));
+        let old = self
+            .ptr
+            .swap(Arc::into_raw(Arc::clone(&new)) as *mut T, Ordering::SeqCst);
+        // Waiting for the next counter first catches the readers which announced themselves
+        // with a stale epoch, then flipping the epoch lets the current counter drain.
+        let current = self.epoch.load(Ordering::SeqCst) & 1;
+        let next = current ^ 1;
+        self.wait_for_readers(next);
+        self.epoch.store(next, Ordering::SeqCst);
+        self.wait_for_readers(current);
+        // SAFETY: `old` comes from `Arc::into_raw()` and no reader can load it anymore.
+        drop(unsafe { Arc::from_raw(old) });
+        new
+    }
+
+    fn wait_for_readers(&self, epoch: usize) {
+        while self.readers[epoch].load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+    }
+}
+
+impl<T> Drop for Published<T> {
+    fn drop(&mut self) {
+        // SAFETY: `ptr` comes from `Arc::into_raw()` and no reader is left on dropping.
+        drop(unsafe { Arc::from_raw(*self.ptr.get_mut()) });
+    }
+}
+
+// SAFETY: `Published` hands out `Arc<T>` across threads like `Arc<T>` itself does.
+unsafe impl<T> Send for Published<T> where T: Send + Sync {}
+// SAFETY: see above. Writers are serialized by `writer`.
+unsafe impl<T> Sync for Published<T> where T: Send + Sync {}
+
+/// The current state published together with what [`SharedStateMachine::status()`]
+/// reports, so that readers always see them consistent.
+struct Current<State, Input> {
+    state: Arc<State>,
+    transition_count: u64,
+    /// The mutex is never locked for writing once published. It only lets the input be
+    /// shared among the readers while it is `Send` but not `Sync`.
+    last_input: Option<Arc<Mutex<Input>>>,
+    faulted: bool,
+}
+
+/// The callback which is called with the previous state, the next state and the input.
+/// See [`SharedStateMachine::add_observer()`].
+type SharedObserver<State, Input> = Arc<dyn Fn(&State, &State, &Input) + Send + Sync>;
+
+/// A transition to be delivered to the observers. The states are shared with the readers,
+/// so that observing never clones them.
+struct Notification<State, Input> {
+    prev: Arc<State>,
+    next: Arc<State>,
+    input: Input,
+}
+
+/// Locks `mutex` ignoring the poisoning, since the callbacks never leave
+/// the guarded data half-updated.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The observers of [`SharedStateMachine`], which are always called without holding
+/// the writer lock.
+struct Notifier<State, Input> {
+    observers: Mutex<Vec<SharedObserver<State, Input>>>,
+    /// Clones the input for the observers. It is set on registering the first observer,
+    /// where `Input: Clone` holds, and the inputs are never cloned until then.
+    clone_input: OnceLock<fn(&Input) -> Input>,
+    /// `true` if the notifications are queued and delivered by a single notifier
+    /// in the transition order. See [`SharedStateMachine::strict_ordering()`].
+    strict: bool,
+    queue: Mutex<VecDeque<Notification<State, Input>>>,
+    delivering: AtomicBool,
+}
+
+/// Releases the notifier role even if an observer panics.
+struct Delivering<'a>(&'a AtomicBool);
+
+impl Drop for Delivering<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl<State, Input> Notifier<State, Input> {
+    fn new() -> Self {
+        Notifier {
+            observers: Mutex::new(Vec::new()),
+            clone_input: OnceLock::new(),
+            strict: false,
+            queue: Mutex::new(VecDeque::new()),
+            delivering: AtomicBool::new(false),
+        }
+    }
+
+    /// Queues `notification` in the strict ordering mode, or returns it to be delivered
+    /// right away otherwise. It is called while the writer lock is held, so that
+    /// the queue follows the transition order.
+    fn publish(
+        &self,
+        notification: Notification<State, Input>,
+    ) -> Option<Notification<State, Input>> {
+        if self.strict {
+            lock(&self.queue).push_back(notification);
+            None
+        } else {
+            Some(notification)
+        }
+    }
+
+    /// Delivers `notification`, or the queued ones if it is `None`.
+    fn notify(&self, notification: Option<Notification<State, Input>>) {
+        match notification {
+            Some(notification) => self.call(&notification),
+            None => self.deliver(),
+        }
+    }
+
+    /// Delivers the queued notifications in order unless another thread, or an outer
+    /// call on this thread, is already delivering them. The notifier keeps going until
+    /// the queue is empty, so no queued notification is left behind.
+    fn deliver(&self) {
+        loop {
+            if self
+                .delivering
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+            {
+                return;
+            }
+            {
+                let _delivering = Delivering(&self.delivering);
+                loop {
+                    let notification = lock(&self.queue).pop_front();
+                    match notification {
+                        Some(notification) => self.call(&notification),
+                        None => break,
+                    }
+                }
+            }
+            // A notification queued after the last pop and before the release
+            // would be missed by its own thread, so check again.
+            if lock(&self.queue).is_empty() {
+                return;
+            }
+        }
+    }
+
+    fn call(&self, notification: &Notification<State, Input>) {
+        let observers = lock(&self.observers).clone();
+        for observer in observers {
+            observer(&notification.prev, &notification.next, &notification.input);
+        }
+    }
+}
+
+/// A state machine which can be shared among threads for read-heavy workloads.
+/// The current state is published as `Arc<State>`, so reading it via
+/// [`SharedStateMachine::load()`] or peeking never takes a lock and never waits for writers.
+/// The writers, i.e. `consume`, `reset` and `set`, are serialized by a small mutex.
+///
+/// It is `Send` and `Sync` if the state is `Send + Sync`, the input is `Send` and
+/// the transition is `Send + Sync`. The input must be `Send` since the observers may be
+/// notified of it on another thread.
+///
+/// # Example
+/// ```
+/// use std::{sync::Arc, thread};
+/// use statemachine_rs::machine::{shared::SharedStateMachine, StateMachine};
+///
+/// let sm = Arc::new(SharedStateMachine::new(0, |count: &u32, step: u32| count + step));
+/// let handles: Vec<_> = (0..4)
+///     .map(|_| {
+///         let sm = Arc::clone(&sm);
+///         thread::spawn(move || sm.consume(1))
+///     })
+///     .collect();
+/// for handle in handles {
+///     handle.join().unwrap();
+/// }
+/// assert_eq!(4, *sm.load());
+/// ```
+pub struct SharedStateMachine<State, Input, Transition>
+where
+    Transition: Fn(&State, Input) -> State,
+{
+    initial_state: State,
+    current_state: Published<Current<State, Input>>,
+    transition: Transition,
+    clone_last_input: Option<fn(&Input) -> Input>,
+    notifier: Notifier<State, Input>,
+    _marker: PhantomData<fn(Input)>,
+}
+
+impl<State, Input, Transition> SharedStateMachine<State, Input, Transition>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+{
+    /// Creates a new state machine starting from `initial_state`.
+    pub fn new(initial_state: State, transition: Transition) -> Self {
+        SharedStateMachine {
+            current_state: Published::new(Arc::new(Current {
+                state: Arc::new(initial_state.clone()),
+                transition_count: 0,
+                last_input: None,
+                faulted: false,
+            })),
+            initial_state,
+            transition,
+            clone_last_input: None,
+            notifier: Notifier::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Queues the notifications to the observers and delivers them from a single notifier
+    /// in the transition order, instead of calling the observers on each consuming thread.
+    /// Note that `consume` may then return before its own observers are called, since
+    /// another thread or an outer call on this thread may be delivering them.
+    ///
+    /// # Example
+    /// ```
+    /// use std::sync::{Arc, Mutex};
+    /// use statemachine_rs::machine::{shared::SharedStateMachine, StateMachine};
+    ///
+    /// let sm = SharedStateMachine::new(0, |count: &u32, step: u32| count + step).strict_ordering();
+    /// let log = Arc::new(Mutex::new(Vec::new()));
+    /// let observed = Arc::clone(&log);
+    /// sm.add_observer(move |prev: &u32, next: &u32, _: &u32| {
+    ///     observed.lock().unwrap().push((*prev, *next))
+    /// });
+    ///
+    /// sm.consume(1);
+    /// sm.consume(2);
+    /// assert_eq!(vec![(0, 1), (1, 3)], *log.lock().unwrap());
+    /// ```
+    pub fn strict_ordering(mut self) -> Self {
+        self.notifier.strict = true;
+        self
+    }
+
+    /// Registers `observer` which is called with the previous state, the next state and
+    /// the input on every transition via [`StateMachine::consume()`]. The states are passed
+    /// without being cloned, and the input is cloned only once observers are registered.
+    ///
+    /// The observers are called after the writer lock is released, so they may freely call
+    /// back into the state machine, e.g. [`StateMachine::current_state()`] or even
+    /// [`StateMachine::consume()`]. As a result, a transition on another thread may interleave
+    /// before the observers of a transition run, and the observers on different threads may
+    /// see the transitions out of order. Use [`SharedStateMachine::strict_ordering()`] if
+    /// the order matters.
+    pub fn add_observer<F>(&self, observer: F)
+    where
+        F: Fn(&State, &State, &Input) + Send + Sync + 'static,
+        Input: Clone,
+    {
+        lock(&self.notifier.observers).push(Arc::new(observer));
+        self.notifier.clone_input.get_or_init(|| Input::clone);
+    }
+
+    /// Returns the current state without cloning it nor taking a lock.
+    pub fn load(&self) -> Arc<State> {
+        Arc::clone(&self.current_state.load().state)
+    }
+
+    /// Returns the current state, the last input, the transition count and whether the last
+    /// transition panicked, published together without taking a lock, so that they always
+    /// match each other even while writers run. The transition count includes only
+    /// the consumed transitions, not `reset` nor `set`. The last input is reported only if
+    /// [`SharedStateMachine::track_last_input()`] is called, and the time in the state
+    /// isn't tracked.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{shared::SharedStateMachine, StateMachine};
+    ///
+    /// let sm = SharedStateMachine::new(0, |count: &u32, step: u32| count + step)
+    ///     .track_last_input();
+    /// sm.consume(2);
+    /// sm.set(5);
+    ///
+    /// let status = sm.status();
+    /// assert_eq!(5, status.state);
+    /// assert_eq!(Some(2), status.last_input);
+    /// assert_eq!(1, status.transition_count);
+    /// assert!(!status.is_faulted);
+    /// ```
+    pub fn status(&self) -> MachineStatus<State, Input>
+    where
+        Input: Clone,
+    {
+        let current = self.current_state.load();
+        MachineStatus {
+            state: State::clone(&current.state),
+            last_input: current.last_input.as_ref().map(|input| lock(input).clone()),
+            transition_count: current.transition_count,
+            is_faulted: current.faulted,
+            time_in_state: None,
+        }
+    }
+
+    /// Enables keeping the input of the last consumed transition.
+    /// See [`SharedStateMachine::status()`].
+    pub fn track_last_input(mut self) -> Self
+    where
+        Input: Clone,
+    {
+        self.clone_last_input = Some(Input::clone);
+        self
+    }
+
+    /// Publishes the state computed from the current one by `f` outside the transition.
+    fn update(&self, f: impl FnOnce(&State) -> State) {
+        self.current_state.update(|current| Current {
+            state: Arc::new(f(&current.state)),
+            transition_count: current.transition_count,
+            last_input: current.last_input.clone(),
+            faulted: false,
+        });
+    }
+
+    /// Publishes the state computed from the current one by `f` as a consumed transition
+    /// by `input` and returns it with the result of `then`, which is called with
+    /// the previous and the new states while the writer lock is still held.
+    /// If `f` panics, the current state is published again as faulted and the panic resumes.
+    fn update_with<R>(
+        &self,
+        f: impl FnOnce(&State) -> State,
+        input: Option<Input>,
+        then: impl FnOnce(&Arc<State>, &Arc<State>) -> R,
+    ) -> (Arc<State>, R) {
+        let mut result = None;
+        let mut fault = None;
+        let current = self.current_state.update(|current| {
+            match panic::catch_unwind(AssertUnwindSafe(|| f(&current.state))) {
+                Ok(state) => {
+                    let state = Arc::new(state);
+                    result = Some(then(&current.state, &state));
+                    Current {
+                        state,
+                        transition_count: current.transition_count + 1,
+                        last_input: input
+                            .map(|input| Arc::new(Mutex::new(input)))
+                            .or_else(|| current.last_input.clone()),
+                        faulted: false,
+                    }
+                }
+                Err(payload) => {
+                    fault = Some(payload);
+                    Current {
+                        state: Arc::clone(&current.state),
+                        transition_count: current.transition_count,
+                        last_input: current.last_input.clone(),
+                        faulted: true,
+                    }
+                }
+            }
+        });
+        if let Some(payload) = fault {
+            panic::resume_unwind(payload);
+        }
+        (
+            Arc::clone(&current.state),
+            result.expect("the update always runs the transition"),
+        )
+    }
+
+    /// Transits by `input`, computing the response by `respond` under the writer lock,
+    /// and then notifies the observers if any.
+    fn transit<R>(
+        &self,
+        input: Input,
+        respond: impl FnOnce(&State, &State) -> R,
+    ) -> (Arc<State>, R) {
+        let last_input = self.clone_last_input.map(|clone_input| clone_input(&input));
+        let clone_input = match self.notifier.clone_input.get() {
+            Some(clone_input) => clone_input,
+            None => {
+                return self.update_with(
+                    |current| (self.transition)(current, input),
+                    last_input,
+                    |prev, next| respond(prev, next),
+                )
+            }
+        };
+        let observed = clone_input(&input);
+        let (state, (response, notification)) = self.update_with(
+            |current| (self.transition)(current, input),
+            last_input,
+            |prev, next| {
+                let response = respond(prev, next);
+                let notification = self.notifier.publish(Notification {
+                    prev: Arc::clone(prev),
+                    next: Arc::clone(next),
+                    input: observed,
+                });
+                (response, notification)
+            },
+        );
+        self.notifier.notify(notification);
+        (state, response)
+    }
+}
+
+impl<State, Input, Transition> StateMachine<State, Input>
+    for SharedStateMachine<State, Input, Transition>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+{
+    fn current_state(&self) -> State {
+        State::clone(&self.load())
+    }
+
+    fn consume(&self, input: Input) -> State {
+        State::clone(&self.transit(input, |_, _| ()).0)
+    }
+
+    fn peek(&self, input: Input) -> State {
+        (self.transition)(&self.load(), input)
+    }
+
+    fn consume_respond<R, F>(&self, input: Input, respond: F) -> R
+    where
+        F: FnOnce(&State, &State) -> R,
+    {
+        // The responder runs while the writer lock is held, so that no other input
+        // is consumed between the transition and the response.
+        self.transit(input, respond).1
+    }
+
+    fn reset(&self) -> State {
+        self.update(|_| self.initial_state.clone());
+        self.initial_state.clone()
+    }
+
+    fn set(&self, new_state: State) {
+        self.update(|_| new_state);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+    };
+
+    use super::SharedStateMachine;
+    use crate::machine::StateMachine;
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Light {
+        Red(String),
+        Yellow(String),
+        Green(String),
+    }
+
+    impl Light {
+        fn is_legal(&self) -> bool {
+            match self {
+                Light::Red(label) => label == "stop",
+                Light::Yellow(label) => label == "caution",
+                Light::Green(label) => label == "go",
+            }
+        }
+    }
+
+    #[test]
+    fn test_readers_never_see_torn_states() {
+        let sm = Arc::new(SharedStateMachine::new(
+            Light::Red("stop".to_string()),
+            |light: &Light, _: ()| match light {
+                Light::Red(_) => Light::Green("go".to_string()),
+                Light::Green(_) => Light::Yellow("caution".to_string()),
+                Light::Yellow(_) => Light::Red("stop".to_string()),
+            },
+        ));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..8)
+            .map(|_| {
+                let (sm, done) = (Arc::clone(&sm), Arc::clone(&done));
+                thread::spawn(move || {
+                    while !done.load(Ordering::SeqCst) {
+                        assert!(sm.load().is_legal());
+                        assert!(sm.peek(()).is_legal());
+                    }
+                })
+            })
+            .collect();
+
+        for _ in 0..10_000 {
+            sm.consume(());
+        }
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        // 10,000 = 3 * 3,333 + 1
+        assert_eq!(Light::Green("go".to_string()), sm.current_state());
+        assert_eq!(Light::Red("stop".to_string()), sm.reset());
+        sm.set(Light::Yellow("caution".to_string()));
+        assert_eq!(Light::Yellow("caution".to_string()), *sm.load());
+    }
+
+    #[derive(Debug)]
+    struct Receipt {
+        accepted: bool,
+        balance_before: u32,
+        balance_after: u32,
+    }
+
+    #[test]
+    fn test_consume_respond() {
+        let sm = Arc::new(SharedStateMachine::new(
+            20_000,
+            |balance: &u32, amount: u32| balance.checked_sub(amount).unwrap_or(*balance),
+        ));
+
+        let clients: Vec<_> = (0..4)
+            .map(|_| {
+                let sm = Arc::clone(&sm);
+                thread::spawn(move || {
+                    (0..6_000)
+                        .map(|_| {
+                            sm.consume_respond(1, |before: &u32, after: &u32| Receipt {
+                                accepted: before != after,
+                                balance_before: *before,
+                                balance_after: *after,
+                            })
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut receipts: Vec<_> = clients
+            .into_iter()
+            .flat_map(|client| client.join().unwrap())
+            .collect();
+
+        // Every withdrawal is answered with the balances around itself,
+        // so no two accepted withdrawals see the same balance.
+        let (mut accepted, rejected): (Vec<_>, Vec<_>) =
+            receipts.drain(..).partition(|receipt| receipt.accepted);
+        accepted.sort_by_key(|receipt| receipt.balance_before);
+        assert_eq!(20_000, accepted.len());
+        for (balance, receipt) in (1..=20_000).zip(&accepted) {
+            assert_eq!(balance, receipt.balance_before);
+            assert_eq!(balance - 1, receipt.balance_after);
+        }
+        assert_eq!(4_000, rejected.len());
+        assert!(rejected
+            .iter()
+            .all(|receipt| receipt.balance_before == 0 && receipt.balance_after == 0));
+        assert_eq!(0, *sm.load());
+    }
+
+    #[test]
+    fn test_status_is_never_torn() {
+        // The state is always the number of transitions mod 3 as the label.
+        let sm = Arc::new(
+            SharedStateMachine::new("0".to_string(), |state: &String, _: ()| {
+                ((state.parse::<u64>().unwrap() + 1) % 3).to_string()
+            })
+            .track_last_input(),
+        );
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..8)
+            .map(|_| {
+                let (sm, done) = (Arc::clone(&sm), Arc::clone(&done));
+                thread::spawn(move || {
+                    while !done.load(Ordering::SeqCst) {
+                        let status = sm.status();
+                        assert_eq!((status.transition_count % 3).to_string(), status.state);
+                        assert_eq!(status.transition_count > 0, status.last_input.is_some());
+                        assert!(!status.is_faulted);
+                        assert_eq!(None, status.time_in_state);
+                    }
+                })
+            })
+            .collect();
+
+        let writers: Vec<_> = (0..2)
+            .map(|_| {
+                let sm = Arc::clone(&sm);
+                thread::spawn(move || {
+                    for _ in 0..5_000 {
+                        sm.consume(());
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(10_000, sm.status().transition_count);
+        // `reset` isn't a consumed transition
+        sm.reset();
+        assert_eq!(10_000, sm.status().transition_count);
+    }
+
+    #[test]
+    fn test_status_reports_fault() {
+        let sm =
+            SharedStateMachine::new(10, |count: &u32, step: u32| count / step).track_last_input();
+        sm.consume(2);
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| sm.consume(0))).is_err());
+
+        let status = sm.status();
+        assert_eq!(5, status.state);
+        assert_eq!(Some(2), status.last_input);
+        assert_eq!(1, status.transition_count);
+        assert!(status.is_faulted);
+
+        sm.consume(5);
+        let status = sm.status();
+        assert_eq!(Some(5), status.last_input);
+        assert_eq!(2, status.transition_count);
+        assert!(!status.is_faulted);
+    }
+
+    #[test]
+    fn test_observer_calls_back_into_machine() {
+        let sm = Arc::new(SharedStateMachine::new(0, |count: &u32, step: u32| {
+            count + step
+        }));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        {
+            let machine = Arc::downgrade(&sm);
+            let log = Arc::clone(&log);
+            sm.add_observer(move |prev: &u32, next: &u32, step: &u32| {
+                let sm = machine.upgrade().unwrap();
+                // Neither reading nor consuming blocks, since the writer lock is released.
+                assert_eq!(*next, sm.current_state());
+                if *step == 1 {
+                    sm.consume(10);
+                }
+                log.lock().unwrap().push((*prev, *next, *step));
+            });
+        }
+
+        assert_eq!(1, sm.consume(1));
+        assert_eq!(11, sm.current_state());
+        assert_eq!(vec![(1, 11, 10), (0, 1, 1)], *log.lock().unwrap());
+    }
+
+    #[test]
+    fn test_strict_ordering() {
+        let sm =
+            Arc::new(SharedStateMachine::new(0, |count: &u64, _: ()| count + 1).strict_ordering());
+        let log = Arc::new(Mutex::new(Vec::new()));
+        {
+            let log = Arc::clone(&log);
+            sm.add_observer(move |prev: &u64, next: &u64, _: &()| {
+                log.lock().unwrap().push((*prev, *next))
+            });
+        }
+
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let sm = Arc::clone(&sm);
+                thread::spawn(move || {
+                    for _ in 0..2_500 {
+                        sm.consume(());
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        // Every transition is delivered once, in the order of the transitions.
+        let log = log.lock().unwrap();
+        assert_eq!(10_000, log.len());
+        for (count, transition) in (0..10_000).zip(log.iter()) {
+            assert_eq!((count, count + 1), *transition);
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use std::{collections::HashSet, hash::Hash, time::Duration};
+
+/// A snapshot of the states of a state machine, which can be persisted and
+/// restored later by passing the fields to the builder via `initial_state` and
+/// `current_state`, or via [`crate::machine::BasicStateMachine::restore()`].
+/// See [`crate::machine::BasicStateMachine::snapshot()`] and
+/// [`crate::machine::table::TableStateMachine::snapshot()`].
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot<State> {
+    /// The initial state of the state machine.
+    pub initial_state: State,
+    /// The current state of the state machine at the time of the snapshot.
+    pub current_state: State,
+    /// The epoch of the state machine at the time of the snapshot.
+    /// See [`crate::machine::BasicStateMachine::epoch()`].
+    /// It is always `0` for the table state machine, which doesn't track it.
+    pub epoch: u64,
+}
+
+/// A snapshot of a state machine including its counters and timestamps in addition to
+/// the states, for persisting the observability as well.
+/// It can be restored via [`crate::machine::builder::BasicStateMachineBuilder::restore_full()`].
+/// See [`crate::machine::BasicStateMachine::full_snapshot()`].
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        serialize = "State: serde::Serialize",
+        deserialize = "State: serde::Deserialize<'de> + Eq + Hash"
+    ))
+)]
+pub struct FullSnapshot<State> {
+    /// The initial state of the state machine.
+    pub initial_state: State,
+    /// The current state of the state machine at the time of the snapshot.
+    pub current_state: State,
+    /// The epoch of the state machine. See [`crate::machine::BasicStateMachine::epoch()`].
+    pub epoch: u64,
+    /// The number of the transitions consumed up to the current state. Changing the state
+    /// outside the transition, e.g. via `reset` or `set`, doesn't count.
+    /// See [`MachineStatus::transition_count`].
+    pub transition_count: u64,
+    /// The number of times the current state has been changed, including the changes
+    /// outside the transition. See [`crate::machine::BasicStateMachine::generation()`].
+    pub generation: u64,
+    /// The states the machine has ever been in.
+    /// It is empty unless [`crate::machine::builder::BasicStateMachineBuilder::track_visited()`]
+    /// is called. See [`crate::machine::BasicStateMachine::visited_states()`].
+    pub visited: HashSet<State>,
+    /// The milliseconds elapsed since the machine entered the current state.
+    /// See [`crate::machine::BasicStateMachine::state_entered_at()`].
+    pub entered_ms_ago: u64,
+}
+
+impl<State> PartialEq for FullSnapshot<State>
+where
+    State: Eq + Hash,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.initial_state == other.initial_state
+            && self.current_state == other.current_state
+            && self.epoch == other.epoch
+            && self.transition_count == other.transition_count
+            && self.generation == other.generation
+            && self.visited == other.visited
+            && self.entered_ms_ago == other.entered_ms_ago
+    }
+}
+
+/// The status of a state machine captured at once, so that the fields are consistent
+/// with each other even if the machine is changed concurrently.
+/// See [`crate::machine::BasicStateMachine::status()`] and
+/// [`crate::machine::shared::SharedStateMachine::status()`].
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MachineStatus<State, Input> {
+    /// The current state of the state machine.
+    pub state: State,
+    /// The input of the last transition consumed up to `state`. It is `None` until
+    /// an input is consumed or unless the machine is built to track it.
+    pub last_input: Option<Input>,
+    /// The number of the transitions consumed up to `state`. Changing the state outside
+    /// the transition, e.g. via `reset` or `set`, doesn't count.
+    pub transition_count: u64,
+    /// `true` if the transition function panicked on the last consume and the state
+    /// hasn't changed since, i.e. `state` is the one the machine was left in.
+    pub is_faulted: bool,
+    /// The time elapsed since the machine entered `state`, measured by the clock of the machine.
+    /// It is `None` for the machines which don't track the time.
+    pub time_in_state: Option<Duration>,
+}
+
+/// The policy applied when a stale snapshot is restored.
+/// See [`crate::machine::BasicStateMachine::restore()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StalePolicy {
+    /// The snapshot is rejected with [`crate::machine::error::StateMachineError::StaleSnapshot`].
+    /// This is the default.
+    Reject,
+    /// The snapshot is restored anyway, and the staleness is reported as
+    /// [`crate::machine::error::StateMachineError::StaleSnapshot`] to the hook set via
+    /// [`crate::machine::builder::BasicStateMachineBuilder::on_stale_snapshot()`], if any.
+    Warn,
+}
+
+/// Migrates `snapshot` from the old state type to the new one by mapping both
+/// the initial and the current state through `f`. It is useful when the state type
+/// evolves (e.g. variants are added or renamed) while old snapshots are persisted.
+///
+/// # Example
+/// ```
+/// use statemachine_rs::machine::snapshot::{migrate_snapshot, Snapshot};
+///
+/// let old = Snapshot {
+///     initial_state: 0u8,
+///     current_state: 1u8,
+///     epoch: 0,
+/// };
+/// let new = migrate_snapshot(old, |state| state == 1);
+/// assert_eq!(false, new.initial_state);
+/// assert_eq!(true, new.current_state);
+/// ```
+pub fn migrate_snapshot<Old, New>(
+    snapshot: Snapshot<Old>,
+    f: impl Fn(Old) -> New,
+) -> Snapshot<New> {
+    Snapshot {
+        initial_state: f(snapshot.initial_state),
+        current_state: f(snapshot.current_state),
+        epoch: snapshot.epoch,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::{migrate_snapshot, Snapshot, StalePolicy};
+    use crate::machine::{
+        builder::{BasicStateMachineBuilder, StateMachineBuilder},
+        error::StateMachineError,
+        time::ManualClock,
+        StateMachine,
+    };
+
+    #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+    enum OldDoor {
+        Opened,
+        Closed,
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum NewDoor {
+        Opened,
+        Closed,
+        #[allow(dead_code)]
+        Locked,
+    }
+
+    #[test]
+    fn test_restore_stale_snapshot() {
+        let builder = || {
+            BasicStateMachineBuilder::start()
+                .initial_state(OldDoor::Closed)
+                .transition(|door, _: ()| match door {
+                    OldDoor::Opened => OldDoor::Closed,
+                    OldDoor::Closed => OldDoor::Opened,
+                })
+        };
+
+        let sm = builder().build().unwrap();
+        let snapshot = sm.snapshot();
+        sm.consume(());
+        // consuming doesn't make the snapshot stale
+        sm.restore(snapshot.clone()).unwrap();
+        assert_eq!(OldDoor::Closed, sm.current_state());
+
+        sm.set(OldDoor::Opened);
+        match sm.restore(snapshot.clone()) {
+            Err(StateMachineError::StaleSnapshot { snapshot, actual }) => {
+                assert_eq!((0, 2), (snapshot, actual))
+            }
+            _ => panic!("the stale snapshot must be rejected"),
+        }
+        assert_eq!(OldDoor::Opened, sm.current_state());
+        sm.restore(sm.snapshot()).unwrap();
+
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let sm = {
+            let warnings = Arc::clone(&warnings);
+            builder()
+                .stale_snapshot_policy(StalePolicy::Warn)
+                .on_stale_snapshot(move |error| match error {
+                    StateMachineError::StaleSnapshot { snapshot, actual } => {
+                        warnings.lock().unwrap().push((*snapshot, *actual))
+                    }
+                    _ => panic!("only the stale snapshot is reported"),
+                })
+                .build()
+                .unwrap()
+        };
+        let opened = {
+            sm.consume(());
+            sm.snapshot()
+        };
+        sm.reset();
+        sm.restore(opened).unwrap();
+        assert_eq!(OldDoor::Opened, sm.current_state());
+        assert_eq!(vec![(0, 1)], *warnings.lock().unwrap());
+    }
+
+    #[test]
+    fn test_migrate_snapshot() {
+        let old = BasicStateMachineBuilder::start()
+            .initial_state(OldDoor::Closed)
+            .transition(|door, _: ()| match door {
+                OldDoor::Opened => OldDoor::Closed,
+                OldDoor::Closed => OldDoor::Opened,
+            })
+            .build()
+            .unwrap();
+        old.consume(());
+
+        let snapshot = migrate_snapshot(old.snapshot(), |door| match door {
+            OldDoor::Opened => NewDoor::Opened,
+            OldDoor::Closed => NewDoor::Closed,
+        });
+        assert_eq!(
+            Snapshot {
+                initial_state: NewDoor::Closed,
+                current_state: NewDoor::Opened,
+                epoch: 0,
+            },
+            snapshot
+        );
+
+        let new = BasicStateMachineBuilder::start()
+            .initial_state(snapshot.initial_state)
+            .current_state(snapshot.current_state)
+            .transition(|door, _: ()| match door {
+                NewDoor::Opened => NewDoor::Closed,
+                NewDoor::Closed | NewDoor::Locked => NewDoor::Opened,
+            })
+            .build()
+            .unwrap();
+        assert_eq!(NewDoor::Opened, new.current_state());
+        assert_eq!(NewDoor::Closed, new.reset());
+    }
+
+    #[test]
+    fn test_full_snapshot_round_trip() {
+        let clock = Arc::new(ManualClock::new());
+        let builder = BasicStateMachineBuilder::start()
+            .transition(|door, _: ()| match door {
+                OldDoor::Opened => OldDoor::Closed,
+                OldDoor::Closed => OldDoor::Opened,
+            })
+            .clock(Arc::clone(&clock))
+            .track_visited();
+        let sm = builder
+            .clone()
+            .initial_state(OldDoor::Closed)
+            .build()
+            .unwrap();
+        sm.consume(());
+        sm.consume(());
+        sm.set(OldDoor::Opened);
+        clock.advance(Duration::from_millis(1500));
+
+        let snapshot = sm.full_snapshot();
+        assert_eq!(OldDoor::Opened, snapshot.current_state);
+        assert_eq!(1, snapshot.epoch);
+        // `set` changes the state but isn't a transition
+        assert_eq!(2, snapshot.transition_count);
+        assert_eq!(sm.status().transition_count, snapshot.transition_count);
+        assert_eq!(3, snapshot.generation);
+        assert_eq!(2, snapshot.visited.len());
+        assert_eq!(1500, snapshot.entered_ms_ago);
+
+        let restored = builder.restore_full(snapshot.clone()).build().unwrap();
+        assert_eq!(snapshot, restored.full_snapshot());
+        assert_eq!(2, restored.status().transition_count);
+        assert_eq!(3, restored.generation());
+        assert_eq!(1, restored.epoch());
+
+        // the restored counters keep counting separately
+        restored.consume(());
+        restored.set(OldDoor::Opened);
+        let snapshot = restored.full_snapshot();
+        assert_eq!(3, snapshot.transition_count);
+        assert_eq!(5, snapshot.generation);
+        assert_eq!(2, snapshot.epoch);
+        assert_eq!(OldDoor::Closed, restored.reset());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_full_snapshot_serde_round_trip() {
+        use super::FullSnapshot;
+
+        let clock = Arc::new(ManualClock::new());
+        let builder = BasicStateMachineBuilder::start()
+            .initial_state(0u32)
+            .transition(|count: &u32, step: u32| count + step)
+            .clock(Arc::clone(&clock))
+            .track_visited();
+        let sm = builder.clone().build().unwrap();
+        sm.consume(1);
+        sm.consume(2);
+        clock.advance(Duration::from_millis(250));
+
+        let snapshot = sm.full_snapshot();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let persisted: FullSnapshot<u32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot, persisted);
+
+        let restored = builder.restore_full(persisted).build().unwrap();
+        assert_eq!(3, restored.current_state());
+        assert_eq!(snapshot, restored.full_snapshot());
+
+        let status = sm.status();
+        let json = serde_json::to_string(&status).unwrap();
+        assert_eq!(status, serde_json::from_str(&json).unwrap());
+    }
+}
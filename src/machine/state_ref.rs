@@ -0,0 +1,27 @@
+use std::{cell::Ref, ops::Deref};
+
+/// A guarded reference to the current state of the state machine.
+/// It is created by [`crate::machine::BasicStateMachine::current_state_ref()`].
+///
+/// It lets you inspect the current state without cloning it.
+/// The guard holds the borrow of the current state while it is alive, so
+/// transiting the state machine (e.g. `consume`, `reset`, `set`) while the guard
+/// is alive causes a panic. Drop the guard (or narrow its scope) before transiting.
+/// The guard can't outlive the state machine and can't be sent to another thread.
+pub struct StateRef<'a, State> {
+    state: Ref<'a, State>,
+}
+
+impl<'a, State> StateRef<'a, State> {
+    pub(crate) fn new(state: Ref<'a, State>) -> Self {
+        StateRef { state }
+    }
+}
+
+impl<State> Deref for StateRef<'_, State> {
+    type Target = State;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+};
+
+/// The function which increments the counter of the state.
+/// It is captured where `State: Eq + Hash` is available, so that the machine
+/// itself doesn't have to require those bounds.
+pub(crate) type Count<State> = fn(&mut HashMap<State, usize>, &State);
+
+pub(crate) fn count<State>(counters: &mut HashMap<State, usize>, state: &State)
+where
+    State: Clone + Eq + Hash,
+{
+    *counters.entry(state.clone()).or_default() += 1;
+}
+
+/// The function which inserts the state into the set.
+/// It is captured where `State: Eq + Hash` is available like [`Count`].
+pub(crate) type Insert<State> = fn(&mut HashSet<State>, &State);
+
+pub(crate) fn insert<State>(states: &mut HashSet<State>, state: &State)
+where
+    State: Clone + Eq + Hash,
+{
+    if !states.contains(state) {
+        states.insert(state.clone());
+    }
+}
+
+/// The function which folds the state into the running hash.
+/// It is captured where `State: Hash` is available like [`Count`].
+pub(crate) type Fold<State> = fn(u64, &State) -> u64;
+
+pub(crate) fn fold<State>(hash: u64, state: &State) -> u64
+where
+    State: Hash,
+{
+    // `DefaultHasher::new()` always uses the same keys, so the hash is reproducible.
+    let mut hasher = DefaultHasher::new();
+    hash.hash(&mut hasher);
+    state.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The running hash of the states the machine has been in, in order.
+pub(crate) struct Trajectory<State> {
+    hash: Cell<u64>,
+    fold: Fold<State>,
+}
+
+impl<State> Trajectory<State> {
+    pub(crate) fn new(fold: Fold<State>, current: &State) -> Self {
+        Trajectory {
+            hash: Cell::new(fold(0, current)),
+            fold,
+        }
+    }
+
+    pub(crate) fn record(&self, state: &State) {
+        self.hash.set((self.fold)(self.hash.get(), state))
+    }
+
+    /// Starts over, so that the next recorded state is folded as if the machine
+    /// were just built in it.
+    pub(crate) fn restart(&self) {
+        self.hash.set(0)
+    }
+
+    pub(crate) fn get(&self) -> u64 {
+        self.hash.get()
+    }
+
+    pub(crate) fn restore(&self, hash: u64) {
+        self.hash.set(hash)
+    }
+}
+
+/// The set of the states the machine has ever been in.
+pub(crate) struct Visited<State> {
+    states: RefCell<HashSet<State>>,
+    insert: Insert<State>,
+}
+
+impl<State> Visited<State> {
+    pub(crate) fn new(insert: Insert<State>, mut states: HashSet<State>, current: &State) -> Self {
+        insert(&mut states, current);
+        Visited {
+            states: RefCell::new(states),
+            insert,
+        }
+    }
+
+    pub(crate) fn record(&self, state: &State) {
+        (self.insert)(&mut self.states.borrow_mut(), state)
+    }
+
+    pub(crate) fn get(&self) -> HashSet<State>
+    where
+        State: Clone,
+    {
+        self.states.borrow().clone()
+    }
+
+    pub(crate) fn restore(&self, states: HashSet<State>) {
+        *self.states.borrow_mut() = states;
+    }
+}
+
+/// The number of transitions resulting in each state.
+pub(crate) struct Frequencies<State> {
+    counters: RefCell<HashMap<State, usize>>,
+    count: Count<State>,
+}
+
+impl<State> Frequencies<State> {
+    pub(crate) fn new(count: Count<State>) -> Self {
+        Frequencies {
+            counters: RefCell::new(HashMap::new()),
+            count,
+        }
+    }
+
+    pub(crate) fn record(&self, state: &State) {
+        (self.count)(&mut self.counters.borrow_mut(), state)
+    }
+
+    pub(crate) fn restore(&self, counters: HashMap<State, usize>) {
+        *self.counters.borrow_mut() = counters;
+    }
+
+    pub(crate) fn get(&self) -> HashMap<State, usize>
+    where
+        State: Clone,
+    {
+        self.counters.borrow().clone()
+    }
+}
+
+/// The function which clones the consumed input to be reported as the last one.
+/// It is captured where `Input: Clone` is available like [`Count`].
+pub(crate) type CloneInput<Input> = fn(&Input) -> Input;
+
+/// What [`crate::machine::BasicStateMachine::status()`] reports besides the current state.
+pub(crate) struct Telemetry<Input> {
+    transitions: Cell<u64>,
+    faulted: Cell<bool>,
+    clone_input: Option<CloneInput<Input>>,
+    last_input: RefCell<Option<Input>>,
+    /// `true` if the time in the state is reported. See
+    /// [`crate::machine::builder::BasicStateMachineBuilder::track_time_in_state()`].
+    timed: bool,
+}
+
+impl<Input> Telemetry<Input> {
+    pub(crate) fn new(clone_input: Option<CloneInput<Input>>, timed: bool) -> Self {
+        Telemetry {
+            transitions: Cell::new(0),
+            faulted: Cell::new(false),
+            clone_input,
+            last_input: RefCell::new(None),
+            timed,
+        }
+    }
+
+    /// Clones `input` if the last input is tracked.
+    pub(crate) fn observe(&self, input: &Input) -> Option<Input> {
+        self.clone_input.map(|clone_input| clone_input(input))
+    }
+
+    /// Counts a consumed transition and keeps its input if it is tracked.
+    pub(crate) fn record(&self, input: Option<Input>) {
+        self.transitions.set(self.transitions.get() + 1);
+        if input.is_some() {
+            *self.last_input.borrow_mut() = input;
+        }
+    }
+
+    pub(crate) fn set_faulted(&self, faulted: bool) {
+        self.faulted.set(faulted)
+    }
+
+    pub(crate) fn transitions(&self) -> u64 {
+        self.transitions.get()
+    }
+
+    /// Carries over the transitions counted before the machine is restored.
+    pub(crate) fn set_transitions(&self, transitions: u64) {
+        self.transitions.set(transitions)
+    }
+
+    pub(crate) fn is_faulted(&self) -> bool {
+        self.faulted.get()
+    }
+
+    pub(crate) fn last_input(&self) -> Option<Input> {
+        let clone_input = self.clone_input?;
+        self.last_input.borrow().as_ref().map(clone_input)
+    }
+
+    pub(crate) fn is_timed(&self) -> bool {
+        self.timed
+    }
+
+    pub(crate) fn snapshot(&self) -> TelemetrySnapshot<Input> {
+        TelemetrySnapshot {
+            transitions: self.transitions(),
+            faulted: self.is_faulted(),
+            last_input: self.last_input(),
+        }
+    }
+
+    pub(crate) fn restore(&self, snapshot: TelemetrySnapshot<Input>) {
+        self.transitions.set(snapshot.transitions);
+        self.faulted.set(snapshot.faulted);
+        *self.last_input.borrow_mut() = snapshot.last_input;
+    }
+}
+
+/// The copy of [`Telemetry`] restored when an atomic batch is rolled back.
+pub(crate) struct TelemetrySnapshot<Input> {
+    transitions: u64,
+    faulted: bool,
+    last_input: Option<Input>,
+}
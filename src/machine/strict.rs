@@ -0,0 +1,37 @@
+use std::cell::Cell;
+
+/// Tracks the operation in progress on the state machine to detect overlapping
+/// operations, e.g. `set` called from inside a hook while `consume` is running.
+/// See [`crate::machine::builder::BasicStateMachineBuilder::strict_borrow()`].
+pub(crate) struct ActiveOperation(Cell<Option<&'static str>>);
+
+impl ActiveOperation {
+    pub(crate) fn new() -> Self {
+        ActiveOperation(Cell::new(None))
+    }
+
+    /// Marks `operation` as in progress until the returned guard is dropped.
+    ///
+    /// # Panics
+    /// Panics if another operation is already in progress.
+    pub(crate) fn enter(&self, operation: &'static str) -> OperationGuard<'_> {
+        if let Some(active) = self.0.get() {
+            panic!(
+                "`{}` is called on the state machine while `{}` is in progress. \
+                 The operations on the same state machine must not overlap, \
+                 e.g. don't call them from inside the transition or the hooks.",
+                operation, active
+            );
+        }
+        self.0.set(Some(operation));
+        OperationGuard(&self.0)
+    }
+}
+
+pub(crate) struct OperationGuard<'a>(&'a Cell<Option<&'static str>>);
+
+impl Drop for OperationGuard<'_> {
+    fn drop(&mut self) {
+        self.0.set(None);
+    }
+}
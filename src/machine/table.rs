@@ -0,0 +1,1502 @@
+use std::{
+    borrow::Borrow,
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+use super::{error::StateMachineError, snapshot::Snapshot, PeekResult, StateMachine, StateWrapper};
+
+/// Returns `true` if the two table machines behave identically for `inputs`,
+/// i.e. they are bisimilar. Starting from the pair of their initial states,
+/// it checks that for every input both machines have a transition (or both don't)
+/// from every reachable pair of states. The state types may differ, so that
+/// a machine with renamed states can be compared with the original one.
+///
+/// # Example
+/// ```
+/// use statemachine_rs::machine::{builder::TableStateMachineBuilder, table::bisimilar};
+///
+/// let a = TableStateMachineBuilder::start()
+///     .initial_state("Off")
+///     .add_transition("Off", "Press", "On")
+///     .add_transition("On", "Press", "Off")
+///     .build()
+///     .unwrap();
+/// let b = TableStateMachineBuilder::start()
+///     .initial_state(false)
+///     .add_transition(false, "Press", true)
+///     .add_transition(true, "Press", false)
+///     .build()
+///     .unwrap();
+///
+/// assert!(bisimilar(&a, &b, &["Press"]));
+/// ```
+pub fn bisimilar<StateA, StateB, Input>(
+    a: &TableStateMachine<StateA, Input>,
+    b: &TableStateMachine<StateB, Input>,
+    inputs: &[Input],
+) -> bool
+where
+    StateA: Clone + Eq + Hash,
+    StateB: Clone + Eq + Hash,
+    Input: Clone + Eq + Hash,
+{
+    let start = (a.initial_state.clone(), b.initial_state.clone());
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::new();
+    visited.insert(start.clone());
+    queue.push_back(start);
+
+    while let Some((state_a, state_b)) = queue.pop_front() {
+        for input in inputs {
+            let next_a = a.lookup(&(state_a.clone(), input.clone()));
+            let next_b = b.lookup(&(state_b.clone(), input.clone()));
+            match (next_a, next_b) {
+                (Some(next_a), Some(next_b)) => {
+                    let pair = (next_a.clone(), next_b.clone());
+                    if visited.insert(pair.clone()) {
+                        queue.push_back(pair);
+                    }
+                }
+                (None, None) => {}
+                _ => return false,
+            }
+        }
+    }
+    true
+}
+
+/// The state machine whose transitions are defined as a table of
+/// `(current state, input) -> next state` rather than a closure.
+/// Since the definition is data, it can be inspected and analyzed.
+/// It can be assembled via [`crate::machine::builder::TableStateMachineBuilder`].
+///
+/// The transition for the pair of the current state and given input is resolved
+/// in the following precedence order.
+///
+/// 1. The exact transition added via `add_transition`.
+/// 2. The any-input rule of the current state added via `add_any_input`.
+/// 3. The any-state rule of the input added via `add_any_state`.
+/// 4. Otherwise, the state machine retains in its current state.
+///
+/// Like [`crate::machine::BasicStateMachine`], it is never `Sync`. Since the rows may be
+/// shared via `Arc` with the other machines built by
+/// [`crate::machine::builder::TableStateMachineBuilder::build_many()`], it is `Send`
+/// if the state and the input are both `Send` and `Sync`.
+pub struct TableStateMachine<State, Input>
+where
+    State: Clone,
+{
+    /// `initial_state` is literally an initial state of the state machine.
+    pub(crate) initial_state: State,
+    /// `current_state` is the current state of the state machine.
+    pub(crate) current_state: RefCell<StateWrapper<State>>,
+    /// `table` is the definition of state transition.
+    pub(crate) table: Arc<HashMap<(State, Input), State>>,
+    /// `any_input` holds the rules applied to any input from particular state.
+    pub(crate) any_input: Arc<HashMap<State, State>>,
+    /// `any_state` holds the rules applied to particular input from any state.
+    pub(crate) any_state: Arc<HashMap<Input, State>>,
+    /// `ignored` holds the pairs of the state and the input which are deliberately ignored.
+    /// They are also stored in `table` as self-loops.
+    pub(crate) ignored: Arc<HashSet<(State, Input)>>,
+    /// `labels` holds the human-readable descriptions of transitions.
+    pub(crate) labels: Arc<HashMap<(State, Input), String>>,
+    /// `forbidden` holds the pairs of the state and the input which must not be consumed.
+    /// They take precedence over the wildcard rules.
+    pub(crate) forbidden: Arc<HashSet<(State, Input)>>,
+    /// `observers` are notified of each transition taken.
+    pub(crate) observers: Arc<Vec<TransitionObserver<State, Input>>>,
+    /// `tags` holds the tags attached to particular states.
+    pub(crate) tags: Arc<HashMap<State, Vec<String>>>,
+}
+
+/// The transition passed to the observers registered via
+/// [`crate::machine::builder::TableStateMachineBuilder::on_transition()`].
+#[derive(Debug)]
+pub struct TransitionContext<'a, State, Input> {
+    /// The state the machine left.
+    pub from: &'a State,
+    /// The input which caused the transition.
+    pub input: &'a Input,
+    /// The state the machine entered.
+    pub to: &'a State,
+    /// The label of the transition added via
+    /// [`crate::machine::builder::TableStateMachineBuilder::add_transition_labeled()`], if any.
+    pub label: Option<&'a str>,
+}
+
+/// The observer called with each transition taken by [`TableStateMachine`].
+pub(crate) type TransitionObserver<State, Input> =
+    Arc<dyn Fn(&TransitionContext<'_, State, Input>) + Send + Sync>;
+
+/// The trait is representing the types whose values can be listed exhaustively,
+/// typically fieldless `enum`s. It is required by
+/// [`crate::machine::builder::TableStateMachineBuilder::total()`].
+pub trait Enumerable: Sized {
+    /// Returns every value of the type.
+    fn all() -> Vec<Self>;
+}
+
+/// How the pair of a state and an input is decided in the table.
+/// See [`TableStateMachine::coverage_manifest()`].
+enum Decision<'a, State> {
+    Transition(&'a State),
+    Ignore,
+    Forbid,
+}
+
+/// The view of the declarations in a table, which doesn't require the bounds of
+/// [`TableStateMachine`] so that the builder can hold [`Coverage::check_total()`].
+pub(crate) struct Coverage<'a, State, Input> {
+    table: &'a HashMap<(State, Input), State>,
+    any_input: &'a HashMap<State, State>,
+    any_state: &'a HashMap<Input, State>,
+    ignored: &'a HashSet<(State, Input)>,
+    forbidden: &'a HashSet<(State, Input)>,
+}
+
+impl<'a, State, Input> Coverage<'a, State, Input> {
+    /// Returns the explicit decisions on the pair of `state` and `input`: the transition
+    /// (including the wildcard rules only if nothing else is declared), the ignore and the forbid.
+    fn decisions(&self, state: &State, input: &Input) -> Vec<Decision<'a, State>>
+    where
+        State: Clone + Eq + Hash,
+        Input: Clone + Eq + Hash,
+    {
+        let key = (state.clone(), input.clone());
+        let mut decisions = Vec::new();
+        if self.ignored.contains(&key) {
+            decisions.push(Decision::Ignore);
+        } else if let Some(to) = self.table.get(&key) {
+            decisions.push(Decision::Transition(to));
+        }
+        if self.forbidden.contains(&key) {
+            decisions.push(Decision::Forbid);
+        }
+        if decisions.is_empty() {
+            if let Some(to) = self
+                .any_input
+                .get(state)
+                .or_else(|| self.any_state.get(input))
+            {
+                decisions.push(Decision::Transition(to));
+            }
+        }
+        decisions
+    }
+
+    /// Returns the wildcard rules which never apply to `states` and `inputs`.
+    /// See [`TableStateMachine::shadowed_wildcards()`].
+    fn shadowed_wildcards(
+        &self,
+        states: &[State],
+        inputs: &[Input],
+    ) -> Vec<ShadowedWildcard<State, Input>>
+    where
+        State: Clone + Eq + Hash,
+        Input: Clone + Eq + Hash,
+    {
+        // The forbidden pairs take precedence over the wildcard rules as well.
+        let specific = |state: &State, input: &Input| {
+            let key = (state.clone(), input.clone());
+            self.table.contains_key(&key) || self.forbidden.contains(&key)
+        };
+        let any_input = self
+            .any_input
+            .keys()
+            .filter(|from| inputs.iter().all(|input| specific(from, input)))
+            .map(|from| ShadowedWildcard::AnyInput(from.clone()));
+        let any_state = self
+            .any_state
+            .keys()
+            .filter(|input| {
+                states
+                    .iter()
+                    .all(|state| specific(state, input) || self.any_input.contains_key(state))
+            })
+            .map(|input| ShadowedWildcard::AnyState(input.clone()));
+        any_input.chain(any_state).collect()
+    }
+
+    /// Checks that every pair of the state and the input is decided exactly once
+    /// and that no wildcard rule is shadowed.
+    /// See [`crate::machine::builder::TableStateMachineBuilder::total()`].
+    pub(crate) fn check_total(&self) -> Result<(), StateMachineError>
+    where
+        State: Enumerable + Debug + Clone + Eq + Hash,
+        Input: Enumerable + Debug + Clone + Eq + Hash,
+    {
+        let states = State::all();
+        let inputs = Input::all();
+        let mut undecided = Vec::new();
+        let mut conflicting = Vec::new();
+        for state in &states {
+            for input in &inputs {
+                match self.decisions(state, input).len() {
+                    1 => {}
+                    0 => undecided.push(format!("({:?}, {:?})", state, input)),
+                    _ => conflicting.push(format!("({:?}, {:?})", state, input)),
+                }
+            }
+        }
+        let mut shadowed: Vec<_> = self
+            .shadowed_wildcards(&states, &inputs)
+            .iter()
+            .map(|wildcard| format!("{:?}", wildcard))
+            .collect();
+        shadowed.sort();
+        if undecided.is_empty() && conflicting.is_empty() && shadowed.is_empty() {
+            Ok(())
+        } else {
+            Err(StateMachineError::IncompleteCoverage {
+                undecided,
+                conflicting,
+                shadowed,
+            })
+        }
+    }
+}
+
+/// The transition table of `(source state, input) -> target state`.
+/// See [`TableStateMachine::collapse_inputs()`].
+pub type TransitionTable<State, Input> = HashMap<(State, Input), State>;
+
+/// A class of inputs which lead to the same target from the same source state.
+/// The inputs are sorted, so that the classes consisting of the same inputs are equal.
+/// See [`TableStateMachine::collapse_inputs()`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct InputClass<Input>(Vec<Input>);
+
+impl<Input> InputClass<Input> {
+    /// Returns the inputs in the class in ascending order.
+    pub fn inputs(&self) -> &[Input] {
+        &self.0
+    }
+
+    /// Returns `true` if the class contains `input`.
+    pub fn contains(&self, input: &Input) -> bool
+    where
+        Input: Ord,
+    {
+        self.0.binary_search(input).is_ok()
+    }
+}
+
+/// A wildcard rule which is entirely shadowed by more specific rules.
+/// See [`TableStateMachine::shadowed_wildcards()`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum ShadowedWildcard<State, Input> {
+    /// The any-input rule from the state.
+    AnyInput(State),
+    /// The any-state rule on the input.
+    AnyState(Input),
+}
+
+impl<State, Input> TableStateMachine<State, Input>
+where
+    State: Clone + Eq + Hash,
+    Input: Eq + Hash,
+{
+    fn next(&self, input: Input) -> Option<State> {
+        self.lookup_by(&self.current_state.borrow().0, &input)
+            .cloned()
+    }
+
+    /// Resolves the transition for the pair of the state and the input
+    /// according to the precedence order.
+    pub(crate) fn lookup(&self, key: &(State, Input)) -> Option<&State> {
+        if self.forbidden.contains(key) {
+            return None;
+        }
+        self.table
+            .get(key)
+            .or_else(|| self.any_input.get(&key.0))
+            .or_else(|| self.any_state.get(&key.1))
+    }
+
+    /// Resolves the transition like [`TableStateMachine::lookup()`], but by the borrowed
+    /// forms of the state and the input, so that the key doesn't have to be allocated.
+    pub(crate) fn lookup_by<Q>(&self, state: &State, input: &Q) -> Option<&State>
+    where
+        Input: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        if self.is_forbidden(state, input) {
+            return None;
+        }
+        self.table
+            .get(&(state, input) as &dyn EdgeKey<State, Q>)
+            .or_else(|| self.any_input.get(state))
+            .or_else(|| self.any_state.get(input))
+    }
+
+    fn is_forbidden<Q>(&self, state: &State, input: &Q) -> bool
+    where
+        Input: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        !self.forbidden.is_empty()
+            && self
+                .forbidden
+                .contains(&(state, input) as &dyn EdgeKey<State, Q>)
+    }
+
+    /// Transits to the next state like [`StateMachine::consume()`], but returns
+    /// [`StateMachineError::ForbiddenTransition`] without changing the current state
+    /// if the input is forbidden from the current state via
+    /// [`crate::machine::builder::TableStateMachineBuilder::forbid()`].
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{
+    ///     builder::TableStateMachineBuilder, error::StateMachineError, StateMachine,
+    /// };
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Paid")
+    ///     .add_any_state("Cancel", "Cancelled")
+    ///     .forbid("Paid", vec!["Cancel"])
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert!(matches!(
+    ///     sm.try_consume("Cancel"),
+    ///     Err(StateMachineError::ForbiddenTransition)
+    /// ));
+    /// assert_eq!("Paid", sm.current_state());
+    /// ```
+    pub fn try_consume(&self, input: Input) -> Result<State, StateMachineError> {
+        if self.is_forbidden(&self.current_state.borrow().0, &input) {
+            return Err(StateMachineError::ForbiddenTransition);
+        }
+        Ok(self.consume(input))
+    }
+
+    pub(crate) fn coverage(&self) -> Coverage<'_, State, Input> {
+        Coverage {
+            table: &self.table,
+            any_input: &self.any_input,
+            any_state: &self.any_state,
+            ignored: &self.ignored,
+            forbidden: &self.forbidden,
+        }
+    }
+
+    /// Renders the decision on every pair of the state and the input as a Markdown table,
+    /// e.g. to be attached to a review as an audit artifact. Each row is a state and
+    /// each column is an input in the order of [`Enumerable::all()`], and each cell is
+    /// `-> Target` for a transition, `ignore`, `forbid`, or `undecided`.
+    /// The pairs decided more than once are listed as all of the decisions joined by ` / `.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{builder::TableStateMachineBuilder, table::Enumerable};
+    ///
+    /// #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+    /// enum Light {
+    ///     Off,
+    ///     On,
+    /// }
+    ///
+    /// impl Enumerable for Light {
+    ///     fn all() -> Vec<Self> {
+    ///         vec![Light::Off, Light::On]
+    ///     }
+    /// }
+    ///
+    /// #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+    /// enum Switch {
+    ///     Press,
+    /// }
+    ///
+    /// impl Enumerable for Switch {
+    ///     fn all() -> Vec<Self> {
+    ///         vec![Switch::Press]
+    ///     }
+    /// }
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state(Light::Off)
+    ///     .add_transition(Light::Off, Switch::Press, Light::On)
+    ///     .forbid(Light::On, vec![Switch::Press])
+    ///     .total()
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(
+    ///     "| state | Press |\n|---|---|\n| Off | -> On |\n| On | forbid |\n",
+    ///     sm.coverage_manifest()
+    /// );
+    /// ```
+    pub fn coverage_manifest(&self) -> String
+    where
+        State: Enumerable + Debug,
+        Input: Enumerable + Debug + Clone,
+    {
+        let inputs = Input::all();
+        let mut manifest = String::from("| state |");
+        for input in &inputs {
+            manifest.push_str(&format!(" {:?} |", input));
+        }
+        manifest.push_str("\n|---|");
+        manifest.push_str(&"---|".repeat(inputs.len()));
+        manifest.push('\n');
+        for state in State::all() {
+            manifest.push_str(&format!("| {:?} |", state));
+            for input in &inputs {
+                let decisions: Vec<String> = self
+                    .coverage()
+                    .decisions(&state, input)
+                    .into_iter()
+                    .map(|decision| match decision {
+                        Decision::Transition(to) => format!("-> {:?}", to),
+                        Decision::Ignore => "ignore".to_string(),
+                        Decision::Forbid => "forbid".to_string(),
+                    })
+                    .collect();
+                if decisions.is_empty() {
+                    manifest.push_str(" undecided |");
+                } else {
+                    manifest.push_str(&format!(" {} |", decisions.join(" / ")));
+                }
+            }
+            manifest.push('\n');
+        }
+        manifest
+    }
+
+    /// Transits to the next state like [`StateMachine::consume()`], but takes
+    /// any borrowed form of the input, like [`HashMap::get()`] does.
+    /// For example, the machine whose input is `String` can be driven by `&str`
+    /// without allocating a `String` per input. Since there is no `Input` to pass,
+    /// the observers registered via
+    /// [`crate::machine::builder::TableStateMachineBuilder::on_transition()`] aren't notified.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{builder::TableStateMachineBuilder, StateMachine};
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "press".to_string(), "On")
+    ///     .add_transition("On", "press".to_string(), "Off")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!("On", sm.consume_by("press"));
+    /// assert_eq!("On", sm.consume_by("pull"));
+    /// ```
+    pub fn consume_by<Q>(&self, input: &Q) -> State
+    where
+        Input: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let new_state = self
+            .lookup_by(&self.current_state.borrow().0, input)
+            .cloned();
+        if let Some(new_state) = new_state {
+            self.current_state.borrow_mut().set(new_state);
+        }
+        self.current_state()
+    }
+
+    /// Builds the input lazily by `make` with read access to the current state and
+    /// then transits to the next state like [`StateMachine::consume()`].
+    /// If no transition is defined from the current state (ignored inputs aside),
+    /// the machine can't use any input, so `make` isn't called at all and
+    /// the state machine retains in its current state.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::builder::TableStateMachineBuilder;
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Draft")
+    ///     .add_transition("Draft", "submit", "Submitted")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!("Submitted", sm.consume_with(|_| "submit"));
+    /// // "Submitted" accepts no input, so the closure isn't called
+    /// assert_eq!("Submitted", sm.consume_with(|_| unreachable!()));
+    /// ```
+    pub fn consume_with(&self, make: impl FnOnce(&State) -> Input) -> State {
+        let input = {
+            let current_state = &self.current_state.borrow().0;
+            if !self.accepts_any_input(current_state) {
+                return self.current_state();
+            }
+            make(current_state)
+        };
+        self.consume(input)
+    }
+
+    /// Returns `true` if any transition (ignored inputs aside) is defined from `state`.
+    fn accepts_any_input(&self, state: &State) -> bool {
+        !self.any_state.is_empty()
+            || self.any_input.contains_key(state)
+            || self
+                .table
+                .keys()
+                .any(|key| &key.0 == state && !self.ignored.contains(key))
+    }
+
+    /// Returns the exact transitions with the inputs leading to the same target from
+    /// the same source state grouped into an [`InputClass`]. The resulting table is
+    /// smaller than the original one when many inputs are redundant.
+    /// The wildcard rules aren't included since they are already collapsed.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::builder::TableStateMachineBuilder;
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "Press", "On")
+    ///     .add_transition("Off", "Touch", "On")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// let collapsed = sm.collapse_inputs();
+    /// assert_eq!(1, collapsed.len());
+    /// let ((_, class), _) = collapsed.iter().next().unwrap();
+    /// assert_eq!(&["Press", "Touch"], class.inputs());
+    /// ```
+    pub fn collapse_inputs(&self) -> TransitionTable<State, InputClass<Input>>
+    where
+        Input: Clone + Ord,
+    {
+        let mut classes: HashMap<(State, State), Vec<Input>> = HashMap::new();
+        for ((from, input), to) in self.table.iter() {
+            classes
+                .entry((from.clone(), to.clone()))
+                .or_default()
+                .push(input.clone());
+        }
+        classes
+            .into_iter()
+            .map(|((from, to), mut inputs)| {
+                inputs.sort();
+                ((from, InputClass(inputs)), to)
+            })
+            .collect()
+    }
+
+    /// Simulates consuming `inputs` one by one from the current state and returns every
+    /// intermediate state, without changing the current state.
+    /// Unlike [`StateMachine::consume()`], the simulation stops at the first input which has
+    /// no transition, so the length of the returned `Vec` tells how far it got.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{builder::TableStateMachineBuilder, StateMachine};
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "Press", "On")
+    ///     .add_transition("On", "Press", "Off")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(vec!["On", "Off"], sm.peek_chain(vec!["Press", "Press"]));
+    /// assert_eq!(vec!["On"], sm.peek_chain(vec!["Press", "Pull", "Press"]));
+    /// assert_eq!("Off", sm.current_state());
+    /// ```
+    pub fn peek_chain(&self, inputs: impl IntoIterator<Item = Input>) -> Vec<State> {
+        let current_state = self.current_state.borrow();
+        let mut states: Vec<State> = Vec::new();
+        for input in inputs {
+            let from = states.last().unwrap_or(&current_state.0);
+            match self.lookup_by(from, &input) {
+                Some(to) => {
+                    let to = to.clone();
+                    states.push(to)
+                }
+                None => break,
+            }
+        }
+        states
+    }
+
+    /// Returns the snapshot of the initial state and the current state.
+    /// See [`Snapshot`] for how to restore it.
+    pub fn snapshot(&self) -> Snapshot<State> {
+        Snapshot {
+            initial_state: self.initial_state.clone(),
+            current_state: self.current_state(),
+            epoch: 0,
+        }
+    }
+
+    /// Returns the wildcard rules which never apply to the given `states` and `inputs`
+    /// because more specific rules always take precedence over them.
+    /// An any-input rule is shadowed if every input has an exact transition from its state,
+    /// and an any-state rule is shadowed if every state has an exact transition
+    /// or an any-input rule for its input. The forbidden pairs count as exact transitions
+    /// since they take precedence over the wildcard rules.
+    /// [`crate::machine::builder::TableStateMachineBuilder::total()`] reports them on building.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{builder::TableStateMachineBuilder, table::ShadowedWildcard};
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "Press", "On")
+    ///     .add_transition("On", "Press", "Off")
+    ///     .add_any_state("Press", "Broken")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(
+    ///     vec![ShadowedWildcard::AnyState("Press")],
+    ///     sm.shadowed_wildcards(&["Off", "On"], &["Press"])
+    /// );
+    /// ```
+    pub fn shadowed_wildcards(
+        &self,
+        states: &[State],
+        inputs: &[Input],
+    ) -> Vec<ShadowedWildcard<State, Input>>
+    where
+        Input: Clone,
+    {
+        self.coverage().shadowed_wildcards(states, inputs)
+    }
+
+    /// Returns whether `input` is valid from the current state and the state
+    /// it leads to, without changing the current state.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{builder::TableStateMachineBuilder, PeekResult};
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "Press", "On")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(PeekResult::Valid("On"), sm.peek_edge(&"Press"));
+    /// assert_eq!(PeekResult::Invalid, sm.peek_edge(&"Pull"));
+    /// ```
+    pub fn peek_edge(&self, input: &Input) -> PeekResult<State>
+    where
+        Input: Clone,
+    {
+        match self.next(input.clone()) {
+            Some(state) => PeekResult::Valid(state),
+            None => PeekResult::Invalid,
+        }
+    }
+
+    /// Returns the state each pair of `states` and `inputs` leads to, as a nested map
+    /// keyed by the state and then by the input, e.g. to render a decision table.
+    /// The undefined pairs are `None`. The current state isn't changed.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::builder::TableStateMachineBuilder;
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "Press", "On")
+    ///     .add_transition("On", "Press", "Off")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// let matrix = sm.peek_matrix(&["Off", "On"], &["Press", "Pull"]);
+    /// assert_eq!(Some("On"), matrix["Off"]["Press"]);
+    /// assert_eq!(None, matrix["On"]["Pull"]);
+    /// ```
+    pub fn peek_matrix(
+        &self,
+        states: &[State],
+        inputs: &[Input],
+    ) -> HashMap<State, HashMap<Input, Option<State>>>
+    where
+        Input: Clone,
+    {
+        states
+            .iter()
+            .map(|state| {
+                let row = inputs
+                    .iter()
+                    .map(|input| (input.clone(), self.lookup_by(state, input).cloned()))
+                    .collect();
+                (state.clone(), row)
+            })
+            .collect()
+    }
+
+    /// Returns every state which appears in the definition.
+    pub(crate) fn states(&self) -> HashSet<State> {
+        self.table
+            .iter()
+            .flat_map(|((from, _), to)| vec![from.clone(), to.clone()])
+            .chain(
+                self.any_input
+                    .iter()
+                    .flat_map(|(from, to)| vec![from.clone(), to.clone()]),
+            )
+            .chain(self.any_state.values().cloned())
+            .chain(std::iter::once(self.initial_state.clone()))
+            .collect()
+    }
+
+    /// Returns every state reachable from the initial state, including itself.
+    pub(crate) fn reachable_states(&self) -> HashSet<State> {
+        let mut edges: HashMap<&State, Vec<&State>> = HashMap::new();
+        for ((from, _), to) in self.table.iter() {
+            edges.entry(from).or_default().push(to);
+        }
+        for (from, to) in self.any_input.iter() {
+            edges.entry(from).or_default().push(to);
+        }
+        let mut reachable: HashSet<State> = HashSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(&self.initial_state);
+        // The any-state rules apply to every reachable state.
+        queue.extend(self.any_state.values());
+        while let Some(state) = queue.pop_front() {
+            if reachable.insert(state.clone()) {
+                queue.extend(edges.get(state).into_iter().flatten());
+            }
+        }
+        reachable
+    }
+
+    /// Returns `true` if `target` is reachable from the initial state, including the initial
+    /// state itself, by the transitions, the any-input rules and the any-state rules.
+    /// It is handy to debug why the machine never reaches a state.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::builder::TableStateMachineBuilder;
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "Press", "On")
+    ///     .add_transition("Broken", "Repair", "Off")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert!(sm.is_reachable(&"On"));
+    /// assert!(!sm.is_reachable(&"Broken"));
+    /// ```
+    pub fn is_reachable(&self, target: &State) -> bool {
+        self.reachable_states().contains(target)
+    }
+
+    /// Returns the label of the transition from `from` on `input`.
+    /// See [`crate::machine::builder::TableStateMachineBuilder::add_transition_labeled()`].
+    pub fn label_of(&self, from: &State, input: &Input) -> Option<&str>
+    where
+        Input: Clone,
+    {
+        self.labels
+            .get(&(from.clone(), input.clone()))
+            .map(|label| label.as_str())
+    }
+
+    /// Returns `true` if `input` is deliberately ignored in `state`.
+    /// See [`crate::machine::builder::TableStateMachineBuilder::ignore()`].
+    pub fn is_ignored(&self, state: &State, input: &Input) -> bool
+    where
+        Input: Clone,
+    {
+        self.ignored.contains(&(state.clone(), input.clone()))
+    }
+
+    /// Returns every transition whose target is the same as its source,
+    /// as the pairs of the state and the input.
+    /// The ignored inputs aren't included since they are deliberate self-loops.
+    /// Self-loops often indicate a modeling mistake, so this is useful
+    /// to assert there are none of them.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::builder::TableStateMachineBuilder;
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "Press", "On")
+    ///     .add_transition("On", "Press", "Off")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert!(sm.self_loops().is_empty());
+    /// ```
+    pub fn self_loops(&self) -> Vec<(State, Input)>
+    where
+        Input: Clone,
+    {
+        self.table
+            .iter()
+            .filter(|(key, to)| &key.0 == *to && !self.ignored.contains(*key))
+            .map(|((from, input), _)| (from.clone(), input.clone()))
+            .collect()
+    }
+
+    /// Returns every input sequence of at most `max_len` inputs which leads the machine from
+    /// the initial state to a terminal state, i.e. a state no input moves out of.
+    /// It is handy to generate the test cases walking through the whole lifecycle.
+    ///
+    /// The sequences are enumerated by a depth-first search over the inputs appearing in
+    /// the transitions and the any-state rules, with the same precedence as
+    /// [`StateMachine::consume()`]. A cycle may be taken repeatedly as long as the sequence
+    /// fits in `max_len`, so keep it small for the machines with cycles.
+    /// The sequences are in no particular order. The empty sequence is returned
+    /// if the initial state is terminal.
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::builder::TableStateMachineBuilder;
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Cart")
+    ///     .add_transition("Cart", "Pay", "Paid")
+    ///     .add_transition("Paid", "Ship", "Shipped")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert_eq!(vec![vec!["Pay", "Ship"]], sm.terminal_paths(5));
+    /// assert!(sm.terminal_paths(1).is_empty());
+    /// ```
+    pub fn terminal_paths(&self, max_len: usize) -> Vec<Vec<Input>>
+    where
+        Input: Clone,
+    {
+        let alphabet: HashSet<&Input> = self
+            .table
+            .keys()
+            .map(|(_, input)| input)
+            .chain(self.any_state.keys())
+            .collect();
+        let mut paths = Vec::new();
+        let mut path = Vec::new();
+        self.walk_to_terminals(
+            &self.initial_state,
+            &alphabet,
+            max_len,
+            &mut path,
+            &mut paths,
+        );
+        paths
+    }
+
+    fn walk_to_terminals<'a>(
+        &'a self,
+        state: &State,
+        alphabet: &HashSet<&'a Input>,
+        max_len: usize,
+        path: &mut Vec<&'a Input>,
+        paths: &mut Vec<Vec<Input>>,
+    ) where
+        Input: Clone,
+    {
+        let edges: Vec<(&Input, &State)> = alphabet
+            .iter()
+            .filter_map(|input| {
+                self.lookup_by(state, *input)
+                    .filter(|next| *next != state)
+                    .map(|next| (*input, next))
+            })
+            .collect();
+        if edges.is_empty() {
+            paths.push(path.iter().map(|input| (*input).clone()).collect());
+            return;
+        }
+        if path.len() == max_len {
+            return;
+        }
+        for (input, next) in edges {
+            path.push(input);
+            self.walk_to_terminals(next, alphabet, max_len, path, paths);
+            path.pop();
+        }
+    }
+
+    /// Returns the tags attached to the current state via
+    /// [`crate::machine::builder::TableStateMachineBuilder::tag()`].
+    ///
+    /// # Example
+    /// ```
+    /// use statemachine_rs::machine::{builder::TableStateMachineBuilder, StateMachine};
+    ///
+    /// let sm = TableStateMachineBuilder::start()
+    ///     .initial_state("Off")
+    ///     .add_transition("Off", "Press", "On")
+    ///     .add_transition("On", "Press", "Off")
+    ///     .tag("On", "billable")
+    ///     .tag("On", "user-visible")
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// assert!(sm.current_tags().is_empty());
+    /// sm.consume("Press");
+    /// assert_eq!(&["billable", "user-visible"], sm.current_tags());
+    /// ```
+    pub fn current_tags(&self) -> &[String] {
+        self.tags
+            .get(&self.current_state.borrow().0)
+            .map(|tags| tags.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Returns `true` if the current state has `tag`.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.current_tags().iter().any(|t| t == tag)
+    }
+
+    /// Returns every state which has `tag`. The order of the states is unspecified.
+    pub fn states_with_tag(&self, tag: &str) -> Vec<State> {
+        self.tags
+            .iter()
+            .filter(|(_, tags)| tags.iter().any(|t| t == tag))
+            .map(|(state, _)| state.clone())
+            .collect()
+    }
+}
+
+impl<State, Input> StateMachine<State, Input> for TableStateMachine<State, Input>
+where
+    State: Clone + Eq + Hash,
+    Input: Eq + Hash,
+{
+    fn current_state(&self) -> State {
+        self.current_state.borrow().get()
+    }
+
+    fn consume(&self, input: Input) -> State {
+        if self.observers.is_empty() {
+            if let Some(new_state) = self.next(input) {
+                self.current_state.borrow_mut().set(new_state);
+            }
+            return self.current_state();
+        }
+        let new_state = match self.lookup_by(&self.current_state.borrow().0, &input) {
+            Some(new_state) => new_state.clone(),
+            None => return self.current_state(),
+        };
+        let prev = self.current_state.borrow_mut().set(new_state.clone());
+        let key = &(&prev, &input) as &dyn EdgeKey<State, Input>;
+        if !self.ignored.contains(key) {
+            let context = TransitionContext {
+                from: &prev,
+                input: &input,
+                to: &new_state,
+                label: self.labels.get(key).map(|label| label.as_str()),
+            };
+            for observer in self.observers.iter() {
+                observer(&context);
+            }
+        }
+        new_state
+    }
+
+    fn peek(&self, input: Input) -> State {
+        self.next(input).unwrap_or_else(|| self.current_state())
+    }
+
+    fn reset(&self) -> State {
+        self.current_state
+            .borrow_mut()
+            .set(self.initial_state.clone());
+        self.initial_state.clone()
+    }
+
+    fn set(&self, new_state: State) {
+        self.current_state.borrow_mut().set(new_state);
+    }
+}
+
+/// The key of the transition table which can be looked up by the pair of
+/// the borrowed state and the borrowed input. `HashMap<(State, Input), _>` can be
+/// looked up only by `&(State, Input)`, so the pair is erased to this trait object.
+trait EdgeKey<State, Q: ?Sized> {
+    fn state(&self) -> &State;
+    fn input(&self) -> &Q;
+}
+
+impl<State, Input, Q> EdgeKey<State, Q> for (State, Input)
+where
+    Input: Borrow<Q>,
+    Q: ?Sized,
+{
+    fn state(&self) -> &State {
+        &self.0
+    }
+
+    fn input(&self) -> &Q {
+        self.1.borrow()
+    }
+}
+
+impl<State, Q> EdgeKey<State, Q> for (&State, &Q)
+where
+    Q: ?Sized,
+{
+    fn state(&self) -> &State {
+        self.0
+    }
+
+    fn input(&self) -> &Q {
+        self.1
+    }
+}
+
+impl<'a, State, Input, Q> Borrow<dyn EdgeKey<State, Q> + 'a> for (State, Input)
+where
+    State: 'a,
+    Input: Borrow<Q> + 'a,
+    Q: ?Sized + 'a,
+{
+    fn borrow(&self) -> &(dyn EdgeKey<State, Q> + 'a) {
+        self
+    }
+}
+
+/// It must be consistent with the hash of `(State, Input)`, which hashes
+/// the elements in order. `Borrow` guarantees that `Q` hashes the same as `Input`.
+impl<State, Q> Hash for dyn EdgeKey<State, Q> + '_
+where
+    State: Hash,
+    Q: Hash + ?Sized,
+{
+    fn hash<H: Hasher>(&self, hasher: &mut H) {
+        self.state().hash(hasher);
+        self.input().hash(hasher);
+    }
+}
+
+impl<State, Q> PartialEq for dyn EdgeKey<State, Q> + '_
+where
+    State: PartialEq,
+    Q: PartialEq + ?Sized,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.state() == other.state() && self.input() == other.input()
+    }
+}
+
+impl<State, Q> Eq for dyn EdgeKey<State, Q> + '_
+where
+    State: Eq,
+    Q: Eq + ?Sized,
+{
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::RefCell,
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use super::{bisimilar, ShadowedWildcard};
+    use crate::machine::builder::TableStateMachineBuilder;
+    use crate::machine::{PeekResult, StateMachine};
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Stations {
+        Shibuya,
+        IkejiriOhashi,
+        Sangendyaya,
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+    enum Train {
+        Local,
+        Express,
+    }
+
+    fn builder() -> TableStateMachineBuilder<Stations, Train> {
+        TableStateMachineBuilder::start()
+            .add_transition(Stations::Shibuya, Train::Local, Stations::IkejiriOhashi)
+            .add_transition(Stations::Shibuya, Train::Express, Stations::Sangendyaya)
+            .add_transition(Stations::IkejiriOhashi, Train::Local, Stations::Sangendyaya)
+    }
+
+    #[test]
+    fn test_consume() {
+        let sm = builder().initial_state(Stations::Shibuya).build().unwrap();
+
+        assert_eq!(Stations::IkejiriOhashi, sm.peek(Train::Local));
+        assert_eq!(Stations::IkejiriOhashi, sm.consume(Train::Local));
+        // undefined transition retains the current state
+        assert_eq!(Stations::IkejiriOhashi, sm.consume(Train::Express));
+        assert_eq!(Stations::Sangendyaya, sm.consume(Train::Local));
+        assert_eq!(Stations::Shibuya, sm.reset());
+    }
+
+    #[test]
+    fn test_is_reachable() {
+        let sm = builder()
+            .initial_state(Stations::IkejiriOhashi)
+            .build()
+            .unwrap();
+
+        assert!(sm.is_reachable(&Stations::IkejiriOhashi));
+        assert!(sm.is_reachable(&Stations::Sangendyaya));
+        // no transition leads back to Shibuya
+        assert!(!sm.is_reachable(&Stations::Shibuya));
+    }
+
+    #[test]
+    fn test_terminal_paths() {
+        let sm = builder().initial_state(Stations::Shibuya).build().unwrap();
+
+        let mut paths = sm.terminal_paths(5);
+        paths.sort();
+        assert_eq!(
+            vec![vec![Train::Local, Train::Local], vec![Train::Express]],
+            paths
+        );
+        // the longer path doesn't fit
+        assert_eq!(vec![vec![Train::Express]], sm.terminal_paths(1));
+        assert!(sm.terminal_paths(0).is_empty());
+    }
+
+    #[test]
+    fn test_self_loops() {
+        let sm = builder()
+            .initial_state(Stations::Shibuya)
+            .add_transition(Stations::Sangendyaya, Train::Express, Stations::Sangendyaya)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            vec![(Stations::Sangendyaya, Train::Express)],
+            sm.self_loops()
+        );
+    }
+
+    #[test]
+    fn test_peek_matrix() {
+        let sm = builder().initial_state(Stations::Shibuya).build().unwrap();
+
+        let matrix = sm.peek_matrix(
+            &[Stations::Shibuya, Stations::IkejiriOhashi],
+            &[Train::Local, Train::Express],
+        );
+        let mut expected = HashMap::new();
+        expected.insert(
+            Stations::Shibuya,
+            vec![
+                (Train::Local, Some(Stations::IkejiriOhashi)),
+                (Train::Express, Some(Stations::Sangendyaya)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        expected.insert(
+            Stations::IkejiriOhashi,
+            vec![
+                (Train::Local, Some(Stations::Sangendyaya)),
+                (Train::Express, None),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        assert_eq!(expected, matrix);
+        assert_eq!(Stations::Shibuya, sm.current_state());
+    }
+
+    #[test]
+    fn test_peek_edge() {
+        let sm = builder()
+            .initial_state(Stations::IkejiriOhashi)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            PeekResult::Valid(Stations::Sangendyaya),
+            sm.peek_edge(&Train::Local)
+        );
+        assert_eq!(PeekResult::Invalid, sm.peek_edge(&Train::Express));
+        assert_eq!(Stations::IkejiriOhashi, sm.current_state());
+    }
+
+    #[test]
+    fn test_wildcard_precedence() {
+        let sm = builder()
+            .initial_state(Stations::Shibuya)
+            .add_any_input(Stations::IkejiriOhashi, Stations::Shibuya)
+            .add_any_state(Train::Express, Stations::Sangendyaya)
+            .build()
+            .unwrap();
+
+        // exact pair > any-state
+        assert_eq!(Stations::IkejiriOhashi, sm.consume(Train::Local));
+        // exact pair > any-input
+        assert_eq!(Stations::Sangendyaya, sm.peek(Train::Local));
+        // any-input > any-state
+        assert_eq!(Stations::Shibuya, sm.peek(Train::Express));
+        sm.set(Stations::Sangendyaya);
+        // any-state
+        assert_eq!(Stations::Sangendyaya, sm.consume(Train::Express));
+        // default policy
+        assert_eq!(Stations::Sangendyaya, sm.consume(Train::Local));
+    }
+
+    #[test]
+    fn test_shadowed_wildcards() {
+        let sm = builder()
+            .initial_state(Stations::Shibuya)
+            .add_transition(Stations::Sangendyaya, Train::Local, Stations::Shibuya)
+            .add_any_input(Stations::Shibuya, Stations::Shibuya)
+            .add_any_input(Stations::IkejiriOhashi, Stations::Shibuya)
+            .add_any_state(Train::Local, Stations::Shibuya)
+            .add_any_state(Train::Express, Stations::Shibuya)
+            .build()
+            .unwrap();
+
+        let states = [
+            Stations::Shibuya,
+            Stations::IkejiriOhashi,
+            Stations::Sangendyaya,
+        ];
+        let shadowed = sm.shadowed_wildcards(&states, &[Train::Local, Train::Express]);
+        assert_eq!(2, shadowed.len());
+        // every input from `Shibuya` has an exact transition.
+        assert!(shadowed.contains(&ShadowedWildcard::AnyInput(Stations::Shibuya)));
+        // `Local` is covered by exact transitions and the any-input rules.
+        assert!(shadowed.contains(&ShadowedWildcard::AnyState(Train::Local)));
+    }
+
+    #[test]
+    fn test_ignored() {
+        let sm = builder()
+            .initial_state(Stations::IkejiriOhashi)
+            .add_transition(Stations::Sangendyaya, Train::Express, Stations::Sangendyaya)
+            .ignore(Stations::IkejiriOhashi, vec![Train::Express])
+            .build()
+            .unwrap();
+
+        assert_eq!(Stations::IkejiriOhashi, sm.consume(Train::Express));
+        assert!(sm.is_ignored(&Stations::IkejiriOhashi, &Train::Express));
+        assert!(!sm.is_ignored(&Stations::Sangendyaya, &Train::Express));
+        // only the unintentional self-loop is reported
+        assert_eq!(
+            vec![(Stations::Sangendyaya, Train::Express)],
+            sm.self_loops()
+        );
+    }
+
+    #[test]
+    fn test_bisimilar() {
+        let original = TableStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .add_transition(Stations::Shibuya, Train::Local, Stations::IkejiriOhashi)
+            .add_transition(Stations::Shibuya, Train::Express, Stations::Sangendyaya)
+            .add_transition(Stations::IkejiriOhashi, Train::Local, Stations::Sangendyaya)
+            .build()
+            .unwrap();
+        let renamed = TableStateMachineBuilder::start()
+            .initial_state("DT01")
+            .add_transition("DT01", Train::Local, "DT02")
+            .add_transition("DT01", Train::Express, "DT03")
+            .add_transition("DT02", Train::Local, "DT03")
+            .build()
+            .unwrap();
+        // `IkejiriOhashi` accepts `Express` here.
+        let different = TableStateMachineBuilder::start()
+            .initial_state("DT01")
+            .add_transition("DT01", Train::Local, "DT02")
+            .add_transition("DT01", Train::Express, "DT03")
+            .add_transition("DT02", Train::Local, "DT03")
+            .add_transition("DT02", Train::Express, "DT03")
+            .build()
+            .unwrap();
+
+        let inputs = [Train::Local, Train::Express];
+        assert!(bisimilar(&original, &renamed, &inputs));
+        assert!(!bisimilar(&original, &different, &inputs));
+        // they are indistinguishable if only `Local` is given.
+        assert!(bisimilar(&original, &different, &[Train::Local]));
+    }
+
+    #[test]
+    fn test_tags() {
+        let sm = builder()
+            .initial_state(Stations::Shibuya)
+            .tag(Stations::Shibuya, "terminal")
+            .tag(Stations::Sangendyaya, "terminal")
+            .tag(Stations::Sangendyaya, "express stop")
+            .tag(Stations::Sangendyaya, "terminal")
+            .build()
+            .unwrap();
+
+        assert!(sm.has_tag("terminal"));
+        assert!(!sm.has_tag("express stop"));
+        sm.consume(Train::Local);
+        assert!(sm.current_tags().is_empty());
+        sm.consume(Train::Local);
+        assert_eq!(&["terminal", "express stop"], sm.current_tags());
+
+        let terminals = sm.states_with_tag("terminal");
+        assert_eq!(2, terminals.len());
+        assert!(terminals.contains(&Stations::Shibuya));
+        assert!(terminals.contains(&Stations::Sangendyaya));
+        assert!(sm.states_with_tag("depot").is_empty());
+    }
+
+    #[test]
+    fn test_on_transition() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sm = {
+            let log = Arc::clone(&log);
+            TableStateMachineBuilder::start()
+                .initial_state(Stations::Shibuya)
+                .add_transition_labeled(
+                    Stations::Shibuya,
+                    Train::Local,
+                    Stations::IkejiriOhashi,
+                    "departed for \"Ikejiri\"",
+                )
+                .ignore(Stations::IkejiriOhashi, vec![Train::Express])
+                .add_any_state(Train::Express, Stations::Shibuya)
+                .on_transition(move |context| {
+                    log.lock().unwrap().push((
+                        *context.from,
+                        *context.input,
+                        *context.to,
+                        context.label.map(String::from),
+                    ))
+                })
+                .build()
+                .unwrap()
+        };
+
+        sm.consume(Train::Local);
+        // the ignored input and the undefined transition aren't notified
+        sm.consume(Train::Express);
+        sm.consume(Train::Local);
+        sm.set(Stations::Sangendyaya);
+        sm.consume(Train::Express);
+        assert_eq!(
+            vec![
+                (
+                    Stations::Shibuya,
+                    Train::Local,
+                    Stations::IkejiriOhashi,
+                    Some("departed for \"Ikejiri\"".to_string())
+                ),
+                (
+                    Stations::Sangendyaya,
+                    Train::Express,
+                    Stations::Shibuya,
+                    None
+                ),
+            ],
+            *log.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_consume_by() {
+        let sm = TableStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .add_transition(
+                Stations::Shibuya,
+                "local".to_string(),
+                Stations::IkejiriOhashi,
+            )
+            .add_transition(
+                Stations::IkejiriOhashi,
+                "local".to_string(),
+                Stations::Sangendyaya,
+            )
+            .add_any_state("express".to_string(), Stations::Sangendyaya)
+            .build()
+            .unwrap();
+
+        assert_eq!(Stations::IkejiriOhashi, sm.consume_by("local"));
+        // unknown inputs retain the current state
+        assert_eq!(Stations::IkejiriOhashi, sm.consume_by("rapid"));
+        assert_eq!(Stations::Sangendyaya, sm.consume_by("local"));
+        sm.reset();
+        assert_eq!(Stations::Sangendyaya, sm.consume_by("express"));
+    }
+
+    #[test]
+    fn test_consume_with() {
+        let sm = TableStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .add_transition(Stations::Shibuya, Train::Local, Stations::IkejiriOhashi)
+            .add_transition(Stations::IkejiriOhashi, Train::Local, Stations::Sangendyaya)
+            .ignore(Stations::Sangendyaya, vec![Train::Local])
+            .build()
+            .unwrap();
+        let made = RefCell::new(Vec::new());
+        let make = |station: &Stations| {
+            made.borrow_mut().push(*station);
+            Train::Local
+        };
+
+        assert_eq!(Stations::IkejiriOhashi, sm.consume_with(make));
+        assert_eq!(Stations::Sangendyaya, sm.consume_with(make));
+        assert_eq!(
+            vec![Stations::Shibuya, Stations::IkejiriOhashi],
+            *made.borrow()
+        );
+        // `Sangendyaya` only ignores inputs, so the input isn't made
+        assert_eq!(Stations::Sangendyaya, sm.consume_with(make));
+        assert_eq!(2, made.borrow().len());
+    }
+
+    #[test]
+    fn test_collapse_inputs() {
+        let sm = TableStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .add_transition(Stations::Shibuya, Train::Local, Stations::Sangendyaya)
+            .add_transition(Stations::Shibuya, Train::Express, Stations::Sangendyaya)
+            .add_transition(Stations::IkejiriOhashi, Train::Local, Stations::Sangendyaya)
+            .add_transition(
+                Stations::IkejiriOhashi,
+                Train::Express,
+                Stations::IkejiriOhashi,
+            )
+            .build()
+            .unwrap();
+
+        let collapsed = sm.collapse_inputs();
+        assert_eq!(3, collapsed.len());
+        let from_shibuya: Vec<_> = collapsed
+            .iter()
+            .filter(|((from, _), _)| *from == Stations::Shibuya)
+            .collect();
+        assert_eq!(1, from_shibuya.len());
+        let ((_, class), to) = from_shibuya[0];
+        assert_eq!(&[Train::Local, Train::Express], class.inputs());
+        assert!(class.contains(&Train::Express));
+        assert_eq!(Stations::Sangendyaya, *to);
+    }
+
+    #[test]
+    fn test_peek_chain() {
+        let sm = TableStateMachineBuilder::start()
+            .initial_state(Stations::Shibuya)
+            .add_transition(Stations::Shibuya, Train::Local, Stations::IkejiriOhashi)
+            .add_transition(Stations::IkejiriOhashi, Train::Local, Stations::Sangendyaya)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            vec![Stations::IkejiriOhashi, Stations::Sangendyaya],
+            sm.peek_chain(vec![Train::Local, Train::Local])
+        );
+        // stops at the first undefined transition
+        assert_eq!(
+            vec![Stations::IkejiriOhashi],
+            sm.peek_chain(vec![Train::Local, Train::Express, Train::Local])
+        );
+        assert_eq!(Stations::Shibuya, sm.current_state());
+    }
+}
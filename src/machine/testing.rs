@@ -0,0 +1,342 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug, Display},
+    hash::Hash,
+};
+
+use super::StateMachine;
+
+/// The result of [`soak()`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SoakReport<State, Input>
+where
+    State: Eq + Hash,
+{
+    /// The number of the inputs consumed. It is less than the requested iterations
+    /// if the invariant is violated.
+    pub iterations: usize,
+    /// How many times each state is entered, i.e. the state after each input.
+    /// The counts sum up to `iterations`.
+    pub occupancy: HashMap<State, usize>,
+    /// The first violation of the invariant, if any. The soak stops there.
+    pub violation: Option<Violation<State, Input>>,
+}
+
+/// The state violating the invariant of [`soak()`] and the inputs leading to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Violation<State, Input> {
+    pub state: State,
+    /// The inputs consumed from the start of the soak, the last of which leads to `state`.
+    /// It is empty if the state at the start already violates the invariant.
+    pub inputs: Vec<Input>,
+}
+
+/// SplitMix64, which is small, fast and good enough to pick the inputs.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Soak-tests a live state machine by consuming `iterations` inputs picked at random from
+/// `weighted_inputs`, where each input is picked in proportion to its weight, and checking
+/// `invariant` on the state at the start and after every input.
+/// The inputs are picked by a deterministic PRNG, so the same `seed` reproduces the same run
+/// as long as the state machine is deterministic.
+///
+/// # Panics
+/// It panics if the weights sum up to zero.
+///
+/// # Example
+/// ```
+/// use statemachine_rs::machine::{
+///     builder::BasicStateMachineBuilder, builder::StateMachineBuilder, testing::soak,
+/// };
+///
+/// let sm = BasicStateMachineBuilder::start()
+///     .initial_state(0)
+///     .transition(|level: &i32, step: i32| (level + step).clamp(0, 3))
+///     .build()
+///     .unwrap();
+///
+/// let report = soak(&sm, &[(1, 1), (-1, 1)], 1_000, 7, |level| *level <= 3);
+/// assert_eq!(1_000, report.iterations);
+/// assert_eq!(1_000, report.occupancy.values().sum::<usize>());
+/// assert_eq!(None, report.violation);
+/// ```
+pub fn soak<State, Input>(
+    machine: &impl StateMachine<State, Input>,
+    weighted_inputs: &[(Input, u32)],
+    iterations: usize,
+    seed: u64,
+    invariant: impl Fn(&State) -> bool,
+) -> SoakReport<State, Input>
+where
+    State: Eq + Hash,
+    Input: Clone,
+{
+    let total: u64 = weighted_inputs
+        .iter()
+        .map(|(_, weight)| u64::from(*weight))
+        .sum();
+    assert!(
+        total > 0,
+        "the weights of the inputs must not sum up to zero"
+    );
+    let mut rng = SplitMix64(seed);
+    let mut report = SoakReport {
+        iterations: 0,
+        occupancy: HashMap::new(),
+        violation: None,
+    };
+    let mut inputs = Vec::with_capacity(iterations);
+
+    let state = machine.current_state();
+    if !invariant(&state) {
+        report.violation = Some(Violation { state, inputs });
+        return report;
+    }
+    for _ in 0..iterations {
+        let mut pick = rng.next() % total;
+        let input = weighted_inputs
+            .iter()
+            .find(|(_, weight)| match pick.checked_sub(u64::from(*weight)) {
+                Some(rest) => {
+                    pick = rest;
+                    false
+                }
+                None => true,
+            })
+            .map(|(input, _)| input.clone())
+            .expect("the pick is less than the total weight");
+        inputs.push(input.clone());
+        let state = machine.consume(input);
+        report.iterations += 1;
+        let violated = !invariant(&state);
+        *report.occupancy.entry(state).or_insert(0) += 1;
+        if violated {
+            report.violation = Some(Violation {
+                state: machine.current_state(),
+                inputs,
+            });
+            return report;
+        }
+    }
+    report
+}
+
+/// The result of [`compare_trace()`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceComparison<'a, State> {
+    pub actual_len: usize,
+    pub expected_len: usize,
+    /// The first mismatch, if any.
+    pub mismatch: Option<TraceMismatch<'a, State>>,
+}
+
+/// The first position where the traces of [`compare_trace()`] differ.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceMismatch<'a, State> {
+    pub index: usize,
+    /// The actual state at `index`, or `None` if the actual trace is shorter.
+    pub actual: Option<&'a State>,
+    /// The expected state at `index`, or `None` if the expected trace is shorter.
+    pub expected: Option<&'a State>,
+}
+
+impl<State> TraceComparison<'_, State> {
+    /// Returns `true` if the traces are the same.
+    pub fn is_match(&self) -> bool {
+        self.mismatch.is_none()
+    }
+}
+
+impl<State> Display for TraceComparison<'_, State>
+where
+    State: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mismatch = match &self.mismatch {
+            Some(mismatch) => mismatch,
+            None => return write!(f, "The traces match in all {} states.", self.actual_len),
+        };
+        writeln!(
+            f,
+            "The traces differ at index {} (actual: {} states, expected: {} states)",
+            mismatch.index, self.actual_len, self.expected_len
+        )?;
+        for (label, state) in [("actual", mismatch.actual), ("expected", mismatch.expected)] {
+            match state {
+                Some(state) => writeln!(f, "  {:<9} {:?}", format!("{}:", label), state)?,
+                None => writeln!(f, "  {:<9} (end of trace)", format!("{}:", label))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compares the actual trace of states, e.g. [`crate::machine::BasicStateMachine::history()`]
+/// of a run, against the expected one and reports the first mismatch, so that a failing
+/// test tells where the run went astray instead of only that it did.
+/// A trace shorter than the other one mismatches where it ends.
+///
+/// # Example
+/// ```
+/// use statemachine_rs::machine::testing::compare_trace;
+///
+/// let comparison = compare_trace(&["Off", "On", "Off"], &["Off", "On", "Broken"]);
+/// assert_eq!(Some(2), comparison.mismatch.as_ref().map(|mismatch| mismatch.index));
+/// assert_eq!(
+///     "The traces differ at index 2 (actual: 3 states, expected: 3 states)\n  \
+///      actual:   \"Off\"\n  \
+///      expected: \"Broken\"\n",
+///     comparison.to_string()
+/// );
+/// assert!(compare_trace(&["Off"], &["Off"]).is_match());
+/// ```
+pub fn compare_trace<'a, State>(
+    actual: &'a [State],
+    expected: &'a [State],
+) -> TraceComparison<'a, State>
+where
+    State: PartialEq + Debug,
+{
+    let mismatch = (0..actual.len().max(expected.len()))
+        .map(|index| TraceMismatch {
+            index,
+            actual: actual.get(index),
+            expected: expected.get(index),
+        })
+        .find(|mismatch| mismatch.actual != mismatch.expected);
+    TraceComparison {
+        actual_len: actual.len(),
+        expected_len: expected.len(),
+        mismatch,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{compare_trace, soak, TraceMismatch, Violation};
+    use crate::machine::{
+        builder::{BasicStateMachineBuilder, StateMachineBuilder},
+        StateMachine,
+    };
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    enum Door {
+        Open,
+        Closed,
+        Locked,
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    enum Action {
+        Push,
+        Lock,
+        Unlock,
+    }
+
+    fn door() -> impl StateMachine<Door, Action> {
+        BasicStateMachineBuilder::start()
+            .initial_state(Door::Open)
+            .transition(|door, action| match (door, action) {
+                (Door::Open, Action::Push) => Door::Closed,
+                (Door::Closed, Action::Push) => Door::Open,
+                (Door::Closed, Action::Lock) => Door::Locked,
+                (Door::Locked, Action::Unlock) => Door::Closed,
+                (door, _) => *door,
+            })
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_soak() {
+        let inputs = [(Action::Push, 5), (Action::Lock, 2), (Action::Unlock, 1)];
+        let report = soak(&door(), &inputs, 1_000, 42, |_| true);
+
+        assert_eq!(1_000, report.iterations);
+        assert_eq!(
+            vec![(Door::Open, 212), (Door::Closed, 226), (Door::Locked, 562)]
+                .into_iter()
+                .collect::<HashMap<_, _>>(),
+            report.occupancy
+        );
+        assert_eq!(None, report.violation);
+        // The same seed reproduces the same run.
+        assert_eq!(report, soak(&door(), &inputs, 1_000, 42, |_| true));
+    }
+
+    #[test]
+    fn test_soak_violation() {
+        let inputs = [(Action::Push, 1), (Action::Lock, 1)];
+        let report = soak(&door(), &inputs, 1_000, 42, |door| *door != Door::Locked);
+
+        let violation = report.violation.unwrap();
+        assert_eq!(Door::Locked, violation.state);
+        assert_eq!(report.iterations, violation.inputs.len());
+        assert_eq!(Some(&Action::Lock), violation.inputs.last());
+        assert_eq!(1, report.occupancy[&Door::Locked]);
+
+        let report = soak(&door(), &inputs, 10, 42, |door| *door == Door::Locked);
+        assert_eq!(0, report.iterations);
+        assert_eq!(
+            Some(Violation {
+                state: Door::Open,
+                inputs: Vec::new(),
+            }),
+            report.violation
+        );
+    }
+
+    #[test]
+    fn test_compare_trace() {
+        let sm = door();
+        sm.consume(Action::Push);
+        sm.consume(Action::Lock);
+        let actual = [Door::Open, Door::Closed, Door::Locked];
+        let expected = [Door::Open, Door::Locked, Door::Locked];
+        assert_eq!(actual[2], sm.current_state());
+
+        let comparison = compare_trace(&actual, &expected);
+        assert!(!comparison.is_match());
+        assert_eq!(
+            Some(TraceMismatch {
+                index: 1,
+                actual: Some(&Door::Closed),
+                expected: Some(&Door::Locked),
+            }),
+            comparison.mismatch
+        );
+        assert_eq!(
+            "The traces differ at index 1 (actual: 3 states, expected: 3 states)\n  \
+             actual:   Closed\n  \
+             expected: Locked\n",
+            comparison.to_string()
+        );
+
+        let comparison = compare_trace(&actual[..2], &actual);
+        assert_eq!(
+            Some(TraceMismatch {
+                index: 2,
+                actual: None,
+                expected: Some(&Door::Locked),
+            }),
+            comparison.mismatch
+        );
+        assert!(comparison.to_string().contains("actual:   (end of trace)"));
+
+        let comparison = compare_trace(&actual, &actual);
+        assert!(comparison.is_match());
+        assert_eq!("The traces match in all 3 states.", comparison.to_string());
+    }
+}
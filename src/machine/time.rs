@@ -0,0 +1,340 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    hash::Hash,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The trait is representing the source of "now" for the time-aware features.
+/// [`SystemClock`] is used by default and [`ManualClock`] enables us to
+/// control the time in tests without sleeping.
+pub trait Clock {
+    /// Returns the current instant.
+    fn now(&self) -> Instant;
+}
+
+/// The clock which returns [`std::time::Instant::now()`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// The clock which only moves when it is told to.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+/// use statemachine_rs::machine::time::{Clock, ManualClock};
+///
+/// let clock = ManualClock::new();
+/// let start = clock.now();
+/// clock.advance(Duration::from_secs(3));
+/// assert_eq!(Duration::from_secs(3), clock.now() - start);
+/// ```
+#[derive(Debug)]
+pub struct ManualClock(Mutex<Instant>);
+
+impl ManualClock {
+    /// Creates a new clock which starts from [`std::time::Instant::now()`].
+    pub fn new() -> Self {
+        ManualClock(Mutex::new(Instant::now()))
+    }
+
+    /// Sets the current instant of the clock.
+    pub fn set(&self, now: Instant) {
+        *self.0.lock().unwrap() = now;
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+impl<C> Clock for Rc<C>
+where
+    C: Clock + ?Sized,
+{
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+impl<C> Clock for Arc<C>
+where
+    C: Clock + ?Sized,
+{
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+/// The function which accumulates a duration into the bucket of the state.
+/// It is captured where `State: Eq + Hash` is available, so that the machine
+/// itself doesn't have to require those bounds.
+pub(crate) type Accumulate<State> = fn(&mut HashMap<State, Duration>, &State, Duration);
+
+pub(crate) fn accumulate<State>(
+    buckets: &mut HashMap<State, Duration>,
+    state: &State,
+    elapsed: Duration,
+) where
+    State: Clone + Eq + Hash,
+{
+    *buckets.entry(state.clone()).or_default() += elapsed;
+}
+
+/// The cumulative durations of transitions bucketed by their resulting states.
+pub(crate) struct Timings<State> {
+    buckets: RefCell<HashMap<State, Duration>>,
+    accumulate: Accumulate<State>,
+}
+
+impl<State> Timings<State> {
+    pub(crate) fn new(accumulate: Accumulate<State>) -> Self {
+        Timings {
+            buckets: RefCell::new(HashMap::new()),
+            accumulate,
+        }
+    }
+
+    pub(crate) fn record(&self, state: &State, elapsed: Duration) {
+        (self.accumulate)(&mut self.buckets.borrow_mut(), state, elapsed)
+    }
+
+    pub(crate) fn restore(&self, buckets: HashMap<State, Duration>) {
+        *self.buckets.borrow_mut() = buckets;
+    }
+
+    pub(crate) fn get(&self) -> HashMap<State, Duration>
+    where
+        State: Clone,
+    {
+        self.buckets.borrow().clone()
+    }
+}
+
+/// The function which keeps the longest duration for the pair of the previous and
+/// the next states. It is captured where `State: Eq + Hash` is available like [`Accumulate`].
+pub(crate) type KeepSlowest<State> =
+    fn(&mut HashMap<(State, State), Duration>, &State, &State, Duration);
+
+pub(crate) fn keep_slowest<State>(
+    slowest: &mut HashMap<(State, State), Duration>,
+    prev: &State,
+    next: &State,
+    elapsed: Duration,
+) where
+    State: Clone + Eq + Hash,
+{
+    let slowest = slowest.entry((prev.clone(), next.clone())).or_default();
+    if elapsed > *slowest {
+        *slowest = elapsed;
+    }
+}
+
+/// The longest durations of transitions for each pair of the previous and the next states.
+pub(crate) struct TransitionMetrics<State> {
+    slowest: RefCell<HashMap<(State, State), Duration>>,
+    keep: KeepSlowest<State>,
+}
+
+impl<State> TransitionMetrics<State> {
+    pub(crate) fn new(keep: KeepSlowest<State>) -> Self {
+        TransitionMetrics {
+            slowest: RefCell::new(HashMap::new()),
+            keep,
+        }
+    }
+
+    pub(crate) fn record(&self, prev: &State, next: &State, elapsed: Duration) {
+        (self.keep)(&mut self.slowest.borrow_mut(), prev, next, elapsed)
+    }
+
+    pub(crate) fn restore(&self, slowest: HashMap<(State, State), Duration>) {
+        *self.slowest.borrow_mut() = slowest;
+    }
+
+    pub(crate) fn get(&self) -> HashMap<(State, State), Duration>
+    where
+        State: Clone,
+    {
+        self.slowest.borrow().clone()
+    }
+}
+
+/// What the occupancy of each state is measured by.
+/// See [`crate::machine::builder::BasicStateMachineBuilder::track_occupancy()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OccupancyBasis {
+    /// The time spent in each state, measured by the clock of the machine.
+    /// The time spent so far in the current state is included.
+    DwellTime,
+    /// The number of the transitions resulting in each state. Being the initial state isn't
+    /// a visit, so the initial state is counted only when the machine returns to it.
+    Visits,
+}
+
+/// The weights of the states, each with the order in which the state was first weighed.
+pub(crate) type Weights<State> = HashMap<State, (usize, f64)>;
+
+/// The function which adds a weight to the state.
+/// It is captured where `State: Eq + Hash` is available like [`Accumulate`].
+pub(crate) type Weigh<State> = fn(&mut Weights<State>, &State, f64);
+
+pub(crate) fn weigh<State>(weights: &mut Weights<State>, state: &State, weight: f64)
+where
+    State: Clone + Eq + Hash,
+{
+    let order = weights.len();
+    weights.entry(state.clone()).or_insert((order, 0.0)).1 += weight;
+}
+
+/// The weights of the states the machine has been in, together with the current state
+/// and the time it was entered, which are needed to weigh the dwell time on leaving it.
+pub(crate) struct Occupancy<State> {
+    basis: OccupancyBasis,
+    keep_on_reset: bool,
+    weights: RefCell<Weights<State>>,
+    current: RefCell<(State, Instant)>,
+    weigh: Weigh<State>,
+}
+
+/// The snapshot of [`Occupancy`] to roll it back.
+pub(crate) type OccupancySnapshot<State> = (Weights<State>, (State, Instant));
+
+impl<State> Occupancy<State>
+where
+    State: Clone,
+{
+    pub(crate) fn new(
+        weigh: Weigh<State>,
+        basis: OccupancyBasis,
+        keep_on_reset: bool,
+        current: State,
+        entered_at: Instant,
+    ) -> Self {
+        Occupancy {
+            basis,
+            keep_on_reset,
+            weights: RefCell::new(HashMap::new()),
+            current: RefCell::new((current, entered_at)),
+            weigh,
+        }
+    }
+
+    /// Records that the machine entered `state` at `now`.
+    pub(crate) fn record(&self, state: &State, now: Instant) {
+        let mut current = self.current.borrow_mut();
+        let mut weights = self.weights.borrow_mut();
+        match self.basis {
+            OccupancyBasis::DwellTime => {
+                let dwelled = now.saturating_duration_since(current.1);
+                (self.weigh)(&mut weights, &current.0, dwelled.as_secs_f64())
+            }
+            OccupancyBasis::Visits => (self.weigh)(&mut weights, state, 1.0),
+        }
+        *current = (state.clone(), now);
+    }
+
+    /// Starts over from `initial` at `now` unless the occupancy is kept on reset.
+    /// It is called after the reset is recorded, so that the reset itself isn't counted.
+    pub(crate) fn reset(&self, initial: &State, now: Instant) {
+        if !self.keep_on_reset {
+            self.weights.borrow_mut().clear();
+            *self.current.borrow_mut() = (initial.clone(), now);
+        }
+    }
+
+    /// Returns the normalized weights at `now` in the descending order, or an empty `Vec`
+    /// if nothing is weighed yet. The states of the same weight are in the order in which
+    /// they were first weighed, so that the order doesn't depend on the hashing.
+    pub(crate) fn get(&self, now: Instant) -> Vec<(State, f64)> {
+        let mut weights = self.weights.borrow().clone();
+        if self.basis == OccupancyBasis::DwellTime {
+            let current = self.current.borrow();
+            let dwelled = now.saturating_duration_since(current.1);
+            (self.weigh)(&mut weights, &current.0, dwelled.as_secs_f64());
+        }
+        let total: f64 = weights.values().map(|(_, weight)| weight).sum();
+        if total <= 0.0 {
+            return Vec::new();
+        }
+        let mut fractions: Vec<_> = weights
+            .into_iter()
+            .filter(|(_, (_, weight))| *weight > 0.0)
+            .map(|(state, (order, weight))| (order, state, weight / total))
+            .collect();
+        fractions.sort_by(|a, b| b.2.total_cmp(&a.2).then(a.0.cmp(&b.0)));
+        fractions
+            .into_iter()
+            .map(|(_, state, fraction)| (state, fraction))
+            .collect()
+    }
+
+    pub(crate) fn snapshot(&self) -> OccupancySnapshot<State> {
+        (self.weights.borrow().clone(), self.current.borrow().clone())
+    }
+
+    pub(crate) fn restore(&self, (weights, current): OccupancySnapshot<State>) {
+        *self.weights.borrow_mut() = weights;
+        *self.current.borrow_mut() = current;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use super::{Clock, ManualClock, SystemClock};
+
+    #[test]
+    fn test_system_clock() {
+        let before = Instant::now();
+        assert!(before <= SystemClock.now());
+    }
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        assert_eq!(start, clock.now());
+
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(Duration::from_millis(500), clock.now() - start);
+
+        clock.set(start);
+        assert_eq!(start, clock.now());
+    }
+
+    #[test]
+    fn test_shared_manual_clock() {
+        let clock = Arc::new(ManualClock::new());
+        let shared: Arc<dyn Clock> = clock.clone();
+        let start = shared.now();
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(Duration::from_secs(1), shared.now() - start);
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    any::Any,
+    borrow::Cow,
+    fmt::{Debug, Display},
+    io::Write,
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+};
+
+use super::TransitionFilter;
+
+/// Writes a line per transition, e.g. `Off --Press--> On`.
+/// The formatting functions are captured where `State: Display` and `Input: Display`
+/// are available, so that the machine itself doesn't have to require those bounds.
+pub(crate) struct Tracer<State, Input> {
+    writer: Arc<Mutex<dyn Write + Send>>,
+    describe_state: fn(&State) -> String,
+    describe_input: fn(&Input) -> String,
+    filter: Option<TransitionFilter<State>>,
+}
+
+impl<State, Input> Tracer<State, Input>
+where
+    State: Display,
+    Input: Display,
+{
+    pub(crate) fn new<W>(writer: W) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        Tracer {
+            writer: Arc::new(Mutex::new(writer)),
+            describe_state: |state| state.to_string(),
+            describe_input: |input| input.to_string(),
+            filter: None,
+        }
+    }
+}
+
+impl<State, Input> Clone for Tracer<State, Input> {
+    fn clone(&self) -> Self {
+        Tracer {
+            writer: Arc::clone(&self.writer),
+            describe_state: self.describe_state,
+            describe_input: self.describe_input,
+            filter: self.filter.clone(),
+        }
+    }
+}
+
+impl<State, Input> Tracer<State, Input> {
+    /// Sets the filter which decides whether to write the transition.
+    pub(crate) fn with_filter(mut self, filter: Option<TransitionFilter<State>>) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Returns `true` if the transition from `from` to `to` is written.
+    pub(crate) fn accepts(&self, from: &State, to: &State) -> bool {
+        self.filter.as_ref().map_or(true, |filter| filter(from, to))
+    }
+
+    pub(crate) fn describe_state(&self, state: &State) -> String {
+        (self.describe_state)(state)
+    }
+
+    pub(crate) fn describe_input(&self, input: &Input) -> String {
+        (self.describe_input)(input)
+    }
+
+    /// Writes the transition. Errors on writing are ignored.
+    pub(crate) fn write(&self, from: &str, input: &str, to: &State) {
+        if let Ok(mut writer) = self.writer.lock() {
+            let _ = writeln!(
+                writer,
+                "{} --{}--> {}",
+                from,
+                input,
+                (self.describe_state)(to)
+            );
+        }
+    }
+}
+
+/// Re-panics the panics in the transition with the name of the machine, the current state
+/// and the input. Like [`Tracer`], the formatting functions are captured where
+/// `State: Debug` and `Input: Debug` are available.
+pub(crate) struct PanicContext<State, Input> {
+    name: Cow<'static, str>,
+    describe_state: fn(&State) -> String,
+    describe_input: fn(&Input) -> String,
+}
+
+impl<State, Input> PanicContext<State, Input>
+where
+    State: Debug,
+    Input: Debug,
+{
+    pub(crate) fn new(name: Cow<'static, str>) -> Self {
+        PanicContext {
+            name,
+            describe_state: |state| format!("{:?}", state),
+            describe_input: |input| format!("{:?}", input),
+        }
+    }
+}
+
+impl<State, Input> Clone for PanicContext<State, Input> {
+    fn clone(&self) -> Self {
+        PanicContext {
+            name: self.name.clone(),
+            describe_state: self.describe_state,
+            describe_input: self.describe_input,
+        }
+    }
+}
+
+impl<State, Input> PanicContext<State, Input> {
+    /// Calls `transition` and re-panics with the context if it panics. The input is described
+    /// beforehand since it is moved into the transition, while the state is described only
+    /// on the panic.
+    pub(crate) fn call<T>(
+        &self,
+        state: &State,
+        input: Input,
+        transition: impl FnOnce(&State, Input) -> T,
+    ) -> T {
+        let by = (self.describe_input)(&input);
+        match panic::catch_unwind(AssertUnwindSafe(|| transition(state, input))) {
+            Ok(next) => next,
+            Err(payload) => panic!(
+                "the state machine `{}` panicked in the transition from {} by {}: {}",
+                self.name,
+                (self.describe_state)(state),
+                by,
+                payload_message(&*payload)
+            ),
+        }
+    }
+}
+
+/// Returns the message of the panic payload, which is `&str` for `panic!("literal")`
+/// and `String` for the formatted ones.
+fn payload_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
@@ -0,0 +1,325 @@
+use std::{mem, time::Duration};
+
+use super::{error::StateMachineError, BasicStateMachine, StateMachine};
+
+/// A transition consumed through the transaction and committed later.
+struct Pending<State, Input> {
+    prev: State,
+    next: State,
+    /// The input kept for [`BasicStateMachine::status()`] if it is tracked.
+    input: Option<Input>,
+    /// How long the transition function took.
+    elapsed: Duration,
+    /// The described state and input to be written by the tracer.
+    trace: Option<(String, String)>,
+}
+
+/// An open transaction on the state machine, created by [`BasicStateMachine::begin()`].
+///
+/// The inputs consumed through it are applied to a shadow copy of the state, so the
+/// state machine itself keeps the state at the time the transaction began until
+/// [`Transaction::commit()`] publishes the shadow state.
+/// [`Transaction::rollback()`] or dropping it discards the shadow state.
+pub struct Transaction<'a, State, Input, Transition, M = ()>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+{
+    sm: &'a BasicStateMachine<State, Input, Transition, M>,
+    shadow: State,
+    generation: u64,
+    transitions: Vec<Pending<State, Input>>,
+}
+
+impl<'a, State, Input, Transition, M> Transaction<'a, State, Input, Transition, M>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+{
+    pub(crate) fn new(sm: &'a BasicStateMachine<State, Input, Transition, M>) -> Self {
+        Transaction {
+            shadow: sm.current_state(),
+            generation: sm.generation(),
+            sm,
+            transitions: Vec::new(),
+        }
+    }
+
+    /// Returns the shadow state of the transaction.
+    pub fn current_state(&self) -> State {
+        self.shadow.clone()
+    }
+
+    /// Transits the shadow state like [`BasicStateMachine::try_consume()`].
+    /// The input validator and the guards are consulted and the transition function is timed
+    /// right away, while the exit hooks and everything after them are deferred to
+    /// [`Transaction::commit()`].
+    pub fn consume(&mut self, input: Input) -> Result<State, StateMachineError> {
+        let sm = self.sm;
+        if let Some(validator) = &sm.input_validator {
+            validator(&input).map_err(|reason| StateMachineError::InvalidInput { reason })?;
+        }
+        if let Some(index) = sm.failing_guard(&self.shadow, &input) {
+            return Err(StateMachineError::GuardFailed {
+                name: sm.guards[index].0.clone(),
+            });
+        }
+        let trace = sm.tracer.as_ref().map(|tracer| {
+            (
+                tracer.describe_state(&self.shadow),
+                tracer.describe_input(&input),
+            )
+        });
+        let observed = sm.telemetry.observe(&input);
+        let started_at = sm.clock.now();
+        let new_state = sm.apply(&self.shadow, input);
+        let elapsed = sm.clock.now() - started_at;
+        let trace = trace.filter(|_| {
+            sm.tracer
+                .as_ref()
+                .is_some_and(|tracer| tracer.accepts(&self.shadow, &new_state))
+        });
+        let prev = mem::replace(&mut self.shadow, new_state.clone());
+        self.transitions.push(Pending {
+            prev,
+            next: new_state.clone(),
+            input: observed,
+            elapsed,
+            trace,
+        });
+        Ok(new_state)
+    }
+
+    /// Publishes the shadow state to the state machine with a single swap and returns it.
+    ///
+    /// The exit hooks are consulted first for every transition in the transaction, and if any
+    /// of them vetoes, it returns [`StateMachineError::ExitVetoed`] and discards the shadow
+    /// state. Otherwise, once the shadow state is swapped in, every transition is recorded to
+    /// the history, timed, traced and notified to the enter hooks, the metric sinks and
+    /// the observers in order, like the ones consumed via [`BasicStateMachine::try_consume()`].
+    ///
+    /// It returns [`StateMachineError::StaleGeneration`] and discards the shadow state if
+    /// the state machine has been changed since the transaction began.
+    pub fn commit(mut self) -> Result<State, StateMachineError> {
+        let sm = self.sm;
+        let actual = sm.generation();
+        if actual != self.generation {
+            return Err(StateMachineError::StaleGeneration {
+                expected: self.generation,
+                actual,
+            });
+        }
+        for pending in &self.transitions {
+            for hook in &sm.exit_hooks {
+                hook(&pending.prev).map_err(|reason| StateMachineError::ExitVetoed { reason })?;
+            }
+        }
+        sm.current_state.borrow_mut().set(self.shadow.clone());
+        if let Some(guard_cache) = &sm.guard_cache {
+            guard_cache.clear();
+        }
+        for pending in mem::take(&mut self.transitions) {
+            sm.record_transition(&pending.next, pending.input);
+            sm.committed(&pending.prev, &pending.next, pending.elapsed, pending.trace);
+        }
+        Ok(self.shadow.clone())
+    }
+
+    /// Discards the shadow state. It is the same as dropping the transaction.
+    pub fn rollback(self) {}
+}
+
+impl<State, Input, Transition, M> Drop for Transaction<'_, State, Input, Transition, M>
+where
+    Transition: Fn(&State, Input) -> State,
+    State: Clone,
+{
+    fn drop(&mut self) {
+        self.sm.in_transaction.set(false);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Write,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use crate::machine::{
+        builder::{BasicStateMachineBuilder, StateMachineBuilder},
+        error::StateMachineError,
+        time::ManualClock,
+        StateMachine,
+    };
+
+    #[derive(Clone)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_commit() {
+        let transitions = Arc::new(Mutex::new(Vec::new()));
+        let sm = {
+            let transitions = Arc::clone(&transitions);
+            BasicStateMachineBuilder::start()
+                .initial_state(0)
+                .transition(|state: &i32, input: i32| state + input)
+                .metric_sink(move |prev: &i32, next: &i32| {
+                    transitions.lock().unwrap().push((*prev, *next))
+                })
+                .with_history()
+                .build()
+                .unwrap()
+        };
+
+        let mut transaction = sm.begin().unwrap();
+        assert_eq!(1, transaction.consume(1).unwrap());
+        assert_eq!(3, transaction.consume(2).unwrap());
+        assert_eq!(3, transaction.current_state());
+        assert_eq!(0, sm.current_state());
+        assert!(transitions.lock().unwrap().is_empty());
+
+        assert_eq!(3, transaction.commit().unwrap());
+        assert_eq!(3, sm.current_state());
+        assert_eq!(vec![0, 1, 3], sm.history());
+        assert_eq!(vec![(0, 1), (1, 3)], *transitions.lock().unwrap());
+        assert!(sm.begin().is_ok());
+    }
+
+    #[test]
+    fn test_commit_bookkeeping() {
+        let clock = Arc::new(ManualClock::new());
+        let ticking = Arc::clone(&clock);
+        let buffer = SharedBuffer(Arc::new(Mutex::new(Vec::new())));
+        let busy = Arc::new(AtomicBool::new(true));
+        let exits = Arc::new(Mutex::new(Vec::new()));
+        let sm = {
+            let (busy, exits) = (Arc::clone(&busy), Arc::clone(&exits));
+            BasicStateMachineBuilder::start()
+                .initial_state(0)
+                .transition(move |state: &i32, input: i32| {
+                    ticking.advance(Duration::from_millis(10));
+                    state + input
+                })
+                .clock(Arc::clone(&clock))
+                .with_timing()
+                .with_metrics()
+                .trace_to(buffer.clone())
+                .on_exit_state(1, move |state| {
+                    exits.lock().unwrap().push(*state);
+                    if busy.load(Ordering::SeqCst) {
+                        Err("busy".to_string())
+                    } else {
+                        Ok(())
+                    }
+                })
+                .build()
+                .unwrap()
+        };
+
+        // The exit hooks are deferred to the commit, which vetoes the whole transaction.
+        let mut transaction = sm.begin().unwrap();
+        transaction.consume(1).unwrap();
+        transaction.consume(2).unwrap();
+        assert!(exits.lock().unwrap().is_empty());
+        assert!(matches!(
+            transaction.commit(),
+            Err(StateMachineError::ExitVetoed { .. })
+        ));
+        assert_eq!(vec![1], *exits.lock().unwrap());
+        assert_eq!(0, sm.current_state());
+        assert!(buffer.0.lock().unwrap().is_empty());
+        assert!(sm.timing_by_state().is_empty());
+
+        busy.store(false, Ordering::SeqCst);
+        let mut transaction = sm.begin().unwrap();
+        transaction.consume(1).unwrap();
+        transaction.consume(2).unwrap();
+        assert_eq!(3, transaction.commit().unwrap());
+        assert_eq!(
+            "0 --1--> 1\n1 --2--> 3\n",
+            String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap()
+        );
+        assert_eq!(
+            Some(&Duration::from_millis(10)),
+            sm.timing_by_state().get(&3)
+        );
+        assert_eq!(2, sm.slowest_transitions(10).len());
+        assert_eq!(2, sm.status().transition_count);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|state: &i32, input: i32| state + input)
+            .with_history()
+            .build()
+            .unwrap();
+
+        let mut transaction = sm.begin().unwrap();
+        transaction.consume(1).unwrap();
+        transaction.rollback();
+        assert_eq!(0, sm.current_state());
+
+        {
+            let mut transaction = sm.begin().unwrap();
+            transaction.consume(2).unwrap();
+        }
+        assert_eq!(0, sm.current_state());
+        assert_eq!(vec![0], sm.history());
+        assert_eq!(0, sm.generation());
+    }
+
+    #[test]
+    fn test_nested_begin() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|state: &i32, input: i32| state + input)
+            .build()
+            .unwrap();
+
+        let transaction = sm.begin().unwrap();
+        assert!(matches!(
+            sm.begin(),
+            Err(StateMachineError::TransactionInProgress)
+        ));
+        drop(transaction);
+        assert!(sm.begin().is_ok());
+    }
+
+    #[test]
+    fn test_stale_commit() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(0)
+            .transition(|state: &i32, input: i32| state + input)
+            .build()
+            .unwrap();
+
+        let mut transaction = sm.begin().unwrap();
+        transaction.consume(1).unwrap();
+        sm.consume(10);
+        assert!(matches!(
+            transaction.commit(),
+            Err(StateMachineError::StaleGeneration {
+                expected: 0,
+                actual: 1
+            })
+        ));
+        assert_eq!(10, sm.current_state());
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll, Waker},
+};
+
+/// The shared slot between [`WatchFor`] and the state machine.
+/// The target is captured as a predicate where `State: PartialEq` is available,
+/// so that the machine itself doesn't have to require the bound.
+pub(crate) struct Watcher<State> {
+    matches: Box<dyn Fn(&State) -> bool + Send>,
+    reached: bool,
+    waker: Option<Waker>,
+}
+
+/// The future which resolves once the state machine reaches the target state.
+/// It is created by [`crate::machine::BasicStateMachine::watch_for()`].
+///
+/// It doesn't depend on any particular async runtime. Transitions made after
+/// the future is created are observed even if the future hasn't been polled yet,
+/// so the target is never missed even if the machine leaves it immediately.
+pub struct WatchFor<State> {
+    watcher: Arc<Mutex<Watcher<State>>>,
+}
+
+impl<State> WatchFor<State> {
+    pub(crate) fn new<F>(matches: F, reached: bool) -> Self
+    where
+        F: Fn(&State) -> bool + Send + 'static,
+    {
+        WatchFor {
+            watcher: Arc::new(Mutex::new(Watcher {
+                matches: Box::new(matches),
+                reached,
+                waker: None,
+            })),
+        }
+    }
+
+    pub(crate) fn watcher(&self) -> Weak<Mutex<Watcher<State>>> {
+        Arc::downgrade(&self.watcher)
+    }
+}
+
+impl<State> Future for WatchFor<State> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut watcher = self.watcher.lock().unwrap();
+        if watcher.reached {
+            Poll::Ready(())
+        } else {
+            watcher.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// Notifies `state` to the watchers and wakes up the futures which reach their targets.
+/// The watchers which are reached or whose futures are dropped are removed.
+pub(crate) fn notify<State>(watchers: &mut Vec<Weak<Mutex<Watcher<State>>>>, state: &State) {
+    watchers.retain(|watcher| {
+        let watcher = match watcher.upgrade() {
+            Some(watcher) => watcher,
+            None => return false,
+        };
+        let mut watcher = watcher.lock().unwrap();
+        if (watcher.matches)(state) {
+            watcher.reached = true;
+            if let Some(waker) = watcher.waker.take() {
+                waker.wake();
+            }
+            false
+        } else {
+            true
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        future::Future,
+        pin::Pin,
+        sync::Arc,
+        task::{Context, Poll, Wake},
+        thread::{self, Thread},
+    };
+
+    use crate::machine::{
+        builder::{BasicStateMachineBuilder, StateMachineBuilder},
+        StateMachine,
+    };
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    enum ButtonState {
+        On,
+        Off,
+    }
+
+    enum Input {
+        Press,
+    }
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Runs `future` to completion on the current thread.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Arc::new(ThreadWaker(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            match Pin::new(&mut future).poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_watch_for() {
+        let sm = BasicStateMachineBuilder::start()
+            .initial_state(ButtonState::Off)
+            .transition(|state, input| match (state, input) {
+                (ButtonState::On, Input::Press) => ButtonState::Off,
+                (ButtonState::Off, Input::Press) => ButtonState::On,
+            })
+            .build()
+            .unwrap();
+
+        let on = sm.watch_for(ButtonState::On);
+        let waiting = thread::spawn(move || block_on(on));
+        sm.consume(Input::Press);
+        waiting.join().unwrap();
+
+        // the target is observed even if the machine leaves it before polling
+        let off = sm.watch_for(ButtonState::Off);
+        sm.consume(Input::Press);
+        sm.consume(Input::Press);
+        assert_eq!(ButtonState::On, sm.current_state());
+        block_on(off);
+
+        // resolves immediately if the machine is already in the target
+        block_on(sm.watch_for(ButtonState::On));
+    }
+}
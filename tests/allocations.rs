@@ -0,0 +1,94 @@
+//! Asserts that the hot paths don't allocate. The counting allocator is installed
+//! for this test binary only, so that the unit tests run on the system allocator.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+use statemachine_rs::machine::{
+    builder::{BasicStateMachineBuilder, StateMachineBuilder, TableStateMachineBuilder},
+    StateMachine,
+};
+
+/// Counts the allocations per thread, so that the other tests running
+/// in parallel don't affect the count.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(|allocations| allocations.get())
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum Stations {
+    Shibuya,
+    IkejiriOhashi,
+    Sangendyaya,
+}
+
+#[test]
+fn test_consume_by_does_not_allocate() {
+    let sm = TableStateMachineBuilder::start()
+        .initial_state(Stations::Shibuya)
+        .add_transition(
+            Stations::Shibuya,
+            "local".to_string(),
+            Stations::IkejiriOhashi,
+        )
+        .add_transition(
+            Stations::IkejiriOhashi,
+            "local".to_string(),
+            Stations::Sangendyaya,
+        )
+        .add_any_state("express".to_string(), Stations::Sangendyaya)
+        .build()
+        .unwrap();
+
+    let before = allocations();
+    assert_eq!(Stations::IkejiriOhashi, sm.consume_by("local"));
+    // unknown inputs retain the current state
+    assert_eq!(Stations::IkejiriOhashi, sm.consume_by("rapid"));
+    assert_eq!(Stations::Sangendyaya, sm.consume_by("local"));
+    sm.reset();
+    assert_eq!(Stations::Sangendyaya, sm.consume_by("express"));
+    assert_eq!(before, allocations());
+}
+
+#[test]
+fn test_bounded_history_does_not_allocate() {
+    let sm = BasicStateMachineBuilder::start()
+        .initial_state(0u32)
+        .transition(|state: &u32, input: u32| state + input)
+        .history_limit(3)
+        .build()
+        .unwrap();
+    // fill the ring buffer up to the limit
+    for _ in 0..3 {
+        sm.consume(1);
+    }
+
+    let before = allocations();
+    for _ in 0..100 {
+        sm.consume(1);
+    }
+    assert_eq!(before, allocations());
+    assert_eq!(vec![101, 102, 103], sm.history());
+}